@@ -201,8 +201,25 @@ defmodule Portal.Presence do
       end
     end
 
+    @doc """
+    Marks the gateway as draining. It stays online for its existing flows but no longer
+    receives new ones.
+    """
+    def start_draining(%Gateway{} = gateway) do
+      with {:ok, _} <-
+             __MODULE__.Account.update(gateway.account_id, gateway.id, %{draining: true}) do
+        :ok
+      end
+    end
+
+    @doc """
+    Returns all connected gateways of the account that accept new flows, i.e. that aren't draining.
+    """
     def all_connected_gateways(account_id) do
       __MODULE__.Account.list(account_id)
+      |> Enum.reject(fn {_gateway_id, %{metas: [meta | _]}} ->
+        Map.get(meta, :draining, false)
+      end)
       |> Enum.map(fn {gateway_id, %{metas: [meta | _]}} ->
         gateway_from_presence_meta(gateway_id, account_id, meta)
       end)
@@ -266,6 +283,10 @@ defmodule Portal.Presence do
         )
       end
 
+      def update(account_id, gateway_id, meta) do
+        Portal.Presence.update(self(), topic(account_id), gateway_id, &Map.merge(&1, meta))
+      end
+
       def subscribe(account_id) do
         account_id
         |> topic()
@@ -364,6 +385,32 @@ defmodule Portal.Presence do
       end)
     end
 
+    @doc """
+    Marks the relay as draining. It stays connected for its existing allocations but
+    is no longer handed out to clients and gateways.
+    """
+    def start_draining(%Relay{id: id}) do
+      with {:ok, _} <-
+             Portal.Presence.update(
+               self(),
+               __MODULE__.Global.topic(),
+               id,
+               &Map.put(&1, :draining, true)
+             ) do
+        :ok
+      end
+    end
+
+    @doc """
+    Drops draining relays from the given ones, unless all of them are draining.
+    """
+    def prefer_not_draining(relays) do
+      case Enum.reject(relays, & &1.draining) do
+        [] -> relays
+        not_draining -> not_draining
+      end
+    end
+
     @doc """
     Disconnects a relay from presence.
     """
@@ -385,7 +432,8 @@ defmodule Portal.Presence do
             ipv6: meta.ipv6,
             port: meta.port,
             lat: Map.get(meta, :lat),
-            lon: Map.get(meta, :lon)
+            lon: Map.get(meta, :lon),
+            draining: Map.get(meta, :draining, false)
           }
         end)
 
@@ -14,7 +14,8 @@ defmodule Portal.Relay do
     :ipv6,
     :port,
     :lat,
-    :lon
+    :lon,
+    draining: false
   ]
 
   @type t :: %__MODULE__{
@@ -24,7 +25,8 @@ defmodule Portal.Relay do
           ipv6: String.t() | nil,
           port: integer(),
           lat: float() | nil,
-          lon: float() | nil
+          lon: float() | nil,
+          draining: boolean()
         }
 
   @doc """
@@ -218,7 +218,9 @@ defmodule PortalAPI.Client.Channel do
           socket.assigns.subject.context.remote_ip_location_lon
         }
 
-        relays = load_balance_relays(location, all_online_relays)
+        relays =
+          load_balance_relays(location, Presence.Relays.prefer_not_draining(all_online_relays))
+
         socket = cache_relays(socket, relays)
 
         push(socket, "relays_presence", %{
@@ -318,6 +320,27 @@ defmodule PortalAPI.Client.Channel do
     end
   end
 
+  # The gateway is draining, the client can retry with another one of the site.
+  def handle_info({:flow_rejected, _socket_ref, rid_bytes, gateway_id, reason}, socket) do
+    resource_id = Ecto.UUID.load!(rid_bytes)
+    pending_flows = Map.get(socket.assigns, :pending_flows, %{})
+
+    if Map.has_key?(pending_flows, resource_id) do
+      Logger.debug("Gateway rejected flow",
+        gateway_id: gateway_id,
+        resource_id: resource_id,
+        reason: reason
+      )
+
+      push(socket, "flow_creation_failed", %{resource_id: resource_id, reason: :offline})
+
+      socket = cancel_pending_flow(socket, resource_id)
+      {:noreply, socket}
+    else
+      {:noreply, socket}
+    end
+  end
+
   def handle_info({:flow_creation_timeout, resource_id}, socket) do
     pending_flows = Map.get(socket.assigns, :pending_flows, %{})
 
@@ -720,7 +743,7 @@ defmodule PortalAPI.Client.Channel do
       socket.assigns.subject.context.remote_ip_location_lon
     }
 
-    relays = load_balance_relays(location, relays)
+    relays = load_balance_relays(location, Presence.Relays.prefer_not_draining(relays))
 
     {:ok, relays}
   end
@@ -189,7 +189,9 @@ defmodule PortalAPI.Gateway.Channel do
           socket.assigns.session.remote_ip_location_lon
         }
 
-        relays = load_balance_relays(location, all_online_relays)
+        relays =
+          load_balance_relays(location, Presence.Relays.prefer_not_draining(all_online_relays))
+
         socket = cache_relays(socket, relays)
 
         push(socket, "relays_presence", %{
@@ -404,6 +406,22 @@ defmodule PortalAPI.Gateway.Channel do
     end
   end
 
+  def handle_in("flow_rejected", %{"ref" => signed_ref, "reason" => reason}, socket) do
+    case decode_ref(socket, signed_ref) do
+      {:ok, {channel_pid, socket_ref, resource_id, _preshared_key, _ice_credentials}} ->
+        send(
+          channel_pid,
+          {:flow_rejected, socket_ref, resource_id, socket.assigns.gateway.id, reason}
+        )
+
+        {:reply, :ok, socket}
+
+      {:error, :invalid_ref} ->
+        Logger.error("Gateway replied with an invalid ref")
+        {:reply, {:error, %{reason: :invalid_ref}}, socket}
+    end
+  end
+
   # DEPRECATED IN 1.4
   @impl true
   def handle_in(
@@ -463,6 +481,21 @@ defmodule PortalAPI.Gateway.Channel do
     {:noreply, socket}
   end
 
+  def handle_in("draining", %{"max_drain_secs" => max_drain_secs}, socket) do
+    gateway = socket.assigns.gateway
+
+    Logger.info("Gateway is draining",
+      gateway_id: gateway.id,
+      site_id: gateway.site_id,
+      account_id: gateway.account_id,
+      max_drain_secs: max_drain_secs
+    )
+
+    :ok = Presence.Gateways.start_draining(gateway)
+
+    {:noreply, socket}
+  end
+
   # Catch-all for unknown messages
   def handle_in(message, payload, socket) do
     Logger.error("Unknown gateway message", message: message, payload: payload)
@@ -505,7 +538,7 @@ defmodule PortalAPI.Gateway.Channel do
       socket.assigns.session.remote_ip_location_lon
     }
 
-    relays = load_balance_relays(location, relays)
+    relays = load_balance_relays(location, Presence.Relays.prefer_not_draining(relays))
 
     {:ok, relays}
   end
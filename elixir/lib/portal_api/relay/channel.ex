@@ -47,8 +47,18 @@ defmodule PortalAPI.Relay.Channel do
     end
   end
 
-  # Catch-all for unknown messages
   @impl true
+  def handle_in("draining", %{"max_drain_secs" => max_drain_secs}, socket) do
+    relay = socket.assigns.relay
+
+    Logger.info("Relay is draining", relay_id: relay.id, max_drain_secs: max_drain_secs)
+
+    :ok = Presence.Relays.start_draining(relay)
+
+    {:noreply, socket}
+  end
+
+  # Catch-all for unknown messages
   def handle_in(message, payload, socket) do
     Logger.error("Unknown relay message", message: message, payload: payload)
 
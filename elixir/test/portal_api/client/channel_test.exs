@@ -3108,6 +3108,60 @@ defmodule PortalAPI.Client.ChannelTest do
     end
   end
 
+  describe "handle_info/2 flow_rejected" do
+    test "pushes flow_creation_failed when the gateway rejects the flow", %{
+      dns_resource: resource,
+      dns_resource_policy: policy,
+      membership: membership,
+      client: client,
+      gateway_token: gateway_token,
+      gateway: gateway,
+      global_relay: global_relay,
+      subject: subject
+    } do
+      socket = join_channel(client, subject)
+      :ok = Portal.Presence.Relays.connect(global_relay)
+      :ok = Channels.register_gateway(gateway.id)
+      :ok = connect_gateway_presence(gateway, gateway_token.id)
+
+      send(socket.channel_pid, %Changes.Change{
+        lsn: System.unique_integer([:positive, :monotonic]),
+        op: :insert,
+        struct: resource
+      })
+
+      send(socket.channel_pid, %Changes.Change{
+        lsn: System.unique_integer([:positive, :monotonic]),
+        op: :insert,
+        struct: policy
+      })
+
+      send(socket.channel_pid, %Changes.Change{
+        lsn: System.unique_integer([:positive, :monotonic]),
+        op: :insert,
+        struct: membership
+      })
+
+      push(socket, "create_flow", %{
+        "resource_id" => resource.id,
+        "connected_gateway_ids" => []
+      })
+
+      assert_receive {:authorize_policy, {channel_pid, socket_ref}, _payload}
+
+      rid_bytes = Ecto.UUID.dump!(resource.id)
+      send(channel_pid, {:flow_rejected, socket_ref, rid_bytes, gateway.id, "draining"})
+
+      assert_push "flow_creation_failed", %{resource_id: resource_id, reason: :offline}
+      assert resource_id == resource.id
+
+      # The pending flow is gone, thus the timeout doesn't fire a second time
+      send(channel_pid, {:flow_creation_timeout, resource.id})
+
+      refute_push "flow_creation_failed", _
+    end
+  end
+
   describe "handle_in/3 prepare_connection" do
     test "returns error when resource is not found", %{client: client, subject: subject} do
       socket = join_channel(client, subject)
@@ -2016,6 +2016,89 @@ defmodule PortalAPI.Gateway.ChannelTest do
       assert_reply push_ref, :error, %{reason: :invalid_ref}
     end
 
+    test "flow_rejected forwards the rejection to the client channel", %{
+      client: client,
+      resource: resource,
+      gateway: gateway,
+      site: site,
+      token: token,
+      subject: subject
+    } do
+      socket = join_channel(gateway, site, token)
+      assert_push "init", %{relays: _}
+
+      channel_pid = self()
+      socket_ref = make_ref()
+      gateway_id = gateway.id
+      rid_bytes = Ecto.UUID.dump!(resource.id)
+
+      send(
+        socket.channel_pid,
+        {:authorize_policy, {channel_pid, socket_ref},
+         %{
+           client:
+             PortalAPI.Gateway.Views.Client.render(
+               client,
+               Portal.ClientFixtures.generate_public_key(),
+               "PSK",
+               @test_user_agent
+             ),
+           subject: PortalAPI.Gateway.Views.Subject.render(subject),
+           resource: PortalAPI.Gateway.Views.Resource.render(to_cache(resource)),
+           resource_id: to_cache(resource).id,
+           policy_authorization_id: Ecto.UUID.generate(),
+           authorization_expires_at: DateTime.utc_now() |> DateTime.add(30, :second),
+           ice_credentials: %{
+             client: %{username: "A", password: "B"},
+             gateway: %{username: "C", password: "D"}
+           },
+           preshared_key: "PSK"
+         }}
+      )
+
+      assert_push "authorize_flow", %{ref: ref}
+      push_ref = push(socket, "flow_rejected", %{"ref" => ref, "reason" => "draining"})
+
+      assert_reply push_ref, :ok
+
+      assert_receive {:flow_rejected, ^socket_ref, ^rid_bytes, ^gateway_id, "draining"}
+    end
+
+    test "flow_rejected pushes an error when ref is invalid", %{
+      gateway: gateway,
+      site: site,
+      token: token
+    } do
+      socket = join_channel(gateway, site, token)
+      assert_push "init", %{relays: _}
+
+      push_ref = push(socket, "flow_rejected", %{"ref" => "foo", "reason" => "draining"})
+
+      assert_reply push_ref, :error, %{reason: :invalid_ref}
+    end
+
+    test "draining stops handing out the gateway for new flows", %{
+      account: account,
+      gateway: gateway,
+      site: site,
+      token: token
+    } do
+      socket = join_channel(gateway, site, token)
+      assert_push "init", %{relays: _}
+
+      assert [%{id: gateway_id}] = Portal.Presence.Gateways.all_connected_gateways(account.id)
+      assert gateway_id == gateway.id
+
+      push(socket, "draining", %{"max_drain_secs" => 3600})
+      :sys.get_state(socket.channel_pid)
+
+      assert Portal.Presence.Gateways.all_connected_gateways(account.id) == []
+
+      # The gateway is still online for its existing flows
+      assert {:ok, %{id: ^gateway_id}} =
+               Portal.Presence.Gateways.fetch_gateway(account.id, gateway.id)
+    end
+
     test "connection ready forwards RFC session description to the client channel", %{
       client: client,
       account: account,
@@ -94,6 +94,27 @@ defmodule PortalAPI.Relay.ChannelTest do
     end
   end
 
+  describe "handle_in/3 draining" do
+    test "stops handing out the relay but keeps it connected", %{socket: socket} do
+      assert_push "init", %{}
+      relay = :sys.get_state(socket.channel_pid).assigns.relay
+
+      push(socket, "draining", %{"max_drain_secs" => 3600})
+      :sys.get_state(socket.channel_pid)
+
+      {:ok, relays} = Portal.Presence.Relays.all_connected_relays()
+      assert %{draining: true} = Enum.find(relays, &(&1.id == relay.id))
+    end
+
+    test "draining relays are only handed out if there are no others" do
+      draining = %Portal.Relay{id: Ecto.UUID.generate(), draining: true}
+      other = %Portal.Relay{id: Ecto.UUID.generate()}
+
+      assert Portal.Presence.Relays.prefer_not_draining([draining, other]) == [other]
+      assert Portal.Presence.Relays.prefer_not_draining([draining]) == [draining]
+    end
+  end
+
   describe "handle_in/3 for unknown messages" do
     test "it doesn't crash", %{socket: socket} do
       assert_push "init", %{}
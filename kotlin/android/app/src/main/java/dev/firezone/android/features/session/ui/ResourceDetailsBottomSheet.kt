@@ -65,6 +65,7 @@ class ResourceDetailsBottomSheet(
                     StatusEnum.ONLINE -> "Gateway connected"
                     StatusEnum.OFFLINE -> "All Gateways offline"
                     StatusEnum.UNKNOWN -> "No activity"
+                    StatusEnum.DEGRADED -> "Resource degraded"
                 }
             siteStatusTextView.text = statusText
             siteStatusLayout.visibility = View.VISIBLE
@@ -76,6 +77,7 @@ class ResourceDetailsBottomSheet(
                     StatusEnum.ONLINE -> Color.GREEN
                     StatusEnum.OFFLINE -> Color.RED
                     StatusEnum.UNKNOWN -> Color.GRAY
+                    StatusEnum.DEGRADED -> Color.YELLOW
                 }
             val dotDrawable = GradientDrawable()
             dotDrawable.shape = GradientDrawable.OVAL
@@ -738,4 +738,5 @@ private fun uniffi.connlib.ResourceStatus.toModel() =
         uniffi.connlib.ResourceStatus.UNKNOWN -> StatusEnum.UNKNOWN
         uniffi.connlib.ResourceStatus.ONLINE -> StatusEnum.ONLINE
         uniffi.connlib.ResourceStatus.OFFLINE -> StatusEnum.OFFLINE
+        uniffi.connlib.ResourceStatus.DEGRADED -> StatusEnum.DEGRADED
     }
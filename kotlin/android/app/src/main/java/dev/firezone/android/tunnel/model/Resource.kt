@@ -43,4 +43,7 @@ enum class StatusEnum {
 
     @Json(name = "Online")
     ONLINE,
+
+    @Json(name = "Degraded")
+    DEGRADED,
 }
@@ -1,9 +1,11 @@
 #![expect(clippy::print_stdout, reason = "We are a CLI.")]
 
-use std::{process::Command, sync::LazyLock};
+use std::{path::PathBuf, process::Command, sync::LazyLock};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use connlib_model::{GatewayStatus, ResourceView};
+use headless_control::{Request, Response};
 use secrecy::{ExposeSecret as _, SecretString};
 use tracing_subscriber::{EnvFilter, util::SubscriberInitExt};
 
@@ -26,6 +28,7 @@ fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    use ClientCommand::*;
     use Component::*;
     use GatewayCommand::*;
 
@@ -79,6 +82,28 @@ fn main() -> Result<()> {
 
             println!("Successfully disabled `firezone-gateway.service`");
         }
+        Client { socket, command } => {
+            let request = match &command {
+                Status { .. } => Request::Status,
+                Resources { .. } => Request::Resources,
+                Reset => Request::Reset,
+                InternetResource { state } => Request::SetInternetResourceState {
+                    active: matches!(state, Toggle::On),
+                },
+                LogFilter { directives } => Request::ApplyLogFilter {
+                    directives: directives.clone(),
+                },
+            };
+            let json = matches!(command, Status { json: true } | Resources { json: true });
+
+            match send_request(&socket, &request)? {
+                Response::Error { message } => anyhow::bail!("{message}"),
+                response if json => println!("{}", serde_json::to_string_pretty(&response)?),
+                Response::Status(status) => print_status(status),
+                Response::Resources { resources } => print_resources(resources),
+                Response::Ok => println!("OK"),
+            }
+        }
     }
 
     Ok(())
@@ -95,6 +120,15 @@ struct Cli {
 enum Component {
     #[command(subcommand)]
     Gateway(GatewayCommand),
+    /// Control a running headless Client.
+    Client {
+        /// The control socket of the headless Client.
+        #[arg(long, env = "FIREZONE_CONTROL_SOCKET", default_value = headless_control::DEFAULT_SOCKET_PATH)]
+        socket: PathBuf,
+
+        #[command(subcommand)]
+        command: ClientCommand,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -111,6 +145,95 @@ enum GatewayCommand {
     DisableService,
 }
 
+#[derive(Debug, Subcommand)]
+enum ClientCommand {
+    /// Show whether the tunnel is up and the status of our connections to Gateways.
+    Status {
+        /// Print the raw JSON response.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// List the Resources we have access to.
+    Resources {
+        /// Print the raw JSON response.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Reconnect to the portal and re-establish all connections.
+    Reset,
+    /// Activate or deactivate the Internet Resource.
+    InternetResource { state: Toggle },
+    /// Replace the log filter of the headless Client, e.g. `info,connlib=debug`.
+    LogFilter { directives: String },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Toggle {
+    On,
+    Off,
+}
+
+#[cfg(unix)]
+fn send_request(socket: &std::path::Path, request: &Request) -> Result<Response> {
+    headless_control::request(socket, request).context("Is the headless Client running?")
+}
+
+#[cfg(not(unix))]
+fn send_request(_: &std::path::Path, _: &Request) -> Result<Response> {
+    anyhow::bail!("Only supported on Linux and macOS right now")
+}
+
+fn print_status(status: headless_control::Status) {
+    println!("Version: {}", status.version);
+    println!(
+        "Tunnel: {}",
+        if status.tunnel_ready {
+            "ready"
+        } else {
+            "not ready"
+        }
+    );
+
+    if status.gateways.is_empty() {
+        println!("Gateways: none");
+        return;
+    }
+
+    println!("Gateways:");
+
+    for gateway in status.gateways {
+        let path = match gateway.status {
+            GatewayStatus::Connected | GatewayStatus::Idle if gateway.relayed => " (relayed)",
+            GatewayStatus::Connected | GatewayStatus::Idle => " (direct)",
+            GatewayStatus::Connecting | GatewayStatus::Failed => "",
+        };
+
+        println!(
+            "  {}  {}{path}, {} resource(s)",
+            gateway.id,
+            gateway.status,
+            gateway.resources.len()
+        );
+    }
+}
+
+fn print_resources(resources: Vec<ResourceView>) {
+    for resource in resources {
+        let address = match &resource {
+            ResourceView::Dns(r) => r.address.clone(),
+            ResourceView::Cidr(r) => r.address.to_string(),
+            ResourceView::Internet(_) => "0.0.0.0/0, ::/0".to_owned(),
+        };
+
+        println!(
+            "{:<8} {:<36} {}",
+            resource.status(),
+            resource.name(),
+            address
+        );
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn is_root() -> bool {
     if *DRY_RUN {
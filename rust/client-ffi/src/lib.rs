@@ -67,6 +67,7 @@ pub enum ResourceStatus {
     Unknown,
     Online,
     Offline,
+    Degraded,
 }
 
 /// Site information for a resource
@@ -521,6 +522,7 @@ fn connect(
         portal,
         is_internet_resource_active,
         Vec::default(),
+        None,
         runtime.handle().clone(),
     );
 
@@ -698,6 +700,7 @@ impl From<connlib_model::ResourceStatus> for ResourceStatus {
             connlib_model::ResourceStatus::Unknown => ResourceStatus::Unknown,
             connlib_model::ResourceStatus::Online => ResourceStatus::Online,
             connlib_model::ResourceStatus::Offline => ResourceStatus::Offline,
+            connlib_model::ResourceStatus::Degraded => ResourceStatus::Degraded,
         }
     }
 }
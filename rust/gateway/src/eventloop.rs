@@ -10,7 +10,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::future::{self, Future, poll_fn};
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ops::ControlFlow;
-use std::pin::pin;
+use std::pin::{Pin, pin};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
@@ -19,13 +19,17 @@ use tokio::sync::mpsc;
 use tunnel::messages::RelaysPresence;
 use tunnel::messages::gateway::{
     AccessAuthorizationExpiryUpdated, Authorization, ClientIceCandidates, ClientsIceCandidates,
-    EgressMessages, IngressMessages, InitGateway, RejectAccess,
+    EgressMessages, FlowRejectionReason, IngressMessages, InitGateway, RejectAccess,
 };
 use tunnel::{
-    GatewayEvent, GatewayTunnel, IPV4_TUNNEL, IPV6_TUNNEL, IpConfig, ResolveDnsRequest, TunnelError,
+    GatewayDraining, GatewayEvent, GatewayTunnel, IPV4_TUNNEL, IPV6_TUNNEL, IpConfig,
+    ResolveDnsRequest, TunnelError,
 };
 
 use crate::RELEASE;
+use crate::flow_logs::FlowLogs;
+use crate::resolver::DnsResolver;
+use crate::resource_health::{self, ResourceProber};
 
 pub const PHOENIX_TOPIC: &str = "gateway";
 
@@ -36,7 +40,9 @@ pub struct Eventloop {
     // Tunnel is `Option` because we need to take ownership on shutdown.
     tunnel: Option<GatewayTunnel>,
     tun_device_manager: TunDeviceManager,
-    resolver: TokioResolver,
+    dns_resolver: DnsResolver,
+    flow_logs: FlowLogs,
+    resource_prober: Option<ResourceProber>,
 
     resolve_tasks: futures_bounded::FuturesTupleSet<
         Result<Vec<IpAddr>, Arc<anyhow::Error>>,
@@ -46,6 +52,12 @@ pub struct Eventloop {
     portal_cmd_tx: mpsc::Sender<PortalCommand>,
 
     sigint: signals::Terminate,
+    sigusr1: signals::Drain,
+
+    /// How long we wait for existing flows to end when draining.
+    max_drain_time: Duration,
+    /// When we shut down regardless of any remaining flows, set once we start draining.
+    drain_deadline: Option<Pin<Box<tokio::time::Sleep>>>,
 
     logged_permission_denied: bool,
 }
@@ -62,6 +74,10 @@ impl Eventloop {
         portal: PhoenixChannel<(), EgressMessages, IngressMessages, PublicKeyParam>,
         tun_device_manager: TunDeviceManager,
         resolver: TokioResolver,
+        dns_resolver: DnsResolver,
+        flow_logs: FlowLogs,
+        resource_prober: Option<ResourceProber>,
+        max_drain_time: Duration,
     ) -> Result<Self> {
         let (portal_event_tx, portal_event_rx) = mpsc::channel(128);
         let (portal_cmd_tx, portal_cmd_rx) = mpsc::channel(128);
@@ -71,13 +87,15 @@ impl Eventloop {
             PublicKeyParam(tunnel.public_key().to_bytes()),
             portal_event_tx,
             portal_cmd_rx,
-            resolver.clone(),
+            resolver,
         ));
 
         Ok(Self {
             tunnel: Some(tunnel),
             tun_device_manager,
-            resolver,
+            dns_resolver,
+            flow_logs,
+            resource_prober,
             resolve_tasks: futures_bounded::FuturesTupleSet::new(
                 || futures_bounded::Delay::tokio(DNS_RESOLUTION_TIMEOUT),
                 1000,
@@ -86,15 +104,21 @@ impl Eventloop {
             portal_event_rx,
             portal_cmd_tx,
             sigint: signals::Terminate::new()?,
+            sigusr1: signals::Drain::new()?,
+            max_drain_time,
+            drain_deadline: None,
         })
     }
 }
 
 enum CombinedEvent {
     SigIntTerm,
+    SigUsr1,
+    Drained,
     Tunnel(GatewayEvent),
     Portal(Option<Result<IngressMessages, phoenix_channel::Error>>),
     DomainResolved((Result<Vec<IpAddr>, Arc<anyhow::Error>>, ResolveDnsRequest)),
+    ResourceHealth(resource_health::Event),
 }
 
 impl Eventloop {
@@ -150,16 +174,57 @@ impl Eventloop {
 
                 Ok(ControlFlow::Continue(()))
             }
+            CombinedEvent::ResourceHealth(event) => {
+                self.handle_resource_health_event(event);
+
+                Ok(ControlFlow::Continue(()))
+            }
             CombinedEvent::SigIntTerm => {
                 tracing::info!("Received SIGINT/SIGTERM");
 
                 self.portal_cmd_tx.send(PortalCommand::Close).await?;
 
+                Ok(ControlFlow::Break(()))
+            }
+            CombinedEvent::SigUsr1 => {
+                tracing::info!("Received SIGUSR1");
+
+                self.start_draining().await?;
+
+                Ok(ControlFlow::Continue(()))
+            }
+            CombinedEvent::Drained => {
+                self.portal_cmd_tx.send(PortalCommand::Close).await?;
+
                 Ok(ControlFlow::Break(()))
             }
         }
     }
 
+    /// Stops accepting new Clients and tells the portal to no longer hand us out.
+    ///
+    /// We shut down once the flows of existing Clients have ended or the max drain time elapsed.
+    async fn start_draining(&mut self) -> Result<()> {
+        let Some(tunnel) = self.tunnel.as_mut() else {
+            return Ok(());
+        };
+
+        if tunnel.state().is_draining() {
+            return Ok(());
+        }
+
+        tunnel.state_mut().start_draining(Instant::now());
+        self.drain_deadline = Some(Box::pin(tokio::time::sleep(self.max_drain_time)));
+
+        self.portal_cmd_tx
+            .send(PortalCommand::Send(EgressMessages::Draining {
+                max_drain_secs: self.max_drain_time.as_secs(),
+            }))
+            .await?;
+
+        Ok(())
+    }
+
     fn next_event(&mut self, cx: &mut Context<'_>) -> Poll<CombinedEvent> {
         if let Poll::Ready(event) = self.portal_event_rx.poll_recv(cx) {
             return Poll::Ready(CombinedEvent::Portal(event));
@@ -179,10 +244,40 @@ impl Eventloop {
             return Poll::Ready(CombinedEvent::Tunnel(event));
         }
 
+        if let Some(Poll::Ready(event)) = self.resource_prober.as_mut().map(|p| p.poll(cx)) {
+            return Poll::Ready(CombinedEvent::ResourceHealth(event));
+        }
+
         if let Poll::Ready(()) = self.sigint.poll_recv(cx) {
             return Poll::Ready(CombinedEvent::SigIntTerm);
         }
 
+        if let Poll::Ready(()) = self.sigusr1.poll_recv(cx) {
+            return Poll::Ready(CombinedEvent::SigUsr1);
+        }
+
+        if let Some(tunnel) = self.tunnel.as_ref()
+            && tunnel.state().is_drained(Instant::now())
+        {
+            tracing::info!("All flows have ended, shutting down");
+
+            return Poll::Ready(CombinedEvent::Drained);
+        }
+
+        if let Some(deadline) = self.drain_deadline.as_mut()
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            let num_active_flows = self
+                .tunnel
+                .as_ref()
+                .map(|t| t.state().num_active_flows())
+                .unwrap_or_default();
+
+            tracing::info!(%num_active_flows, "Max drain time elapsed, shutting down");
+
+            return Poll::Ready(CombinedEvent::Drained);
+        }
+
         Poll::Pending
     }
 
@@ -238,12 +333,39 @@ impl Eventloop {
                     tracing::warn!("Too many dns resolution requests, dropping existing one");
                 };
             }
+            tunnel::GatewayEvent::FlowCompleted(flow) => self.flow_logs.export(&flow),
+            tunnel::GatewayEvent::DnsQueryCompleted(query) => {
+                self.flow_logs.export_dns_query(&query)
+            }
             GatewayEvent::Error(error) => self.handle_tunnel_error(error)?,
         }
 
         Ok(())
     }
 
+    fn handle_resource_health_event(&mut self, event: resource_health::Event) {
+        let Some(tunnel) = self.tunnel.as_mut() else {
+            tracing::debug!("Ignoring resource health event during shutdown");
+
+            return;
+        };
+
+        match event {
+            resource_health::Event::RoundDue => {
+                let Some(prober) = self.resource_prober.as_mut() else {
+                    return;
+                };
+
+                prober.start_round(tunnel.state().resource_probe_targets(), &self.dns_resolver);
+            }
+            resource_health::Event::Health { resource, status } => {
+                tunnel
+                    .state_mut()
+                    .update_resource_health(resource, status, Instant::now());
+            }
+        }
+    }
+
     fn handle_tunnel_error(&mut self, mut e: TunnelError) -> Result<()> {
         for e in e.drain() {
             if e.any_downcast_ref::<io::Error>()
@@ -303,7 +425,9 @@ impl Eventloop {
 
         match msg {
             IngressMessages::AuthorizeFlow(msg) => {
-                if let Err(snownet::NoTurnServers {}) = tunnel.state_mut().authorize_flow(
+                let client = msg.client.id;
+
+                if let Err(e) = tunnel.state_mut().authorize_flow(
                     msg.client,
                     msg.subject,
                     msg.client_ice_credentials,
@@ -312,7 +436,20 @@ impl Eventloop {
                     msg.resource,
                     Instant::now(),
                 ) {
-                    tracing::debug!("Failed to authorise flow: No TURN servers available");
+                    if e.any_is::<GatewayDraining>() {
+                        tracing::debug!(%client, "Rejecting flow for new Client while draining");
+
+                        self.portal_cmd_tx
+                            .send(PortalCommand::Send(EgressMessages::FlowRejected {
+                                reference: msg.reference,
+                                reason: FlowRejectionReason::Draining,
+                            }))
+                            .await?;
+
+                        return Ok(());
+                    }
+
+                    tracing::debug!("Failed to authorise flow: {e:#}");
 
                     // Re-connecting to the portal means we will receive another `init` and thus new TURN servers.
                     self.portal_cmd_tx
@@ -488,7 +625,7 @@ impl Eventloop {
         &self,
         domain: DomainName,
     ) -> impl Future<Output = Result<Vec<IpAddr>, Arc<anyhow::Error>>> + use<> {
-        let resolver = self.resolver.clone();
+        let resolver = self.dns_resolver.for_domain(&domain).clone();
 
         async move {
             let ipv4_lookup = resolver
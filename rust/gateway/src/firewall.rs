@@ -0,0 +1,318 @@
+//! Manages the nftables rules the Gateway needs to forward traffic from Clients to resources.
+//!
+//! We own a dedicated `inet` table which is created on startup and deleted again on shutdown.
+//! It accepts all forwarded traffic to and from the TUN device and masquerades traffic from the tunnel subnets that leaves via one of the egress interfaces.
+//! The egress interfaces are the ones of the default routes; we re-install the table whenever those change.
+//! The rules are installed via netlink, thus we neither depend on the `nft` nor the `iptables` binaries.
+//!
+//! An `accept` verdict in our table cannot override a `drop` in another table that is hooked into the forward path.
+//! Most notably, this is the case for an iptables `FORWARD` chain with a `DROP` policy, as e.g. set up by Docker.
+//! Our packaging thus still inserts iptables `ACCEPT` rules for the TUN device if iptables is installed.
+
+use std::{collections::BTreeSet, io, net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context as _, Result};
+use bin_shared::nftables::{
+    self, Batch, NF_INET_FORWARD, NF_INET_POST_ROUTING, NF_IP_PRI_FILTER, NF_IP_PRI_NAT_SRC,
+    NFT_META_IIFNAME, NFT_META_OIFNAME, NftSocket,
+};
+use futures::{StreamExt as _, TryStreamExt as _, channel::mpsc::UnboundedReceiver};
+use ip_network::IpNetwork;
+use netlink_packet_core::NetlinkMessage;
+use netlink_packet_route::{
+    RouteNetlinkMessage,
+    link::LinkAttribute,
+    route::{RouteAttribute, RouteMessage, RouteType},
+};
+use rtnetlink::{
+    Handle, RouteMessageBuilder, new_connection,
+    sys::{AsyncSocket as _, SocketAddr},
+};
+
+const TABLE_NAME: &str = "firezone";
+const FORWARD_CHAIN: &str = "forward";
+const POSTROUTING_CHAIN: &str = "postrouting";
+
+/// How long we wait for routes to settle before re-installing our table.
+const ROUTE_CHANGE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Our nftables table, deleted again when dropped.
+pub struct Firewall {
+    socket: Arc<NftSocket>,
+    refresh_egress_interfaces: tokio::task::JoinHandle<()>,
+}
+
+impl Firewall {
+    /// Enables IP forwarding and installs our nftables table.
+    ///
+    /// A table left behind by a previous instance (e.g. after a crash) is replaced atomically.
+    /// Until dropped, the table is re-installed whenever the interfaces of the default routes change.
+    pub async fn install(tun: &'static str, sources: Vec<IpNetwork>) -> Result<Self> {
+        enable_forwarding();
+
+        let socket = Arc::new(NftSocket::new().context("Failed to open netfilter netlink socket")?);
+
+        let (mut connection, handle, route_changes) =
+            new_connection().context("Failed to create netlink connection")?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(
+                0,
+                (libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE) as u32,
+            ))
+            .context("Failed to subscribe to route changes")?;
+        tokio::spawn(connection);
+
+        let egress = egress_interfaces(&handle, tun)
+            .await
+            .context("Failed to detect egress interfaces")?;
+        if egress.is_empty() {
+            tracing::warn!("No default route found, traffic from Clients will not be masqueraded");
+        }
+
+        install_table(&socket, tun, &egress, &sources)
+            .context("Failed to install nftables table")?;
+
+        tracing::info!(table = %TABLE_NAME, %tun, ?egress, "Installed nftables table");
+
+        let refresh_egress_interfaces = tokio::spawn(refresh_egress_interfaces(
+            route_changes,
+            handle,
+            socket.clone(),
+            tun,
+            sources,
+            egress,
+        ));
+
+        Ok(Self {
+            socket,
+            refresh_egress_interfaces,
+        })
+    }
+}
+
+impl Drop for Firewall {
+    fn drop(&mut self) {
+        self.refresh_egress_interfaces.abort();
+
+        let mut batch = Batch::new(TABLE_NAME);
+        batch.delete_table();
+
+        match self.socket.send_batch(batch) {
+            Ok(()) => tracing::debug!(table = %TABLE_NAME, "Removed nftables table"),
+            Err(e) => tracing::warn!(table = %TABLE_NAME, "Failed to remove nftables table: {e}"),
+        }
+    }
+}
+
+/// Re-installs our table on every change of the egress interfaces.
+///
+/// Like for link-scope routes in the TUN device manager, we debounce route notifications because they often come in bursts.
+async fn refresh_egress_interfaces(
+    mut route_changes: UnboundedReceiver<(NetlinkMessage<RouteNetlinkMessage>, SocketAddr)>,
+    handle: Handle,
+    socket: Arc<NftSocket>,
+    tun: &'static str,
+    sources: Vec<IpNetwork>,
+    mut egress: BTreeSet<String>,
+) {
+    let mut debounce_timer = Box::pin(tokio::time::sleep(Duration::MAX));
+
+    loop {
+        tokio::select! {
+            message = route_changes.next() => {
+                if message.is_none() {
+                    break;
+                }
+
+                debounce_timer
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + ROUTE_CHANGE_DEBOUNCE);
+            }
+            () = debounce_timer.as_mut() => {
+                debounce_timer = Box::pin(tokio::time::sleep(Duration::MAX));
+
+                let new_egress = match egress_interfaces(&handle, tun).await {
+                    Ok(new_egress) => new_egress,
+                    Err(e) => {
+                        tracing::debug!("Failed to detect egress interfaces: {e:#}");
+                        continue;
+                    }
+                };
+
+                if new_egress == egress {
+                    continue;
+                }
+
+                if let Err(e) = install_table(&socket, tun, &new_egress, &sources) {
+                    tracing::warn!(?new_egress, "Failed to update nftables table: {e}");
+                    continue;
+                }
+
+                tracing::info!(table = %TABLE_NAME, old = ?egress, new = ?new_egress, "Egress interfaces changed");
+
+                egress = new_egress;
+            }
+        }
+    }
+}
+
+/// Returns the names of the interfaces of all IPv4 and IPv6 default routes in the main routing table, except our own.
+#[expect(
+    clippy::wildcard_enum_match_arm,
+    reason = "We only want the `IfName` attribute."
+)]
+async fn egress_interfaces(handle: &Handle, tun: &str) -> Result<BTreeSet<String>> {
+    let indices = handle
+        .route()
+        .get(RouteMessageBuilder::<IpAddr>::new().build())
+        .execute()
+        .try_filter_map(|route| async move { Ok(default_route_interface(&route)) })
+        .try_collect::<BTreeSet<_>>()
+        .await
+        .context("Failed to list routes")?;
+
+    let mut names = BTreeSet::new();
+
+    for index in indices {
+        let Some(link) = handle
+            .link()
+            .get()
+            .match_index(index)
+            .execute()
+            .try_next()
+            .await
+            .with_context(|| format!("Failed to get link {index}"))?
+        else {
+            continue;
+        };
+
+        names.extend(link.attributes.into_iter().find_map(|a| match a {
+            LinkAttribute::IfName(name) => Some(name),
+            _ => None,
+        }));
+    }
+
+    names.retain(|name| name != "lo" && name != tun);
+
+    Ok(names)
+}
+
+#[expect(
+    clippy::wildcard_enum_match_arm,
+    reason = "We only want the `Oif` attribute."
+)]
+fn default_route_interface(route: &RouteMessage) -> Option<u32> {
+    if route.header.destination_prefix_length != 0
+        || route.header.table != libc::RT_TABLE_MAIN
+        || route.header.kind != RouteType::Unicast
+    {
+        return None;
+    }
+
+    route.attributes.iter().find_map(|a| match a {
+        RouteAttribute::Oif(index) => Some(*index),
+        _ => None,
+    })
+}
+
+fn enable_forwarding() {
+    for sysctl in [
+        "/proc/sys/net/ipv4/ip_forward",
+        "/proc/sys/net/ipv6/conf/all/forwarding",
+    ] {
+        match std::fs::write(sysctl, "1") {
+            Ok(()) => tracing::debug!(%sysctl, "Enabled IP forwarding"),
+            Err(e) => tracing::info!(%sysctl, "Failed to enable IP forwarding: {e}"),
+        }
+    }
+}
+
+/// Atomically replaces our table with one for the given egress interfaces.
+fn install_table(
+    socket: &NftSocket,
+    tun: &str,
+    egress: &BTreeSet<String>,
+    sources: &[IpNetwork],
+) -> io::Result<()> {
+    let mut batch = Batch::new(TABLE_NAME);
+
+    batch.replace_table();
+    batch.add_base_chain(FORWARD_CHAIN, "filter", NF_INET_FORWARD, NF_IP_PRI_FILTER);
+    batch.add_base_chain(
+        POSTROUTING_CHAIN,
+        "nat",
+        NF_INET_POST_ROUTING,
+        NF_IP_PRI_NAT_SRC,
+    );
+
+    for key in [NFT_META_IIFNAME, NFT_META_OIFNAME] {
+        batch.append_rule(
+            FORWARD_CHAIN,
+            [
+                nftables::meta(key),
+                nftables::cmp_eq(&nftables::ifname(tun)),
+                nftables::accept(),
+            ],
+        );
+    }
+
+    for iface in egress {
+        for source in sources {
+            batch.append_rule(
+                POSTROUTING_CHAIN,
+                nftables::match_source(*source).into_iter().chain([
+                    nftables::meta(NFT_META_OIFNAME),
+                    nftables::cmp_eq(&nftables::ifname(iface)),
+                    nftables::masquerade(),
+                ]),
+            );
+        }
+    }
+
+    socket.send_batch(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn default_routes_of_main_table() {
+        let default_route = RouteMessageBuilder::<Ipv4Addr>::new()
+            .output_interface(3)
+            .build();
+        let subnet_route = RouteMessageBuilder::<Ipv4Addr>::new()
+            .destination_prefix(Ipv4Addr::new(192, 168, 0, 0), 24)
+            .output_interface(3)
+            .build();
+        let other_table = RouteMessageBuilder::<Ipv4Addr>::new()
+            .table_id(1000)
+            .output_interface(4)
+            .build();
+
+        assert_eq!(default_route_interface(&default_route), Some(3));
+        assert_eq!(default_route_interface(&subnet_route), None);
+        assert_eq!(default_route_interface(&other_table), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "Requires root, runs in its own network namespace"]
+    async fn installs_and_removes_table() {
+        // SAFETY: Only affects the current thread, which runs the single-threaded runtime of this test.
+        let ret = unsafe { libc::unshare(libc::CLONE_NEWNET) };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+
+        let sources = vec![tunnel::IPV4_TUNNEL.into(), tunnel::IPV6_TUNNEL.into()];
+        let socket = NftSocket::new().unwrap();
+
+        let firewall = Firewall::install("tun-firezone", sources).await.unwrap();
+        assert!(socket.table_exists(TABLE_NAME).unwrap());
+
+        drop(firewall);
+        assert!(!socket.table_exists(TABLE_NAME).unwrap());
+    }
+}
@@ -0,0 +1,490 @@
+//! Export of completed TCP and UDP flows and DNS queries to external sinks.
+//!
+//! Each sink runs on its own thread and is fed through a bounded channel.
+//! If a sink cannot keep up, we drop records instead of stalling the event-loop.
+
+mod ipfix;
+mod ndjson;
+mod syslog;
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs as _, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use connlib_model::{ClientId, ResourceId};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tunnel::{CompletedDnsQuery, CompletedFlow, DnsQueryKind};
+
+/// How many flow records we buffer per sink before we start dropping them.
+const MAX_BUFFERED_FLOWS: usize = 4096;
+
+/// A destination for completed flows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    /// Newline-delimited JSON, written to a file that is rotated by size.
+    Ndjson(PathBuf),
+    /// IPFIX (NetFlow v10) over UDP, see <https://datatracker.ietf.org/doc/html/rfc7011>.
+    Ipfix(String),
+    /// Syslog over UDP, see <https://datatracker.ietf.org/doc/html/rfc5424>.
+    Syslog(String),
+}
+
+impl FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, target) = s
+            .split_once(':')
+            .context("Expected sink in the form `<kind>:<target>`")?;
+
+        anyhow::ensure!(!target.is_empty(), "Sink target must not be empty");
+
+        match kind {
+            "ndjson" => Ok(Self::Ndjson(PathBuf::from(target))),
+            "ipfix" => Ok(Self::Ipfix(target.to_owned())),
+            "syslog" => Ok(Self::Syslog(target.to_owned())),
+            other => {
+                anyhow::bail!("Unknown sink `{other}`, expected `ndjson`, `ipfix` or `syslog`")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::Ndjson(path) => write!(f, "ndjson:{}", path.display()),
+            Sink::Ipfix(target) => write!(f, "ipfix:{target}"),
+            Sink::Syslog(target) => write!(f, "syslog:{target}"),
+        }
+    }
+}
+
+/// When to rotate the files of [`Sink::Ndjson`].
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Rotate once the current file exceeds this many bytes.
+    pub max_size: u64,
+    /// How many rotated files to keep around in addition to the current one.
+    pub max_files: usize,
+}
+
+/// Forwards completed flows and DNS queries to all configured sinks.
+pub struct FlowLogs {
+    sinks: Vec<SinkHandle>,
+}
+
+struct SinkHandle {
+    sink: Sink,
+    records: mpsc::Sender<Arc<Record>>,
+    dropped: u64,
+}
+
+enum Record {
+    Flow(FlowRecord),
+    DnsQuery(DnsQueryRecord),
+}
+
+impl FlowLogs {
+    pub fn new(sinks: &[Sink], rotation: Rotation) -> Result<Self> {
+        let sinks = sinks
+            .iter()
+            .map(|sink| {
+                let writer: Box<dyn Writer> = match sink {
+                    Sink::Ndjson(path) => Box::new(ndjson::Writer::new(path.clone(), rotation)),
+                    Sink::Ipfix(target) => Box::new(ipfix::Writer::new(target)?),
+                    Sink::Syslog(target) => Box::new(syslog::Writer::new(target)?),
+                };
+                let (tx, rx) = mpsc::channel(MAX_BUFFERED_FLOWS);
+
+                std::thread::Builder::new()
+                    .name(format!("flow-logs-{}", sink.kind()))
+                    .spawn({
+                        let sink = sink.clone();
+
+                        move || run_sink(sink, writer, rx)
+                    })
+                    .context("Failed to spawn flow-logs thread")?;
+
+                Ok(SinkHandle {
+                    sink: sink.clone(),
+                    records: tx,
+                    dropped: 0,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to set up flow-log sinks")?;
+
+        Ok(Self { sinks })
+    }
+
+    /// Hands the flow to all sinks without blocking.
+    pub fn export(&mut self, flow: &CompletedFlow) {
+        if self.sinks.is_empty() {
+            return;
+        }
+
+        self.send(Record::Flow(FlowRecord::new(flow)));
+    }
+
+    /// Hands the DNS query to all sinks without blocking.
+    pub fn export_dns_query(&mut self, query: &CompletedDnsQuery) {
+        if self.sinks.is_empty() {
+            return;
+        }
+
+        self.send(Record::DnsQuery(DnsQueryRecord::new(query)));
+    }
+
+    fn send(&mut self, record: Record) {
+        let record = Arc::new(record);
+
+        for handle in &mut self.sinks {
+            match handle.records.try_send(record.clone()) {
+                Ok(()) => {
+                    if handle.dropped > 0 {
+                        tracing::warn!(sink = %handle.sink, dropped = %handle.dropped, "Flow-log sink could not keep up; dropped records");
+
+                        handle.dropped = 0;
+                    }
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    handle.dropped += 1;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    tracing::debug!(sink = %handle.sink, "Flow-log sink is gone");
+                }
+            }
+        }
+    }
+}
+
+impl Sink {
+    fn kind(&self) -> &'static str {
+        match self {
+            Sink::Ndjson(_) => "ndjson",
+            Sink::Ipfix(_) => "ipfix",
+            Sink::Syslog(_) => "syslog",
+        }
+    }
+}
+
+trait Writer: Send + 'static {
+    fn write(&mut self, record: &FlowRecord) -> Result<()>;
+    fn write_dns_query(&mut self, record: &DnsQueryRecord) -> Result<()>;
+}
+
+/// Creates a UDP socket that is connected to the given `host:port`.
+fn connect_udp(target: &str) -> Result<UdpSocket> {
+    let addr = target
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve `{target}`"))?
+        .next()
+        .with_context(|| format!("`{target}` did not resolve to any address"))?;
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let socket = UdpSocket::bind(local).context("Failed to bind UDP socket")?;
+    socket
+        .connect(addr)
+        .with_context(|| format!("Failed to connect UDP socket to {addr}"))?;
+
+    Ok(socket)
+}
+
+fn run_sink(sink: Sink, mut writer: Box<dyn Writer>, mut records: mpsc::Receiver<Arc<Record>>) {
+    while let Some(record) = records.blocking_recv() {
+        let result = match record.as_ref() {
+            Record::Flow(record) => writer.write(record),
+            Record::DnsQuery(record) => writer.write_dns_query(record),
+        };
+
+        if let Err(e) = result {
+            tracing::debug!(%sink, "Failed to export record: {e:#}");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// A completed flow in the format we export it.
+#[derive(Debug, Serialize)]
+pub struct FlowRecord {
+    pub protocol: Protocol,
+
+    pub client_id: ClientId,
+    pub client_version: Option<String>,
+
+    pub device_os_name: Option<String>,
+    pub device_os_version: Option<String>,
+    pub device_serial: Option<String>,
+    pub device_uuid: Option<String>,
+    pub device_identifier_for_vendor: Option<String>,
+    pub device_firebase_installation_id: Option<String>,
+
+    pub auth_provider_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub actor_id: Option<String>,
+    pub actor_email: Option<String>,
+
+    pub resource_id: ResourceId,
+    pub resource_name: String,
+    pub resource_address: String,
+
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub last_packet: DateTime<Utc>,
+
+    pub inner_src_ip: IpAddr,
+    pub inner_dst_ip: IpAddr,
+    pub inner_src_port: u16,
+    pub inner_dst_port: u16,
+    pub inner_domain: Option<String>,
+
+    pub outer_src_ip: IpAddr,
+    pub outer_dst_ip: IpAddr,
+    pub outer_src_port: u16,
+    pub outer_dst_port: u16,
+
+    /// Packets sent by the resource to the client.
+    pub rx_packets: u64,
+    /// Packets sent by the client to the resource.
+    pub tx_packets: u64,
+    /// Bytes sent by the resource to the client.
+    pub rx_bytes: u64,
+    /// Bytes sent by the client to the resource.
+    pub tx_bytes: u64,
+}
+
+impl FlowRecord {
+    fn new(flow: &CompletedFlow) -> Self {
+        macro_rules! record {
+            ($protocol:expr, $flow:ident) => {
+                Self {
+                    protocol: $protocol,
+                    client_id: $flow.client_id,
+                    client_version: $flow.client_version.clone(),
+                    device_os_name: $flow.device_os_name.clone(),
+                    device_os_version: $flow.device_os_version.clone(),
+                    device_serial: $flow.device_serial.clone(),
+                    device_uuid: $flow.device_uuid.clone(),
+                    device_identifier_for_vendor: $flow.device_identifier_for_vendor.clone(),
+                    device_firebase_installation_id: $flow.device_firebase_installation_id.clone(),
+                    auth_provider_id: $flow.auth_provider_id.clone(),
+                    actor_name: $flow.actor_name.clone(),
+                    actor_id: $flow.actor_id.clone(),
+                    actor_email: $flow.actor_email.clone(),
+                    resource_id: $flow.resource_id,
+                    resource_name: $flow.resource_name.clone(),
+                    resource_address: $flow.resource_address.clone(),
+                    start: $flow.start,
+                    end: $flow.end,
+                    last_packet: $flow.last_packet,
+                    inner_src_ip: $flow.inner_src_ip,
+                    inner_dst_ip: $flow.inner_dst_ip,
+                    inner_src_port: $flow.inner_src_port,
+                    inner_dst_port: $flow.inner_dst_port,
+                    inner_domain: $flow.inner_domain.as_ref().map(|d| d.to_string()),
+                    outer_src_ip: $flow.outer_src_ip,
+                    outer_dst_ip: $flow.outer_dst_ip,
+                    outer_src_port: $flow.outer_src_port,
+                    outer_dst_port: $flow.outer_dst_port,
+                    rx_packets: $flow.rx_packets,
+                    tx_packets: $flow.tx_packets,
+                    rx_bytes: $flow.rx_bytes,
+                    tx_bytes: $flow.tx_bytes,
+                }
+            };
+        }
+
+        match flow {
+            CompletedFlow::Tcp(flow) => record!(Protocol::Tcp, flow),
+            CompletedFlow::Udp(flow) => record!(Protocol::Udp, flow),
+        }
+    }
+}
+
+/// A DNS query resolved on behalf of a client in the format we export it.
+#[derive(Debug, Serialize)]
+pub struct DnsQueryRecord {
+    /// Always `dns`, allows telling DNS query records apart from flow records.
+    pub protocol: &'static str,
+    pub kind: DnsQueryRecordKind,
+
+    pub client_id: ClientId,
+    pub client_version: Option<String>,
+
+    pub device_os_name: Option<String>,
+    pub device_os_version: Option<String>,
+    pub device_serial: Option<String>,
+    pub device_uuid: Option<String>,
+    pub device_identifier_for_vendor: Option<String>,
+    pub device_firebase_installation_id: Option<String>,
+
+    pub auth_provider_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub actor_id: Option<String>,
+    pub actor_email: Option<String>,
+
+    pub resource_id: Option<ResourceId>,
+
+    pub domain: String,
+    /// The query type, absent if we looked up both A and AAAA records.
+    pub qtype: Option<String>,
+    pub answers: Vec<String>,
+    pub rcode: String,
+
+    pub timestamp: DateTime<Utc>,
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsQueryRecordKind {
+    Forwarded,
+    ResourceNat,
+}
+
+impl DnsQueryRecord {
+    fn new(query: &CompletedDnsQuery) -> Self {
+        Self {
+            protocol: "dns",
+            kind: match query.kind {
+                DnsQueryKind::Forwarded => DnsQueryRecordKind::Forwarded,
+                DnsQueryKind::ResourceNat => DnsQueryRecordKind::ResourceNat,
+            },
+            client_id: query.client_id,
+            client_version: query.client_version.clone(),
+            device_os_name: query.device_os_name.clone(),
+            device_os_version: query.device_os_version.clone(),
+            device_serial: query.device_serial.clone(),
+            device_uuid: query.device_uuid.clone(),
+            device_identifier_for_vendor: query.device_identifier_for_vendor.clone(),
+            device_firebase_installation_id: query.device_firebase_installation_id.clone(),
+            auth_provider_id: query.auth_provider_id.clone(),
+            actor_name: query.actor_name.clone(),
+            actor_id: query.actor_id.clone(),
+            actor_email: query.actor_email.clone(),
+            resource_id: query.resource_id,
+            domain: query.domain.to_string(),
+            qtype: query.qtype.map(|t| t.to_string()),
+            answers: query.answers.clone(),
+            rcode: query.rcode.to_string(),
+            timestamp: query.timestamp,
+            latency_ms: query.latency.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sinks() {
+        assert_eq!(
+            "ndjson:/var/log/firezone/flows.ndjson"
+                .parse::<Sink>()
+                .unwrap(),
+            Sink::Ndjson(PathBuf::from("/var/log/firezone/flows.ndjson"))
+        );
+        assert_eq!(
+            "ipfix:collector.example.com:4739".parse::<Sink>().unwrap(),
+            Sink::Ipfix("collector.example.com:4739".to_owned())
+        );
+        assert_eq!(
+            "syslog:10.0.0.1:514".parse::<Sink>().unwrap(),
+            Sink::Syslog("10.0.0.1:514".to_owned())
+        );
+        assert!("netflow:10.0.0.1:2055".parse::<Sink>().is_err());
+        assert!("ndjson:".parse::<Sink>().is_err());
+    }
+
+    #[test]
+    fn sink_display_roundtrips() {
+        for sink in [
+            "ndjson:/tmp/flows.ndjson",
+            "ipfix:[::1]:4739",
+            "syslog:10.0.0.1:514",
+        ] {
+            assert_eq!(sink.parse::<Sink>().unwrap().to_string(), sink);
+        }
+    }
+
+    pub(super) fn dns_query_record() -> DnsQueryRecord {
+        DnsQueryRecord {
+            protocol: "dns",
+            kind: DnsQueryRecordKind::Forwarded,
+            client_id: ClientId::from_u128(1),
+            client_version: Some("1.5.0".to_owned()),
+            device_os_name: Some("Linux".to_owned()),
+            device_os_version: None,
+            device_serial: None,
+            device_uuid: None,
+            device_identifier_for_vendor: None,
+            device_firebase_installation_id: None,
+            auth_provider_id: None,
+            actor_name: Some("Jane Doe".to_owned()),
+            actor_id: None,
+            actor_email: Some("jane@example.com".to_owned()),
+            resource_id: None,
+            domain: "gitlab.example.com".to_owned(),
+            qtype: Some("A".to_owned()),
+            answers: vec!["10.0.0.5".to_owned()],
+            rcode: "NOERROR".to_owned(),
+            timestamp: DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            latency_ms: 12.5,
+        }
+    }
+
+    pub(super) fn record(protocol: Protocol) -> FlowRecord {
+        FlowRecord {
+            protocol,
+            client_id: ClientId::from_u128(1),
+            client_version: Some("1.5.0".to_owned()),
+            device_os_name: Some("Linux".to_owned()),
+            device_os_version: None,
+            device_serial: None,
+            device_uuid: None,
+            device_identifier_for_vendor: None,
+            device_firebase_installation_id: None,
+            auth_provider_id: None,
+            actor_name: Some("Jane Doe".to_owned()),
+            actor_id: None,
+            actor_email: Some("jane@example.com".to_owned()),
+            resource_id: ResourceId::from_u128(2),
+            resource_name: "GitLab".to_owned(),
+            resource_address: "gitlab.example.com".to_owned(),
+            start: DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            end: DateTime::from_timestamp_millis(1_700_000_060_000).unwrap(),
+            last_packet: DateTime::from_timestamp_millis(1_700_000_059_000).unwrap(),
+            inner_src_ip: "100.64.0.1".parse().unwrap(),
+            inner_dst_ip: "10.0.0.5".parse().unwrap(),
+            inner_src_port: 51000,
+            inner_dst_port: 443,
+            inner_domain: Some("gitlab.example.com".to_owned()),
+            outer_src_ip: "203.0.113.1".parse().unwrap(),
+            outer_dst_ip: "198.51.100.1".parse().unwrap(),
+            outer_src_port: 40000,
+            outer_dst_port: 52625,
+            rx_packets: 10,
+            tx_packets: 5,
+            rx_bytes: 10_000,
+            tx_bytes: 500,
+        }
+    }
+}
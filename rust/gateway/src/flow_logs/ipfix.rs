@@ -0,0 +1,281 @@
+//! Minimal IPFIX exporter, see <https://datatracker.ietf.org/doc/html/rfc7011>.
+//!
+//! We only export the 5-tuple of the tunneled flow, its timestamps and the byte / packet counters.
+//! DNS queries are not exported via IPFIX as there are no standard information elements for them.
+//! Counters for the direction from the resource to the client are exported as reverse information elements (RFC 5103).
+
+use std::{
+    net::{IpAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use chrono::Utc;
+
+use super::{DnsQueryRecord, FlowRecord, Protocol};
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+
+/// The private enterprise number for reverse information elements, see <https://datatracker.ietf.org/doc/html/rfc5103#section-6.1>.
+const REVERSE_PEN: u32 = 29305;
+
+/// Collectors may restart and lose our templates, so we periodically send them again (RFC 7011, section 8.4).
+const TEMPLATE_RESEND_INTERVAL: Duration = Duration::from_secs(60);
+
+// Information elements, see <https://www.iana.org/assignments/ipfix/ipfix.xhtml>.
+const OCTET_DELTA_COUNT: u16 = 1;
+const PACKET_DELTA_COUNT: u16 = 2;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+
+pub struct Writer {
+    socket: UdpSocket,
+
+    /// The number of data records we have sent so far.
+    sequence: u32,
+    templates_sent_at: Option<Instant>,
+}
+
+impl Writer {
+    pub fn new(target: &str) -> Result<Self> {
+        Ok(Self {
+            socket: super::connect_udp(target)?,
+            sequence: 0,
+            templates_sent_at: None,
+        })
+    }
+}
+
+impl super::Writer for Writer {
+    fn write(&mut self, record: &FlowRecord) -> Result<()> {
+        let now = Instant::now();
+        let with_templates = self
+            .templates_sent_at
+            .is_none_or(|sent_at| now.duration_since(sent_at) >= TEMPLATE_RESEND_INTERVAL);
+
+        let message = encode(
+            record,
+            self.sequence,
+            Utc::now().timestamp() as u32,
+            with_templates,
+        )?;
+
+        self.socket
+            .send(&message)
+            .context("Failed to send IPFIX message")?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+
+        if with_templates {
+            self.templates_sent_at = Some(now);
+        }
+
+        Ok(())
+    }
+
+    fn write_dns_query(&mut self, _: &DnsQueryRecord) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Encodes a single flow record as an IPFIX message, optionally preceded by our templates.
+fn encode(
+    record: &FlowRecord,
+    sequence: u32,
+    export_time: u32,
+    with_templates: bool,
+) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(256);
+
+    message.extend_from_slice(&VERSION.to_be_bytes());
+    message.extend_from_slice(&[0, 0]); // Length, filled in at the end.
+    message.extend_from_slice(&export_time.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(&0_u32.to_be_bytes()); // Observation domain ID.
+
+    if with_templates {
+        write_set(&mut message, TEMPLATE_SET_ID, |set| {
+            write_template(
+                set,
+                IPV4_TEMPLATE_ID,
+                SOURCE_IPV4_ADDRESS,
+                DESTINATION_IPV4_ADDRESS,
+                4,
+            );
+            write_template(
+                set,
+                IPV6_TEMPLATE_ID,
+                SOURCE_IPV6_ADDRESS,
+                DESTINATION_IPV6_ADDRESS,
+                16,
+            );
+        });
+    }
+
+    let template_id = match (record.inner_src_ip, record.inner_dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => IPV4_TEMPLATE_ID,
+        (IpAddr::V6(_), IpAddr::V6(_)) => IPV6_TEMPLATE_ID,
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => {
+            anyhow::bail!("Cannot export flow with mixed IP versions")
+        }
+    };
+
+    write_set(&mut message, template_id, |set| {
+        write_ip(set, record.inner_src_ip);
+        write_ip(set, record.inner_dst_ip);
+        set.extend_from_slice(&record.inner_src_port.to_be_bytes());
+        set.extend_from_slice(&record.inner_dst_port.to_be_bytes());
+        set.push(match record.protocol {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        });
+        set.extend_from_slice(&(record.start.timestamp_millis() as u64).to_be_bytes());
+        set.extend_from_slice(&(record.end.timestamp_millis() as u64).to_be_bytes());
+        set.extend_from_slice(&record.tx_bytes.to_be_bytes());
+        set.extend_from_slice(&record.tx_packets.to_be_bytes());
+        set.extend_from_slice(&record.rx_bytes.to_be_bytes());
+        set.extend_from_slice(&record.rx_packets.to_be_bytes());
+    });
+
+    let len = u16::try_from(message.len()).context("IPFIX message too long")?;
+    message[2..4].copy_from_slice(&len.to_be_bytes());
+
+    Ok(message)
+}
+
+fn write_set(message: &mut Vec<u8>, set_id: u16, f: impl FnOnce(&mut Vec<u8>)) {
+    let start = message.len();
+
+    message.extend_from_slice(&set_id.to_be_bytes());
+    message.extend_from_slice(&[0, 0]); // Length, filled in below.
+
+    f(message);
+
+    let len = (message.len() - start) as u16;
+    message[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn write_template(set: &mut Vec<u8>, template_id: u16, src_ip: u16, dst_ip: u16, ip_len: u16) {
+    let fields = [
+        (src_ip, ip_len, None),
+        (dst_ip, ip_len, None),
+        (SOURCE_TRANSPORT_PORT, 2, None),
+        (DESTINATION_TRANSPORT_PORT, 2, None),
+        (PROTOCOL_IDENTIFIER, 1, None),
+        (FLOW_START_MILLISECONDS, 8, None),
+        (FLOW_END_MILLISECONDS, 8, None),
+        (OCTET_DELTA_COUNT, 8, None),
+        (PACKET_DELTA_COUNT, 8, None),
+        (OCTET_DELTA_COUNT, 8, Some(REVERSE_PEN)),
+        (PACKET_DELTA_COUNT, 8, Some(REVERSE_PEN)),
+    ];
+
+    set.extend_from_slice(&template_id.to_be_bytes());
+    set.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+
+    for (id, len, enterprise) in fields {
+        match enterprise {
+            Some(pen) => {
+                set.extend_from_slice(&(id | 0x8000).to_be_bytes());
+                set.extend_from_slice(&len.to_be_bytes());
+                set.extend_from_slice(&pen.to_be_bytes());
+            }
+            None => {
+                set.extend_from_slice(&id.to_be_bytes());
+                set.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn write_ip(set: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => set.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => set.extend_from_slice(&ip.octets()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+
+    const HEADER_LEN: usize = 16;
+    const SET_HEADER_LEN: usize = 4;
+
+    #[test]
+    fn encodes_ipv4_data_record() {
+        let message = encode(&record(Protocol::Tcp), 7, 1_700_000_100, false).unwrap();
+
+        assert_eq!(
+            message.len(),
+            HEADER_LEN + SET_HEADER_LEN + 4 + 4 + 2 + 2 + 1 + 8 * 6
+        );
+        assert_eq!(&message[0..2], &VERSION.to_be_bytes());
+        assert_eq!(&message[2..4], &(message.len() as u16).to_be_bytes());
+        assert_eq!(&message[4..8], &1_700_000_100_u32.to_be_bytes());
+        assert_eq!(&message[8..12], &7_u32.to_be_bytes());
+
+        let set = &message[HEADER_LEN..];
+        assert_eq!(&set[0..2], &IPV4_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(&set[4..8], &[100, 64, 0, 1]);
+        assert_eq!(&set[8..12], &[10, 0, 0, 5]);
+        assert_eq!(&set[12..14], &51000_u16.to_be_bytes());
+        assert_eq!(&set[14..16], &443_u16.to_be_bytes());
+        assert_eq!(set[16], 6);
+        assert_eq!(&set[17..25], &1_700_000_000_000_u64.to_be_bytes());
+        assert_eq!(&set[25..33], &1_700_000_060_000_u64.to_be_bytes());
+        assert_eq!(&set[33..41], &500_u64.to_be_bytes());
+        assert_eq!(&set[41..49], &5_u64.to_be_bytes());
+        assert_eq!(&set[49..57], &10_000_u64.to_be_bytes());
+        assert_eq!(&set[57..65], &10_u64.to_be_bytes());
+    }
+
+    #[test]
+    fn encodes_templates_before_data() {
+        let message = encode(&record(Protocol::Udp), 0, 0, true).unwrap();
+
+        let template_set = &message[HEADER_LEN..];
+        let template_set_len = u16::from_be_bytes([template_set[2], template_set[3]]) as usize;
+
+        assert_eq!(&template_set[0..2], &TEMPLATE_SET_ID.to_be_bytes());
+        assert_eq!(&template_set[4..6], &IPV4_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(&template_set[6..8], &11_u16.to_be_bytes());
+
+        let data_set = &message[HEADER_LEN + template_set_len..];
+        assert_eq!(&data_set[0..2], &IPV4_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(data_set[SET_HEADER_LEN + 12], 17);
+    }
+
+    #[test]
+    fn ipv6_flows_use_ipv6_template() {
+        let mut record = record(Protocol::Tcp);
+        record.inner_src_ip = "fd00:2021:1111::1".parse().unwrap();
+        record.inner_dst_ip = "fd00::5".parse().unwrap();
+
+        let message = encode(&record, 0, 0, false).unwrap();
+
+        assert_eq!(
+            &message[HEADER_LEN..HEADER_LEN + 2],
+            &IPV6_TEMPLATE_ID.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn rejects_mixed_ip_versions() {
+        let mut record = record(Protocol::Tcp);
+        record.inner_dst_ip = "fd00::5".parse().unwrap();
+
+        assert!(encode(&record, 0, 0, false).is_err());
+    }
+}
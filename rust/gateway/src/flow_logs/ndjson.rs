@@ -0,0 +1,217 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+
+use serde::Serialize;
+
+use super::{DnsQueryRecord, FlowRecord, Rotation};
+
+/// Appends one JSON object per line to a file, rotating it once it grows too large.
+///
+/// Rotated files are suffixed with an increasing number, i.e. `flows.ndjson.1` is the most recent one.
+pub struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+
+    file: Option<(File, u64)>,
+}
+
+impl Writer {
+    pub fn new(path: PathBuf, rotation: Rotation) -> Self {
+        Self {
+            path,
+            rotation,
+            file: None,
+        }
+    }
+
+    fn open(&self) -> Result<(File, u64)> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create `{}`", parent.display()))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open `{}`", self.path.display()))?;
+        let size = file.metadata()?.len();
+
+        Ok((file, size))
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+
+        if self.rotation.max_files == 0 {
+            return remove_if_exists(&self.path);
+        }
+
+        remove_if_exists(&rotated(&self.path, self.rotation.max_files))?;
+
+        for n in (1..self.rotation.max_files).rev() {
+            rename_if_exists(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+        }
+
+        rename_if_exists(&self.path, &rotated(&self.path, 1))?;
+
+        Ok(())
+    }
+}
+
+impl super::Writer for Writer {
+    fn write(&mut self, record: &FlowRecord) -> Result<()> {
+        self.write_line(record)
+    }
+
+    fn write_dns_query(&mut self, record: &DnsQueryRecord) -> Result<()> {
+        self.write_line(record)
+    }
+}
+
+impl Writer {
+    fn write_line(&mut self, record: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(record).context("Failed to serialize record")?;
+        line.push(b'\n');
+
+        let (file, size) = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.open()?),
+        };
+
+        if let Err(e) = file.write_all(&line) {
+            self.file = None; // Re-open the file on the next write.
+
+            return Err(anyhow::Error::new(e).context("Failed to write record"));
+        }
+        *size += line.len() as u64;
+
+        if *size >= self.rotation.max_size {
+            self.rotate().context("Failed to rotate flow-log file")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{n}"));
+
+    PathBuf::from(path)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow::Error::new(e).context(format!(
+            "Failed to rename `{}` to `{}`",
+            from.display(),
+            to.display()
+        ))),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            Err(anyhow::Error::new(e).context(format!("Failed to remove `{}`", path.display())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        Protocol, Writer as _,
+        tests::{dns_query_record, record},
+    };
+    use super::*;
+
+    #[test]
+    fn writes_one_record_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flows.ndjson");
+        let mut writer = Writer::new(
+            path.clone(),
+            Rotation {
+                max_size: u64::MAX,
+                max_files: 1,
+            },
+        );
+
+        writer.write(&record(Protocol::Tcp)).unwrap();
+        writer.write(&record(Protocol::Udp)).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines = content
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["protocol"], "tcp");
+        assert_eq!(lines[1]["protocol"], "udp");
+        assert_eq!(lines[0]["inner_dst_port"], 443);
+        assert_eq!(lines[0]["actor_email"], "jane@example.com");
+    }
+
+    #[test]
+    fn writes_dns_queries_to_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flows.ndjson");
+        let mut writer = Writer::new(
+            path.clone(),
+            Rotation {
+                max_size: u64::MAX,
+                max_files: 1,
+            },
+        );
+
+        writer.write(&record(Protocol::Tcp)).unwrap();
+        writer.write_dns_query(&dns_query_record()).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines = content
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["protocol"], "dns");
+        assert_eq!(lines[1]["kind"], "forwarded");
+        assert_eq!(lines[1]["domain"], "gitlab.example.com");
+        assert_eq!(lines[1]["answers"][0], "10.0.0.5");
+        assert_eq!(lines[1]["actor_email"], "jane@example.com");
+    }
+
+    #[test]
+    fn rotates_and_keeps_at_most_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flows.ndjson");
+        let mut writer = Writer::new(
+            path.clone(),
+            Rotation {
+                max_size: 1, // Rotate after every record.
+                max_files: 2,
+            },
+        );
+
+        for _ in 0..5 {
+            writer.write(&record(Protocol::Tcp)).unwrap();
+        }
+
+        assert!(!path.exists());
+        assert!(rotated(&path, 1).exists());
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+    }
+}
@@ -0,0 +1,142 @@
+//! Syslog exporter, see <https://datatracker.ietf.org/doc/html/rfc5424>.
+//!
+//! Each flow or DNS query is sent as a single message with the JSON-encoded [`FlowRecord`] or [`DnsQueryRecord`] as its body.
+
+use std::net::UdpSocket;
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use super::{DnsQueryRecord, FlowRecord, Protocol};
+
+/// `local0`.
+const FACILITY: u8 = 16;
+/// `informational`.
+const SEVERITY: u8 = 6;
+
+const APP_NAME: &str = "firezone-gateway";
+
+/// RFC 5424 uses a single dash for absent header fields.
+const NIL: &str = "-";
+
+pub struct Writer {
+    socket: UdpSocket,
+    hostname: String,
+    procid: u32,
+}
+
+impl Writer {
+    pub fn new(target: &str) -> Result<Self> {
+        Ok(Self {
+            socket: super::connect_udp(target)?,
+            hostname: hostname().unwrap_or_else(|| NIL.to_owned()),
+            procid: std::process::id(),
+        })
+    }
+}
+
+impl super::Writer for Writer {
+    fn write(&mut self, record: &FlowRecord) -> Result<()> {
+        let msgid = match record.protocol {
+            Protocol::Tcp => "tcp-flow",
+            Protocol::Udp => "udp-flow",
+        };
+
+        self.send(msgid, record)
+    }
+
+    fn write_dns_query(&mut self, record: &DnsQueryRecord) -> Result<()> {
+        self.send("dns-query", record)
+    }
+}
+
+impl Writer {
+    fn send(&mut self, msgid: &str, record: &impl Serialize) -> Result<()> {
+        let message = encode(msgid, record, Utc::now(), &self.hostname, self.procid)?;
+
+        self.socket
+            .send(message.as_bytes())
+            .context("Failed to send syslog message")?;
+
+        Ok(())
+    }
+}
+
+fn encode(
+    msgid: &str,
+    record: &impl Serialize,
+    timestamp: DateTime<Utc>,
+    hostname: &str,
+    procid: u32,
+) -> Result<String> {
+    let priority = FACILITY * 8 + SEVERITY;
+    let timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let body = serde_json::to_string(record).context("Failed to serialize record")?;
+
+    Ok(format!(
+        "<{priority}>1 {timestamp} {hostname} {APP_NAME} {procid} {msgid} {NIL} {body}"
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn hostname() -> Option<String> {
+    let hostname = nix::unistd::gethostname().ok()?.into_string().ok()?;
+
+    // Header fields must consist of printable US-ASCII only.
+    (!hostname.is_empty() && hostname.bytes().all(|b| b.is_ascii_graphic())).then_some(hostname)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hostname() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{dns_query_record, record};
+    use super::*;
+
+    #[test]
+    fn encodes_rfc5424_message() {
+        let message = encode(
+            "udp-flow",
+            &record(Protocol::Udp),
+            DateTime::from_timestamp_millis(1_700_000_100_123).unwrap(),
+            "gateway-1",
+            42,
+        )
+        .unwrap();
+
+        let (header, body) = message.split_once(" - ").unwrap();
+
+        assert_eq!(
+            header,
+            "<134>1 2023-11-14T22:15:00.123Z gateway-1 firezone-gateway 42 udp-flow"
+        );
+
+        let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+        assert_eq!(body["protocol"], "udp");
+        assert_eq!(body["resource_name"], "GitLab");
+    }
+
+    #[test]
+    fn encodes_dns_query() {
+        let message = encode(
+            "dns-query",
+            &dns_query_record(),
+            DateTime::from_timestamp_millis(1_700_000_100_123).unwrap(),
+            "gateway-1",
+            42,
+        )
+        .unwrap();
+
+        let (header, body) = message.split_once(" - ").unwrap();
+
+        assert!(header.ends_with("firezone-gateway 42 dns-query"));
+
+        let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+        assert_eq!(body["protocol"], "dns");
+        assert_eq!(body["rcode"], "NOERROR");
+    }
+}
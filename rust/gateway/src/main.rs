@@ -9,7 +9,6 @@ use bin_shared::{
 };
 use clap::Parser;
 
-use hickory_resolver::config::ResolveHosts;
 use ip_packet::IpPacket;
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::metrics::SdkMeterProvider;
@@ -30,6 +29,11 @@ use tun::Tun;
 use url::Url;
 
 mod eventloop;
+#[cfg(target_os = "linux")]
+mod firewall;
+mod flow_logs;
+mod resolver;
+mod resource_health;
 
 const RELEASE: &str = concat!("gateway@", env!("CARGO_PKG_VERSION"));
 
@@ -96,7 +100,10 @@ fn has_necessary_permissions() -> bool {
 
 async fn try_main(cli: Cli, telemetry: &mut Telemetry) -> Result<()> {
     logging::setup_global_subscriber(
-        make_directives(std::env::var("RUST_LOG").ok(), cli.flow_logs),
+        make_directives(
+            std::env::var("RUST_LOG").ok(),
+            cli.flow_logs || cli.dns_query_logs,
+        ),
         layer::Identity::default(),
         match cli.log_format {
             LogFormat::Json => true,
@@ -199,7 +206,8 @@ async fn try_main(cli: Cli, telemetry: &mut Telemetry) -> Result<()> {
         Arc::new(tcp_socket_factory),
         Arc::new(UdpSocketFactory::default()),
         nameservers,
-        cli.flow_logs,
+        cli.flow_logs || !cli.flow_logs_sink.is_empty(),
+        cli.dns_query_logs,
     );
     let max_partition_time = cli
         .max_partition_time
@@ -227,27 +235,83 @@ async fn try_main(cli: Cli, telemetry: &mut Telemetry) -> Result<()> {
         .make_tun()
         .context("Failed to create TUN device")?;
 
+    #[cfg(target_os = "linux")]
+    let _firewall = if cli.no_manage_firewall {
+        tracing::info!(
+            "Not managing firewall rules, ensure forwarding and masquerading are set up"
+        );
+
+        None
+    } else {
+        let firewall = firewall::Firewall::install(
+            TunDeviceManager::IFACE_NAME,
+            vec![tunnel::IPV4_TUNNEL.into(), tunnel::IPV6_TUNNEL.into()],
+        )
+        .await
+        .context("Failed to set up forwarding and masquerading, pass `--no-manage-firewall` to manage them yourself")?;
+
+        Some(firewall)
+    };
+
     if cli.validate_checksums {
         tunnel.set_tun(ValidateChecksumAdapter::wrap(tun));
     } else {
         tunnel.set_tun(tun);
     }
 
-    tokio::spawn(http_health_check::serve(
-        cli.health_check.health_check_addr,
-        || true,
-    ));
-
-    let mut resolver_builder = hickory_resolver::TokioResolver::builder_tokio()?;
-    resolver_builder.options_mut().cache_size = 512;
-    resolver_builder.options_mut().use_hosts_file = ResolveHosts::Always;
+    let resource_prober = cli
+        .resource_probes
+        .then(|| resource_health::ResourceProber::new(cli.resource_probe_interval.into()));
+
+    match resource_prober.as_ref().map(|p| p.snapshot()) {
+        Some(snapshot) => {
+            tokio::spawn(http_health_check::serve_with_resource_health(
+                cli.health_check.health_check_addr,
+                || true,
+                move || {
+                    snapshot
+                        .lock()
+                        .ok()
+                        .and_then(|s| serde_json::to_value(&*s).ok())
+                        .unwrap_or_default()
+                },
+            ));
+        }
+        None => {
+            tokio::spawn(http_health_check::serve(
+                cli.health_check.health_check_addr,
+                || true,
+            ));
+        }
+    }
 
-    let resolver = resolver_builder.build();
+    let resolver = resolver::system()?;
+    let dns_resolver =
+        resolver::DnsResolver::new(resolver.clone(), &cli.dns_upstream, &cli.dns_split)
+            .await
+            .context("Failed to configure DNS resolver")?;
 
-    Eventloop::new(tunnel, portal, tun_device_manager, resolver)?
-        .run()
-        .await
-        .context(EventloopFailed)?;
+    let flow_logs = flow_logs::FlowLogs::new(
+        &cli.flow_logs_sink,
+        flow_logs::Rotation {
+            max_size: cli.flow_logs_max_file_size,
+            max_files: cli.flow_logs_max_files,
+        },
+    )?;
+
+    Eventloop::new(
+        tunnel,
+        portal,
+        tun_device_manager,
+        resolver,
+        dns_resolver,
+        flow_logs,
+        resource_prober,
+        cli.max_drain_time.into(),
+    )?
+    .run()
+    .await
+    .context(EventloopFailed)?;
 
     Ok(())
 }
@@ -336,6 +400,47 @@ struct Cli {
     #[arg(long, env = "FIREZONE_FLOW_LOGS", default_value_t = false)]
     flow_logs: bool,
 
+    /// Enable logging of DNS queries resolved on behalf of clients.
+    ///
+    /// DNS queries are exported to the same sinks as flows.
+    #[arg(long, env = "FIREZONE_DNS_QUERY_LOGS", default_value_t = false)]
+    dns_query_logs: bool,
+
+    /// Export completed flows to the given sinks.
+    ///
+    /// Accepts `ndjson:<path>` for a newline-delimited JSON file, `ipfix:<host>:<port>` for an IPFIX collector
+    /// and `syslog:<host>:<port>` for an RFC 5424 syslog server.
+    /// Flows are tracked whenever a sink is configured, even without `--flow-logs`.
+    #[arg(long, env = "FIREZONE_FLOW_LOGS_SINK", value_delimiter = ',')]
+    flow_logs_sink: Vec<flow_logs::Sink>,
+
+    /// Rotate the NDJSON flow-log file once it exceeds this many bytes.
+    #[arg(long, env = "FIREZONE_FLOW_LOGS_MAX_FILE_SIZE", default_value_t = 100 * 1024 * 1024)]
+    flow_logs_max_file_size: u64,
+
+    /// How many rotated NDJSON flow-log files to keep.
+    #[arg(long, env = "FIREZONE_FLOW_LOGS_MAX_FILES", default_value_t = 5)]
+    flow_logs_max_files: usize,
+
+    /// Periodically probe the resources served by this Gateway and report their health to Clients.
+    ///
+    /// CIDR resources pointing to a single host and the resolved domains of DNS resources are probed
+    /// with TCP connects to the ports allowed by their filters, ICMP echo requests and DNS lookups.
+    /// The results are served as JSON at `http://<health_check_addr>/resourcez`.
+    #[arg(long, env = "FIREZONE_RESOURCE_PROBES", default_value_t = false)]
+    resource_probes: bool,
+
+    /// How often to probe resources.
+    #[arg(long, env = "FIREZONE_RESOURCE_PROBE_INTERVAL", default_value = "30s")]
+    resource_probe_interval: humantime::Duration,
+
+    /// How long to wait for the flows of existing Clients to end when draining before shutting down anyway.
+    ///
+    /// Draining is triggered by SIGUSR1.
+    /// While draining, the Gateway no longer accepts new Clients and asks connected Clients to move new flows to other Gateways of the Site.
+    #[arg(long, env = "FIREZONE_MAX_DRAIN_TIME", default_value = "1h")]
+    max_drain_time: humantime::Duration,
+
     /// Where to export metrics to.
     ///
     /// This configuration option is private API and has no stability guarantees.
@@ -361,6 +466,12 @@ struct Cli {
     )]
     validate_checksums: bool,
 
+    /// Do not install nftables rules for forwarding and masquerading traffic from Clients.
+    ///
+    /// Use this if you manage the firewall of the Gateway's host yourself.
+    #[arg(long, env = "FIREZONE_NO_MANAGE_FIREWALL", default_value_t = false)]
+    no_manage_firewall: bool,
+
     /// Do not try to increase the `core.rmem_max` and `core.wmem_max` kernel parameters.
     #[arg(long, env = "FIREZONE_NO_INC_BUF", default_value_t = false)]
     no_inc_buf: bool,
@@ -369,6 +480,20 @@ struct Cli {
     /// it's down. Accepts human times. e.g. "5m" or "1h" or "30d".
     #[arg(short, long, env = "FIREZONE_MAX_PARTITION_TIME")]
     max_partition_time: Option<humantime::Duration>,
+
+    /// Upstream DNS servers to resolve the domains of DNS resources with.
+    ///
+    /// Accepts IPs (e.g. `10.0.0.53`), socket addresses (e.g. `10.0.0.53:5353`) or DoH URLs (e.g. `https://dns.example.com/dns-query`).
+    /// By default, the system's resolvers are used.
+    #[arg(long, env = "FIREZONE_DNS_UPSTREAM", value_delimiter = ',')]
+    dns_upstream: Vec<resolver::Upstream>,
+
+    /// Resolve the domains matching a pattern via a dedicated upstream, e.g. `*.corp.internal=10.0.0.53`.
+    ///
+    /// `*.` matches all subdomains of a domain.
+    /// If several patterns match, the most specific one wins.
+    #[arg(long, env = "FIREZONE_DNS_SPLIT", value_delimiter = ',')]
+    dns_split: Vec<resolver::SplitRule>,
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
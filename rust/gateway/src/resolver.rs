@@ -0,0 +1,391 @@
+//! Resolution of the domains of DNS resources.
+//!
+//! By default, we resolve via the system's resolvers.
+//! Optionally, the admin can pin specific Do53 / DoH upstreams and route individual domains to dedicated upstreams.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{Context as _, Result};
+use dns_types::{DoHUrl, DomainName};
+use hickory_resolver::{
+    TokioResolver,
+    config::{NameServerConfig, NameServerConfigGroup, ResolveHosts, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::xfer::Protocol,
+};
+use url::Url;
+
+const DNS_PORT: u16 = 53;
+
+/// Creates a resolver from the system's configuration.
+pub fn system() -> Result<TokioResolver> {
+    let mut builder = TokioResolver::builder_tokio()?;
+    builder.options_mut().cache_size = 512;
+    builder.options_mut().use_hosts_file = ResolveHosts::Always;
+
+    Ok(builder.build())
+}
+
+/// Selects the resolver to use for a given domain.
+#[derive(Clone)]
+pub struct DnsResolver {
+    default: TokioResolver,
+    split: Vec<(DomainPattern, TokioResolver)>,
+}
+
+impl DnsResolver {
+    /// Constructs a new [`DnsResolver`].
+    ///
+    /// Without any `upstreams`, domains that don't match a split rule are resolved via the `system` resolver.
+    /// The `system` resolver is also used to resolve the host of DoH upstreams.
+    pub async fn new(
+        system: TokioResolver,
+        upstreams: &[Upstream],
+        split_rules: &[SplitRule],
+    ) -> Result<Self> {
+        let default = if upstreams.is_empty() {
+            system.clone()
+        } else {
+            build_resolver(upstreams, &system).await?
+        };
+
+        let mut split = Vec::with_capacity(split_rules.len());
+
+        for rule in split_rules {
+            let resolver = build_resolver(std::slice::from_ref(&rule.upstream), &system)
+                .await
+                .with_context(|| format!("Failed to create resolver for `{rule}`"))?;
+
+            split.push((rule.pattern.clone(), resolver));
+        }
+
+        Ok(Self { default, split })
+    }
+
+    /// Returns the resolver for the given domain.
+    ///
+    /// If several split rules match, the most specific one wins.
+    pub fn for_domain(&self, domain: &DomainName) -> &TokioResolver {
+        let domain = normalize(&domain.to_string());
+
+        self.split
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(&domain))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, resolver)| resolver)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// An upstream DNS server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    Do53(SocketAddr),
+    DoH(DoHUrl),
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("https://") {
+            let url = s.parse().context("Invalid DoH URL")?;
+
+            return Ok(Self::DoH(url));
+        }
+
+        if let Ok(socket) = s.parse() {
+            return Ok(Self::Do53(socket));
+        }
+
+        let ip = s
+            .parse::<IpAddr>()
+            .context("Expected an IP address, a socket address or an `https://` URL")?;
+
+        Ok(Self::Do53(SocketAddr::new(ip, DNS_PORT)))
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Do53(socket) => write!(f, "{socket}"),
+            Upstream::DoH(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// Routes the resolution of all domains matching a pattern to a dedicated upstream.
+///
+/// Parsed from `<pattern>=<upstream>`, e.g. `*.corp.internal=10.0.0.53`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitRule {
+    pattern: DomainPattern,
+    upstream: Upstream,
+}
+
+impl FromStr for SplitRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (pattern, upstream) = s
+            .split_once('=')
+            .context("Expected split rule in the form `<pattern>=<upstream>`")?;
+
+        Ok(Self {
+            pattern: pattern.parse()?,
+            upstream: upstream.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for SplitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.upstream)
+    }
+}
+
+/// Either an exact domain (`corp.internal`) or all its subdomains (`*.corp.internal`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DomainPattern {
+    domain: String,
+    subdomains: bool,
+}
+
+impl DomainPattern {
+    fn matches(&self, domain: &str) -> bool {
+        if !self.subdomains {
+            return domain == self.domain;
+        }
+
+        domain
+            .strip_suffix(self.domain.as_str())
+            .is_some_and(|label| label.len() > 1 && label.ends_with('.'))
+    }
+
+    fn specificity(&self) -> (usize, bool) {
+        (self.domain.len(), !self.subdomains)
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (domain, subdomains) = match s.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (s, false),
+        };
+
+        DomainName::vec_from_str(domain).with_context(|| format!("Invalid domain `{domain}`"))?;
+
+        Ok(Self {
+            domain: normalize(domain),
+            subdomains,
+        })
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.subdomains {
+            write!(f, "*.")?;
+        }
+
+        write!(f, "{}", self.domain)
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+async fn build_resolver(
+    upstreams: &[Upstream],
+    bootstrap: &TokioResolver,
+) -> Result<TokioResolver> {
+    let mut name_servers = Vec::new();
+
+    for upstream in upstreams {
+        match upstream {
+            Upstream::Do53(server) => {
+                name_servers.push(NameServerConfig::new(*server, Protocol::Udp));
+                name_servers.push(NameServerConfig::new(*server, Protocol::Tcp));
+            }
+            Upstream::DoH(url) => {
+                let host = url.host();
+                let parsed = Url::parse(&url.to_str()).context("Invalid DoH URL")?;
+                let path = parsed.path().to_owned();
+                let port = parsed
+                    .port_or_known_default()
+                    .context("DoH URL has no port")?;
+
+                let ips = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                    Ok(ip) => vec![ip],
+                    Err(_) => bootstrap
+                        .lookup_ip(host.as_ref())
+                        .await
+                        .with_context(|| format!("Failed to resolve DoH host `{host}`"))?
+                        .into_iter()
+                        .collect(),
+                };
+
+                for ip in ips {
+                    let mut config =
+                        NameServerConfig::new(SocketAddr::new(ip, port), Protocol::Https);
+                    config.tls_dns_name = Some(host.to_string());
+                    config.http_endpoint = Some(path.clone());
+
+                    name_servers.push(config);
+                }
+            }
+        }
+    }
+
+    let config =
+        ResolverConfig::from_parts(None, Vec::new(), NameServerConfigGroup::from(name_servers));
+
+    let mut builder =
+        TokioResolver::builder_with_config(config, TokioConnectionProvider::default());
+    builder.options_mut().cache_size = 512;
+    builder.options_mut().use_hosts_file = ResolveHosts::Always;
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, net::Ipv4Addr};
+
+    use super::*;
+
+    #[test]
+    fn parses_upstreams() {
+        assert_eq!(
+            "10.0.0.53".parse::<Upstream>().unwrap(),
+            Upstream::Do53("10.0.0.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            "[fd00::53]:5353".parse::<Upstream>().unwrap(),
+            Upstream::Do53("[fd00::53]:5353".parse().unwrap())
+        );
+        assert_eq!(
+            "https://dns.quad9.net/dns-query"
+                .parse::<Upstream>()
+                .unwrap(),
+            Upstream::DoH(DoHUrl::quad9())
+        );
+        assert!("dns.quad9.net".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn parses_split_rules() {
+        let rule = "*.corp.internal=10.0.0.53".parse::<SplitRule>().unwrap();
+
+        assert_eq!(rule.to_string(), "*.corp.internal=10.0.0.53:53");
+        assert!("*.corp.internal".parse::<SplitRule>().is_err());
+    }
+
+    #[test]
+    fn wildcard_pattern_matches_only_subdomains() {
+        let pattern = "*.corp.internal".parse::<DomainPattern>().unwrap();
+
+        assert!(pattern.matches("foo.corp.internal"));
+        assert!(pattern.matches("bar.foo.corp.internal"));
+        assert!(!pattern.matches("corp.internal"));
+        assert!(!pattern.matches("foocorp.internal"));
+    }
+
+    #[test]
+    fn exact_pattern_matches_only_domain() {
+        let pattern = "Corp.Internal".parse::<DomainPattern>().unwrap();
+
+        assert!(pattern.matches("corp.internal"));
+        assert!(!pattern.matches("foo.corp.internal"));
+    }
+
+    #[tokio::test]
+    async fn routes_domains_to_upstreams() {
+        let default_upstream = stub_server(Ipv4Addr::new(10, 0, 0, 1));
+        let corp_upstream = stub_server(Ipv4Addr::new(10, 0, 0, 2));
+        let eng_upstream = stub_server(Ipv4Addr::new(10, 0, 0, 3));
+
+        let resolver = DnsResolver::new(
+            system().unwrap(),
+            &[Upstream::Do53(default_upstream)],
+            &[
+                format!("*.corp.internal={corp_upstream}").parse().unwrap(),
+                format!("*.eng.corp.internal={eng_upstream}")
+                    .parse()
+                    .unwrap(),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            lookup_a(&resolver, "example.com").await,
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        assert_eq!(
+            lookup_a(&resolver, "corp.internal").await,
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        assert_eq!(
+            lookup_a(&resolver, "gitlab.corp.internal").await,
+            Ipv4Addr::new(10, 0, 0, 2)
+        );
+        assert_eq!(
+            lookup_a(&resolver, "ci.eng.corp.internal").await,
+            Ipv4Addr::new(10, 0, 0, 3)
+        );
+    }
+
+    async fn lookup_a(resolver: &DnsResolver, domain: &str) -> Ipv4Addr {
+        let domain = DomainName::vec_from_str(domain).unwrap();
+
+        resolver
+            .for_domain(&domain)
+            .ipv4_lookup(domain.to_string())
+            .await
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .0
+    }
+
+    /// Spawns a DNS server on localhost that answers all A queries with the given IP.
+    fn stub_server(ip: Ipv4Addr) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut server = l4_udp_dns_server::Server::default();
+        server.rebind(socket).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let query = poll_fn(|cx| server.poll(cx)).await.unwrap();
+
+                let records = (query.message.qtype() == dns_types::RecordType::A)
+                    .then(|| (query.message.domain(), 60, dns_types::records::a(ip)));
+                let response = dns_types::ResponseBuilder::for_query(
+                    &query.message,
+                    dns_types::ResponseCode::NOERROR,
+                )
+                .with_records(records)
+                .build();
+
+                server.send_response(query.remote, response).unwrap();
+            }
+        });
+
+        socket
+    }
+}
@@ -0,0 +1,455 @@
+//! Synthetic probing of the resources served by this Gateway.
+//!
+//! Every probe round, we probe all targets derived from the resources that Clients currently have access to.
+//! The health of a resource is aggregated from the latest result of each of its probes.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, ErrorExt as _, Result};
+use connlib_model::ResourceId;
+use dns_types::DomainName;
+use hickory_resolver::TokioResolver;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Gauge, Histogram};
+use socket2::{Domain, Protocol, Socket, Type};
+use tunnel::{HealthStatus, ProbeTarget};
+
+use crate::resolver::DnsResolver;
+
+/// How long a single probe may take before we consider it failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many probes we at most run concurrently.
+const MAX_CONCURRENT_PROBES: usize = 1000;
+
+/// The identifier of the next ICMP echo request, allows us to match replies on raw sockets.
+static NEXT_ICMP_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+pub struct ResourceProber {
+    interval: tokio::time::Interval,
+    probes: futures_bounded::FuturesTupleSet<Result<()>, (ResourceId, ProbeTarget, Instant)>,
+
+    results: BTreeMap<ResourceId, BTreeMap<ProbeTarget, ProbeResult>>,
+
+    /// A snapshot of the health of all resources, served by the health-check endpoint.
+    snapshot: Arc<Mutex<BTreeMap<ResourceId, ResourceReport>>>,
+
+    probe_duration: Histogram<f64>,
+    resource_health: Gauge<u64>,
+}
+
+pub enum Event {
+    /// It is time to start a new round of probes.
+    RoundDue,
+    /// We have a new result for the health of a resource.
+    Health {
+        resource: ResourceId,
+        status: HealthStatus,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum ProbeResult {
+    Success,
+    Failure(String),
+}
+
+/// The health of a resource as reported by the health-check endpoint.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ResourceReport {
+    status: HealthStatus,
+    failing_probes: BTreeMap<String, String>,
+}
+
+/// We can not send ICMP echo requests because we are not allowed to open an ICMP socket.
+///
+/// Probes failing with this error don't count towards the health of a resource.
+#[derive(thiserror::Error, Debug)]
+#[error("Not permitted to open ICMP socket")]
+struct IcmpNotPermitted;
+
+impl ResourceProber {
+    pub fn new(interval: Duration) -> Self {
+        let meter = opentelemetry::global::meter("gateway");
+
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        Self {
+            interval,
+            probes: futures_bounded::FuturesTupleSet::new(
+                || futures_bounded::Delay::tokio(PROBE_TIMEOUT),
+                MAX_CONCURRENT_PROBES,
+            ),
+            results: Default::default(),
+            snapshot: Default::default(),
+            probe_duration: meter
+                .f64_histogram("gateway.resource.probe.duration")
+                .with_description("Duration of synthetic probes against resources")
+                .with_unit("s")
+                .build(),
+            resource_health: meter
+                .u64_gauge("gateway.resource.health")
+                .with_description(
+                    "The health of a resource: 0 = healthy, 1 = degraded, 2 = unreachable",
+                )
+                .build(),
+        }
+    }
+
+    /// A handle to the latest health of all resources.
+    pub fn snapshot(&self) -> Arc<Mutex<BTreeMap<ResourceId, ResourceReport>>> {
+        self.snapshot.clone()
+    }
+
+    /// Starts probing the given targets.
+    ///
+    /// Results of targets that are no longer present are discarded.
+    pub fn start_round(
+        &mut self,
+        targets: BTreeSet<(ResourceId, ProbeTarget)>,
+        dns_resolver: &DnsResolver,
+    ) {
+        self.results.retain(|rid, results| {
+            results.retain(|target, _| targets.contains(&(*rid, target.clone())));
+
+            !results.is_empty()
+        });
+        self.update_snapshot();
+
+        tracing::debug!(num_targets = %targets.len(), "Probing resources");
+
+        for (rid, target) in targets {
+            let probe = probe(target.clone(), dns_resolver);
+
+            if self
+                .probes
+                .try_push(probe, (rid, target, Instant::now()))
+                .is_err()
+            {
+                tracing::debug!(%rid, "Too many concurrent probes, skipping");
+            }
+        }
+    }
+
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Event> {
+        loop {
+            if let Poll::Ready((result, (rid, target, started_at))) = self.probes.poll_unpin(cx) {
+                let result = result.unwrap_or_else(|_| Err(anyhow::anyhow!("Probe timed out")));
+
+                let Some(status) = self.on_probe_result(rid, target, result, started_at.elapsed())
+                else {
+                    continue;
+                };
+
+                return Poll::Ready(Event::Health {
+                    resource: rid,
+                    status,
+                });
+            }
+
+            if self.interval.poll_tick(cx).is_ready() {
+                return Poll::Ready(Event::RoundDue);
+            }
+
+            return Poll::Pending;
+        }
+    }
+
+    fn on_probe_result(
+        &mut self,
+        rid: ResourceId,
+        target: ProbeTarget,
+        result: Result<()>,
+        duration: Duration,
+    ) -> Option<HealthStatus> {
+        let outcome = match &result {
+            Ok(()) => "success",
+            Err(e) if e.any_is::<IcmpNotPermitted>() => "skipped",
+            Err(_) => "failure",
+        };
+        // Resources and their targets are unbounded, thus we only log them.
+        self.probe_duration.record(
+            duration.as_secs_f64(),
+            &[
+                KeyValue::new("probe", target.kind()),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+
+        let result = match result {
+            Ok(()) => {
+                tracing::trace!(%rid, %target, ?duration, "Probe succeeded");
+
+                ProbeResult::Success
+            }
+            Err(e) if e.any_is::<IcmpNotPermitted>() => {
+                tracing::debug!(%rid, %target, "Skipping probe: {e:#}");
+
+                return None;
+            }
+            Err(e) => {
+                tracing::debug!(%rid, %target, ?duration, "Probe failed: {e:#}");
+
+                ProbeResult::Failure(format!("{e:#}"))
+            }
+        };
+
+        let results = self.results.entry(rid).or_default();
+        results.insert(target, result);
+        let status = aggregate(results);
+
+        self.resource_health.record(
+            match status {
+                HealthStatus::Healthy => 0,
+                HealthStatus::Degraded => 1,
+                HealthStatus::Unreachable | HealthStatus::Unknown => 2,
+            },
+            &[KeyValue::new("resource_id", rid.to_string())],
+        );
+        self.update_snapshot();
+
+        Some(status)
+    }
+
+    fn update_snapshot(&self) {
+        let snapshot = self
+            .results
+            .iter()
+            .map(|(rid, results)| {
+                let failing_probes = results
+                    .iter()
+                    .filter_map(|(target, result)| match result {
+                        ProbeResult::Success => None,
+                        ProbeResult::Failure(e) => Some((target.to_string(), e.clone())),
+                    })
+                    .collect();
+
+                (
+                    *rid,
+                    ResourceReport {
+                        status: aggregate(results),
+                        failing_probes,
+                    },
+                )
+            })
+            .collect();
+
+        match self.snapshot.lock() {
+            Ok(mut guard) => *guard = snapshot,
+            Err(e) => tracing::warn!("Failed to update resource health snapshot: {e}"),
+        }
+    }
+}
+
+fn aggregate(results: &BTreeMap<ProbeTarget, ProbeResult>) -> HealthStatus {
+    let num_failures = results
+        .values()
+        .filter(|r| matches!(r, ProbeResult::Failure(_)))
+        .count();
+
+    match num_failures {
+        0 => HealthStatus::Healthy,
+        n if n == results.len() => HealthStatus::Unreachable,
+        _ => HealthStatus::Degraded,
+    }
+}
+
+fn probe(
+    target: ProbeTarget,
+    dns_resolver: &DnsResolver,
+) -> futures::future::BoxFuture<'static, Result<()>> {
+    match target {
+        ProbeTarget::TcpConnect(socket) => Box::pin(tcp_connect(socket)),
+        ProbeTarget::IcmpEcho(ip) => Box::pin(icmp_echo(ip)),
+        ProbeTarget::Dns(domain) => {
+            Box::pin(resolve(dns_resolver.for_domain(&domain).clone(), domain))
+        }
+    }
+}
+
+async fn tcp_connect(socket: SocketAddr) -> Result<()> {
+    tokio::net::TcpStream::connect(socket)
+        .await
+        .context("Failed to connect")?;
+
+    Ok(())
+}
+
+async fn resolve(resolver: TokioResolver, domain: DomainName) -> Result<()> {
+    let lookup = resolver
+        .lookup_ip(domain.to_string())
+        .await
+        .context("Failed to resolve domain")?;
+
+    anyhow::ensure!(lookup.iter().next().is_some(), "No A / AAAA records");
+
+    Ok(())
+}
+
+/// Sends an ICMP echo request to the given IP and waits for the reply.
+///
+/// We first try an unprivileged ICMP socket and fall back to a raw socket.
+async fn icmp_echo(ip: IpAddr) -> Result<()> {
+    let (domain, protocol, request_type, reply_type) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4, 8, 0),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6, 128, 129),
+    };
+
+    let (socket, is_raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(_) => (
+            Socket::new(domain, Type::RAW, Some(protocol)).context(IcmpNotPermitted)?,
+            true,
+        ),
+    };
+    socket.set_nonblocking(true)?;
+
+    let socket = tokio::net::UdpSocket::from_std(std::net::UdpSocket::from(socket))?;
+    socket.connect(SocketAddr::new(ip, 0)).await?;
+
+    let identifier = NEXT_ICMP_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    socket
+        .send(&echo_request(request_type, identifier))
+        .await
+        .context("Failed to send ICMP echo request")?;
+
+    let mut buf = [0u8; 1500];
+
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .context("Failed to receive ICMP echo reply")?;
+        let packet = buf.get(..len).unwrap_or_default();
+
+        // Raw ICMPv4 sockets include the IP header.
+        let icmp = if is_raw && ip.is_ipv4() {
+            let header_len = packet.first().map(|b| usize::from(b & 0x0F) * 4);
+
+            header_len.and_then(|l| packet.get(l..)).unwrap_or_default()
+        } else {
+            packet
+        };
+
+        if icmp.first() != Some(&reply_type) {
+            continue;
+        }
+
+        // Raw sockets receive all ICMP packets, unprivileged ones have their identifier rewritten by the kernel.
+        if is_raw && icmp.get(4..6) != Some(&identifier.to_be_bytes()) {
+            continue;
+        }
+
+        return Ok(());
+    }
+}
+
+fn echo_request(request_type: u8, identifier: u16) -> [u8; 16] {
+    let mut packet = [0u8; 16];
+    packet[0] = request_type;
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&1u16.to_be_bytes()); // Sequence number
+    packet[8..].copy_from_slice(b"firezone");
+
+    // The kernel computes the checksum for ICMPv6, doing it for ICMPv4 is harmless in all cases.
+    let checksum = !packet
+        .chunks_exact(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .fold(0u32, |acc, word| {
+            let sum = acc + word;
+
+            (sum & 0xFFFF) + (sum >> 16)
+        }) as u16;
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn all_probes_succeeding_is_healthy() {
+        let results = BTreeMap::from([
+            (tcp(443), ProbeResult::Success),
+            (icmp(), ProbeResult::Success),
+        ]);
+
+        assert_eq!(aggregate(&results), HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn some_probes_failing_is_degraded() {
+        let results = BTreeMap::from([
+            (
+                tcp(443),
+                ProbeResult::Failure("Connection refused".to_owned()),
+            ),
+            (icmp(), ProbeResult::Success),
+        ]);
+
+        assert_eq!(aggregate(&results), HealthStatus::Degraded);
+    }
+
+    #[test]
+    fn all_probes_failing_is_unreachable() {
+        let results = BTreeMap::from([
+            (
+                tcp(443),
+                ProbeResult::Failure("Connection refused".to_owned()),
+            ),
+            (icmp(), ProbeResult::Failure("Timeout".to_owned())),
+        ]);
+
+        assert_eq!(aggregate(&results), HealthStatus::Unreachable);
+    }
+
+    #[test]
+    fn echo_request_has_valid_checksum() {
+        let packet = echo_request(8, 0x1234);
+
+        let mut sum = packet
+            .chunks_exact(2)
+            .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+            .sum::<u32>();
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        assert_eq!(sum, 0xFFFF);
+    }
+
+    #[tokio::test]
+    async fn skipped_probes_do_not_count() {
+        let mut prober = ResourceProber::new(Duration::from_secs(30));
+
+        let status = prober.on_probe_result(
+            RID,
+            icmp(),
+            Err(anyhow::Error::new(IcmpNotPermitted)),
+            Duration::ZERO,
+        );
+
+        assert!(status.is_none());
+        assert!(prober.results.is_empty());
+    }
+
+    fn tcp(port: u16) -> ProbeTarget {
+        ProbeTarget::TcpConnect(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), port))
+    }
+
+    fn icmp() -> ProbeTarget {
+        ProbeTarget::IcmpEcho(Ipv4Addr::new(10, 0, 0, 1).into())
+    }
+
+    const RID: ResourceId = ResourceId::from_u128(1);
+}
@@ -23,6 +23,7 @@ export default function GeneralSettingsPage({
       start_on_login: false,
       account_slug_is_managed: false,
       connect_on_start_is_managed: false,
+      persist_dns_cache: false,
     }
   );
 
@@ -35,6 +36,7 @@ export default function GeneralSettingsPage({
         start_on_login: false,
         account_slug_is_managed: false,
         connect_on_start_is_managed: false,
+        persist_dns_cache: false,
       }
     );
   }, [settings]);
@@ -43,6 +45,7 @@ export default function GeneralSettingsPage({
   const startMinimizedInputId = useId();
   const startOnLoginInputId = useId();
   const connectOnStartInputId = useId();
+  const persistDnsCacheInputId = useId();
   return (
     <div className="container p-4">
       <form
@@ -122,6 +125,26 @@ export default function GeneralSettingsPage({
               }
             />
           </div>
+
+          <div className="flex justify-between items-center">
+            <Label
+              className="text-neutral-600"
+              htmlFor={persistDnsCacheInputId}
+            >
+              Remember DNS records across restarts
+            </Label>
+            <ToggleSwitch
+              name="persist_dns_cache"
+              id={persistDnsCacheInputId}
+              checked={localSettings.persist_dns_cache}
+              onChange={(e) =>
+                setLocalSettings({
+                  ...localSettings,
+                  persist_dns_cache: e,
+                })
+              }
+            />
+          </div>
         </div>
 
         <div className="flex justify-end gap-4 mt-4">
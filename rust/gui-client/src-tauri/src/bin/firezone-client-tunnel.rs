@@ -25,8 +25,17 @@ fn main() -> anyhow::Result<()> {
 
     match cli.command {
         Cmd::Install => service::install(),
-        Cmd::Run => service::run(cli.log_dir, cli.dns_control),
-        Cmd::RunDebug => service::run_debug(cli.dns_control),
+        Cmd::Run => service::run(
+            cli.log_dir,
+            cli.dns_control,
+            #[cfg(target_os = "linux")]
+            cli.cgroup_split_tunnel,
+        ),
+        Cmd::RunDebug => service::run_debug(
+            cli.dns_control,
+            #[cfg(target_os = "linux")]
+            cli.cgroup_split_tunnel,
+        ),
         Cmd::RunSmokeTest => service::run_smoke_test(),
     }
 }
@@ -49,6 +58,10 @@ pub struct Cli {
     #[arg(long, env = "FIREZONE_DNS_CONTROL", default_value = "none")]
     dns_control: DnsControlMethod,
 
+    #[cfg(target_os = "linux")]
+    #[command(flatten)]
+    cgroup_split_tunnel: bin_shared::CgroupSplitTunnel,
+
     /// File logging directory. Should be a path that's writeable by the current user.
     #[arg(short, long, env = "LOG_DIR")]
     log_dir: Option<PathBuf>,
@@ -340,6 +340,7 @@ impl<I: GuiIntegration> Controller<I> {
             api_url: api_url.to_string(),
             token,
             is_internet_resource_active: self.general_settings.internet_resource_enabled(),
+            persist_dns_cache: self.general_settings.persist_dns_cache(),
         })
         .await?;
 
@@ -435,6 +436,7 @@ impl<I: GuiIntegration> Controller<I> {
                     start_on_login: Some(settings.start_on_login),
                     connect_on_start: Some(settings.connect_on_start),
                     account_slug: (!account_slug.is_empty()).then_some(account_slug.to_owned()),
+                    persist_dns_cache: Some(settings.persist_dns_cache),
                     ..self.general_settings.clone()
                 })
                 .await?;
@@ -445,6 +447,7 @@ impl<I: GuiIntegration> Controller<I> {
                     start_on_login: None,
                     connect_on_start: None,
                     account_slug: None,
+                    persist_dns_cache: None,
                     ..self.general_settings.clone()
                 })
                 .await?;
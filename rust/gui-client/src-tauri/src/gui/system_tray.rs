@@ -36,6 +36,7 @@ const QUIT_TEXT_SIGNED_OUT: &str = "Quit Firezone";
 const NO_ACTIVITY: &str = "[-] No activity";
 const GATEWAY_CONNECTED: &str = "[O] Gateway connected";
 const ALL_GATEWAYS_OFFLINE: &str = "[X] All Gateways offline";
+const RESOURCE_DEGRADED: &str = "[!] Resource degraded";
 
 const ENABLED_SYMBOL: &str = "<->";
 const DISABLED_SYMBOL: &str = "—";
@@ -346,6 +347,7 @@ impl SignedIn {
                 ResourceStatus::Unknown => NO_ACTIVITY,
                 ResourceStatus::Online => GATEWAY_CONNECTED,
                 ResourceStatus::Offline => ALL_GATEWAYS_OFFLINE,
+                ResourceStatus::Degraded => RESOURCE_DEGRADED,
             };
 
             submenu
@@ -56,6 +56,8 @@ pub enum ClientMsg {
         #[serde(serialize_with = "serialize_token")]
         token: SecretString,
         is_internet_resource_active: bool,
+        #[serde(default)]
+        persist_dns_cache: bool,
     },
     Disconnect,
     ApplyLogFilter {
@@ -115,6 +117,7 @@ impl ServerMsg {
 /// client a hint about that before we exit.
 async fn ipc_listen(
     dns_control_method: DnsControlMethod,
+    #[cfg(target_os = "linux")] cgroup_split_tunnel: bin_shared::CgroupSplitTunnel,
     log_filter_reloader: &FilterReloadHandle,
     signals: &mut signals::Terminate,
 ) -> Result<()> {
@@ -172,6 +175,10 @@ async fn ipc_listen(
                 continue;
             }
         };
+        #[cfg(target_os = "linux")]
+        handler
+            .tun_device
+            .set_cgroup_split_tunnel(cgroup_split_tunnel.clone());
         if let HandlerOk::ServiceTerminating = handler.run(signals).await {
             break;
         }
@@ -209,6 +216,7 @@ enum Session {
         api_url: String,
         token: SecretString,
         is_internet_resource_active: bool,
+        persist_dns_cache: bool,
     },
     #[default]
     None,
@@ -394,6 +402,7 @@ impl<'a> Handler<'a> {
                         api_url,
                         token,
                         is_internet_resource_active,
+                        persist_dns_cache,
                     } => {
                         tracing::info!("Attempting to re-connect upon network change");
 
@@ -401,6 +410,7 @@ impl<'a> Handler<'a> {
                             &api_url.clone(),
                             token.clone(),
                             *is_internet_resource_active,
+                            *persist_dns_cache,
                         );
 
                         if let Some(e) = result
@@ -529,12 +539,18 @@ impl<'a> Handler<'a> {
                 api_url,
                 token,
                 is_internet_resource_active,
+                persist_dns_cache,
             } => {
                 if !self.session.is_none() {
                     tracing::debug!(session = ?self.session, "Connecting despite existing session");
                 }
 
-                let result = self.try_connect(&api_url, token.clone(), is_internet_resource_active);
+                let result = self.try_connect(
+                    &api_url,
+                    token.clone(),
+                    is_internet_resource_active,
+                    persist_dns_cache,
+                );
 
                 if let Some(e) = result
                     .as_ref()
@@ -548,6 +564,7 @@ impl<'a> Handler<'a> {
                         api_url,
                         token,
                         is_internet_resource_active,
+                        persist_dns_cache,
                     };
 
                     return Ok(());
@@ -624,6 +641,7 @@ impl<'a> Handler<'a> {
         api_url: &str,
         token: SecretString,
         is_internet_resource_active: bool,
+        persist_dns_cache: bool,
     ) -> Result<Session> {
         let started_at = Instant::now();
 
@@ -664,6 +682,9 @@ impl<'a> Handler<'a> {
             portal,
             is_internet_resource_active,
             dns,
+            persist_dns_cache
+                .then(known_dirs::dns_cache)
+                .and_then(Result::ok),
             tokio::runtime::Handle::current(),
         );
 
@@ -712,7 +733,10 @@ impl<'a> Handler<'a> {
     }
 }
 
-pub fn run_debug(dns_control: DnsControlMethod) -> Result<()> {
+pub fn run_debug(
+    dns_control: DnsControlMethod,
+    #[cfg(target_os = "linux")] cgroup_split_tunnel: bin_shared::CgroupSplitTunnel,
+) -> Result<()> {
     let log_filter_reloader = logging::setup_stdout()?;
     tracing::info!(
         arch = std::env::consts::ARCH,
@@ -730,7 +754,13 @@ pub fn run_debug(dns_control: DnsControlMethod) -> Result<()> {
     let _guard = rt.enter();
     let mut signals = signals::Terminate::new()?;
 
-    rt.block_on(ipc_listen(dns_control, &log_filter_reloader, &mut signals))
+    rt.block_on(ipc_listen(
+        dns_control,
+        #[cfg(target_os = "linux")]
+        cgroup_split_tunnel,
+        &log_filter_reloader,
+        &mut signals,
+    ))
 }
 
 /// Listen for exactly one connection from a GUI, then exit
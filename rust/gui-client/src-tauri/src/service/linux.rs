@@ -1,12 +1,16 @@
 use std::{path::PathBuf, time::Duration};
 
 use anyhow::{Context as _, Result, bail};
-use bin_shared::{DnsControlMethod, signals};
+use bin_shared::{CgroupSplitTunnel, DnsControlMethod, signals};
 
 /// Cross-platform entry point for systemd / Windows services
 ///
 /// Linux uses the CLI args from here, Windows does not
-pub fn run(log_dir: Option<PathBuf>, dns_control: DnsControlMethod) -> Result<()> {
+pub fn run(
+    log_dir: Option<PathBuf>,
+    dns_control: DnsControlMethod,
+    cgroup_split_tunnel: CgroupSplitTunnel,
+) -> Result<()> {
     let (_handle, log_filter_reloader) = crate::logging::setup_tunnel(log_dir)?;
     if !elevation_check()? {
         bail!("Tunnel service failed its elevation check, try running as admin / root");
@@ -22,6 +26,7 @@ pub fn run(log_dir: Option<PathBuf>, dns_control: DnsControlMethod) -> Result<()
 
     rt.block_on(super::ipc_listen(
         dns_control,
+        cgroup_split_tunnel,
         &log_filter_reloader,
         &mut signals,
     ))
@@ -73,6 +73,8 @@ pub struct GeneralSettings {
     pub connect_on_start: Option<bool>,
     #[serde(default)]
     pub account_slug: Option<String>,
+    #[serde(default)]
+    pub persist_dns_cache: Option<bool>,
 }
 
 fn start_minimized_default() -> bool {
@@ -87,6 +89,7 @@ pub struct GeneralSettingsViewModel {
     pub connect_on_start_is_managed: bool,
     pub account_slug: String,
     pub account_slug_is_managed: bool,
+    pub persist_dns_cache: bool,
 }
 
 impl GeneralSettingsViewModel {
@@ -104,6 +107,7 @@ impl GeneralSettingsViewModel {
                 .account_slug
                 .or(general_settings.account_slug)
                 .unwrap_or_default(),
+            persist_dns_cache: general_settings.persist_dns_cache(),
         }
     }
 }
@@ -170,6 +174,11 @@ impl GeneralSettings {
     pub fn internet_resource_enabled(&self) -> bool {
         self.internet_resource_enabled.is_some_and(|v| v)
     }
+
+    /// Whether the DNS cache should be written to disk on disconnect and restored on the next connect.
+    pub fn persist_dns_cache(&self) -> bool {
+        self.persist_dns_cache.is_some_and(|v| v)
+    }
 }
 
 impl Default for AdvancedSettings {
@@ -216,6 +225,7 @@ pub async fn migrate_legacy_settings(
         start_on_login: None,
         connect_on_start: None,
         account_slug: None,
+        persist_dns_cache: None,
     };
 
     if let Err(e) = save_general(&general).await {
@@ -18,6 +18,7 @@ pub struct GeneralSettingsForm {
     pub start_on_login: bool,
     pub connect_on_start: bool,
     pub account_slug: String,
+    pub persist_dns_cache: bool,
 }
 
 #[derive(Clone, Debug, serde::Serialize, specta::Type, PartialEq, Eq)]
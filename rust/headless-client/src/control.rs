@@ -0,0 +1,175 @@
+//! Serves the local control API, see [`headless_control`] for the protocol.
+
+use std::{
+    os::unix::fs::{DirBuilderExt as _, PermissionsExt as _},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result};
+use connlib_model::ResourceView;
+use headless_control::{Request, Response, Status};
+use logging::FilterReloadHandle;
+use nix::sys::stat::{Mode, umask};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::watch,
+    task::JoinHandle,
+};
+
+/// The state of the headless Client that is exposed via the control API.
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub(crate) resources: Vec<ResourceView>,
+    pub(crate) tunnel_ready: bool,
+}
+
+/// Accepts connections on the control socket until dropped.
+pub(crate) struct Server {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+struct Context {
+    session: client_shared::Session,
+    state: watch::Receiver<State>,
+    log_filter: FilterReloadHandle,
+}
+
+impl Server {
+    pub(crate) fn spawn(
+        path: PathBuf,
+        session: client_shared::Session,
+        state: watch::Receiver<State>,
+        log_filter: FilterReloadHandle,
+    ) -> Result<Self> {
+        // Never take over the socket of a Client that is still running.
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            anyhow::bail!(
+                "Another instance is already listening on `{}`",
+                path.display()
+            );
+        }
+
+        // Remove the socket if a previous run left it there.
+        std::fs::remove_file(&path).ok();
+
+        if let Some(dir) = path.parent() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .context("Failed to create socket parent directory")?;
+        }
+
+        // Controlling the tunnel is as privileged as running the Client itself,
+        // so the socket must not be accessible by anyone else, not even between `bind` and `chmod`.
+        let previous_umask = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(&path);
+        umask(previous_umask);
+        let listener =
+            listener.with_context(|| format!("Couldn't bind UDS `{}`", path.display()))?;
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+            .context("Failed to set permissions on UDS")?;
+
+        tracing::info!(socket = %path.display(), "Listening for control connections");
+
+        let context = Arc::new(Context {
+            session,
+            state,
+            log_filter,
+        });
+
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::debug!("Failed to accept control connection: {e}");
+                        continue;
+                    }
+                };
+
+                tokio::spawn({
+                    let context = context.clone();
+
+                    async move {
+                        if let Err(e) = handle_connection(stream, &context).await {
+                            tracing::debug!("Control connection failed: {e:#}");
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { path, task })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::debug!(path = %self.path.display(), "Failed to delete control socket: {e}");
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream, context: &Context) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await.context("Failed to read request")? {
+        let response = match headless_control::decode::<Request>(&line) {
+            Ok(request) => handle_request(request, context).await,
+            Err(e) => Response::Error {
+                message: format!("{e:#}"),
+            },
+        };
+
+        write
+            .write_all(&headless_control::encode(&response)?)
+            .await
+            .context("Failed to write response")?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(request: Request, context: &Context) -> Response {
+    tracing::debug!(?request, "Handling control request");
+
+    match request {
+        Request::Status => {
+            let gateways = context.session.gateways().await;
+
+            Response::Status(Status {
+                version: crate::VERSION.to_owned(),
+                tunnel_ready: context.state.borrow().tunnel_ready,
+                gateways,
+            })
+        }
+        Request::Resources => Response::Resources {
+            resources: context.state.borrow().resources.clone(),
+        },
+        Request::SetInternetResourceState { active } => {
+            context.session.set_internet_resource_state(active);
+
+            Response::Ok
+        }
+        Request::Reset => {
+            context.session.reset("control API".to_owned());
+
+            Response::Ok
+        }
+        Request::ApplyLogFilter { directives } => match context.log_filter.reload(&directives) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error {
+                message: format!("{e:#}"),
+            },
+        },
+    }
+}
@@ -15,7 +15,8 @@ pub(crate) fn default_token_path() -> PathBuf {
     PathBuf::from("/etc").join(BUNDLE_ID).join("token")
 }
 
-pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
+/// Checks that only root can read the token, or only the current user in userspace mode because we don't run as root then.
+pub(crate) fn check_token_permissions(path: &Path, userspace: bool) -> Result<()> {
     let Ok(stat) = nix::sys::stat::fstatat(AT_FDCWD, path, nix::fcntl::AtFlags::empty()) else {
         // File doesn't exist or can't be read
         tracing::info!(
@@ -25,13 +26,22 @@ pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
         );
         bail!("Token file doesn't exist");
     };
-    if stat.st_uid != ROOT_USER {
+    if userspace {
+        let uid = nix::unistd::geteuid();
+
+        if stat.st_uid != uid.as_raw() {
+            bail!(
+                "Token file `{}` should be owned by the current user ({uid})",
+                path.display()
+            );
+        }
+    } else if stat.st_uid != ROOT_USER {
         bail!(
             "Token file `{}` should be owned by root user",
             path.display()
         );
     }
-    if stat.st_gid != ROOT_GROUP {
+    if !userspace && stat.st_gid != ROOT_GROUP {
         bail!(
             "Token file `{}` should be owned by root group",
             path.display()
@@ -4,7 +4,7 @@ use std::path::{Path, PathBuf};
 
 // The return value is useful on Linux
 #[expect(clippy::unnecessary_wraps)]
-pub(crate) fn check_token_permissions(_path: &Path) -> Result<()> {
+pub(crate) fn check_token_permissions(_path: &Path, _userspace: bool) -> Result<()> {
     // TODO: Implement token permission checks on macOS
     Ok(())
 }
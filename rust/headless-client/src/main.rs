@@ -6,7 +6,7 @@ use anyhow::{Context as _, Result, anyhow};
 use backoff::ExponentialBackoffBuilder;
 use bin_shared::{
     DnsControlMethod, DnsController, TOKEN_ENV_KEY, TunDeviceManager, device_id, device_info,
-    new_dns_notifier, new_network_notifier,
+    known_dirs, new_dns_notifier, new_network_notifier,
     platform::{UdpSocketFactory, tcp_socket_factory},
     signals,
 };
@@ -18,7 +18,9 @@ use phoenix_channel::PhoenixChannel;
 use phoenix_channel::get_user_agent;
 use phoenix_channel::{DeviceInfo, LoginUrl};
 use secrecy::SecretString;
+use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::{
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
@@ -28,6 +30,9 @@ use telemetry::{
 };
 use tokio::time::Instant;
 
+#[cfg(unix)]
+mod control;
+
 #[cfg(target_os = "linux")]
 #[path = "linux.rs"]
 mod platform;
@@ -40,6 +45,8 @@ mod platform;
 #[path = "macos.rs"]
 mod platform;
 
+mod userspace;
+
 /// Command-line args for the headless Client
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -112,6 +119,35 @@ struct Cli {
     )]
     activate_internet_resource: bool,
 
+    /// Write the DNS cache to disk on shutdown and restore it on the next start.
+    ///
+    /// This allows answering DNS queries for Resources right away after a restart but leaves a record of recently resolved domains on disk.
+    #[arg(long, env = "FIREZONE_PERSIST_DNS_CACHE", default_value_t = false)]
+    persist_dns_cache: bool,
+
+    #[cfg(target_os = "linux")]
+    #[command(flatten)]
+    cgroup_split_tunnel: bin_shared::CgroupSplitTunnel,
+
+    /// Run without a TUN device and expose Resources through local SOCKS5 and HTTP CONNECT proxies instead.
+    ///
+    /// This doesn't require elevated privileges and leaves the system's DNS configuration untouched.
+    /// The token file only needs to be owned by the current user and state is kept in the user's data directory.
+    #[arg(long, env = "FIREZONE_USERSPACE", default_value_t = false)]
+    userspace: bool,
+
+    /// Where to listen for SOCKS5 connections in userspace mode.
+    #[arg(long, env = "FIREZONE_SOCKS5_LISTEN", default_value = "127.0.0.1:1080")]
+    socks5_listen: SocketAddr,
+
+    /// Where to listen for HTTP CONNECT connections in userspace mode.
+    #[arg(
+        long,
+        env = "FIREZONE_HTTP_CONNECT_LISTEN",
+        default_value = "127.0.0.1:3128"
+    )]
+    http_connect_listen: SocketAddr,
+
     /// Disable sentry.io crash-reporting agent.
     #[arg(long, env = "FIREZONE_NO_TELEMETRY", default_value_t = false)]
     no_telemetry: bool,
@@ -143,6 +179,24 @@ struct Cli {
     /// Increase the `core.rmem_max` and `core.wmem_max` kernel parameters.
     #[arg(long, env = "FIREZONE_INC_BUF", hide = true, default_value_t = false)]
     inc_buf: bool,
+
+    /// Where to listen for the local control API, used by e.g. `firezone client status`.
+    #[cfg(unix)]
+    #[arg(long, env = "FIREZONE_CONTROL_SOCKET", default_value = headless_control::DEFAULT_SOCKET_PATH)]
+    control_socket: PathBuf,
+
+    /// Don't expose the local control API.
+    #[cfg(unix)]
+    #[arg(long, env = "FIREZONE_NO_CONTROL_SOCKET", default_value_t = false)]
+    no_control_socket: bool,
+}
+
+/// How connlib's packets reach applications.
+enum Device {
+    /// A TUN device, configured through the OS.
+    Tun(TunDeviceManager),
+    /// Our own network stack behind local proxies, see [`userspace`].
+    Userspace(userspace::Userspace),
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
@@ -257,7 +311,14 @@ fn try_main() -> Result<()> {
         .as_deref()
         .map(|dir| logging::file::layer(dir, "firezone-headless-client"))
         .unzip();
-    logging::setup_global_subscriber(
+    #[cfg_attr(
+        not(unix),
+        expect(
+            unused_variables,
+            reason = "Only the control API reloads the log filter"
+        )
+    )]
+    let log_filter_reloader = logging::setup_global_subscriber(
         std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
         layer,
         false,
@@ -284,7 +345,10 @@ fn try_main() -> Result<()> {
     let mut dns_controller = DnsController { dns_control_method };
     // Deactivate Firezone DNS control in case the system or Tunnel service crashed
     // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
-    dns_controller.deactivate()?;
+    // In userspace mode, we never touch the system's DNS configuration.
+    if !cli.userspace {
+        dns_controller.deactivate()?;
+    }
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(1)
@@ -310,6 +374,7 @@ fn try_main() -> Result<()> {
     // AKA "Device ID", not the Firezone slug
     let firezone_id = match cli.firezone_id.clone() {
         Some(id) => id,
+        None if cli.userspace => device_id::get_or_create_unprivileged_client().context("Could not get `firezone_id` from CLI, could not read it from disk, could not generate it and save it to disk")?.id,
         None => device_id::get_or_create_client().context("Could not get `firezone_id` from CLI, could not read it from disk, could not generate it and save it to disk")?.id,
     };
 
@@ -332,7 +397,7 @@ fn try_main() -> Result<()> {
 
     tracing::info!(arch = std::env::consts::ARCH, version = VERSION);
 
-    let token = get_token(token_env_var, &cli.token_path)?.with_context(|| {
+    let token = get_token(token_env_var, &cli.token_path, cli.userspace)?.with_context(|| {
         format!(
             "Can't find the Firezone token in ${TOKEN_ENV_KEY} or in `{}`",
             cli.token_path.display()
@@ -357,6 +422,13 @@ fn try_main() -> Result<()> {
         return Ok(());
     }
 
+    let (tcp_socket_factory, udp_socket_factory) = socket_factories(cli.userspace);
+    let dns_cache_path = match (cli.persist_dns_cache, cli.userspace) {
+        (false, _) => None,
+        (true, true) => known_dirs::session().map(|dir| dir.join("dns-cache.json")),
+        (true, false) => known_dirs::dns_cache().ok(),
+    };
+
     // The name matches that in `ipc_service.rs`
     let mut last_connlib_start_instant = Some(Instant::now());
 
@@ -409,14 +481,15 @@ fn try_main() -> Result<()> {
                     .with_max_elapsed_time(max_partition_time)
                     .build()
             },
-            Arc::new(tcp_socket_factory),
+            tcp_socket_factory.clone(),
         );
         let (session, mut event_stream) = client_shared::Session::connect(
-            Arc::new(tcp_socket_factory),
-            Arc::new(UdpSocketFactory::default()),
+            tcp_socket_factory,
+            udp_socket_factory,
             portal,
             cli.activate_internet_resource,
             dns_controller.system_resolvers(),
+            dns_cache_path,
             rt.handle().clone(),
         );
 
@@ -425,7 +498,20 @@ fn try_main() -> Result<()> {
         let mut terminate = signals::Terminate::new()?;
         let mut hangup = signals::Hangup::new()?;
 
-        let mut tun_device = TunDeviceManager::new(ip_packet::MAX_IP_SIZE)?;
+        let mut device = if cli.userspace {
+            let (userspace, tun) =
+                userspace::spawn(cli.socks5_listen, cli.http_connect_listen).await?;
+            session.set_tun(tun);
+
+            Device::Userspace(userspace)
+        } else {
+            let mut tun_device = TunDeviceManager::new(ip_packet::MAX_IP_SIZE)?;
+            #[cfg(target_os = "linux")]
+            tun_device.set_cgroup_split_tunnel(cli.cgroup_split_tunnel.clone());
+            session.set_tun(tun_device.make_tun()?);
+
+            Device::Tun(tun_device)
+        };
 
         let tokio_handle = tokio::runtime::Handle::current();
 
@@ -435,8 +521,25 @@ fn try_main() -> Result<()> {
             new_network_notifier(tokio_handle.clone(), dns_control_method).await?;
         drop(tokio_handle);
 
-        let tun = tun_device.make_tun()?;
-        session.set_tun(tun);
+        #[cfg(unix)]
+        let (control_state, control_state_rx) =
+            tokio::sync::watch::channel(control::State::default());
+        #[cfg(unix)]
+        let control_server = (!cli.no_control_socket)
+            .then(|| {
+                control::Server::spawn(
+                    cli.control_socket.clone(),
+                    session.clone(),
+                    control_state_rx,
+                    log_filter_reloader,
+                )
+            })
+            .transpose()
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to start control API: {e:#}");
+
+                None
+            });
 
         let result = loop {
             let event = tokio::select! {
@@ -466,20 +569,37 @@ fn try_main() -> Result<()> {
 
             match event {
                 client_shared::Event::Disconnected(error) => break Err(anyhow!(error).context("Firezone disconnected")),
-                client_shared::Event::ResourcesUpdated(_) => {
+                client_shared::Event::ResourcesUpdated(resources) => {
                     // On every Resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
-                    dns_controller.flush()?;
+                    if let Device::Tun(_) = device {
+                        dns_controller.flush()?;
+                    }
+
+                    #[cfg(unix)]
+                    control_state.send_modify(|state| state.resources = resources);
+                    #[cfg(not(unix))]
+                    let _ = resources;
                 }
                 client_shared::Event::TunInterfaceUpdated(config) => {
-                    let tun_ip_stack = tun_device.set_ips(config.ip.v4, config.ip.v6).await?;
-                    dns_controller.set_dns(config.dns_by_sentinel.sentinel_ips(), config.search_domain).await?;
-                    tun_device.set_routes(config.routes.into_iter().filter(|r| match r {
-                        IpNetwork::V4(_) => tun_ip_stack.supports_ipv4(),
-                        IpNetwork::V6(_) => tun_ip_stack.supports_ipv6(),
-                    })).await?;
+                    match &mut device {
+                        Device::Tun(tun_device) => {
+                            let tun_ip_stack = tun_device.set_ips(config.ip.v4, config.ip.v6).await?;
+                            dns_controller.set_dns(config.dns_by_sentinel.sentinel_ips(), config.search_domain).await?;
+                            tun_device.set_routes(config.routes.into_iter().filter(|r| match r {
+                                IpNetwork::V4(_) => tun_ip_stack.supports_ipv4(),
+                                IpNetwork::V6(_) => tun_ip_stack.supports_ipv6(),
+                            })).await?;
+                        }
+                        Device::Userspace(userspace) => {
+                            userspace.set_tun_config(&config).await?;
+                        }
+                    }
 
                     // `on_set_interface_config` is guaranteed to be called when the tunnel is completely ready
                     // <https://github.com/firezone/firezone/pull/6026#discussion_r1692297438>
+                    #[cfg(unix)]
+                    control_state.send_modify(|state| state.tunnel_ready = true);
+
                     if let Some(instant) = last_connlib_start_instant.take() {
                         // `OnUpdateResources` appears to be the latest callback that happens during startup
                         tracing::debug!(elapsed = ?instant.elapsed(), "Tunnel ready");
@@ -496,6 +616,8 @@ fn try_main() -> Result<()> {
 
         telemetry.stop().await; // Stop telemetry before dropping session. `connlib` needs to be active for this, otherwise we won't be able to resolve the DNS name for sentry.
 
+        #[cfg(unix)]
+        drop(control_server);
         drop(session);
 
         // Drain the event-stream to allow the event-loop to gracefully shutdown.
@@ -509,6 +631,26 @@ fn try_main() -> Result<()> {
     Ok(())
 }
 
+/// Returns the factories for connlib's sockets.
+///
+/// Without a TUN device, there is no routing loop to prevent, so in userspace mode we don't mark our sockets.
+/// Setting `SO_MARK` requires `CAP_NET_ADMIN`, which we don't have when running unprivileged.
+fn socket_factories(
+    userspace: bool,
+) -> (
+    Arc<dyn SocketFactory<TcpSocket>>,
+    Arc<dyn SocketFactory<UdpSocket>>,
+) {
+    if userspace {
+        return (Arc::new(socket_factory::tcp), Arc::new(socket_factory::udp));
+    }
+
+    (
+        Arc::new(tcp_socket_factory),
+        Arc::new(UdpSocketFactory::default()),
+    )
+}
+
 /// Constructs the authentication URL for browser-based sign-in.
 fn build_auth_url(auth_base_url: &url::Url, account_slug: Option<&str>) -> url::Url {
     let mut auth_url = auth_base_url.clone();
@@ -632,22 +774,23 @@ fn handle_sign_out(token_path: &Path, force: bool) -> Result<()> {
 fn get_token(
     token_env_var: Option<SecretString>,
     token_path: &Path,
+    userspace: bool,
 ) -> Result<Option<SecretString>> {
     // This is very simple but I don't want to write it twice
     if let Some(token) = token_env_var {
         return Ok(Some(token));
     }
-    read_token_file(token_path)
+    read_token_file(token_path, userspace)
 }
 
 /// Try to retrieve the token from disk
 ///
 /// Sync because we do blocking file I/O
-fn read_token_file(path: &Path) -> Result<Option<SecretString>> {
+fn read_token_file(path: &Path, userspace: bool) -> Result<Option<SecretString>> {
     if std::fs::metadata(path).is_err() {
         return Ok(None);
     }
-    platform::check_token_permissions(path)?;
+    platform::check_token_permissions(path, userspace)?;
 
     let Ok(bytes) = std::fs::read(path) else {
         // We got the metadata a second ago, but can't read the file itself.
@@ -700,6 +843,36 @@ mod tests {
         assert_eq!(actual.log_dir, Some(PathBuf::from("bogus_log_dir")));
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cgroup_split_tunnel() {
+        let exe_name = "firezone-headless-client";
+
+        let actual = Cli::try_parse_from([
+            exe_name,
+            "--internet-resource-exclude-cgroup",
+            "system.slice/docker.service,user.slice",
+        ])
+        .unwrap();
+        assert_eq!(
+            actual.cgroup_split_tunnel.exclude,
+            vec![
+                PathBuf::from("system.slice/docker.service"),
+                PathBuf::from("user.slice")
+            ]
+        );
+        assert!(actual.cgroup_split_tunnel.include.is_empty());
+
+        Cli::try_parse_from([
+            exe_name,
+            "--internet-resource-include-cgroup",
+            "system.slice",
+            "--internet-resource-exclude-cgroup",
+            "user.slice",
+        ])
+        .unwrap_err();
+    }
+
     #[test]
     fn sign_in_bare() {
         let actual = Cli::try_parse_from(["firezone-headless-client", "sign-in"]).unwrap();
@@ -812,13 +985,46 @@ mod tests {
             .expect("set_token_permissions should succeed");
 
         // Verify that check_token_permissions is satisfied
-        super::platform::check_token_permissions(&token_path)
+        super::platform::check_token_permissions(&token_path, false)
             .expect("check_token_permissions should succeed after set_token_permissions");
 
         // Cleanup
         let _ = std::fs::remove_file(&token_path);
     }
 
+    /// Userspace mode must work without root, e.g. in locked-down CI containers.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn userspace_mode_runs_unprivileged() {
+        use std::io::Write as _;
+        use std::os::unix::fs::OpenOptionsExt as _;
+
+        let token_path = std::env::temp_dir().join("firezone_test_userspace_token");
+        let _ = std::fs::remove_file(&token_path);
+
+        // Owned by whoever runs the test, not necessarily root.
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&token_path)
+            .unwrap();
+        file.write_all(b"test_token").unwrap();
+        drop(file);
+
+        let token = super::read_token_file(&token_path, true);
+        let _ = std::fs::remove_file(&token_path);
+        assert!(token.unwrap().is_some());
+
+        let (tcp, udp) = super::socket_factories(true);
+        let loopback = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+
+        tcp.bind(loopback)
+            .expect("TCP sockets should not require `CAP_NET_ADMIN`");
+        udp.bind(loopback)
+            .expect("UDP sockets should not require `CAP_NET_ADMIN`");
+    }
+
     // =========================================================================
     // Tests for sign-in/sign-out core logic
     // =========================================================================
@@ -943,7 +1149,7 @@ mod tests {
         super::platform::write_token(&token_path, test_token).unwrap();
 
         // Read it back using the same function used by the client
-        let read_token = super::read_token_file(&token_path)
+        let read_token = super::read_token_file(&token_path, false)
             .expect("read_token_file should succeed")
             .expect("Token should exist");
 
@@ -0,0 +1,242 @@
+//! Userspace mode: Run connlib without a TUN device and expose Resources through local proxies.
+//!
+//! Instead of routing packets through the OS, applications connect to a local SOCKS5 or HTTP CONNECT proxy.
+//! Connections to Resources are made through a userspace TCP/IP stack that connlib treats as its TUN device.
+//! All other connections are made directly from the host.
+
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{Context as _, Result};
+use client_shared::TunConfig;
+use dns_types::{DomainName, RecordType};
+use ip_network::IpNetwork;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+};
+
+mod http_connect;
+mod netstack;
+mod socks5;
+
+/// How many commands we buffer for the userspace network stack.
+const COMMAND_BUFFER: usize = 128;
+
+/// The size of the in-memory pipe between a proxy connection and the network stack.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Handle to the proxies and the network stack of userspace mode.
+///
+/// Dropping this stops all of them.
+pub(crate) struct Userspace {
+    commands: mpsc::Sender<netstack::Command>,
+    routes: watch::Sender<BTreeSet<IpNetwork>>,
+
+    _tasks: JoinSet<()>,
+}
+
+/// Binds the proxy listeners and starts the userspace network stack.
+///
+/// Returns the TUN device that should be handed to connlib.
+pub(crate) async fn spawn(
+    socks5_listen: SocketAddr,
+    http_connect_listen: SocketAddr,
+) -> Result<(Userspace, Box<dyn tun::Tun>)> {
+    let socks5_listener = TcpListener::bind(socks5_listen)
+        .await
+        .with_context(|| format!("Failed to bind SOCKS5 proxy to {socks5_listen}"))?;
+    let http_connect_listener = TcpListener::bind(http_connect_listen)
+        .await
+        .with_context(|| format!("Failed to bind HTTP CONNECT proxy to {http_connect_listen}"))?;
+
+    let (commands_tx, commands_rx) = mpsc::channel(COMMAND_BUFFER);
+    let (routes_tx, routes_rx) = watch::channel(BTreeSet::default());
+    let (netstack, tun) = netstack::new(commands_rx);
+
+    let dialer = Dialer {
+        commands: commands_tx.clone(),
+        routes: routes_rx,
+    };
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(netstack.run());
+    tasks.spawn(serve(socks5_listener, dialer.clone(), socks5::handle));
+    tasks.spawn(serve(http_connect_listener, dialer, http_connect::handle));
+
+    tracing::info!(socks5 = %socks5_listen, http_connect = %http_connect_listen, "Userspace mode enabled");
+
+    Ok((
+        Userspace {
+            commands: commands_tx,
+            routes: routes_tx,
+            _tasks: tasks,
+        },
+        Box::new(tun),
+    ))
+}
+
+impl Userspace {
+    /// Applies a new interface configuration from connlib.
+    pub(crate) async fn set_tun_config(&self, config: &TunConfig) -> Result<()> {
+        let sentinels = config.dns_by_sentinel.sentinel_ips();
+        let dns_server = sentinels
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| sentinels.first())
+            .copied();
+
+        self.commands
+            .send(netstack::Command::SetInterface {
+                v4: config.ip.v4,
+                v6: config.ip.v6,
+                dns_server,
+            })
+            .await
+            .context("Userspace network stack is gone")?;
+        self.routes.send_replace(config.routes.clone());
+
+        Ok(())
+    }
+}
+
+/// Where a proxy client wants to connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Target {
+    Ip(SocketAddr),
+    Domain(DomainName, u16),
+}
+
+/// An established connection to a [`Target`].
+pub(crate) enum Upstream {
+    /// A connection through the tunnel.
+    Tunnel(DuplexStream),
+    /// A connection from the host, outside of the tunnel.
+    Direct(TcpStream),
+}
+
+/// Opens connections on behalf of the proxies.
+#[derive(Clone)]
+pub(crate) struct Dialer {
+    commands: mpsc::Sender<netstack::Command>,
+    routes: watch::Receiver<BTreeSet<IpNetwork>>,
+}
+
+impl Dialer {
+    pub(crate) async fn connect(&self, target: Target) -> io::Result<Upstream> {
+        let remote = match target {
+            Target::Ip(remote) => remote,
+            Target::Domain(domain, port) => {
+                let ip = self.resolve(domain).await?;
+
+                SocketAddr::new(ip, port)
+            }
+        };
+
+        if !self.is_routed(remote.ip()) {
+            tracing::debug!(%remote, "Connecting directly");
+
+            return Ok(Upstream::Direct(TcpStream::connect(remote).await?));
+        }
+
+        let (stream, netstack_stream) = tokio::io::duplex(STREAM_BUFFER);
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.commands
+            .send(netstack::Command::Connect {
+                remote,
+                stream: netstack_stream,
+                reply: reply_tx,
+            })
+            .await
+            .map_err(|_| netstack_gone())?;
+        reply_rx.await.map_err(|_| netstack_gone())??;
+
+        Ok(Upstream::Tunnel(stream))
+    }
+
+    /// Resolves a domain through connlib, preferring IPv4 addresses.
+    async fn resolve(&self, domain: DomainName) -> io::Result<IpAddr> {
+        for qtype in [RecordType::A, RecordType::AAAA] {
+            let (reply_tx, reply_rx) = oneshot::channel();
+
+            self.commands
+                .send(netstack::Command::Resolve {
+                    domain: domain.clone(),
+                    qtype,
+                    reply: reply_tx,
+                })
+                .await
+                .map_err(|_| netstack_gone())?;
+
+            let ips = reply_rx
+                .await
+                .map_err(|_| netstack_gone())?
+                .map_err(|e| io::Error::other(format!("Failed to resolve {domain}: {e:#}")))?;
+
+            if let Some(ip) = ips.first() {
+                return Ok(*ip);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No addresses found for {domain}"),
+        ))
+    }
+
+    fn is_routed(&self, ip: IpAddr) -> bool {
+        self.routes.borrow().iter().any(|route| route.contains(ip))
+    }
+}
+
+/// Copies data in both directions until either side closes.
+pub(crate) async fn relay<S>(client: &mut S, upstream: Upstream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match upstream {
+        Upstream::Tunnel(mut stream) => {
+            tokio::io::copy_bidirectional(client, &mut stream).await?;
+        }
+        Upstream::Direct(mut stream) => {
+            tokio::io::copy_bidirectional(client, &mut stream).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn serve<F, Fut>(listener: TcpListener, dialer: Dialer, handler: F)
+where
+    F: Fn(TcpStream, Dialer) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!("Failed to accept proxy connection: {e}");
+                continue;
+            }
+        };
+
+        let connection = handler(stream, dialer.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!(%peer, "Proxy connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+fn netstack_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Userspace network stack is gone")
+}
@@ -0,0 +1,174 @@
+//! A minimal HTTP proxy that only supports the `CONNECT` method.
+
+use std::net::SocketAddr;
+
+use anyhow::{Context as _, Result, bail};
+use dns_types::DomainName;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use super::{Dialer, Target, Upstream};
+
+/// The maximum size of the request head we are willing to buffer.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+pub(crate) async fn handle<S>(mut client: S, dialer: Dialer) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, leftover) = read_request_head(&mut client).await?;
+
+    let target = match parse_connect_request(&head) {
+        Ok(target) => target,
+        Err(e) => {
+            client
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n")
+                .await?;
+
+            return Err(e);
+        }
+    };
+
+    let mut upstream = match dialer.connect(target.clone()).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                .await?;
+
+            return Err(e).with_context(|| format!("Failed to connect to {target:?}"));
+        }
+    };
+
+    // Clients may send data right after the request, without waiting for our response.
+    if !leftover.is_empty() {
+        match &mut upstream {
+            Upstream::Tunnel(stream) => stream.write_all(&leftover).await?,
+            Upstream::Direct(stream) => stream.write_all(&leftover).await?,
+        }
+    }
+
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    super::relay(&mut client, upstream).await?;
+
+    Ok(())
+}
+
+/// Reads until the end of the request head.
+///
+/// Returns the head and any bytes the client sent after it.
+async fn read_request_head<S>(client: &mut S) -> Result<(String, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("Client closed connection before sending a complete request");
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buf.split_off(end + 4);
+            let head = String::from_utf8(buf).context("Request is not valid UTF-8")?;
+
+            return Ok((head, leftover));
+        }
+
+        if buf.len() > MAX_REQUEST_HEAD {
+            bail!("Request head exceeds {MAX_REQUEST_HEAD} bytes");
+        }
+    }
+}
+
+fn parse_connect_request(head: &str) -> Result<Target> {
+    let request_line = head.lines().next().context("Empty request")?;
+    let mut parts = request_line.split_whitespace();
+
+    let method = parts.next().context("Missing method")?;
+    let authority = parts.next().context("Missing request target")?;
+
+    if method != "CONNECT" {
+        bail!("Unsupported method {method}");
+    }
+
+    if let Ok(socket) = authority.parse::<SocketAddr>() {
+        return Ok(Target::Ip(socket));
+    }
+
+    let (host, port) = authority
+        .rsplit_once(':')
+        .context("Request target is missing a port")?;
+    let port = port.parse::<u16>().context("Invalid port")?;
+
+    if let Ok(ip) = host.parse() {
+        return Ok(Target::Ip(SocketAddr::new(ip, port)));
+    }
+
+    let domain = DomainName::vec_from_str(host).context("Invalid domain")?;
+
+    Ok(Target::Domain(domain, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_domain_target() {
+        let target = parse_connect_request(
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            target,
+            Target::Domain(DomainName::vec_from_str("example.com").unwrap(), 443)
+        );
+    }
+
+    #[test]
+    fn parses_ipv4_target() {
+        let target = parse_connect_request("CONNECT 10.0.0.1:22 HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(target, Target::Ip("10.0.0.1:22".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_ipv6_target() {
+        let target = parse_connect_request("CONNECT [fd00::1]:22 HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(target, Target::Ip("[fd00::1]:22".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_other_methods() {
+        parse_connect_request("GET http://example.com/ HTTP/1.1\r\n\r\n").unwrap_err();
+    }
+
+    #[test]
+    fn rejects_target_without_port() {
+        parse_connect_request("CONNECT example.com HTTP/1.1\r\n\r\n").unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn keeps_bytes_after_request_head() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n\x16\x03\x01")
+            .await
+            .unwrap();
+
+        let (head, leftover) = read_request_head(&mut server).await.unwrap();
+
+        assert_eq!(head, "CONNECT example.com:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(leftover, b"\x16\x03\x01");
+    }
+}
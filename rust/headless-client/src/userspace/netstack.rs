@@ -0,0 +1,504 @@
+//! A userspace TCP/IP stack that connlib uses instead of a TUN device.
+
+use std::{
+    collections::HashMap,
+    future::Future as _,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Instant,
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use dns_types::{DomainName, RecordType, ResponseCode};
+use ip_packet::IpPacket;
+use l3_tcp::{InMemoryDevice, Interface, PollResult, SocketHandle, SocketSet};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+
+/// How many packets we buffer between connlib and the network stack in each direction.
+const PACKET_BUFFER: usize = 1024;
+
+/// The first port of the ephemeral range, used as the local port of our TCP connections.
+const MIN_PORT: u16 = 49152;
+
+/// Size of the send and receive buffer of each proxied TCP connection.
+const TCP_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How long we wait for the remote to acknowledge our segments, including the SYN, before aborting the connection.
+const TCP_TIMEOUT: l3_tcp::Duration = l3_tcp::Duration::from_secs(30);
+
+/// How often we probe idle connections, must be lower than [`TCP_TIMEOUT`] to not abort them.
+const TCP_KEEP_ALIVE: l3_tcp::Duration = l3_tcp::Duration::from_secs(10);
+
+/// Creates the userspace network stack together with the [`tun::Tun`] device connlib should use.
+pub(crate) fn new(commands: mpsc::Receiver<Command>) -> (NetStack, UserspaceTun) {
+    let (inbound_tx, inbound_rx) = mpsc::channel(PACKET_BUFFER);
+    let (outbound_tx, outbound_rx) = mpsc::channel(PACKET_BUFFER);
+
+    let mut device = InMemoryDevice::default();
+    let interface = l3_tcp::create_interface(&mut device);
+
+    let netstack = NetStack {
+        device,
+        interface,
+        sockets: SocketSet::new(Vec::default()),
+        connections: Vec::default(),
+        next_port: MIN_PORT,
+        interface_config: None,
+        dns_client: l3_udp_dns_client::Client::new(rand::random()),
+        pending_resolutions: HashMap::default(),
+        commands,
+        inbound: inbound_rx,
+        outbound: outbound_tx,
+        timer: Box::pin(tokio::time::sleep_until(tokio::time::Instant::now())),
+        created_at: Instant::now(),
+    };
+    let tun = UserspaceTun {
+        inbound: inbound_tx,
+        outbound: outbound_rx,
+    };
+
+    (netstack, tun)
+}
+
+/// Commands for the [`NetStack`].
+pub(crate) enum Command {
+    /// The tunnel interface has been (re-)configured.
+    SetInterface {
+        v4: Ipv4Addr,
+        v6: Ipv6Addr,
+        dns_server: Option<IpAddr>,
+    },
+    /// Open a TCP connection through the tunnel and splice it with the given stream.
+    Connect {
+        remote: SocketAddr,
+        stream: DuplexStream,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+    /// Resolve a domain using connlib's DNS resolver.
+    Resolve {
+        domain: DomainName,
+        qtype: RecordType,
+        reply: oneshot::Sender<Result<Vec<IpAddr>>>,
+    },
+}
+
+/// A TUN device for connlib that is backed by our userspace network stack.
+pub(crate) struct UserspaceTun {
+    /// Packets from connlib to the network stack.
+    inbound: mpsc::Sender<IpPacket>,
+    /// Packets from the network stack to connlib.
+    outbound: mpsc::Receiver<IpPacket>,
+}
+
+impl tun::Tun for UserspaceTun {
+    fn poll_send_ready(&mut self, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn send(&mut self, packet: IpPacket) -> io::Result<()> {
+        match self.inbound.try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // Like a real TUN device, we drop packets if we can't keep up.
+                tracing::debug!("Userspace network stack is busy, dropping packet");
+
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Userspace network stack is gone",
+            )),
+        }
+    }
+
+    fn poll_recv_many(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut Vec<IpPacket>,
+        max: usize,
+    ) -> Poll<usize> {
+        self.outbound.poll_recv_many(cx, buf, max)
+    }
+
+    fn name(&self) -> &str {
+        "userspace"
+    }
+}
+
+/// A userspace TCP/IP stack, built on `smoltcp`.
+///
+/// Connections are opened through [`Command::Connect`] and then spliced with the given [`DuplexStream`].
+/// Domains are resolved by sending DNS queries to connlib's stub resolver, just like the OS would do with a TUN device.
+pub(crate) struct NetStack {
+    device: InMemoryDevice,
+    interface: Interface,
+    sockets: SocketSet<'static>,
+    connections: Vec<Connection>,
+    next_port: u16,
+
+    interface_config: Option<InterfaceConfig>,
+
+    dns_client: l3_udp_dns_client::Client,
+    pending_resolutions: HashMap<SocketAddr, oneshot::Sender<Result<Vec<IpAddr>>>>,
+
+    commands: mpsc::Receiver<Command>,
+    inbound: mpsc::Receiver<IpPacket>,
+    outbound: mpsc::Sender<IpPacket>,
+
+    timer: Pin<Box<tokio::time::Sleep>>,
+    created_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InterfaceConfig {
+    v4: Ipv4Addr,
+    v6: Ipv6Addr,
+    dns_server: Option<IpAddr>,
+}
+
+struct Connection {
+    handle: SocketHandle,
+    remote: SocketAddr,
+    stream: DuplexStream,
+
+    /// Notifies the dialer once the connection is established or failed.
+    established: Option<oneshot::Sender<io::Result<()>>>,
+    /// Whether the local side has closed the stream.
+    stream_closed: bool,
+    /// Whether we have forwarded the remote's FIN to the stream.
+    socket_closed: bool,
+}
+
+impl NetStack {
+    pub(crate) async fn run(mut self) {
+        std::future::poll_fn(|cx| self.poll(cx)).await;
+
+        tracing::debug!("Userspace network stack stopped");
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            loop {
+                match self.commands.poll_recv(cx) {
+                    Poll::Ready(Some(command)) => self.handle_command(command),
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => break,
+                }
+            }
+
+            loop {
+                let packet = match self.inbound.poll_recv(cx) {
+                    Poll::Ready(Some(packet)) => packet,
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => break,
+                };
+
+                if self.dns_client.accepts(&packet) {
+                    self.dns_client.handle_inbound(packet);
+                    continue;
+                }
+
+                self.device.receive(packet);
+            }
+
+            let now = Instant::now();
+
+            // Keep moving data between the sockets and the streams until neither side makes progress.
+            loop {
+                let mut progress = false;
+
+                for connection in &mut self.connections {
+                    let socket = self.sockets.get_mut::<l3_tcp::Socket>(connection.handle);
+
+                    progress |= connection.poll(socket, cx);
+                }
+
+                let result = self.interface.poll(
+                    l3_tcp::now(self.created_at, now),
+                    &mut self.device,
+                    &mut self.sockets,
+                );
+                progress |= result == PollResult::SocketStateChanged;
+
+                if !progress {
+                    break;
+                }
+            }
+
+            self.connections.retain(|c| {
+                let socket = self.sockets.get::<l3_tcp::Socket>(c.handle);
+
+                if socket.state() != l3_tcp::State::Closed {
+                    return true;
+                }
+
+                tracing::debug!(remote = %c.remote, "Connection closed");
+                self.sockets.remove(c.handle);
+
+                false
+            });
+
+            self.dns_client.handle_timeout(now);
+            while let Some(result) = self.dns_client.poll_query_result() {
+                let Some(reply) = self.pending_resolutions.remove(&result.local) else {
+                    continue;
+                };
+
+                let _ = reply.send(result.result.and_then(into_ips));
+            }
+
+            while let Some(packet) = self
+                .device
+                .next_send()
+                .or_else(|| self.dns_client.poll_outbound())
+            {
+                if self.outbound.try_send(packet).is_err() {
+                    tracing::debug!("connlib is busy, dropping packet");
+                }
+            }
+
+            let poll_delay = self
+                .interface
+                .poll_delay(l3_tcp::now(self.created_at, now), &self.sockets)
+                .map(|delay| now + std::time::Duration::from(delay));
+            let next_timeout = poll_delay
+                .into_iter()
+                .chain(self.dns_client.poll_timeout())
+                .min();
+
+            let Some(next_timeout) = next_timeout else {
+                return Poll::Pending;
+            };
+
+            self.timer
+                .as_mut()
+                .reset(tokio::time::Instant::from_std(next_timeout));
+
+            ready!(self.timer.as_mut().poll(cx));
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::SetInterface { v4, v6, dns_server } => {
+                tracing::debug!(%v4, %v6, ?dns_server, "Configuring userspace network stack");
+
+                self.interface_config = Some(InterfaceConfig { v4, v6, dns_server });
+                self.dns_client.set_source_interface(v4, v6);
+            }
+            Command::Connect {
+                remote,
+                stream,
+                reply,
+            } => {
+                if let Err(e) = self.connect(remote, stream, reply) {
+                    tracing::debug!(%remote, "Failed to connect: {e:#}");
+                }
+            }
+            Command::Resolve {
+                domain,
+                qtype,
+                reply,
+            } => {
+                let result = self.send_query(domain, qtype);
+
+                match result {
+                    Ok(local) => {
+                        self.pending_resolutions.insert(local, reply);
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect(
+        &mut self,
+        remote: SocketAddr,
+        stream: DuplexStream,
+        reply: oneshot::Sender<io::Result<()>>,
+    ) -> Result<()> {
+        let Some(config) = self.interface_config else {
+            let _ = reply.send(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Tunnel is not ready",
+            )));
+
+            return Err(anyhow!("Tunnel is not ready"));
+        };
+
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::new(config.v4.into(), self.next_port()),
+            SocketAddr::V6(_) => SocketAddr::new(config.v6.into(), self.next_port()),
+        };
+
+        let mut socket = l3_tcp::create_tcp_socket_with_buffer_size(TCP_BUFFER_SIZE);
+        socket.set_timeout(Some(TCP_TIMEOUT));
+        socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
+
+        if let Err(e) = socket.connect(self.interface.context(), remote, local) {
+            let _ = reply.send(Err(io::Error::other(e.to_string())));
+
+            return Err(anyhow!("{e}"));
+        }
+
+        tracing::debug!(%local, %remote, "Connecting through tunnel");
+
+        let handle = self.sockets.add(socket);
+        self.connections.push(Connection {
+            handle,
+            remote,
+            stream,
+            established: Some(reply),
+            stream_closed: false,
+            socket_closed: false,
+        });
+
+        Ok(())
+    }
+
+    fn send_query(&mut self, domain: DomainName, qtype: RecordType) -> Result<SocketAddr> {
+        let server = self
+            .interface_config
+            .and_then(|c| c.dns_server)
+            .context("Tunnel is not ready")?;
+
+        self.dns_client.send_query(
+            SocketAddr::new(server, 53),
+            dns_types::Query::new(domain, qtype),
+            Instant::now(),
+        )
+    }
+
+    fn next_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(MIN_PORT);
+
+        port
+    }
+}
+
+impl Connection {
+    /// Moves data between the socket and the stream.
+    ///
+    /// Returns `true` if we made progress.
+    fn poll(&mut self, socket: &mut l3_tcp::Socket, cx: &mut Context) -> bool {
+        let mut progress = false;
+
+        if let Some(established) = self.established.take() {
+            match socket.state() {
+                l3_tcp::State::Listen | l3_tcp::State::SynSent | l3_tcp::State::SynReceived => {
+                    self.established = Some(established);
+
+                    return false;
+                }
+                l3_tcp::State::Closed => {
+                    let _ = established.send(Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "Failed to connect through tunnel, the remote refused or didn't answer in time",
+                    )));
+
+                    return false;
+                }
+                l3_tcp::State::Established
+                | l3_tcp::State::FinWait1
+                | l3_tcp::State::FinWait2
+                | l3_tcp::State::CloseWait
+                | l3_tcp::State::Closing
+                | l3_tcp::State::LastAck
+                | l3_tcp::State::TimeWait => {
+                    let _ = established.send(Ok(()));
+                }
+            }
+        }
+
+        // Local -> remote
+        while !self.stream_closed && socket.can_send() {
+            let result = socket.send(|buf| {
+                let mut read_buf = ReadBuf::new(buf);
+
+                match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) => {
+                        let n = read_buf.filled().len();
+
+                        (n, Poll::Ready(Ok(n)))
+                    }
+                    Poll::Ready(Err(e)) => (0, Poll::Ready(Err(e))),
+                    Poll::Pending => (0, Poll::Pending),
+                }
+            });
+
+            match result {
+                Ok(Poll::Ready(Ok(0))) | Ok(Poll::Ready(Err(_))) => {
+                    self.stream_closed = true;
+                    socket.close();
+                    progress = true;
+                }
+                Ok(Poll::Ready(Ok(_))) => progress = true,
+                Ok(Poll::Pending) => break,
+                Err(e) => {
+                    tracing::debug!(remote = %self.remote, "Failed to send: {e}");
+                    socket.abort();
+                    break;
+                }
+            }
+        }
+
+        // Remote -> local
+        while socket.can_recv() {
+            let result = socket.recv(|buf| match Pin::new(&mut self.stream).poll_write(cx, buf) {
+                Poll::Ready(Ok(n)) => (n, Poll::Ready(Ok(n))),
+                Poll::Ready(Err(e)) => (0, Poll::Ready(Err(e))),
+                Poll::Pending => (0, Poll::Pending),
+            });
+
+            match result {
+                Ok(Poll::Ready(Ok(_))) => progress = true,
+                Ok(Poll::Ready(Err(_))) => {
+                    // The local side is gone, no point in receiving more data.
+                    socket.abort();
+                    break;
+                }
+                Ok(Poll::Pending) => break,
+                Err(e) => {
+                    tracing::debug!(remote = %self.remote, "Failed to receive: {e}");
+                    socket.abort();
+                    break;
+                }
+            }
+        }
+
+        // Forward the remote's FIN once we have written all data to the stream.
+        if !self.socket_closed && !socket.may_recv() && !socket.can_recv() {
+            match Pin::new(&mut self.stream).poll_shutdown(cx) {
+                Poll::Ready(_) => {
+                    self.socket_closed = true;
+                    progress = true;
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        progress
+    }
+}
+
+fn into_ips(response: dns_types::Response) -> Result<Vec<IpAddr>> {
+    anyhow::ensure!(
+        response.response_code() == ResponseCode::NOERROR,
+        "DNS query failed with {}",
+        response.response_code()
+    );
+
+    Ok(response
+        .records()
+        .filter_map(dns_types::records::extract_ip)
+        .collect())
+}
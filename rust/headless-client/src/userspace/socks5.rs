@@ -0,0 +1,245 @@
+//! A minimal SOCKS5 server as per [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928).
+//!
+//! Only the `CONNECT` command without authentication is supported.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::{Context as _, Result, bail};
+use dns_types::DomainName;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use super::{Dialer, Target};
+
+const VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+pub(crate) async fn handle<S>(mut client: S, dialer: Dialer) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    negotiate_method(&mut client).await?;
+
+    let target = match read_request(&mut client).await? {
+        Ok(target) => target,
+        Err(reply) => {
+            write_reply(&mut client, reply).await?;
+            bail!("Unsupported SOCKS5 request");
+        }
+    };
+
+    let upstream = match dialer.connect(target.clone()).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            write_reply(&mut client, reply_for_error(&e)).await?;
+
+            return Err(e).with_context(|| format!("Failed to connect to {target:?}"));
+        }
+    };
+
+    write_reply(&mut client, REPLY_SUCCEEDED).await?;
+
+    super::relay(&mut client, upstream).await?;
+
+    Ok(())
+}
+
+async fn negotiate_method<S>(client: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = client.read_u8().await?;
+    if version != VERSION {
+        bail!("Unsupported SOCKS version {version}");
+    }
+
+    let num_methods = client.read_u8().await?;
+    let mut methods = vec![0; usize::from(num_methods)];
+    client.read_exact(&mut methods).await?;
+
+    if !methods.contains(&METHOD_NO_AUTH) {
+        client.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        bail!("SOCKS5 client does not support connecting without authentication");
+    }
+
+    client.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    Ok(())
+}
+
+/// Reads the client's request.
+///
+/// Returns the reply code to send if we can't serve the request.
+async fn read_request<S>(client: &mut S) -> Result<Result<Target, u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await?;
+    let [version, cmd, _reserved, atyp] = header;
+
+    if version != VERSION {
+        bail!("Unsupported SOCKS version {version}");
+    }
+
+    let target = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await?;
+            let port = client.read_u16().await?;
+
+            Target::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip).await?;
+            let port = client.read_u16().await?;
+
+            Target::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        ATYP_DOMAIN => {
+            let len = client.read_u8().await?;
+            let mut domain = vec![0; usize::from(len)];
+            client.read_exact(&mut domain).await?;
+            let port = client.read_u16().await?;
+
+            let domain = std::str::from_utf8(&domain).context("Domain is not valid UTF-8")?;
+            let domain = DomainName::vec_from_str(domain).context("Invalid domain")?;
+
+            Target::Domain(domain, port)
+        }
+        _ => return Ok(Err(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)),
+    };
+
+    if cmd != CMD_CONNECT {
+        return Ok(Err(REPLY_COMMAND_NOT_SUPPORTED));
+    }
+
+    Ok(Ok(target))
+}
+
+async fn write_reply<S>(client: &mut S, reply: u8) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // We don't expose the address we bound to, clients don't need it for `CONNECT`.
+    client
+        .write_all(&[VERSION, reply, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    client.flush().await?;
+
+    Ok(())
+}
+
+fn reply_for_error(e: &io::Error) -> u8 {
+    let kind = e.kind();
+
+    if kind == io::ErrorKind::ConnectionRefused {
+        return REPLY_CONNECTION_REFUSED;
+    }
+
+    if matches!(
+        kind,
+        io::ErrorKind::NotFound
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::TimedOut
+    ) {
+        return REPLY_HOST_UNREACHABLE;
+    }
+
+    REPLY_GENERAL_FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_ipv4_connect_request() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client
+            .write_all(&[
+                VERSION,
+                CMD_CONNECT,
+                0x00,
+                ATYP_IPV4,
+                10,
+                0,
+                0,
+                1,
+                0x01,
+                0xBB,
+            ])
+            .await
+            .unwrap();
+
+        let target = read_request(&mut server).await.unwrap().unwrap();
+
+        assert_eq!(target, Target::Ip("10.0.0.1:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_domain_connect_request() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client
+            .write_all(&[VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN, 11])
+            .await
+            .unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&[0x00, 0x50]).await.unwrap();
+
+        let target = read_request(&mut server).await.unwrap().unwrap();
+
+        assert_eq!(
+            target,
+            Target::Domain(DomainName::vec_from_str("example.com").unwrap(), 80)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_bind_command() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client
+            .write_all(&[VERSION, 0x02, 0x00, ATYP_IPV4, 10, 0, 0, 1, 0x01, 0xBB])
+            .await
+            .unwrap();
+
+        let reply = read_request(&mut server).await.unwrap().unwrap_err();
+
+        assert_eq!(reply, REPLY_COMMAND_NOT_SUPPORTED);
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_requiring_authentication() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client.write_all(&[VERSION, 1, 0x02]).await.unwrap();
+
+        negotiate_method(&mut server).await.unwrap_err();
+
+        let mut response = [0u8; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [VERSION, METHOD_NOT_ACCEPTABLE]);
+    }
+}
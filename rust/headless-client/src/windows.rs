@@ -11,7 +11,7 @@ use std::path::{Path, PathBuf};
 
 // The return value is useful on Linux
 #[expect(clippy::unnecessary_wraps)]
-pub(crate) fn check_token_permissions(_path: &Path) -> Result<()> {
+pub(crate) fn check_token_permissions(_path: &Path, _userspace: bool) -> Result<()> {
     // TODO: Verify that the token file has the correct ACLs
     // https://github.com/firezone/firezone/issues/XXXXX
     Ok(())
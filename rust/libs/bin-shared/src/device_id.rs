@@ -65,6 +65,18 @@ pub fn get_or_create_client() -> Result<DeviceId> {
     Ok(id)
 }
 
+/// Like [`get_or_create_client`] but stores the ID in the current user's data directory.
+///
+/// For Clients that don't run as root and thus cannot write to the Tunnel service's config dir.
+pub fn get_or_create_unprivileged_client() -> Result<DeviceId> {
+    let path = crate::known_dirs::session()
+        .context("Failed to compute path for firezone-id file")?
+        .join("firezone-id.json");
+    let id = get_or_create_at(&path, CLIENT_APP_ID)?;
+
+    Ok(id)
+}
+
 pub fn get_or_create_gateway() -> Result<DeviceId> {
     const ID_PATH: &str = "/var/lib/firezone/gateway_id";
 
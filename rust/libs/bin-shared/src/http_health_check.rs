@@ -1,5 +1,5 @@
 use axum::Router;
-use axum::http::StatusCode;
+use axum::http::{StatusCode, header};
 use axum::routing::get;
 use std::net::SocketAddr;
 
@@ -27,6 +27,36 @@ pub async fn serve_with_version(
     Ok(())
 }
 
+/// Like [`serve`], but additionally serves the health of resources:
+/// - `GET /resourcez` - Returns the JSON document produced by `resource_health`
+pub async fn serve_with_resource_health(
+    addr: impl Into<SocketAddr>,
+    is_ready: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    resource_health: impl Fn() -> serde_json::Value + Clone + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let addr = addr.into();
+    let service = with_resource_health(router(None, is_ready), resource_health).into_make_service();
+
+    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+
+    Ok(())
+}
+
+fn with_resource_health(
+    router: Router,
+    resource_health: impl Fn() -> serde_json::Value + Clone + Send + Sync + 'static,
+) -> Router {
+    router.route(
+        "/resourcez",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "application/json")],
+                resource_health().to_string(),
+            )
+        }),
+    )
+}
+
 fn router(
     version: Option<&'static str>,
     is_ready: impl Fn() -> bool + Clone + Send + Sync + 'static,
@@ -136,6 +166,27 @@ mod tests {
         assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
     }
 
+    #[tokio::test]
+    async fn resourcez_returns_resource_health() {
+        let app = with_resource_health(
+            router(None, || true),
+            || serde_json::json!({ "resource": "healthy" }),
+        );
+
+        let response = app
+            .oneshot(Request::get("/resourcez").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(body.as_ref(), br#"{"resource":"healthy"}"#);
+    }
+
     #[tokio::test]
     async fn readyz_returns_version_in_body() {
         let app = router(Some("abc123"), || true);
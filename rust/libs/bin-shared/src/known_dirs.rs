@@ -32,6 +32,13 @@ pub fn tunnel_log_filter() -> Result<PathBuf> {
         .join("log-filter"))
 }
 
+/// Path of the persisted DNS cache of the Tunnel service.
+pub fn dns_cache() -> Result<PathBuf> {
+    Ok(tunnel_service_config()
+        .context("Failed to compute `tunnel_service_config` directory")?
+        .join("dns-cache.json"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
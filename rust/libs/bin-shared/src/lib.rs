@@ -12,6 +12,9 @@ pub mod linux;
 #[cfg(target_os = "linux")]
 pub use linux as platform;
 
+#[cfg(target_os = "linux")]
+pub mod nftables;
+
 #[cfg(target_os = "windows")]
 pub mod windows;
 
@@ -56,3 +59,6 @@ pub const FIREZONE_MARK: u32 = 0xfd002021;
 pub use dns_control::{DnsControlMethod, DnsController};
 pub use network_changes::{new_dns_notifier, new_network_notifier};
 pub use tun_device_manager::{TunDeviceManager, TunIpStack};
+
+#[cfg(target_os = "linux")]
+pub use tun_device_manager::platform::CgroupSplitTunnel;
@@ -0,0 +1,639 @@
+//! A minimal nftables client on top of netlink, shared by the Gateway's firewall and the Client's split tunneling.
+//!
+//! Each user owns a dedicated `inet` table which is replaced atomically via a [`Batch`].
+//! Talking netlink directly means we neither depend on the `nft` nor the `iptables` binaries.
+
+use std::io;
+
+use ip_network::IpNetwork;
+use netlink_packet_core::{
+    DecodeError, Emitable as _, NLM_F_ACK, NLM_F_APPEND, NLM_F_CREATE, NLM_F_REQUEST,
+    NetlinkBuffer, NetlinkDeserializable, NetlinkHeader, NetlinkMessage, NetlinkPayload,
+    NetlinkSerializable, Nla,
+};
+use rtnetlink::sys::{Socket, SocketAddr, protocols::NETLINK_NETFILTER};
+
+const IFNAMSIZ: usize = 16;
+
+// From `linux/netlink.h`.
+const NLA_F_NESTED: u16 = 0x8000;
+
+// From `linux/netfilter/nfnetlink.h`.
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNETLINK_V0: u8 = 0;
+const NFGENMSG_LEN: usize = 4;
+
+// From `linux/netfilter.h`.
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+const NF_ACCEPT: u32 = 1;
+pub const NF_INET_FORWARD: u32 = 2;
+pub const NF_INET_LOCAL_OUT: u32 = 3;
+pub const NF_INET_POST_ROUTING: u32 = 4;
+pub const NF_IP_PRI_MANGLE: i32 = -150;
+pub const NF_IP_PRI_FILTER: i32 = 0;
+pub const NF_IP_PRI_NAT_SRC: i32 = 100;
+
+// From `linux/netfilter/nf_tables.h`.
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_GETTABLE: u16 = 1;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_META_SREG: u16 = 3;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_SOCKET_KEY: u16 = 1;
+const NFTA_SOCKET_DREG: u16 = 2;
+const NFTA_SOCKET_LEVEL: u16 = 3;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+pub const NFT_META_MARK: u32 = 3;
+pub const NFT_META_IIFNAME: u32 = 6;
+pub const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_SOCKET_CGROUPV2: u32 = 3;
+
+/// A netlink socket to the nftables subsystem.
+///
+/// This one is blocking because our users also need it in [`Drop`] but the kernel answers our requests right away.
+pub struct NftSocket {
+    socket: Socket,
+}
+
+impl NftSocket {
+    pub fn new() -> io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_NETFILTER)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+
+        Ok(Self { socket })
+    }
+
+    pub fn send_batch(&self, batch: Batch) -> io::Result<()> {
+        let (buf, num_acks) = batch.finish();
+
+        self.socket.send(&buf, 0)?;
+        self.wait_for_acks(num_acks)
+    }
+
+    pub fn table_exists(&self, table: &str) -> io::Result<bool> {
+        let mut batch = Batch::new(table);
+        batch.push(NFT_MSG_GETTABLE, 0, vec![batch.table_name()]);
+        let message = batch.messages.pop().expect("we just pushed it");
+
+        let mut buf = vec![0; message.buffer_len()];
+        message.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+
+        match self.wait_for_acks(1) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads responses from the kernel until it acknowledged or reported an error for `num_acks` messages.
+    ///
+    /// We always read all of them, otherwise the remaining ones would be mistaken for responses to the next batch.
+    /// Returns the first error the kernel reported, if any.
+    fn wait_for_acks(&self, mut num_acks: u32) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16 * 1024);
+        let mut first_error = None;
+
+        while num_acks > 0 {
+            buf.clear();
+
+            // The kernel queues all responses to a batch before `send` returns.
+            // After an error, it may not respond to the remaining messages at all, e.g. if we lack permissions, thus we must not block.
+            let flags = if first_error.is_some() {
+                libc::MSG_DONTWAIT
+            } else {
+                0
+            };
+
+            match self.socket.recv(&mut buf, flags) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+
+            let mut offset = 0;
+
+            while offset < buf.len() {
+                let bytes = &buf[offset..];
+                let len = NetlinkBuffer::new_checked(bytes)
+                    .map_err(io::Error::other)?
+                    .length() as usize;
+                let message = NetlinkMessage::<NftMessage>::deserialize(&bytes[..len])
+                    .map_err(io::Error::other)?;
+
+                offset += len.next_multiple_of(4);
+
+                // Skip replies to `GET` requests, acknowledgements are errors with no error code.
+                let NetlinkPayload::Error(error) = message.payload else {
+                    continue;
+                };
+
+                num_acks = num_acks.saturating_sub(1);
+
+                if let Some(code) = error.code
+                    && first_error.is_none()
+                {
+                    first_error = Some(io::Error::from_raw_os_error(-code.get()));
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A batch of nftables messages for a single `inet` table which the kernel applies atomically.
+pub struct Batch {
+    table: String,
+    messages: Vec<NetlinkMessage<NftMessage>>,
+}
+
+impl Batch {
+    pub fn new(table: &str) -> Self {
+        let mut batch = Self {
+            table: table.to_owned(),
+            messages: Vec::new(),
+        };
+        batch.push_message(
+            NFNL_MSG_BATCH_BEGIN,
+            NLM_F_REQUEST,
+            NFPROTO_UNSPEC,
+            NFNL_SUBSYS_NFTABLES,
+            Vec::new(),
+        );
+
+        batch
+    }
+
+    /// Replaces our table with an empty one.
+    ///
+    /// Creating the table before deleting it ensures the deletion doesn't fail if it doesn't exist yet.
+    pub fn replace_table(&mut self) {
+        self.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, vec![self.table_name()]);
+        self.delete_table();
+        self.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, vec![self.table_name()]);
+    }
+
+    pub fn delete_table(&mut self) {
+        self.push(NFT_MSG_DELTABLE, 0, vec![self.table_name()]);
+    }
+
+    /// Adds a chain with an `accept` policy to our table that is hooked into the packet path.
+    pub fn add_base_chain(&mut self, name: &str, chain_type: &str, hook: u32, priority: i32) {
+        let attributes = vec![
+            Attribute::String(NFTA_CHAIN_TABLE, self.table.clone()),
+            Attribute::String(NFTA_CHAIN_NAME, name.to_owned()),
+            Attribute::Nested(
+                NFTA_CHAIN_HOOK,
+                vec![
+                    Attribute::U32(NFTA_HOOK_HOOKNUM, hook),
+                    Attribute::U32(NFTA_HOOK_PRIORITY, priority as u32),
+                ],
+            ),
+            Attribute::U32(NFTA_CHAIN_POLICY, NF_ACCEPT),
+            Attribute::String(NFTA_CHAIN_TYPE, chain_type.to_owned()),
+        ];
+
+        self.push(NFT_MSG_NEWCHAIN, NLM_F_CREATE, attributes);
+    }
+
+    /// Appends a rule consisting of the given expressions to a chain of our table.
+    pub fn append_rule(&mut self, chain: &str, expressions: impl IntoIterator<Item = Attribute>) {
+        let attributes = vec![
+            Attribute::String(NFTA_RULE_TABLE, self.table.clone()),
+            Attribute::String(NFTA_RULE_CHAIN, chain.to_owned()),
+            Attribute::Nested(NFTA_RULE_EXPRESSIONS, expressions.into_iter().collect()),
+        ];
+
+        self.push(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, attributes);
+    }
+
+    fn table_name(&self) -> Attribute {
+        Attribute::String(NFTA_TABLE_NAME, self.table.clone())
+    }
+
+    fn push(&mut self, msg_type: u16, flags: u16, attributes: Vec<Attribute>) {
+        self.push_message(
+            (NFNL_SUBSYS_NFTABLES << 8) | msg_type,
+            NLM_F_REQUEST | NLM_F_ACK | flags,
+            NFPROTO_INET,
+            0,
+            attributes,
+        );
+    }
+
+    /// Terminates the batch, returning the bytes to send and the number of acknowledgements to expect.
+    fn finish(mut self) -> (Vec<u8>, u32) {
+        let num_acks = self.messages.len() as u32 - 1; // The begin and end messages are not acknowledged.
+
+        self.push_message(
+            NFNL_MSG_BATCH_END,
+            NLM_F_REQUEST,
+            NFPROTO_UNSPEC,
+            NFNL_SUBSYS_NFTABLES,
+            Vec::new(),
+        );
+
+        let mut buf = vec![0; self.messages.iter().map(|m| m.buffer_len()).sum()];
+        let mut offset = 0;
+
+        for message in &self.messages {
+            message.serialize(&mut buf[offset..]);
+            offset += message.buffer_len();
+        }
+
+        (buf, num_acks)
+    }
+
+    fn push_message(
+        &mut self,
+        message_type: u16,
+        flags: u16,
+        family: u8,
+        res_id: u16,
+        attributes: Vec<Attribute>,
+    ) {
+        let mut header = NetlinkHeader::default();
+        header.flags = flags;
+        header.sequence_number = self.messages.len() as u32;
+
+        let mut message = NetlinkMessage::new(
+            header,
+            NetlinkPayload::InnerMessage(NftMessage {
+                message_type,
+                family,
+                res_id,
+                attributes,
+            }),
+        );
+        message.finalize();
+
+        self.messages.push(message);
+    }
+}
+
+/// A message of the nftables subsystem: A `nfgenmsg` header followed by attributes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NftMessage {
+    message_type: u16,
+    family: u8,
+    res_id: u16,
+    attributes: Vec<Attribute>,
+}
+
+impl NetlinkSerializable for NftMessage {
+    fn message_type(&self) -> u16 {
+        self.message_type
+    }
+
+    fn buffer_len(&self) -> usize {
+        NFGENMSG_LEN + self.attributes.as_slice().buffer_len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8]) {
+        buffer[0] = self.family;
+        buffer[1] = NFNETLINK_V0;
+        buffer[2..NFGENMSG_LEN].copy_from_slice(&self.res_id.to_be_bytes());
+
+        self.attributes.as_slice().emit(&mut buffer[NFGENMSG_LEN..]);
+    }
+}
+
+impl NetlinkDeserializable for NftMessage {
+    type Error = DecodeError;
+
+    /// We only care about acknowledgements and errors, thus we don't parse the attributes of replies.
+    fn deserialize(header: &NetlinkHeader, payload: &[u8]) -> Result<Self, Self::Error> {
+        let nfgenmsg = payload
+            .get(..NFGENMSG_LEN)
+            .ok_or_else(|| DecodeError::from("Truncated nfgenmsg header"))?;
+
+        Ok(Self {
+            message_type: header.message_type,
+            family: nfgenmsg[0],
+            res_id: u16::from_be_bytes([nfgenmsg[2], nfgenmsg[3]]),
+            attributes: Vec::new(),
+        })
+    }
+}
+
+/// A netfilter netlink attribute.
+///
+/// nftables encodes all integer attributes in network byte order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attribute {
+    String(u16, String),
+    U32(u16, u32),
+    Bytes(u16, Vec<u8>),
+    Nested(u16, Vec<Attribute>),
+}
+
+impl Nla for Attribute {
+    fn value_len(&self) -> usize {
+        match self {
+            Attribute::String(_, value) => value.len() + 1, // NUL-terminated
+            Attribute::U32(_, _) => 4,
+            Attribute::Bytes(_, value) => value.len(),
+            Attribute::Nested(_, attributes) => attributes.as_slice().buffer_len(),
+        }
+    }
+
+    fn kind(&self) -> u16 {
+        match self {
+            Attribute::String(kind, _) | Attribute::U32(kind, _) | Attribute::Bytes(kind, _) => {
+                *kind
+            }
+            Attribute::Nested(kind, _) => *kind | NLA_F_NESTED,
+        }
+    }
+
+    fn emit_value(&self, buffer: &mut [u8]) {
+        match self {
+            Attribute::String(_, value) => {
+                buffer[..value.len()].copy_from_slice(value.as_bytes());
+                buffer[value.len()] = 0;
+            }
+            Attribute::U32(_, value) => buffer[..4].copy_from_slice(&value.to_be_bytes()),
+            Attribute::Bytes(_, value) => buffer[..value.len()].copy_from_slice(value),
+            Attribute::Nested(_, attributes) => attributes.as_slice().emit(buffer),
+        }
+    }
+}
+
+fn expression(name: &str, data: Option<Vec<Attribute>>) -> Attribute {
+    let mut attributes = vec![Attribute::String(NFTA_EXPR_NAME, name.to_owned())];
+    attributes.extend(data.map(|data| Attribute::Nested(NFTA_EXPR_DATA, data)));
+
+    Attribute::Nested(NFTA_LIST_ELEM, attributes)
+}
+
+fn data_value(attr_type: u16, value: &[u8]) -> Attribute {
+    Attribute::Nested(
+        attr_type,
+        vec![Attribute::Bytes(NFTA_DATA_VALUE, value.to_vec())],
+    )
+}
+
+/// Loads the given meta key into register 1.
+pub fn meta(key: u32) -> Attribute {
+    expression(
+        "meta",
+        Some(vec![
+            Attribute::U32(NFTA_META_KEY, key),
+            Attribute::U32(NFTA_META_DREG, NFT_REG_1),
+        ]),
+    )
+}
+
+/// Sets the given meta key to the value of register 1.
+pub fn meta_set(key: u32) -> Attribute {
+    expression(
+        "meta",
+        Some(vec![
+            Attribute::U32(NFTA_META_KEY, key),
+            Attribute::U32(NFTA_META_SREG, NFT_REG_1),
+        ]),
+    )
+}
+
+/// Loads `value` into register 1.
+pub fn immediate(value: &[u8]) -> Attribute {
+    expression(
+        "immediate",
+        Some(vec![
+            Attribute::U32(NFTA_IMMEDIATE_DREG, NFT_REG_1),
+            data_value(NFTA_IMMEDIATE_DATA, value),
+        ]),
+    )
+}
+
+/// Loads the ID of the cgroup v2 at `level` of the packet's socket into register 1.
+pub fn socket_cgroupv2(level: u32) -> Attribute {
+    expression(
+        "socket",
+        Some(vec![
+            Attribute::U32(NFTA_SOCKET_KEY, NFT_SOCKET_CGROUPV2),
+            Attribute::U32(NFTA_SOCKET_DREG, NFT_REG_1),
+            Attribute::U32(NFTA_SOCKET_LEVEL, level),
+        ]),
+    )
+}
+
+/// Breaks unless register 1 equals `value`.
+pub fn cmp_eq(value: &[u8]) -> Attribute {
+    cmp(NFT_CMP_EQ, value)
+}
+
+/// Breaks if register 1 equals `value`.
+pub fn cmp_neq(value: &[u8]) -> Attribute {
+    cmp(NFT_CMP_NEQ, value)
+}
+
+fn cmp(op: u32, value: &[u8]) -> Attribute {
+    expression(
+        "cmp",
+        Some(vec![
+            Attribute::U32(NFTA_CMP_SREG, NFT_REG_1),
+            Attribute::U32(NFTA_CMP_OP, op),
+            data_value(NFTA_CMP_DATA, value),
+        ]),
+    )
+}
+
+/// Breaks unless the packet's source address is within `network`.
+pub fn match_source(network: IpNetwork) -> [Attribute; 5] {
+    let (nfproto, offset, address, mask) = match network {
+        IpNetwork::V4(n) => (
+            NFPROTO_IPV4,
+            12,
+            n.network_address().octets().to_vec(),
+            ipv4_netmask(n.netmask()).to_vec(),
+        ),
+        IpNetwork::V6(n) => (
+            NFPROTO_IPV6,
+            8,
+            n.network_address().octets().to_vec(),
+            ipv6_netmask(n.netmask()).to_vec(),
+        ),
+    };
+
+    [
+        meta(NFT_META_NFPROTO),
+        cmp_eq(&[nfproto]),
+        expression(
+            "payload",
+            Some(vec![
+                Attribute::U32(NFTA_PAYLOAD_DREG, NFT_REG_1),
+                Attribute::U32(NFTA_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER),
+                Attribute::U32(NFTA_PAYLOAD_OFFSET, offset),
+                Attribute::U32(NFTA_PAYLOAD_LEN, address.len() as u32),
+            ]),
+        ),
+        expression(
+            "bitwise",
+            Some(vec![
+                Attribute::U32(NFTA_BITWISE_SREG, NFT_REG_1),
+                Attribute::U32(NFTA_BITWISE_DREG, NFT_REG_1),
+                Attribute::U32(NFTA_BITWISE_LEN, mask.len() as u32),
+                data_value(NFTA_BITWISE_MASK, &mask),
+                data_value(NFTA_BITWISE_XOR, &vec![0; mask.len()]),
+            ]),
+        ),
+        cmp_eq(&address),
+    ]
+}
+
+pub fn accept() -> Attribute {
+    expression(
+        "immediate",
+        Some(vec![
+            Attribute::U32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT),
+            Attribute::Nested(
+                NFTA_IMMEDIATE_DATA,
+                vec![Attribute::Nested(
+                    NFTA_DATA_VERDICT,
+                    vec![Attribute::U32(NFTA_VERDICT_CODE, NF_ACCEPT)],
+                )],
+            ),
+        ]),
+    )
+}
+
+pub fn masquerade() -> Attribute {
+    expression("masq", None)
+}
+
+/// Interface names are compared as zero-padded, fixed-size strings.
+pub fn ifname(name: &str) -> [u8; IFNAMSIZ] {
+    let mut padded = [0; IFNAMSIZ];
+    let len = name.len().min(IFNAMSIZ - 1);
+    padded[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    padded
+}
+
+fn ipv4_netmask(prefix_len: u8) -> [u8; 4] {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or_default();
+
+    mask.to_be_bytes()
+}
+
+fn ipv6_netmask(prefix_len: u8) -> [u8; 16] {
+    let mask = u128::MAX
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or_default();
+
+    mask.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_nested_attributes() {
+        let message = NftMessage {
+            message_type: 0,
+            family: NFPROTO_INET,
+            res_id: 0,
+            attributes: vec![Attribute::Nested(
+                NFTA_CHAIN_HOOK,
+                vec![Attribute::U32(NFTA_HOOK_HOOKNUM, NF_INET_FORWARD)],
+            )],
+        };
+
+        let mut buf = vec![0; message.buffer_len()];
+        message.serialize(&mut buf);
+
+        assert_eq!(
+            buf,
+            [
+                [NFPROTO_INET, NFNETLINK_V0, 0, 0].as_slice(),
+                &12u16.to_ne_bytes(),
+                &(NFTA_CHAIN_HOOK | NLA_F_NESTED).to_ne_bytes(),
+                &8u16.to_ne_bytes(),
+                &NFTA_HOOK_HOOKNUM.to_ne_bytes(),
+                &NF_INET_FORWARD.to_be_bytes(),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn netmasks_of_tunnel_networks() {
+        assert_eq!(ipv4_netmask(11), [0xff, 0xe0, 0x00, 0x00]);
+        assert_eq!(ipv4_netmask(0), [0; 4]);
+
+        assert_eq!(
+            ipv6_netmask(107),
+            [
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xe0,
+                0x00, 0x00
+            ]
+        );
+        assert_eq!(ipv6_netmask(0), [0; 16]);
+    }
+
+    #[test]
+    fn batch_acknowledges_every_message_but_begin_and_end() {
+        let mut batch = Batch::new("test");
+        batch.replace_table();
+        batch.append_rule("chain", [masquerade()]);
+
+        let (_, num_acks) = batch.finish();
+
+        assert_eq!(num_acks, 4);
+    }
+}
@@ -6,4 +6,4 @@ mod platform;
 #[path = "signals/windows.rs"]
 mod platform;
 
-pub use platform::{Hangup, Terminate};
+pub use platform::{Drain, Hangup, Terminate};
@@ -17,6 +17,11 @@ pub struct Hangup {
     sighup: Signal,
 }
 
+pub struct Drain {
+    /// For draining the Relay or Gateway before it gets replaced
+    sigusr1: Signal,
+}
+
 impl Terminate {
     pub fn new() -> Result<Self> {
         let sigint = signal(SignalKind::interrupt())?;
@@ -51,3 +56,16 @@ impl Hangup {
         self.sighup.recv().await;
     }
 }
+
+impl Drain {
+    pub fn new() -> Result<Self> {
+        let sigusr1 = signal(SignalKind::user_defined1())?;
+
+        Ok(Self { sigusr1 })
+    }
+
+    /// Polls for SIGUSR1
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.sigusr1.poll_recv(cx).map(|_| ())
+    }
+}
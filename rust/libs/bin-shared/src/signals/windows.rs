@@ -14,6 +14,9 @@ pub struct Terminate {
 // SIGHUP is used on Linux but not on Windows
 pub struct Hangup {}
 
+// SIGUSR1 is used on Linux but not on Windows
+pub struct Drain {}
+
 impl Terminate {
     pub fn new() -> Result<Self> {
         let sigint = tokio::signal::windows::ctrl_c()?;
@@ -53,3 +56,15 @@ impl Hangup {
         unreachable!()
     }
 }
+
+impl Drain {
+    #[expect(clippy::unnecessary_wraps)]
+    pub fn new() -> Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Never ready - Only implemented for Linux
+    pub fn poll_recv(&mut self, _: &mut Context<'_>) -> Poll<()> {
+        Poll::Pending
+    }
+}
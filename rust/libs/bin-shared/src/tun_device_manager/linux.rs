@@ -7,7 +7,7 @@ use futures::{
     future::{self, Either},
 };
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
-use ip_packet::{IpPacket, IpPacketBuf};
+use ip_packet::IpPacket;
 use libc::{
     EEXIST, ENOENT, ESRCH, F_GETFL, F_SETFL, O_NONBLOCK, O_RDWR, S_IFCHR, fcntl, makedev, mknod,
     open,
@@ -17,9 +17,9 @@ use netlink_packet_route::link::{LinkAttribute, State};
 use netlink_packet_route::route::{
     RouteAddress, RouteAttribute, RouteMessage, RouteProtocol, RouteScope,
 };
-use netlink_packet_route::rule::RuleAction;
+use netlink_packet_route::rule::{RuleAction, RuleAttribute, RuleMessage};
 use rtnetlink::sys::AsyncSocket;
-use rtnetlink::{Error::NetlinkError, Handle, RuleAddRequest, new_connection};
+use rtnetlink::{Error::NetlinkError, Handle, IpVersion, RuleAddRequest, new_connection};
 use rtnetlink::{LinkUnspec, RouteMessageBuilder};
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -43,7 +43,18 @@ use tokio::{sync::mpsc, time::Instant};
 use tokio_util::sync::PollSender;
 use tun::ioctl;
 
+mod cgroup_split_tunnel;
+
+pub use cgroup_split_tunnel::CgroupSplitTunnel;
+
 const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+const TUN_F_USO4: libc::c_uint = 0x20;
+const TUN_F_USO6: libc::c_uint = 0x40;
 const TUN_DEV_MAJOR: u32 = 10;
 const TUN_DEV_MINOR: u32 = 200;
 
@@ -58,6 +69,8 @@ pub struct TunDeviceManager {
     mtu: u32,
     connection: Connection,
     routes: BTreeSet<IpNetwork>,
+    cgroup_split_tunnel: CgroupSplitTunnel,
+    split_tunnel_table: Option<cgroup_split_tunnel::SplitTunnelTable>,
 }
 
 struct Connection {
@@ -107,6 +120,8 @@ impl TunDeviceManager {
         Ok(Self {
             connection,
             routes: Default::default(),
+            cgroup_split_tunnel: Default::default(),
+            split_tunnel_table: None,
             mtu: mtu as u32,
         })
     }
@@ -130,6 +145,13 @@ impl TunDeviceManager {
         Ok(tun)
     }
 
+    /// Configures which applications use the Internet Resource.
+    ///
+    /// Takes effect the next time [`TunDeviceManager::set_ips`] is called.
+    pub fn set_cgroup_split_tunnel(&mut self, cgroup_split_tunnel: CgroupSplitTunnel) {
+        self.cgroup_split_tunnel = cgroup_split_tunnel;
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_ips(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<TunIpStack> {
         let handle = &self.connection.handle;
@@ -204,6 +226,21 @@ impl TunDeviceManager {
             }
         }
 
+        self.cgroup_split_tunnel
+            .apply_nftables(&mut self.split_tunnel_table)
+            .context("Failed to configure split tunneling")?;
+
+        if let Err(e) = set_split_tunnel_rules(
+            handle,
+            &self.cgroup_split_tunnel,
+            res_v4.is_ok(),
+            res_v6.is_ok(),
+        )
+        .await
+        {
+            tracing::warn!("Failed to set split tunneling routing rules: {e:#}");
+        }
+
         let tun_ip_stack = match (res_v4, res_v6) {
             (Ok(()), Ok(())) => TunIpStack::Dual,
             (Ok(()), Err(e)) => {
@@ -335,6 +372,86 @@ fn make_rule(handle: &Handle, table: u32, priority: u32) -> RuleAddRequest {
     rule
 }
 
+/// Lets either the marked or the unmarked packets skip the Internet Resource's routing table.
+fn make_split_tunnel_rule(handle: &Handle, mode: cgroup_split_tunnel::Mode) -> RuleAddRequest {
+    let mut rule = handle
+        .rule()
+        .add()
+        .fw_mark(cgroup_split_tunnel::SPLIT_TUNNEL_MARK)
+        .table_id(libc::RT_TABLE_MAIN as u32)
+        .priority(cgroup_split_tunnel::SPLIT_TUNNEL_RULE_PRIORITY)
+        .action(RuleAction::ToTable);
+
+    if mode == cgroup_split_tunnel::Mode::Include {
+        rule.message_mut()
+            .header
+            .flags
+            .insert(netlink_packet_route::rule::RuleFlags::Invert);
+    }
+
+    rule
+}
+
+/// Replaces the routing rule for split tunneling, removing it if split tunneling is disabled.
+async fn set_split_tunnel_rules(
+    handle: &Handle,
+    cgroup_split_tunnel: &CgroupSplitTunnel,
+    ipv4: bool,
+    ipv6: bool,
+) -> Result<()> {
+    // The mode may have changed since the last run, so always start from scratch.
+    remove_split_tunnel_rules(handle).await?;
+
+    let Some(mode) = cgroup_split_tunnel.mode() else {
+        return Ok(());
+    };
+
+    if ipv4 {
+        install_rules([make_split_tunnel_rule(handle, mode).v4()]).await?;
+    }
+
+    if ipv6 {
+        install_rules([make_split_tunnel_rule(handle, mode).v6()]).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes the routing rules installed by [`make_split_tunnel_rule`], leaving those of other software at the same priority alone.
+async fn remove_split_tunnel_rules(handle: &Handle) -> Result<()> {
+    for ip_version in [IpVersion::V4, IpVersion::V6] {
+        let rules = handle
+            .rule()
+            .get(ip_version)
+            .execute()
+            .try_filter(|rule| future::ready(is_split_tunnel_rule(rule)))
+            .try_collect::<Vec<RuleMessage>>()
+            .await
+            .context("Failed to list routing rules")?;
+
+        for rule in rules {
+            handle
+                .rule()
+                .del(rule)
+                .execute()
+                .await
+                .context("Failed to remove routing rule")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_split_tunnel_rule(rule: &RuleMessage) -> bool {
+    [
+        RuleAttribute::Priority(cgroup_split_tunnel::SPLIT_TUNNEL_RULE_PRIORITY),
+        RuleAttribute::FwMark(cgroup_split_tunnel::SPLIT_TUNNEL_MARK),
+        RuleAttribute::Table(libc::RT_TABLE_MAIN as u32),
+    ]
+    .iter()
+    .all(|attribute| rule.attributes.contains(attribute))
+}
+
 async fn install_rules<const N: usize, T>(
     requests: [RuleAddRequest<T>; N],
 ) -> Result<(), rtnetlink::Error> {
@@ -717,7 +834,7 @@ impl Tun {
 
                 move || {
                     logging::unwrap_or_warn!(
-                        tun::unix::tun_send(fd, outbound_rx, write),
+                        tun::unix::tun_send_offload(fd, outbound_rx, write),
                         "Failed to send to TUN device: {}"
                     )
                 }
@@ -727,7 +844,7 @@ impl Tun {
             .name("TUN recv".to_owned())
             .spawn(move || {
                 logging::unwrap_or_warn!(
-                    tun::unix::tun_recv(fd, inbound_tx, read),
+                    tun::unix::tun_recv_offload(fd, inbound_tx, read),
                     "Failed to recv from TUN device: {}"
                 )
             })
@@ -755,11 +872,13 @@ fn open_tun() -> Result<OwnedFd> {
         ioctl::exec(
             fd,
             TUNSETIFF,
-            &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME),
+            &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME)
+                .with_vnet_hdr(),
         )
         .context("Failed to set flags on TUN device")?;
     }
 
+    set_offloads(fd);
     set_non_blocking(fd).context("Failed to make TUN device non-blocking")?;
 
     // Safety: We are not closing the FD.
@@ -768,6 +887,33 @@ fn open_tun() -> Result<OwnedFd> {
     Ok(fd)
 }
 
+/// Enables checksum, TCP segmentation and UDP segmentation offloads on the TUN device.
+///
+/// USO is only supported since Linux 6.2, so we fall back to TSO only if enabling it fails.
+/// Without any offloads, the kernel still prefixes every packet with an empty `virtio_net_hdr`.
+fn set_offloads(fd: RawFd) {
+    let tso = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+    let uso = TUN_F_USO4 | TUN_F_USO6;
+
+    for offloads in [tso | uso, tso] {
+        // Safety: The file descriptor is open and `TUNSETOFFLOAD` takes its argument by value.
+        match unsafe { libc::ioctl(fd, TUNSETOFFLOAD as _, offloads) } {
+            -1 => {
+                tracing::debug!(
+                    "Failed to set offloads {offloads:#x} on TUN device: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            _ => {
+                tracing::debug!("Enabled offloads {offloads:#x} on TUN device");
+                return;
+            }
+        }
+    }
+
+    tracing::info!("Failed to enable segmentation offloads on TUN device");
+}
+
 impl tun::Tun for Tun {
     fn poll_send_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
         self.outbound_tx
@@ -839,9 +985,7 @@ fn create_tun_device() -> io::Result<()> {
 }
 
 /// Read from the given file descriptor in the buffer.
-fn read(fd: RawFd, dst: &mut IpPacketBuf) -> io::Result<usize> {
-    let dst = dst.buf();
-
+fn read(fd: RawFd, dst: &mut [u8]) -> io::Result<usize> {
     // Safety: Within this module, the file descriptor is always valid.
     match unsafe { libc::read(fd, dst.as_mut_ptr() as _, dst.len()) } {
         -1 => Err(io::Error::last_os_error()),
@@ -850,9 +994,7 @@ fn read(fd: RawFd, dst: &mut IpPacketBuf) -> io::Result<usize> {
 }
 
 /// Write the packet to the given file descriptor.
-fn write(fd: RawFd, packet: &IpPacket) -> io::Result<usize> {
-    let buf = packet.packet();
-
+fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
     // Safety: Within this module, the file descriptor is always valid.
     match unsafe { libc::write(fd, buf.as_ptr() as _, buf.len() as _) } {
         -1 => Err(io::Error::last_os_error()),
@@ -0,0 +1,316 @@
+//! Per-application split tunneling for the Internet Resource, based on cgroups v2.
+//!
+//! We cannot match on cgroups in routing rules directly.
+//! Instead, an nftables rule marks all packets sent from sockets of the configured cgroups (and all cgroups nested within them).
+//! A routing rule then sends either the marked or the unmarked packets to the main routing table before they reach the Internet Resource's table.
+//!
+//! The kernel routes a packet once before the mark is set and a second time afterwards.
+//! The source address picked on the first pass is the wrong one if the second pass chose a different interface, which we fix by masquerading those packets.
+
+use std::{
+    os::unix::fs::MetadataExt as _,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, bail};
+
+use super::TunDeviceManager;
+use crate::nftables::{
+    self, Attribute, Batch, NF_INET_LOCAL_OUT, NF_INET_POST_ROUTING, NF_IP_PRI_MANGLE,
+    NF_IP_PRI_NAT_SRC, NFT_META_MARK, NFT_META_OIFNAME, NftSocket,
+};
+
+/// Mark for packets of processes in one of the configured cgroups.
+pub(crate) const SPLIT_TUNNEL_MARK: u32 = 0xfd002022;
+
+/// Priority of the routing rule that lets packets bypass the Internet Resource.
+///
+/// This sits between the rule for link-scope routes and the one for the Internet Resource.
+/// Packets to other Resources and to local networks are thus unaffected.
+pub(crate) const SPLIT_TUNNEL_RULE_PRIORITY: u32 = 250;
+
+const CGROUP2_MOUNT: &str = "/sys/fs/cgroup";
+
+/// Must not clash with the Gateway's table, which may run on the same host.
+const TABLE_NAME: &str = "firezone-client-split-tunnel";
+const OUTPUT_CHAIN: &str = "split-tunnel-output";
+const POSTROUTING_CHAIN: &str = "split-tunnel-postrouting";
+
+/// Which applications use the Internet Resource, identified by their cgroup v2.
+///
+/// Paths are relative to the cgroup v2 mount, e.g. `system.slice/docker.service` or `user.slice/user-1000.slice`.
+/// Processes in cgroups nested within a configured cgroup are matched too.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupSplitTunnel {
+    /// Only route traffic of processes in these cgroups through the Internet Resource.
+    ///
+    /// Requires nftables. Traffic to all other Resources is unaffected.
+    #[arg(
+        long = "internet-resource-include-cgroup",
+        env = "FIREZONE_INTERNET_RESOURCE_INCLUDE_CGROUPS",
+        value_delimiter = ',',
+        conflicts_with = "exclude"
+    )]
+    pub include: Vec<PathBuf>,
+
+    /// Never route traffic of processes in these cgroups through the Internet Resource.
+    ///
+    /// Requires nftables. Traffic to all other Resources is unaffected.
+    #[arg(
+        long = "internet-resource-exclude-cgroup",
+        env = "FIREZONE_INTERNET_RESOURCE_EXCLUDE_CGROUPS",
+        value_delimiter = ','
+    )]
+    pub exclude: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Include,
+    Exclude,
+}
+
+impl CgroupSplitTunnel {
+    pub(crate) fn mode(&self) -> Option<Mode> {
+        if !self.include.is_empty() {
+            return Some(Mode::Include);
+        }
+
+        if !self.exclude.is_empty() {
+            return Some(Mode::Exclude);
+        }
+
+        None
+    }
+
+    fn cgroups(&self) -> &[PathBuf] {
+        match self.mode() {
+            Some(Mode::Include) => &self.include,
+            Some(Mode::Exclude) => &self.exclude,
+            None => &[],
+        }
+    }
+
+    /// Replaces our nftables table with one that marks packets of the configured cgroups.
+    ///
+    /// If split tunneling is disabled, we only delete the table if we installed it ourselves.
+    /// Cgroups that don't exist (yet) are skipped because nftables matches them by ID.
+    /// We are called every time the tunnel interface is configured, so they will be picked up on the next reconnect.
+    pub(crate) fn apply_nftables(&self, table: &mut Option<SplitTunnelTable>) -> Result<()> {
+        let Some(mode) = self.mode() else {
+            *table = None;
+
+            return Ok(());
+        };
+
+        let mut cgroups = Vec::with_capacity(self.cgroups().len());
+
+        for cgroup in self.cgroups() {
+            let cgroup = normalize(cgroup)?;
+
+            let id = match std::fs::metadata(Path::new(CGROUP2_MOUNT).join(&cgroup)) {
+                Ok(metadata) if metadata.is_dir() => metadata.ino(),
+                Ok(_) | Err(_) => {
+                    tracing::warn!(cgroup = %cgroup.display(), "Skipping cgroup for split tunneling because it doesn't exist");
+                    continue;
+                }
+            };
+
+            cgroups.push(Cgroup { path: cgroup, id });
+        }
+
+        let installed = match table.take() {
+            Some(installed) => installed,
+            None => SplitTunnelTable {
+                socket: NftSocket::new().context("Failed to open netfilter netlink socket")?,
+            },
+        };
+        let result = installed.socket.send_batch(ruleset(mode, &cgroups));
+        *table = Some(installed);
+        result.context("Failed to install nftables table")?;
+
+        tracing::info!(
+            ?mode,
+            cgroups = ?cgroups.iter().map(|c| &c.path).collect::<Vec<_>>(),
+            "Applied split tunneling for the Internet Resource"
+        );
+
+        Ok(())
+    }
+}
+
+/// Our nftables table, deleted again when dropped.
+pub(crate) struct SplitTunnelTable {
+    socket: NftSocket,
+}
+
+impl Drop for SplitTunnelTable {
+    fn drop(&mut self) {
+        let mut batch = Batch::new(TABLE_NAME);
+        batch.delete_table();
+
+        match self.socket.send_batch(batch) {
+            Ok(()) => tracing::debug!(table = %TABLE_NAME, "Removed nftables table"),
+            Err(e) => tracing::debug!(table = %TABLE_NAME, "Failed to remove nftables table: {e}"),
+        }
+    }
+}
+
+struct Cgroup {
+    /// Relative to the cgroup v2 mount.
+    path: PathBuf,
+    /// The inode number of the cgroup's directory, which is what the kernel compares against.
+    id: u64,
+}
+
+fn ruleset(mode: Mode, cgroups: &[Cgroup]) -> Batch {
+    let mut batch = Batch::new(TABLE_NAME);
+
+    batch.replace_table();
+    batch.add_base_chain(OUTPUT_CHAIN, "route", NF_INET_LOCAL_OUT, NF_IP_PRI_MANGLE);
+    batch.add_base_chain(
+        POSTROUTING_CHAIN,
+        "nat",
+        NF_INET_POST_ROUTING,
+        NF_IP_PRI_NAT_SRC,
+    );
+
+    for cgroup in cgroups {
+        batch.append_rule(OUTPUT_CHAIN, mark_cgroup(cgroup));
+    }
+
+    batch.append_rule(POSTROUTING_CHAIN, masquerade_marked(mode));
+
+    batch
+}
+
+/// `meta mark 0 socket cgroupv2 level <level> "<path>" meta mark set <SPLIT_TUNNEL_MARK>`
+///
+/// Packets that are already marked, e.g. the ones from connlib's own sockets, are left alone.
+fn mark_cgroup(cgroup: &Cgroup) -> [Attribute; 6] {
+    let level = cgroup.path.components().count() as u32;
+
+    [
+        nftables::meta(NFT_META_MARK),
+        nftables::cmp_eq(&0u32.to_ne_bytes()),
+        nftables::socket_cgroupv2(level),
+        nftables::cmp_eq(&cgroup.id.to_ne_bytes()),
+        nftables::immediate(&SPLIT_TUNNEL_MARK.to_ne_bytes()),
+        nftables::meta_set(NFT_META_MARK),
+    ]
+}
+
+/// `meta mark <SPLIT_TUNNEL_MARK> oifname [!=] "tun-firezone" masquerade`
+fn masquerade_marked(mode: Mode) -> [Attribute; 5] {
+    let iface = nftables::ifname(TunDeviceManager::IFACE_NAME);
+    let oif = match mode {
+        Mode::Include => nftables::cmp_eq(&iface),
+        Mode::Exclude => nftables::cmp_neq(&iface),
+    };
+
+    [
+        nftables::meta(NFT_META_MARK),
+        nftables::cmp_eq(&SPLIT_TUNNEL_MARK.to_ne_bytes()),
+        nftables::meta(NFT_META_OIFNAME),
+        oif,
+        nftables::masquerade(),
+    ]
+}
+
+/// Turns a cgroup path into one relative to the cgroup v2 mount.
+fn normalize(cgroup: &Path) -> Result<PathBuf> {
+    let relative = cgroup.strip_prefix(CGROUP2_MOUNT).unwrap_or(cgroup);
+
+    let mut normalized = PathBuf::new();
+
+    for component in relative.components() {
+        match component {
+            Component::RootDir => {}
+            Component::Normal(c) => normalized.push(c),
+            Component::CurDir | Component::ParentDir | Component::Prefix(_) => {
+                bail!(
+                    "cgroup path '{}' must not contain relative components",
+                    cgroup.display()
+                )
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        bail!("Cannot split tunnel the root cgroup");
+    }
+
+    if normalized.to_str().is_none_or(|p| p.contains('"')) {
+        bail!(
+            "cgroup path '{}' must be valid UTF-8 without quotes",
+            cgroup.display()
+        );
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_cgroup_paths() {
+        assert_eq!(
+            normalize(Path::new("/sys/fs/cgroup/system.slice/docker.service")).unwrap(),
+            PathBuf::from("system.slice/docker.service")
+        );
+        assert_eq!(
+            normalize(Path::new("/user.slice/")).unwrap(),
+            PathBuf::from("user.slice")
+        );
+        assert_eq!(
+            normalize(Path::new("user.slice")).unwrap(),
+            PathBuf::from("user.slice")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cgroup_paths() {
+        normalize(Path::new("/")).unwrap_err();
+        normalize(Path::new("/sys/fs/cgroup")).unwrap_err();
+        normalize(Path::new("system.slice/../user.slice")).unwrap_err();
+        normalize(Path::new("system.slice/\"foo\"")).unwrap_err();
+    }
+
+    #[test]
+    fn include_list_takes_precedence() {
+        let split_tunnel = CgroupSplitTunnel {
+            include: vec![PathBuf::from("a")],
+            exclude: vec![PathBuf::from("b")],
+        };
+
+        assert_eq!(split_tunnel.mode(), Some(Mode::Include));
+        assert_eq!(CgroupSplitTunnel::default().mode(), None);
+    }
+
+    #[test]
+    fn matches_nested_cgroups_by_level() {
+        let rule = mark_cgroup(&Cgroup {
+            path: PathBuf::from("user.slice/user-1000.slice/app.slice"),
+            id: 1234,
+        });
+
+        assert!(rule.contains(&nftables::socket_cgroupv2(3)));
+        assert!(rule.contains(&nftables::cmp_eq(&1234u64.to_ne_bytes())));
+    }
+
+    #[test]
+    fn exclude_ruleset_masquerades_packets_leaving_the_tunnel() {
+        let rule = masquerade_marked(Mode::Exclude);
+
+        assert!(rule.contains(&nftables::cmp_neq(&nftables::ifname("tun-firezone"))));
+    }
+
+    #[test]
+    fn include_ruleset_masquerades_packets_into_the_tunnel() {
+        let rule = masquerade_marked(Mode::Include);
+
+        assert!(rule.contains(&nftables::cmp_eq(&nftables::ifname("tun-firezone"))));
+    }
+}
@@ -0,0 +1,102 @@
+#![cfg(target_os = "linux")]
+#![allow(clippy::unwrap_used)]
+
+use bin_shared::{CgroupSplitTunnel, TunDeviceManager};
+use ip_network::Ipv4Network;
+use std::{
+    future::poll_fn,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+const CGROUP: &str = "firezone-split-tunnel-test";
+
+/// Sends UDP packets from processes inside and outside of an excluded cgroup and checks which of them arrive on the TUN device.
+///
+/// This modifies the routing configuration, so it should run in its own network namespace, e.g.:
+/// `sudo unshare --net cargo test -p bin-shared --test cgroup_split_tunnel -- --ignored`
+#[tokio::test]
+#[ignore = "Needs root, cgroups v2, nftables and its own network namespace"]
+async fn excluded_cgroups_bypass_internet_resource() {
+    logging::test_global("debug"); // `Tun` uses threads and we want to see the logs of all threads.
+
+    // Give excluded packets somewhere to go.
+    ip(&["link", "add", "dummy0", "type", "dummy"]);
+    ip(&["link", "set", "dummy0", "up"]);
+    ip(&["addr", "add", "192.0.2.1/24", "dev", "dummy0"]);
+    ip(&["route", "add", "default", "via", "192.0.2.2"]);
+
+    let cgroup = Path::new("/sys/fs/cgroup").join(CGROUP);
+    let nested = cgroup.join("nested");
+    std::fs::create_dir_all(&nested).unwrap();
+
+    let mut device_manager = TunDeviceManager::new(1280).unwrap();
+    device_manager.set_cgroup_split_tunnel(CgroupSplitTunnel {
+        include: Vec::new(),
+        exclude: vec![PathBuf::from(CGROUP)],
+    });
+    let mut tun = device_manager.make_tun().unwrap();
+    device_manager
+        .set_ips(
+            Ipv4Addr::new(100, 90, 215, 97),
+            Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x0, 0x0, 0x0, 0x0016, 0x588f),
+        )
+        .await
+        .unwrap();
+    device_manager
+        .set_routes(vec![Ipv4Network::DEFAULT_ROUTE.into()])
+        .await
+        .unwrap();
+
+    send_udp(None, "tunneled");
+    send_udp(Some(&cgroup), "excluded");
+    send_udp(Some(&nested), "nested");
+
+    let mut payloads = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            let mut packets = Vec::new();
+            poll_fn(|cx| tun.poll_recv_many(cx, &mut packets, 10)).await;
+
+            payloads.extend(
+                packets
+                    .iter()
+                    .filter_map(|p| p.as_udp())
+                    .map(|udp| String::from_utf8_lossy(udp.payload()).into_owned()),
+            );
+        }
+    })
+    .await;
+
+    drop(tun);
+    drop(device_manager);
+    std::fs::remove_dir(&nested).unwrap();
+    std::fs::remove_dir(&cgroup).unwrap();
+
+    assert_eq!(payloads, vec!["tunneled".to_owned()]);
+}
+
+/// Sends a single UDP packet from a new process, optionally moving it into `cgroup` first.
+fn send_udp(cgroup: Option<&Path>, payload: &str) {
+    let join_cgroup = cgroup
+        .map(|c| format!("echo $$ > {}/cgroup.procs && ", c.display()))
+        .unwrap_or_default();
+
+    let status = Command::new("bash")
+        .arg("-c")
+        .arg(format!(
+            "{join_cgroup}printf {payload} > /dev/udp/203.0.113.1/9"
+        ))
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+
+    assert!(status.success());
+}
@@ -1,11 +1,12 @@
 use crate::PHOENIX_TOPIC;
 use anyhow::{Context as _, ErrorExt as _, Result};
-use connlib_model::{PublicKey, ResourceId, ResourceView};
+use connlib_model::{GatewayView, PublicKey, ResourceId, ResourceView};
 use l4_udp_dns_client::UdpDnsClient;
 use parking_lot::Mutex;
 use phoenix_channel::{ErrorReply, PhoenixChannel, PublicKeyParam};
 use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
 use std::sync::Arc;
 use std::time::Instant;
@@ -16,14 +17,17 @@ use std::{
     task::{Context, Poll},
 };
 use std::{future, mem};
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 use tun::Tun;
 use tunnel::messages::RelaysPresence;
 use tunnel::messages::client::{
     EgressMessages, FailReason, FlowCreated, FlowCreationFailed, GatewayIceCandidates,
     GatewaysIceCandidates, IngressMessages, InitClient,
 };
-use tunnel::{ClientEvent, ClientTunnel, DnsResourceRecord, IpConfig, TunConfig, TunnelError};
+use tunnel::{
+    ClientEvent, ClientTunnel, DnsCacheSnapshot, DnsResourceRecord, IpConfig, TunConfig,
+    TunnelError,
+};
 
 /// In-memory cache for DNS resource records.
 ///
@@ -47,6 +51,8 @@ static DNS_RESOURCE_RECORDS_CACHE: Mutex<BTreeSet<DnsResourceRecord>> = Mutex::n
 
 pub struct Eventloop {
     tunnel: Option<ClientTunnel>,
+    /// Where to persist the DNS cache across restarts, if at all.
+    dns_cache_path: Option<PathBuf>,
 
     cmd_rx: mpsc::UnboundedReceiver<Command>,
     resource_list_sender: watch::Sender<Vec<ResourceView>>,
@@ -66,6 +72,7 @@ pub enum Command {
     SetDns(Vec<IpAddr>),
     SetTun(Box<dyn Tun>),
     SetInternetResourceState(bool),
+    GetGateways(oneshot::Sender<Vec<GatewayView>>),
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -107,6 +114,7 @@ impl Eventloop {
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
         is_internet_resource_active: bool,
         dns_servers: Vec<IpAddr>,
+        dns_cache_path: Option<PathBuf>,
         portal: PhoenixChannel<(), EgressMessages, IngressMessages, PublicKeyParam>,
         cmd_rx: mpsc::UnboundedReceiver<Command>,
         resource_list_sender: watch::Sender<Vec<ResourceView>>,
@@ -120,6 +128,10 @@ impl Eventloop {
             tcp_socket_factory,
             udp_socket_factory.clone(),
             DNS_RESOURCE_RECORDS_CACHE.lock().clone(),
+            dns_cache_path
+                .as_deref()
+                .map(load_dns_cache)
+                .unwrap_or_default(),
             is_internet_resource_active,
         );
         tunnel.update_system_resolvers(dns_servers.clone());
@@ -135,6 +147,7 @@ impl Eventloop {
 
         Self {
             tunnel: Some(tunnel),
+            dns_cache_path,
             cmd_rx,
             logged_permission_denied: false,
             portal_event_rx,
@@ -225,6 +238,15 @@ impl Eventloop {
                     .state_mut()
                     .set_internet_resource_state(active, Instant::now())
             }
+            Command::GetGateways(tx) => {
+                let gateways = self
+                    .tunnel
+                    .as_ref()
+                    .map(|tunnel| tunnel.state().gateways())
+                    .unwrap_or_default();
+
+                let _ = tx.send(gateways);
+            }
             Command::SetTun(tun) => {
                 let Some(tunnel) = self.tunnel.as_mut() else {
                     return Ok(ControlFlow::Continue(()));
@@ -502,6 +524,12 @@ impl Eventloop {
             return Ok(());
         };
 
+        if let Some(path) = self.dns_cache_path.as_deref()
+            && let Err(e) = save_dns_cache(path, &tunnel.dns_cache_snapshot())
+        {
+            tracing::debug!(path = %path.display(), "Failed to save DNS cache: {e:#}");
+        }
+
         tunnel
             .shut_down()
             .await
@@ -511,6 +539,62 @@ impl Eventloop {
     }
 }
 
+/// The maximum size of the persisted DNS cache.
+///
+/// The snapshot itself is bounded but this protects us from reading arbitrarily large files into memory.
+const MAX_DNS_CACHE_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Loads a previously persisted DNS cache, falling back to an empty one.
+fn load_dns_cache(path: &Path) -> DnsCacheSnapshot {
+    let content = match read_at_most(path, MAX_DNS_CACHE_FILE_SIZE + 1) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return DnsCacheSnapshot::default(),
+        Err(e) => {
+            tracing::debug!(path = %path.display(), "Failed to read DNS cache: {e}");
+            return DnsCacheSnapshot::default();
+        }
+    };
+
+    if content.len() as u64 > MAX_DNS_CACHE_FILE_SIZE {
+        tracing::debug!(path = %path.display(), "Ignoring DNS cache that exceeds {MAX_DNS_CACHE_FILE_SIZE} bytes");
+        return DnsCacheSnapshot::default();
+    }
+
+    serde_json::from_slice(&content)
+        .inspect_err(|e| tracing::debug!(path = %path.display(), "Failed to parse DNS cache: {e}"))
+        .unwrap_or_default()
+}
+
+fn read_at_most(path: &Path, limit: u64) -> io::Result<Vec<u8>> {
+    use std::io::Read as _;
+
+    let mut content = Vec::new();
+    std::fs::File::open(path)?
+        .take(limit)
+        .read_to_end(&mut content)?;
+
+    Ok(content)
+}
+
+fn save_dns_cache(path: &Path, snapshot: &DnsCacheSnapshot) -> Result<()> {
+    let dir = path
+        .parent()
+        .context("DNS cache path should have a parent")?;
+    std::fs::create_dir_all(dir).context("Failed to create directory for DNS cache")?;
+
+    let content = serde_json::to_vec(snapshot).context("Failed to serialize DNS cache")?;
+    anyhow::ensure!(
+        content.len() as u64 <= MAX_DNS_CACHE_FILE_SIZE,
+        "DNS cache exceeds {MAX_DNS_CACHE_FILE_SIZE} bytes"
+    );
+
+    atomicwrites::AtomicFile::new(path, atomicwrites::OverwriteBehavior::AllowOverwrite)
+        .write(|f| io::Write::write_all(f, &content))
+        .context("Failed to write DNS cache")?;
+
+    Ok(())
+}
+
 async fn phoenix_channel_event_loop(
     mut portal: PhoenixChannel<(), EgressMessages, IngressMessages, PublicKeyParam>,
     param: PublicKeyParam,
@@ -8,7 +8,7 @@ use tunnel::messages::client::EgressMessages;
 pub use tunnel::messages::client::{IngressMessages, ResourceDescription};
 
 use anyhow::Result;
-use connlib_model::{ResourceId, ResourceView};
+use connlib_model::{GatewayView, ResourceId, ResourceView};
 use eventloop::{Command, Eventloop};
 use futures::future::Fuse;
 use futures::{FutureExt, StreamExt};
@@ -17,9 +17,11 @@ use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::collections::HashSet;
 use std::future;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::WatchStream;
@@ -72,6 +74,7 @@ impl Session {
         portal: PhoenixChannel<(), EgressMessages, IngressMessages, PublicKeyParam>,
         is_internet_resource_active: bool,
         dns_servers: Vec<IpAddr>,
+        dns_cache_path: Option<PathBuf>,
         handle: tokio::runtime::Handle,
     ) -> (Self, EventStream) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
@@ -82,6 +85,7 @@ impl Session {
                     udp_socket_factory,
                     is_internet_resource_active,
                     dns_servers,
+                    dns_cache_path,
                     portal,
                     cmd_rx,
                     resource_list_sender,
@@ -121,6 +125,16 @@ impl Session {
         let _ = self.channel.send(Command::SetInternetResourceState(active));
     }
 
+    /// Queries the status of our connections to Gateways.
+    ///
+    /// Resolves to an empty list if the session has already stopped.
+    pub fn gateways(&self) -> impl Future<Output = Vec<GatewayView>> + use<> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.channel.send(Command::GetGateways(tx));
+
+        async move { rx.await.unwrap_or_default() }
+    }
+
     /// Sets a new [`Tun`] device handle.
     pub fn set_tun(&self, new_tun: Box<dyn Tun>) {
         let _ = self.channel.send(Command::SetTun(new_tun));
@@ -7,8 +7,29 @@
 
 use anyhow::{Context as _, Result};
 
+/// Prefixes the given DNS message with its big-endian encoded length.
+pub fn encode(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&length_prefix(message));
+    framed.extend_from_slice(message);
+
+    framed
+}
+
+/// Attempts to decode a single length-prefixed DNS message from the start of `buffer`.
+///
+/// Returns the message together with the number of bytes it occupies in `buffer` (including the length prefix).
+/// Returns `None` if `buffer` does not yet contain a complete message.
+pub fn decode(buffer: &[u8]) -> Option<(&[u8], usize)> {
+    let (header, rest) = buffer.split_first_chunk::<2>()?;
+    let dns_message_length = u16::from_be_bytes(*header) as usize;
+    let message = rest.get(..dns_message_length)?;
+
+    Some((message, 2 + dns_message_length))
+}
+
 pub fn try_send(socket: &mut l3_tcp::Socket, message: &[u8]) -> Result<()> {
-    let dns_message_length = (message.len() as u16).to_be_bytes();
+    let dns_message_length = length_prefix(message);
 
     let written = socket
         .send_slice(&dns_message_length)
@@ -56,16 +77,11 @@ where
 {
     let maybe_message = socket
         .recv(|r| {
-            // DNS over TCP has a 2-byte length prefix at the start, see <https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2>.
-            let Some((header, message)) = r.split_first_chunk::<2>() else {
-                return (0, None);
-            };
-            let dns_message_length = u16::from_be_bytes(*header) as usize;
-            if message.len() < dns_message_length {
+            let Some((message, consumed)) = decode(r) else {
                 return (0, None); // Don't consume any bytes unless we can read the full message at once.
-            }
+            };
 
-            (2 + dns_message_length, Some(M::try_from(message)))
+            (consumed, Some(M::try_from(message)))
         })
         .context("Failed to recv TCP data")?
         .transpose()
@@ -92,3 +108,39 @@ where
 
     Ok(maybe_message)
 }
+
+fn length_prefix(message: &[u8]) -> [u8; 2] {
+    (message.len() as u16).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_roundtrips_encode() {
+        let framed = encode(b"hello");
+
+        assert_eq!(decode(&framed), Some((b"hello".as_slice(), 7)));
+    }
+
+    #[test]
+    fn decode_waits_for_complete_message() {
+        let framed = encode(b"hello");
+
+        assert_eq!(decode(&framed[..1]), None);
+        assert_eq!(decode(&framed[..6]), None);
+    }
+
+    #[test]
+    fn decode_only_consumes_first_message() {
+        let mut buffer = encode(b"foo");
+        buffer.extend(encode(b"bar"));
+
+        let (first, consumed) = decode(&buffer).unwrap();
+        let (second, _) = decode(&buffer[consumed..]).unwrap();
+
+        assert_eq!(first, b"foo");
+        assert_eq!(second, b"bar");
+    }
+}
@@ -1,6 +1,9 @@
+#![cfg_attr(test, allow(clippy::unwrap_used))]
+
 mod client;
 mod codec;
 mod server;
 
 pub use client::{Client, QueryResult};
+pub use codec::{decode, encode};
 pub use server::{Query, Server};
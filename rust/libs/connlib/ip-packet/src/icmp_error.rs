@@ -47,6 +47,20 @@ impl IcmpError {
         }
     }
 
+    /// Whether this error indicates that the destination cannot be reached.
+    ///
+    /// Errors that are part of path MTU discovery are not considered "unreachable".
+    pub fn is_unreachable(&self) -> bool {
+        use IcmpError::*;
+        use icmpv4::DestUnreachableHeader::*;
+
+        match self {
+            V4Unreachable(header) => !matches!(header, FragmentationNeeded { .. }),
+            V6Unreachable(_) => true,
+            V4TimeExceeded(_) | V6PacketTooBig { .. } | V6TimeExceeded(_) => false,
+        }
+    }
+
     pub fn is_unreachable_prohibited(&self) -> bool {
         use IcmpError::*;
         use icmpv4::DestUnreachableHeader::*;
@@ -5,6 +5,8 @@ pub mod make;
 mod fz_p2p_control;
 mod fz_p2p_control_slice;
 mod icmp_error;
+mod nat46;
+mod nat64;
 
 #[cfg(feature = "proptest")]
 #[allow(clippy::unwrap_used)]
@@ -272,6 +274,25 @@ impl IpPacket {
         })
     }
 
+    /// Assembles a new packet from the given parts and computes all checksums.
+    fn from_parts(parts: &[&[u8]]) -> Result<Self> {
+        let mut buf = IpPacketBuf::new();
+        let mut len = 0;
+
+        for part in parts {
+            buf.buf()
+                .get_mut(len..len + part.len())
+                .context("Packet exceeds buffer size")?
+                .copy_from_slice(part);
+            len += part.len();
+        }
+
+        let mut packet = Self::new(buf, len)?;
+        packet.update_checksum();
+
+        Ok(packet)
+    }
+
     pub fn version(&self) -> IpVersion {
         self.version
     }
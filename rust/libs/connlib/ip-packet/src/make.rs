@@ -189,6 +189,34 @@ pub fn icmp_dest_unreachable_network(original_packet: &IpPacket) -> Result<IpPac
     )
 }
 
+/// Creates an ICMP "fragmentation needed" (IPv4) or "packet too big" (IPv6) error for a packet that exceeds the given MTU.
+pub fn icmp_packet_too_big(original_packet: &IpPacket, mtu: u16) -> Result<IpPacket> {
+    let src = original_packet.source();
+    let dst = original_packet.destination();
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => icmpv4_error(
+            dst,
+            src,
+            original_packet,
+            crate::Icmpv4Type::DestinationUnreachable(
+                icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: mtu },
+            ),
+        ),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => icmpv6_error(
+            dst,
+            src,
+            original_packet,
+            crate::Icmpv6Type::PacketTooBig {
+                mtu: u32::from(mtu),
+            },
+        ),
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => {
+            bail!("Invalid IP packet: Inconsistent IP address versions")
+        }
+    }
+}
+
 fn icmp_dest_unreachable(
     original_packet: &IpPacket,
     icmpv4: icmpv4::DestUnreachableHeader,
@@ -221,8 +249,21 @@ fn icmpv4_unreachable(
     original_packet: &IpPacket,
     code: icmpv4::DestUnreachableHeader,
 ) -> Result<IpPacket, anyhow::Error> {
-    let builder = PacketBuilder::ipv4(src.octets(), dst.octets(), 20)
-        .icmpv4(crate::Icmpv4Type::DestinationUnreachable(code));
+    icmpv4_error(
+        src,
+        dst,
+        original_packet,
+        crate::Icmpv4Type::DestinationUnreachable(code),
+    )
+}
+
+fn icmpv4_error(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    original_packet: &IpPacket,
+    icmp_type: crate::Icmpv4Type,
+) -> Result<IpPacket, anyhow::Error> {
+    let builder = PacketBuilder::ipv4(src.octets(), dst.octets(), 20).icmpv4(icmp_type);
     let payload = original_packet.packet();
 
     let header_len = original_packet
@@ -244,11 +285,24 @@ fn icmpv6_unreachable(
     dst: Ipv6Addr,
     original_packet: &IpPacket,
     code: icmpv6::DestUnreachableCode,
+) -> Result<IpPacket, anyhow::Error> {
+    icmpv6_error(
+        src,
+        dst,
+        original_packet,
+        crate::Icmpv6Type::DestinationUnreachable(code),
+    )
+}
+
+fn icmpv6_error(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    original_packet: &IpPacket,
+    icmp_type: crate::Icmpv6Type,
 ) -> Result<IpPacket, anyhow::Error> {
     const MAX_ICMP_ERROR_PAYLOAD_LEN: usize = MAX_IP_SIZE - Ipv6Header::LEN - Icmpv6Header::MAX_LEN;
 
-    let builder = PacketBuilder::ipv6(src.octets(), dst.octets(), 20)
-        .icmpv6(crate::Icmpv6Type::DestinationUnreachable(code));
+    let builder = PacketBuilder::ipv6(src.octets(), dst.octets(), 20).icmpv6(icmp_type);
     let payload = original_packet.packet();
 
     let actual_payload_len = std::cmp::min(payload.len(), MAX_ICMP_ERROR_PAYLOAD_LEN);
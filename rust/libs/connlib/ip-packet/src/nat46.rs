@@ -0,0 +1,354 @@
+//! Translation of IPv4 packets to IPv6 as per [RFC 7915, section 4](https://www.rfc-editor.org/rfc/rfc7915#section-4).
+
+use std::borrow::Cow;
+use std::net::Ipv6Addr;
+
+use anyhow::{Context as _, Result, bail};
+use etherparse::{
+    Icmpv4Slice, Icmpv4Type, Icmpv6Header, Icmpv6Type, IpNumber, Ipv4HeaderSlice, Ipv6FlowLabel,
+    Ipv6Header, LaxIpv4Slice, icmpv4, icmpv6,
+};
+
+use crate::{ImpossibleTranslation, IpPacket, MAX_IP_SIZE};
+
+/// The maximum size of an IPv6 packet embedded in an ICMPv6 error.
+const MAX_EMBEDDED_PACKET_LEN: usize = MAX_IP_SIZE - Ipv6Header::LEN - Icmpv6Header::MAX_LEN;
+/// The minimum MTU of IPv6 links, see RFC 8200, section 5.
+const IPV6_MIN_MTU: u32 = 1280;
+
+impl IpPacket {
+    /// Translates this IPv4 packet to an IPv6 packet from `src` to `dst`.
+    ///
+    /// The translation is stateful: The IPv6 addresses are not derived from the IPv4 addresses and must be provided by the caller.
+    /// ICMP errors carry the packet that caused them.
+    /// That packet is translated as well, assuming that it was sent from `dst` to `src`.
+    pub fn consume_to_ipv6(self, src: Ipv6Addr, dst: Ipv6Addr) -> Result<IpPacket> {
+        let ipv4 = self.as_ipv4().context("Not an IPv4 packet")?;
+        // We don't add fragment headers (RFC 7915, section 4.1), thus we cannot translate fragments either.
+        anyhow::ensure!(!ipv4.is_payload_fragmented(), ImpossibleTranslation);
+
+        let next_header = translate_protocol(ipv4.payload_ip_number())?;
+
+        let l4 = match self.as_icmpv4() {
+            Some(icmpv4) => Cow::Owned(translate_icmp(&icmpv4, src, dst)?),
+            None => Cow::Borrowed(ipv4.payload().payload),
+        };
+
+        let header = translate_header(
+            ipv4.header(),
+            u16::try_from(l4.len())?,
+            next_header,
+            src,
+            dst,
+        );
+
+        IpPacket::from_parts(&[header.to_bytes().as_slice(), l4.as_ref()])
+    }
+}
+
+fn translate_header(
+    ipv4: Ipv4HeaderSlice<'_>,
+    payload_length: u16,
+    next_header: IpNumber,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+) -> Ipv6Header {
+    Ipv6Header {
+        traffic_class: (ipv4.dscp().value() << 2) | ipv4.ecn().value(),
+        flow_label: Ipv6FlowLabel::ZERO,
+        payload_length,
+        next_header,
+        hop_limit: ipv4.ttl(),
+        source: src.octets(),
+        destination: dst.octets(),
+    }
+}
+
+fn translate_protocol(protocol: IpNumber) -> Result<IpNumber> {
+    match protocol {
+        IpNumber::TCP | IpNumber::UDP => Ok(protocol),
+        IpNumber::ICMP => Ok(IpNumber::IPV6_ICMP),
+        _ => bail!(ImpossibleTranslation),
+    }
+}
+
+/// Translates an ICMPv4 message to ICMPv6, including its header.
+fn translate_icmp(icmpv4: &Icmpv4Slice<'_>, src: Ipv6Addr, dst: Ipv6Addr) -> Result<Vec<u8>> {
+    use icmpv4::DestUnreachableHeader as V4;
+    use icmpv6::DestUnreachableCode as V6;
+
+    #[expect(
+        clippy::wildcard_enum_match_arm,
+        reason = "All other ICMPv4 messages are dropped as per RFC 7915"
+    )]
+    let icmpv6_type = match icmpv4.icmp_type() {
+        Icmpv4Type::EchoRequest(echo) => {
+            return Ok(icmp_message(
+                Icmpv6Type::EchoRequest(echo),
+                icmpv4.payload(),
+            ));
+        }
+        Icmpv4Type::EchoReply(echo) => {
+            return Ok(icmp_message(Icmpv6Type::EchoReply(echo), icmpv4.payload()));
+        }
+        Icmpv4Type::DestinationUnreachable(header) => match header {
+            V4::Network
+            | V4::Host
+            | V4::SourceRouteFailed
+            | V4::NetworkUnknown
+            | V4::HostUnknown
+            | V4::Isolated
+            | V4::TosNetwork
+            | V4::TosHost => Icmpv6Type::DestinationUnreachable(V6::NoRoute),
+            V4::NetworkProhibited
+            | V4::HostProhibited
+            | V4::FilterProhibited
+            | V4::PrecedenceCutoff => Icmpv6Type::DestinationUnreachable(V6::Prohibited),
+            V4::Port => Icmpv6Type::DestinationUnreachable(V6::Port),
+            V4::Protocol => Icmpv6Type::ParameterProblem(icmpv6::ParameterProblemHeader {
+                code: icmpv6::ParameterProblemCode::UnrecognizedNextHeader,
+                pointer: 6, // Offset of the "Next Header" field.
+            }),
+            // IPv6 headers are 20 bytes larger than IPv4 headers.
+            // IPv6 hosts cannot go below 1280, which also covers routers that predate RFC 1191 and report an MTU of 0.
+            // Packets of up to 1280 bytes are translated without DF and can thus be fragmented on the IPv4 side.
+            V4::FragmentationNeeded { next_hop_mtu } => Icmpv6Type::PacketTooBig {
+                mtu: (u32::from(next_hop_mtu) + 20).max(IPV6_MIN_MTU),
+            },
+            V4::HostPrecedenceViolation => bail!(ImpossibleTranslation),
+        },
+        Icmpv4Type::TimeExceeded(code) => Icmpv6Type::TimeExceeded(match code {
+            icmpv4::TimeExceededCode::TtlExceededInTransit => {
+                icmpv6::TimeExceededCode::HopLimitExceeded
+            }
+            icmpv4::TimeExceededCode::FragmentReassemblyTimeExceeded => {
+                icmpv6::TimeExceededCode::FragmentReassemblyTimeExceeded
+            }
+        }),
+        _ => bail!(ImpossibleTranslation),
+    };
+
+    // The packet that caused the error was sent by the recipient of the error.
+    let embedded_packet = translate_embedded_packet(icmpv4.payload(), dst, src)?;
+
+    Ok(icmp_message(icmpv6_type, &embedded_packet))
+}
+
+fn icmp_message(icmp_type: Icmpv6Type, payload: &[u8]) -> Vec<u8> {
+    let mut message = Icmpv6Header::new(icmp_type).to_bytes().to_vec();
+    message.extend_from_slice(payload);
+
+    message
+}
+
+/// Translates the IPv4 packet embedded in an ICMPv4 error.
+///
+/// The embedded packet is typically truncated, meaning we cannot recompute its checksums.
+/// Hence, we only translate the IP header and the type of embedded ICMP messages.
+fn translate_embedded_packet(packet: &[u8], src: Ipv6Addr, dst: Ipv6Addr) -> Result<Vec<u8>> {
+    let (ipv4, _) = LaxIpv4Slice::from_slice(packet)
+        .context("Failed to parse payload of ICMPv4 error message as IPv4 packet")?;
+    anyhow::ensure!(!ipv4.payload().fragmented, ImpossibleTranslation);
+
+    let header = ipv4.header();
+    let payload = ipv4.payload().payload;
+
+    let next_header = translate_protocol(ipv4.payload().ip_number)?;
+    let payload_length = header
+        .total_len()
+        .saturating_sub(u16::from(header.ihl()) * 4);
+
+    let mut translated = Vec::with_capacity(Ipv6Header::LEN + payload.len());
+    translated.extend_from_slice(
+        &translate_header(header, payload_length, next_header, src, dst).to_bytes(),
+    );
+    translated.extend_from_slice(payload);
+
+    if next_header == IpNumber::IPV6_ICMP {
+        // Only echo requests can cause ICMP errors.
+        let icmp_type = translated
+            .get_mut(Ipv6Header::LEN)
+            .context("Embedded ICMPv4 message is empty")?;
+        anyhow::ensure!(
+            *icmp_type == icmpv4::TYPE_ECHO_REQUEST,
+            ImpossibleTranslation
+        );
+        *icmp_type = icmpv6::TYPE_ECHO_REQUEST;
+    }
+
+    // ICMPv6 errors must not exceed the minimum IPv6 MTU.
+    translated.truncate(MAX_EMBEDDED_PACKET_LEN);
+
+    Ok(translated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::Ecn;
+
+    const SRC_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const DST_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn translates_udp_packet() {
+        let packet = crate::make::udp_packet(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            1234,
+            53,
+            b"foobar".to_vec(),
+        )
+        .unwrap()
+        .with_ecn(Ecn::Ect0);
+
+        let translated = packet.consume_to_ipv6(SRC_V6, DST_V6).unwrap();
+
+        assert_eq!(translated.source(), IpAddr::V6(SRC_V6));
+        assert_eq!(translated.destination(), IpAddr::V6(DST_V6));
+        assert_eq!(translated.ecn(), Ecn::Ect0);
+
+        let udp = translated.as_udp().unwrap();
+        assert_eq!(udp.source_port(), 1234);
+        assert_eq!(udp.destination_port(), 53);
+        assert_eq!(udp.payload(), b"foobar");
+        assert_eq!(udp.checksum(), translated.calculate_udp_checksum().unwrap());
+    }
+
+    #[test]
+    fn translates_icmp_echo_reply() {
+        let packet = crate::make::icmp_reply_packet(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            Ipv4Addr::new(10, 0, 0, 2),
+            5,
+            42,
+            b"ping",
+        )
+        .unwrap();
+
+        let translated = packet.consume_to_ipv6(SRC_V6, DST_V6).unwrap();
+
+        let icmpv6 = translated.as_icmpv6().unwrap();
+        let Icmpv6Type::EchoReply(echo) = icmpv6.icmp_type() else {
+            panic!("Expected echo reply but got {:?}", icmpv6.icmp_type())
+        };
+        assert_eq!(echo.seq, 5);
+        assert_eq!(echo.id, 42);
+        assert_eq!(icmpv6.payload(), b"ping");
+    }
+
+    #[test]
+    fn translates_icmp_error_and_embedded_packet() {
+        let failed_packet = crate::make::udp_packet(
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            1234,
+            53,
+            b"foobar".to_vec(),
+        )
+        .unwrap();
+        let icmp_error = crate::make::icmp_packet_too_big(&failed_packet, 1400).unwrap();
+
+        let translated = icmp_error.consume_to_ipv6(SRC_V6, DST_V6).unwrap();
+
+        let (failed_packet, error) = translated.icmp_error().unwrap().unwrap();
+        assert_eq!(error, crate::IcmpError::V6PacketTooBig { mtu: 1420 });
+        assert_eq!(failed_packet.src(), IpAddr::V6(DST_V6));
+        assert_eq!(failed_packet.dst(), IpAddr::V6(SRC_V6));
+        assert_eq!(
+            failed_packet.layer4_protocol(),
+            crate::Layer4Protocol::Udp { src: 1234, dst: 53 }
+        );
+    }
+
+    #[test]
+    fn clamps_packet_too_big_to_ipv6_minimum_mtu() {
+        let failed_packet = crate::make::udp_packet(
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            1234,
+            53,
+            b"foobar".to_vec(),
+        )
+        .unwrap();
+
+        for next_hop_mtu in [0, 576, 1260] {
+            let icmp_error = icmpv4_error(
+                &failed_packet,
+                Icmpv4Type::DestinationUnreachable(
+                    icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu },
+                ),
+            )
+            .unwrap();
+
+            let translated = icmp_error.consume_to_ipv6(SRC_V6, DST_V6).unwrap();
+
+            let (_, error) = translated.icmp_error().unwrap().unwrap();
+            assert_eq!(error, crate::IcmpError::V6PacketTooBig { mtu: 1280 });
+        }
+    }
+
+    #[test]
+    fn drops_untranslatable_icmp_messages() {
+        let failed_packet = crate::make::udp_packet(
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            1234,
+            53,
+            Vec::new(),
+        )
+        .unwrap();
+        let icmp_error = icmpv4_error(
+            &failed_packet,
+            Icmpv4Type::DestinationUnreachable(
+                icmpv4::DestUnreachableHeader::HostPrecedenceViolation,
+            ),
+        )
+        .unwrap();
+
+        let error = icmp_error.consume_to_ipv6(SRC_V6, DST_V6).unwrap_err();
+
+        assert!(error.chain().any(|e| e.is::<ImpossibleTranslation>()));
+    }
+
+    #[test]
+    fn refuses_to_translate_icmp_error_for_fragment() {
+        let fragment_payload = [0u8; 8];
+        let mut failed_header = etherparse::Ipv4Header::new(
+            fragment_payload.len() as u16,
+            64,
+            IpNumber::UDP,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+        )
+        .unwrap();
+        failed_header.fragment_offset = etherparse::IpFragOffset::try_new(1).unwrap();
+        failed_header.header_checksum = failed_header.calc_header_checksum();
+
+        let mut failed_packet = failed_header.to_bytes().to_vec();
+        failed_packet.extend_from_slice(&fragment_payload);
+
+        let mut icmp_error = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .icmpv4(Icmpv4Type::DestinationUnreachable(
+                icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: 1280 },
+            ))
+            .write(&mut icmp_error, &failed_packet)
+            .unwrap();
+        let icmp_error = IpPacket::from_parts(&[&icmp_error]).unwrap();
+
+        let error = icmp_error.consume_to_ipv6(SRC_V6, DST_V6).unwrap_err();
+
+        assert!(error.chain().any(|e| e.is::<ImpossibleTranslation>()));
+    }
+
+    fn icmpv4_error(failed_packet: &IpPacket, icmp_type: Icmpv4Type) -> Result<IpPacket> {
+        let builder =
+            etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).icmpv4(icmp_type);
+        let payload = failed_packet.packet();
+
+        crate::build!(builder, payload)
+    }
+}
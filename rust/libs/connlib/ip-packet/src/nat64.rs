@@ -0,0 +1,355 @@
+//! Translation of IPv6 packets to IPv4 as per [RFC 7915, section 5](https://www.rfc-editor.org/rfc/rfc7915#section-5).
+
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+
+use anyhow::{Context as _, Result, bail};
+use etherparse::{
+    Icmpv4Header, Icmpv4Type, Icmpv6Slice, Icmpv6Type, IpDscp, IpEcn, IpNumber, Ipv4Header,
+    Ipv6HeaderSlice, LaxIpv6Slice, icmpv4, icmpv6,
+};
+
+use crate::{ImpossibleTranslation, IpPacket};
+
+/// Translated packets up to this size are sent without the DF bit.
+///
+/// That is the IPv6 minimum MTU of 1280 minus the 20 bytes the IPv6 header is larger than the IPv4 header.
+const MAX_UNFRAGMENTABLE_IPV4_SIZE: usize = 1260;
+
+impl IpPacket {
+    /// Translates this IPv6 packet to an IPv4 packet from `src` to `dst`.
+    ///
+    /// The translation is stateful: The IPv4 addresses are not derived from the IPv6 addresses and must be provided by the caller.
+    /// ICMP errors carry the packet that caused them.
+    /// That packet is translated as well, assuming that it was sent from `dst` to `src`.
+    pub fn consume_to_ipv4(self, src: Ipv4Addr, dst: Ipv4Addr) -> Result<IpPacket> {
+        let ipv6 = self.as_ipv6().context("Not an IPv6 packet")?;
+        // We don't translate fragment headers (RFC 7915, section 5.1), thus we cannot translate fragments either.
+        anyhow::ensure!(!ipv6.is_payload_fragmented(), ImpossibleTranslation);
+
+        let protocol = translate_next_header(ipv6.payload().ip_number)?;
+
+        let l4 = match self.as_icmpv6() {
+            Some(icmpv6) => Cow::Owned(translate_icmp(&icmpv6, src, dst)?),
+            None => Cow::Borrowed(ipv6.payload().payload),
+        };
+
+        let header = translate_header(ipv6.header(), u16::try_from(l4.len())?, protocol, src, dst)?;
+
+        IpPacket::from_parts(&[header.to_bytes().as_slice(), l4.as_ref()])
+    }
+}
+
+fn translate_header(
+    ipv6: Ipv6HeaderSlice<'_>,
+    payload_len: u16,
+    protocol: IpNumber,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+) -> Result<Ipv4Header> {
+    let traffic_class = ipv6.traffic_class();
+
+    let mut header = Ipv4Header::new(
+        payload_len,
+        ipv6.hop_limit(),
+        protocol,
+        src.octets(),
+        dst.octets(),
+    )?;
+    header.dscp = IpDscp::try_new(traffic_class >> 2)?;
+    header.ecn = IpEcn::try_new(traffic_class & 0b11)?;
+    // Small packets may be fragmented on the IPv4 side because IPv6 hosts never send less than 1280 bytes, see RFC 7915, section 5.1.
+    header.dont_fragment = usize::from(header.total_len()) > MAX_UNFRAGMENTABLE_IPV4_SIZE;
+    header.header_checksum = header.calc_header_checksum();
+
+    Ok(header)
+}
+
+fn translate_next_header(next_header: IpNumber) -> Result<IpNumber> {
+    match next_header {
+        IpNumber::TCP | IpNumber::UDP => Ok(next_header),
+        IpNumber::IPV6_ICMP => Ok(IpNumber::ICMP),
+        _ => bail!(ImpossibleTranslation),
+    }
+}
+
+/// Translates an ICMPv6 message to ICMPv4, including its header.
+fn translate_icmp(icmpv6: &Icmpv6Slice<'_>, src: Ipv4Addr, dst: Ipv4Addr) -> Result<Vec<u8>> {
+    use icmpv4::DestUnreachableHeader as V4;
+    use icmpv6::DestUnreachableCode as V6;
+
+    #[expect(
+        clippy::wildcard_enum_match_arm,
+        reason = "All other ICMPv6 messages are dropped as per RFC 7915"
+    )]
+    let icmpv4_type = match icmpv6.icmp_type() {
+        Icmpv6Type::EchoRequest(echo) => {
+            return Ok(icmp_message(
+                Icmpv4Type::EchoRequest(echo),
+                icmpv6.payload(),
+            ));
+        }
+        Icmpv6Type::EchoReply(echo) => {
+            return Ok(icmp_message(Icmpv4Type::EchoReply(echo), icmpv6.payload()));
+        }
+        Icmpv6Type::DestinationUnreachable(code) => {
+            Icmpv4Type::DestinationUnreachable(match code {
+                V6::NoRoute | V6::BeyondScope | V6::Address => V4::Host,
+                V6::Prohibited => V4::HostProhibited,
+                V6::Port => V4::Port,
+                V6::SourceAddressFailedPolicy | V6::RejectRoute => bail!(ImpossibleTranslation),
+            })
+        }
+        Icmpv6Type::PacketTooBig { mtu } => {
+            Icmpv4Type::DestinationUnreachable(V4::FragmentationNeeded {
+                // IPv4 headers are 20 bytes smaller than IPv6 headers.
+                next_hop_mtu: u16::try_from(mtu.saturating_sub(20)).unwrap_or(u16::MAX),
+            })
+        }
+        Icmpv6Type::TimeExceeded(code) => Icmpv4Type::TimeExceeded(match code {
+            icmpv6::TimeExceededCode::HopLimitExceeded => {
+                icmpv4::TimeExceededCode::TtlExceededInTransit
+            }
+            icmpv6::TimeExceededCode::FragmentReassemblyTimeExceeded => {
+                icmpv4::TimeExceededCode::FragmentReassemblyTimeExceeded
+            }
+        }),
+        Icmpv6Type::ParameterProblem(header)
+            if header.code == icmpv6::ParameterProblemCode::UnrecognizedNextHeader =>
+        {
+            Icmpv4Type::DestinationUnreachable(V4::Protocol)
+        }
+        _ => bail!(ImpossibleTranslation),
+    };
+
+    // The packet that caused the error was sent by the recipient of the error.
+    let embedded_packet = translate_embedded_packet(icmpv6.payload(), dst, src)?;
+
+    Ok(icmp_message(icmpv4_type, &embedded_packet))
+}
+
+fn icmp_message(icmp_type: Icmpv4Type, payload: &[u8]) -> Vec<u8> {
+    let mut message = Icmpv4Header::new(icmp_type).to_bytes().to_vec();
+    message.extend_from_slice(payload);
+
+    message
+}
+
+/// Translates the IPv6 packet embedded in an ICMPv6 error.
+///
+/// The embedded packet is typically truncated, meaning we cannot recompute its checksums.
+/// Hence, we only translate the IP header and the type of embedded ICMP messages.
+fn translate_embedded_packet(packet: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> Result<Vec<u8>> {
+    let (ipv6, _) = LaxIpv6Slice::from_slice(packet)
+        .context("Failed to parse payload of ICMPv6 error message as IPv6 packet")?;
+    anyhow::ensure!(!ipv6.payload().fragmented, ImpossibleTranslation);
+
+    let header = ipv6.header();
+    let payload = ipv6.payload().payload;
+
+    let protocol = translate_next_header(ipv6.payload().ip_number)?;
+    let extensions_len = u16::try_from(ipv6.extensions().slice().len())?;
+    let payload_len = header.payload_length().saturating_sub(extensions_len);
+
+    let translated_header = translate_header(header, payload_len, protocol, src, dst)?;
+
+    let mut translated = translated_header.to_bytes().to_vec();
+    let header_len = translated.len();
+    translated.extend_from_slice(payload);
+
+    if protocol == IpNumber::ICMP {
+        // Only echo requests can cause ICMP errors.
+        let icmp_type = translated
+            .get_mut(header_len)
+            .context("Embedded ICMPv6 message is empty")?;
+        anyhow::ensure!(
+            *icmp_type == icmpv6::TYPE_ECHO_REQUEST,
+            ImpossibleTranslation
+        );
+        *icmp_type = icmpv4::TYPE_ECHO_REQUEST;
+    }
+
+    Ok(translated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use super::*;
+    use crate::Ecn;
+
+    const SRC_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DST_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    #[test]
+    fn translates_tcp_packet() {
+        let packet = crate::make::tcp_packet(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            1234,
+            443,
+            crate::make::TcpFlags::default(),
+            b"foobar".to_vec(),
+        )
+        .unwrap()
+        .with_ecn(Ecn::Ect1);
+
+        let translated = packet.consume_to_ipv4(SRC_V4, DST_V4).unwrap();
+
+        assert_eq!(translated.source(), IpAddr::V4(SRC_V4));
+        assert_eq!(translated.destination(), IpAddr::V4(DST_V4));
+        assert_eq!(translated.ecn(), Ecn::Ect1);
+
+        let ipv4_header = translated.ipv4_header().unwrap();
+        assert!(!ipv4_header.dont_fragment);
+        assert_eq!(
+            ipv4_header.header_checksum,
+            ipv4_header.calc_header_checksum()
+        );
+
+        let tcp = translated.as_tcp().unwrap();
+        assert_eq!(tcp.source_port(), 1234);
+        assert_eq!(tcp.destination_port(), 443);
+        assert_eq!(tcp.payload(), b"foobar");
+        assert_eq!(tcp.checksum(), translated.calculate_tcp_checksum().unwrap());
+    }
+
+    #[test]
+    fn clears_dont_fragment_for_packets_up_to_1260_bytes() {
+        let packet = crate::make::udp_packet(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            1234,
+            53,
+            vec![0; crate::MAX_IP_SIZE - 40 - 8],
+        )
+        .unwrap();
+
+        let translated = packet.consume_to_ipv4(SRC_V4, DST_V4).unwrap();
+
+        let ipv4_header = translated.ipv4_header().unwrap();
+        assert_eq!(usize::from(ipv4_header.total_len()), 1260);
+        assert!(!ipv4_header.dont_fragment);
+        assert_eq!(
+            ipv4_header.header_checksum,
+            ipv4_header.calc_header_checksum()
+        );
+    }
+
+    #[test]
+    fn translates_icmp_echo_request() {
+        let packet = crate::make::icmp_request_packet(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            5,
+            42,
+            b"ping",
+        )
+        .unwrap();
+
+        let translated = packet.consume_to_ipv4(SRC_V4, DST_V4).unwrap();
+
+        let icmpv4 = translated.as_icmpv4().unwrap();
+        let Icmpv4Type::EchoRequest(echo) = icmpv4.icmp_type() else {
+            panic!("Expected echo request but got {:?}", icmpv4.icmp_type())
+        };
+        assert_eq!(echo.seq, 5);
+        assert_eq!(echo.id, 42);
+        assert_eq!(icmpv4.payload(), b"ping");
+        assert_eq!(
+            icmpv4.checksum(),
+            icmpv4.icmp_type().calc_checksum(icmpv4.payload())
+        );
+    }
+
+    #[test]
+    fn refuses_to_translate_icmp_error_for_fragment() {
+        let src = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let dst = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+        let fragment_header = etherparse::Ipv6FragmentHeader::new(
+            IpNumber::UDP,
+            etherparse::IpFragOffset::try_new(1).unwrap(),
+            false,
+            42,
+        );
+        let fragment_payload = [0u8; 8];
+        let mut failed_packet = etherparse::Ipv6Header {
+            payload_length: (etherparse::Ipv6FragmentHeader::LEN + fragment_payload.len()) as u16,
+            next_header: IpNumber::IPV6_FRAGMENTATION_HEADER,
+            hop_limit: 64,
+            source: dst.octets(),
+            destination: src.octets(),
+            ..Default::default()
+        }
+        .to_bytes()
+        .to_vec();
+        failed_packet.extend_from_slice(&fragment_header.to_bytes());
+        failed_packet.extend_from_slice(&fragment_payload);
+
+        let mut icmp_error = Vec::new();
+        etherparse::PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+            .icmpv6(Icmpv6Type::PacketTooBig { mtu: 1280 })
+            .write(&mut icmp_error, &failed_packet)
+            .unwrap();
+        let icmp_error = IpPacket::from_parts(&[&icmp_error]).unwrap();
+
+        let error = icmp_error.consume_to_ipv4(SRC_V4, DST_V4).unwrap_err();
+
+        assert!(error.chain().any(|e| e.is::<ImpossibleTranslation>()));
+    }
+
+    #[test]
+    fn translates_icmp_error_and_embedded_packet() {
+        let failed_packet = crate::make::icmp_request_packet(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            5,
+            42,
+            b"ping",
+        )
+        .unwrap();
+        let icmp_error = crate::make::icmp_packet_too_big(&failed_packet, 1280).unwrap();
+
+        let translated = icmp_error.consume_to_ipv4(SRC_V4, DST_V4).unwrap();
+
+        let (failed_packet, error) = translated.icmp_error().unwrap().unwrap();
+        assert_eq!(
+            error,
+            crate::IcmpError::V4Unreachable(icmpv4::DestUnreachableHeader::FragmentationNeeded {
+                next_hop_mtu: 1260
+            })
+        );
+        assert_eq!(failed_packet.src(), IpAddr::V4(DST_V4));
+        assert_eq!(failed_packet.dst(), IpAddr::V4(SRC_V4));
+        assert_eq!(
+            failed_packet.layer4_protocol(),
+            crate::Layer4Protocol::Icmp { seq: 5, id: 42 }
+        );
+    }
+
+    #[test]
+    fn round_trip_preserves_packet() {
+        let packet = crate::make::udp_packet(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            1234,
+            53,
+            b"foobar".to_vec(),
+        )
+        .unwrap();
+
+        let round_tripped = packet
+            .clone()
+            .consume_to_ipv4(SRC_V4, DST_V4)
+            .unwrap()
+            .consume_to_ipv6(
+                Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+                Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            )
+            .unwrap();
+
+        assert_eq!(round_tripped.packet(), packet.packet());
+    }
+}
@@ -21,9 +21,14 @@ pub fn create_tcp_socket() -> Socket<'static> {
     /// In practice, this allows the OS to queue multiple queries even if we can't immediately process them.
     const MAX_TCP_DNS_MSG_LENGTH: usize = u16::MAX as usize;
 
+    create_tcp_socket_with_buffer_size(MAX_TCP_DNS_MSG_LENGTH)
+}
+
+/// Creates a TCP socket whose send and receive buffers can each hold `size` bytes.
+pub fn create_tcp_socket_with_buffer_size(size: usize) -> Socket<'static> {
     Socket::new(
-        smoltcp::storage::RingBuffer::new(vec![0u8; MAX_TCP_DNS_MSG_LENGTH]),
-        smoltcp::storage::RingBuffer::new(vec![0u8; MAX_TCP_DNS_MSG_LENGTH]),
+        smoltcp::storage::RingBuffer::new(vec![0u8; size]),
+        smoltcp::storage::RingBuffer::new(vec![0u8; size]),
     )
 }
 
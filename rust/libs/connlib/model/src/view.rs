@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::Debug;
 
+use crate::GatewayId;
 use crate::ResourceId;
 use crate::Site;
 
@@ -12,6 +13,8 @@ pub enum ResourceStatus {
     Unknown,
     Online,
     Offline,
+    /// The site is online but the Gateway reported that the resource or some of its domains are not healthy.
+    Degraded,
 }
 
 impl fmt::Display for ResourceStatus {
@@ -20,6 +23,7 @@ impl fmt::Display for ResourceStatus {
             ResourceStatus::Unknown => write!(f, "unknown"),
             ResourceStatus::Online => write!(f, "online"),
             ResourceStatus::Offline => write!(f, "offline"),
+            ResourceStatus::Degraded => write!(f, "degraded"),
         }
     }
 }
@@ -134,6 +138,37 @@ pub struct InternetResourceView {
     pub status: ResourceStatus,
 }
 
+/// Our connection to a Gateway.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GatewayView {
+    pub id: GatewayId,
+    pub status: GatewayStatus,
+    /// Whether packets to this Gateway are sent via a relay.
+    pub relayed: bool,
+    /// The resources we are currently accessing through this Gateway.
+    pub resources: Vec<ResourceId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GatewayStatus {
+    Connecting,
+    Connected,
+    Idle,
+    Failed,
+}
+
+impl fmt::Display for GatewayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatewayStatus::Connecting => write!(f, "connecting"),
+            GatewayStatus::Connected => write!(f, "connected"),
+            GatewayStatus::Idle => write!(f, "idle"),
+            GatewayStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
 impl PartialOrd for ResourceView {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
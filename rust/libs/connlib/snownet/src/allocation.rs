@@ -30,12 +30,13 @@ use stun_codec::{
         attributes::{
             ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
         },
-        errors::AllocationMismatch,
+        errors::{AllocationMismatch, InsufficientCapacity},
         methods::{ALLOCATE, CHANNEL_BIND, REFRESH},
     },
     rfc8656::attributes::AdditionalAddressFamily,
 };
 use tracing::{Span, field};
+use turn_wire::RelayLoad;
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 const REQUEST_MAX_ELAPSED: Duration = Duration::from_secs(8);
@@ -54,6 +55,10 @@ const BINDING_INTERVAL: Duration = Duration::from_secs(25);
 pub struct Allocation {
     /// The known sockets of the relay.
     server: RelaySocket,
+    /// The transport we use to talk to the relay.
+    ///
+    /// We start with UDP and fall back to TCP and then TLS if the relay doesn't respond to our BINDING requests.
+    transport: Transport,
     /// The socket we have chosen to use to communicate with the relay.
     ///
     /// A relay may be reachable on IPv4, IPv6 or both.
@@ -84,6 +89,11 @@ pub struct Allocation {
     /// When we received the allocation and how long it is valid.
     allocation_lifetime: Option<(Instant, Duration)>,
 
+    /// The load most recently advertised by the relay.
+    load: Option<RelayLoad>,
+    /// The smoothed round-trip time of our requests to the relay.
+    rtt: Option<Duration>,
+
     buffered_transmits: VecDeque<Transmit>,
     events: VecDeque<Event>,
 
@@ -120,6 +130,32 @@ struct Credentials {
     nonce: Option<Nonce>,
 }
 
+/// The transport used to communicate with a relay.
+///
+/// Regardless of the transport, [`Transmit`]s to a relay are always addressed to one of its [`RelaySocket`]s.
+/// For [`Transport::Tls`], the connection must be made to [`RELAY_TLS_PORT`](crate::stream::RELAY_TLS_PORT) on the same IP instead.
+/// Messages sent via [`Transport::Tcp`] or [`Transport::Tls`] need to be framed using [`crate::stream`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, derive_more::Display)]
+pub enum Transport {
+    #[display("UDP")]
+    Udp,
+    #[display("TCP")]
+    Tcp,
+    #[display("TLS")]
+    Tls,
+}
+
+impl Transport {
+    /// The transport to fall back to if the relay is unreachable via this one.
+    fn fallback(&self) -> Option<Self> {
+        match self {
+            Transport::Udp => Some(Transport::Tcp),
+            Transport::Tcp => Some(Transport::Tls),
+            Transport::Tls => None,
+        }
+    }
+}
+
 /// Describes the socket address(es) we know about the relay.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum RelaySocket {
@@ -211,6 +247,8 @@ pub enum FreeReason {
     NoResponseReceived,
     #[error("TURN protocol failure")]
     ProtocolFailure,
+    #[error("relay has insufficient capacity")]
+    InsufficientCapacity,
 }
 
 impl Allocation {
@@ -225,6 +263,7 @@ impl Allocation {
     ) -> Self {
         let mut allocation = Self {
             server,
+            transport: Transport::Udp,
             active_socket: None,
             ip4_host_candidate: Default::default(),
             ip6_host_candidate: Default::default(),
@@ -242,6 +281,8 @@ impl Allocation {
                 nonce: Default::default(),
             }),
             allocation_lifetime: Default::default(),
+            load: Default::default(),
+            rtt: Default::default(),
             channel_bindings: Default::default(),
             buffered_channel_bindings: AllocRingBuffer::new(100),
             software: Software::new(format!("snownet; session={session_id}"))
@@ -286,6 +327,7 @@ impl Allocation {
             tracing::debug!("Attempting to make a new allocation");
 
             self.active_socket = None;
+            self.transport = Transport::Udp; // UDP may have become available in the meantime.
             self.send_binding_requests(now);
             return;
         }
@@ -359,6 +401,7 @@ impl Allocation {
 
         let rtt = now.duration_since(backoff.start_time());
         Span::current().record("rtt", field::debug(rtt));
+        self.update_rtt(rtt);
 
         if tracing::enabled!(target: "wire::turn", tracing::Level::DEBUG) {
             let request = original_request
@@ -478,6 +521,13 @@ impl Allocation {
             match message.method() {
                 ALLOCATE => {
                     self.buffered_channel_bindings.clear();
+
+                    // The relay is full or draining, free the allocation so we use a different relay.
+                    if error.code() == InsufficientCapacity::CODEPOINT {
+                        self.explicit_failure = Some(FreeReason::InsufficientCapacity);
+
+                        return true;
+                    }
                 }
                 CHANNEL_BIND => {
                     let Some(channel) = original_request
@@ -520,28 +570,37 @@ impl Allocation {
             "Method of response should match the one from our request"
         );
 
+        if let Some(load) = message.get_attribute::<RelayLoad>() {
+            self.load = Some(*load);
+        }
+
         match message.method() {
             BINDING => {
-                // First, see if we need to update our host candidate.
-                let current_host_candidate = match local {
-                    SocketAddr::V4(_) => &mut self.ip4_host_candidate,
-                    SocketAddr::V6(_) => &mut self.ip6_host_candidate,
-                };
+                // Candidates observed via TCP or TLS are useless for ICE, those can only be UDP.
+                if self.transport == Transport::Udp {
+                    // First, see if we need to update our host candidate.
+                    let current_host_candidate = match local {
+                        SocketAddr::V4(_) => &mut self.ip4_host_candidate,
+                        SocketAddr::V6(_) => &mut self.ip6_host_candidate,
+                    };
 
-                let maybe_candidate = Candidate::host(local, Protocol::Udp).ok();
-                if update_candidate(maybe_candidate, current_host_candidate, &mut self.events) {
-                    self.log_update(now);
-                }
+                    let maybe_candidate = Candidate::host(local, Protocol::Udp).ok();
+                    if update_candidate(maybe_candidate, current_host_candidate, &mut self.events) {
+                        self.log_update(now);
+                    }
 
-                // Second, process the binding request itself.
-                let current_srflx_candidate = match original_dst {
-                    SocketAddr::V4(_) => &mut self.ip4_srflx_candidate,
-                    SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
-                };
+                    // Second, process the binding request itself.
+                    let current_srflx_candidate = match original_dst {
+                        SocketAddr::V4(_) => &mut self.ip4_srflx_candidate,
+                        SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
+                    };
 
-                let maybe_candidate = message.attributes().find_map(|a| srflx_candidate(local, a));
-                if update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events) {
-                    self.log_update(now);
+                    let maybe_candidate =
+                        message.attributes().find_map(|a| srflx_candidate(local, a));
+                    if update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events)
+                    {
+                        self.log_update(now);
+                    }
                 }
 
                 // Third, check if we have already determined which socket to use for this relay.
@@ -562,7 +621,7 @@ impl Allocation {
                 // If the socket isn't set yet, use the `original_dst` as the primary socket.
                 self.active_socket = Some(ActiveSocket::new(original_dst, now));
 
-                tracing::debug!(active_socket = %original_dst, "Updating active socket");
+                tracing::debug!(active_socket = %original_dst, transport = %self.transport, "Updating active socket");
 
                 if self.has_allocation() {
                     self.authenticate_and_queue(
@@ -747,6 +806,15 @@ impl Allocation {
                 self.active_socket = None; // The socket seems to no longer be reachable.
                 self.invalidate_allocation();
             }
+
+            // If none of our BINDING requests got a response, the relay may be unreachable via the current transport.
+            if !queued
+                && method == BINDING
+                && self.active_socket.is_none()
+                && !self.binding_in_flight()
+            {
+                self.fall_back_to_next_transport(now);
+            }
         }
 
         for (_, _, backoff) in self.sent_requests.values_mut() {
@@ -868,6 +936,7 @@ impl Allocation {
         now: Instant,
     ) -> Option<EncodeOk> {
         let active_socket = self.active_socket?.addr;
+        let transport = self.transport;
         let payload_length = buffer.len() - 4;
 
         let connected_channel_to_peer = self.channel_bindings.connected_channel_to_peer(peer, now);
@@ -892,6 +961,7 @@ impl Allocation {
 
         Some(EncodeOk {
             socket: active_socket,
+            transport,
         })
     }
 
@@ -994,6 +1064,24 @@ impl Allocation {
         self.server
     }
 
+    /// The load most recently advertised by the relay, if it supports advertising it.
+    pub fn load(&self) -> Option<RelayLoad> {
+        self.load
+    }
+
+    /// The smoothed round-trip time to the relay, if we received any responses yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Smoothes the round-trip time in the same way TCP does, see <https://www.rfc-editor.org/rfc/rfc6298#section-2>.
+    fn update_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
     pub fn ip4_socket(&self) -> Option<Socket> {
         let address = self.ip4_allocation.as_ref().map(|c| c.addr())?;
 
@@ -1044,6 +1132,12 @@ impl Allocation {
             .any(|buffered| buffered == peer)
     }
 
+    fn binding_in_flight(&self) -> bool {
+        self.sent_requests
+            .values()
+            .any(|(_, r, _)| r.method() == BINDING)
+    }
+
     fn allocate_in_flight(&self) -> bool {
         self.sent_requests
             .values()
@@ -1089,6 +1183,18 @@ impl Allocation {
         }
     }
 
+    fn fall_back_to_next_transport(&mut self, now: Instant) {
+        let Some(fallback) = self.transport.fallback() else {
+            tracing::debug!(relay_socket = ?self.server, "Relay is unreachable on all transports");
+            return;
+        };
+
+        tracing::info!(relay_socket = ?self.server, "Relay did not respond via {}, falling back to {fallback}", self.transport);
+
+        self.transport = fallback;
+        self.send_binding_requests(now);
+    }
+
     /// Returns: Whether we actually queued a message.
     fn authenticate_and_queue(
         &mut self,
@@ -1140,6 +1246,7 @@ impl Allocation {
             dst,
             payload: self.buffer_pool.pull_initialised(&encode(message)),
             ecn: Ecn::NonEct,
+            transport: self.transport,
         });
 
         true
@@ -1177,6 +1284,7 @@ impl Allocation {
 
 pub struct EncodeOk {
     pub socket: SocketAddr,
+    pub transport: Transport,
 }
 
 impl ActiveSocket {
@@ -1399,7 +1507,8 @@ stun_codec::define_attribute_enums!(
         XorPeerAddress,
         ChannelNumber,
         Lifetime,
-        Software
+        Software,
+        RelayLoad
     ]
 );
 
@@ -1651,6 +1760,7 @@ fn display_attr(attr: &Attribute) -> String {
         Attribute::ChannelNumber(inner) => format!("{inner:?}"),
         Attribute::Lifetime(inner) => format!("{inner:?}"),
         Attribute::Software(inner) => format!("Software({})", inner.description()),
+        Attribute::RelayLoad(inner) => format!("{inner:?}"),
     }
 }
 
@@ -2027,16 +2137,28 @@ mod tests {
         let start = Instant::now();
         let mut allocation = Allocation::for_test_ip4(start);
 
-        let mut expected_backoffs = VecDeque::from(backoff::steps(start));
+        let udp_given_up = backoff::steps(start)[3];
+        let tcp_given_up = backoff::steps(udp_given_up)[3];
+
+        let mut expected_backoffs = VecDeque::from_iter(
+            iter::empty()
+                .chain(backoff::steps(start).map(|t| (t, Transport::Udp)))
+                .chain(backoff::steps(udp_given_up).map(|t| (t, Transport::Tcp)))
+                .chain(backoff::steps(tcp_given_up).map(|t| (t, Transport::Tls))),
+        );
 
         loop {
             let Some((timeout, _)) = allocation.poll_timeout() else {
                 break;
             };
 
-            assert_eq!(expected_backoffs.pop_front().unwrap(), timeout);
+            let (expected_timeout, expected_transport) = expected_backoffs.pop_front().unwrap();
+            assert_eq!(expected_timeout, timeout);
 
-            assert!(allocation.poll_transmit().is_some());
+            assert_eq!(
+                allocation.poll_transmit().unwrap().transport,
+                expected_transport
+            );
             assert!(allocation.poll_transmit().is_none());
 
             allocation.handle_timeout(timeout);
@@ -2045,6 +2167,66 @@ mod tests {
         assert!(expected_backoffs.is_empty())
     }
 
+    #[test]
+    fn falls_back_to_tcp_if_udp_binding_requests_time_out() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start);
+
+        let udp_given_up = backoff::steps(start)[3];
+        allocation.advance_to(udp_given_up);
+
+        let transmit = allocation.poll_transmit().unwrap();
+        assert_eq!(transmit.transport, Transport::Tcp);
+        assert_eq!(transmit.dst, SocketAddr::from(RELAY_V4));
+        assert_eq!(
+            decode(&transmit.payload).unwrap().unwrap().method(),
+            BINDING
+        );
+    }
+
+    #[test]
+    fn allocates_via_fallback_transport_without_host_and_srflx_candidates() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start);
+
+        let udp_given_up = backoff::steps(start)[3];
+        allocation.advance_to(udp_given_up);
+
+        let mut allocation = allocation
+            .with_binding_response(PEER1, udp_given_up)
+            .with_allocate_response(&[RELAY_ADDR_IP4], udp_given_up);
+
+        assert_eq!(allocation.host_and_server_reflexive_candidates().count(), 0);
+        assert_eq!(
+            allocation.current_relay_candidates().collect::<Vec<_>>(),
+            vec![Candidate::relayed(RELAY_ADDR_IP4, PEER1, Protocol::Udp).unwrap()]
+        );
+
+        allocation.bind_channel(PEER2_IP4, udp_given_up);
+        assert_eq!(
+            allocation.poll_transmit().unwrap().transport,
+            Transport::Tcp
+        );
+    }
+
+    #[test]
+    fn refreshing_suspended_allocation_starts_over_with_udp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start);
+
+        while let Some((timeout, _)) = allocation.poll_timeout() {
+            allocation.handle_timeout(timeout);
+        }
+        while allocation.poll_transmit().is_some() {}
+
+        allocation.refresh(Instant::now());
+
+        assert_eq!(
+            allocation.poll_transmit().unwrap().transport,
+            Transport::Udp
+        );
+    }
+
     #[test]
     fn given_no_ip6_allocation_does_not_attempt_to_bind_channel_to_ip6_address() {
         let mut allocation = Allocation::for_test_ip4(Instant::now())
@@ -2376,6 +2558,20 @@ mod tests {
         assert_eq!(next_msg.method(), ALLOCATE)
     }
 
+    #[test]
+    fn allocation_is_freed_if_relay_has_insufficient_capacity() {
+        let mut allocation =
+            Allocation::for_test_ip4(Instant::now()).with_binding_response(PEER1, Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(insufficient_capacity(&allocate), Instant::now());
+
+        assert_eq!(
+            allocation.can_be_freed(),
+            Some(FreeReason::InsufficientCapacity)
+        );
+    }
+
     #[test]
     fn failed_allocation_clears_buffered_channel_bindings() {
         let mut allocation =
@@ -2851,6 +3047,17 @@ mod tests {
         message
     }
 
+    fn insufficient_capacity(request: &Message<Attribute>) -> Message<Attribute> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+        message
+    }
+
     fn stale_nonce_response(request: &Message<Attribute>, nonce: Nonce) -> Message<Attribute> {
         let mut message = Message::new(
             MessageClass::ErrorResponse,
@@ -2940,6 +3147,15 @@ mod tests {
             self
         }
 
+        /// Lets all pending requests time out until `now`, discarding all transmits on the way.
+        fn advance_to(&mut self, now: Instant) {
+            while let Some((timeout, _)) = self.poll_timeout().filter(|(t, _)| *t <= now) {
+                while self.poll_transmit().is_some() {}
+
+                self.handle_timeout(timeout);
+            }
+        }
+
         fn next_message(&mut self) -> Option<Message<Attribute>> {
             let transmit = self.poll_transmit()?;
 
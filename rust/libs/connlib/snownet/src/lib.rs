@@ -9,14 +9,17 @@ mod channel_data;
 mod crypto;
 mod index;
 mod node;
+mod pmtud;
 mod stats;
 mod utils;
 
-pub use allocation::RelaySocket;
+pub use allocation::{RelaySocket, Transport};
 pub use node::{
-    Credentials, Event, IceConfig, IceRole, NoTurnServers, Node, Transmit, UnknownConnection,
+    ConnectionStatus, Credentials, Event, IceConfig, IceRole, NoTurnServers, Node, Transmit,
+    UnknownConnection,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use stats::{ConnectionStats, NodeStats, RelayStats};
+pub use turn_wire::{RelayLoad, stream};
 
 pub(crate) use crypto::CRYPTO_PROVIDER;
 
@@ -3,11 +3,12 @@ mod connections;
 
 pub use connections::UnknownConnection;
 
-use crate::allocation::{self, Allocation, RelaySocket, Socket};
+use crate::allocation::{self, Allocation, RelaySocket, Socket, Transport};
 use crate::index::IndexLfsr;
 use crate::node::allocations::Allocations;
 use crate::node::connections::Connections;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::pmtud::{self, Pmtud};
+use crate::stats::{ConnectionStats, NodeStats, RelayStats};
 use crate::utils::channel_data_packet_buffer;
 use anyhow::{Context, Result, anyhow};
 use boringtun::noise::errors::WireGuardError;
@@ -430,6 +431,33 @@ where
         (self.stats, self.connections.stats())
     }
 
+    /// Statistics about all relays we have allocations on.
+    pub fn relay_stats(&self) -> impl Iterator<Item = (RId, RelayStats)> + '_ {
+        self.allocations.iter().map(|(rid, a)| {
+            (
+                *rid,
+                RelayStats {
+                    rtt: a.rtt(),
+                    load: a.load(),
+                },
+            )
+        })
+    }
+
+    /// The largest IP packet that can be sent through the connection to the given peer, as discovered by probing the current path.
+    ///
+    /// Returns `None` if we haven't (yet) discovered the MTU of the path, e.g. because the remote doesn't support probing.
+    pub fn path_mtu(&self, cid: TId) -> Option<usize> {
+        self.connections.get_established(&cid)?.pmtud.mtu()
+    }
+
+    /// The current status of all our connections.
+    pub fn connection_status(&self) -> impl Iterator<Item = (TId, ConnectionStatus)> + '_ {
+        self.connections
+            .iter_established()
+            .map(|(id, c)| (id, c.status()))
+    }
+
     #[tracing::instrument(level = "info", skip_all, fields(%cid))]
     pub fn add_remote_candidate(&mut self, cid: TId, candidate: Candidate, now: Instant) {
         let Some((agent, state, relay)) = self.connections.agent_and_state_mut(cid) else {
@@ -617,6 +645,16 @@ where
         }
 
         self.allocations.gc();
+        for (_, allocation) in self
+            .allocations
+            .drop_surplus(|rid| self.connections.uses_relay(rid))
+        {
+            invalidate_allocation_candidates(
+                &mut self.connections,
+                &allocation,
+                &mut self.pending_events,
+            );
+        }
         self.connections.check_relays_available(
             &self.allocations,
             &mut self.pending_events,
@@ -782,6 +820,7 @@ where
                 ip_buffer: AllocRingBuffer::new(128),
             },
             disconnected_at: None,
+            pmtud: Pmtud::new(now),
             buffer_pool: self.buffer_pool.clone(),
             last_proactive_handshake_sent_at: None,
             first_handshake_completed_at: None,
@@ -1236,6 +1275,10 @@ pub struct Transmit {
     pub payload: Buffer<Vec<u8>>,
     /// The ECN bits to set for the UDP packet.
     pub ecn: Ecn,
+    /// How the packet should be sent.
+    ///
+    /// Only messages to relays may use a transport other than [`Transport::Udp`].
+    pub transport: Transport,
 }
 
 impl fmt::Debug for Transmit {
@@ -1244,6 +1287,7 @@ impl fmt::Debug for Transmit {
             .field("src", &self.src)
             .field("dst", &self.dst)
             .field("len", &self.payload.len())
+            .field("transport", &self.transport)
             .finish()
     }
 }
@@ -1267,6 +1311,9 @@ struct Connection<RId> {
     state: ConnectionState,
     disconnected_at: Option<Instant>,
 
+    /// Path MTU discovery for the nominated socket.
+    pmtud: Pmtud,
+
     stats: ConnectionStats,
     intent_sent_at: Instant,
     signalling_completed_at: Instant,
@@ -1542,6 +1589,10 @@ impl PeerSocket {
         matches!(self, Self::RelayToPeer { .. } | Self::RelayToRelay { .. })
     }
 
+    fn is_relayed(&self) -> bool {
+        !matches!(self, Self::PeerToPeer { .. })
+    }
+
     fn fmt<RId>(&self, relay: RId) -> String
     where
         RId: fmt::Display,
@@ -1598,9 +1649,20 @@ where
                     .map(|instant| (instant, "disconnect timeout")),
             )
             .chain(self.state.poll_timeout(&self.agent))
+            .chain(
+                self.is_probing_path_mtu()
+                    .then(|| self.pmtud.poll_timeout())
+                    .flatten(),
+            )
             .min_by_key(|(instant, _)| *instant)
     }
 
+    /// We only probe the path MTU of active connections with an established WireGuard session.
+    fn is_probing_path_mtu(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected { .. })
+            && self.first_handshake_completed_at.is_some()
+    }
+
     fn candidate_timeout(&self) -> Option<Instant> {
         if self.agent.remote_candidates().count() > 0 {
             return None;
@@ -1650,6 +1712,7 @@ where
         }
 
         self.handle_tunnel_timeout(now, allocations, transmits);
+        self.handle_pmtud_timeout(now, allocations, transmits);
 
         // If this was a scheduled update, hop to the next interval.
         if now >= self.next_wg_timer_update {
@@ -1777,6 +1840,7 @@ where
                                 peer_socket: remote_socket,
                                 last_activity,
                             };
+                            self.pmtud = Pmtud::new(now); // The new path may have a different MTU.
 
                             Some(peer_socket)
                         }
@@ -1784,6 +1848,7 @@ where
                             self.state = ConnectionState::Idle {
                                 peer_socket: remote_socket,
                             };
+                            self.pmtud = Pmtud::new(now);
 
                             Some(peer_socket)
                         }
@@ -1822,6 +1887,7 @@ where
                     dst,
                     payload: self.buffer_pool.pull_initialised(&Vec::from(stun_packet)),
                     ecn: Ecn::NonEct,
+                    transport: Transport::Udp,
                 });
                 continue;
             };
@@ -1844,6 +1910,7 @@ where
                 dst: encode_ok.socket,
                 payload: self.buffer_pool.pull_initialised(&data_channel_packet),
                 ecn: Ecn::NonEct,
+                transport: encode_ok.transport,
             });
         }
     }
@@ -1891,6 +1958,35 @@ where
         };
     }
 
+    fn handle_pmtud_timeout(
+        &mut self,
+        now: Instant,
+        allocations: &mut Allocations<RId>,
+        transmits: &mut VecDeque<Transmit>,
+    ) {
+        if !self.is_probing_path_mtu() {
+            return;
+        }
+
+        let Some(peer_socket) = self.socket() else {
+            return;
+        };
+
+        let Some((id, size)) = self.pmtud.handle_timeout(now) else {
+            return;
+        };
+
+        tracing::trace!(%id, %size, "Sending PMTUD probe");
+
+        match pmtud::probe(id, size)
+            .and_then(|probe| self.encrypt(peer_socket, &probe, now, allocations))
+        {
+            Ok(Some(transmit)) => transmits.push_back(transmit),
+            Ok(None) => {}
+            Err(e) => tracing::debug!("Failed to send PMTUD probe: {e:#}"),
+        }
+    }
+
     fn encapsulate<TId>(
         &mut self,
         cid: TId,
@@ -1905,6 +2001,17 @@ where
         self.state
             .on_outgoing(cid, &mut self.agent, self.default_ice_config, packet, now);
 
+        self.encrypt(socket, packet, now, allocations)
+    }
+
+    /// Encrypts the packet without counting it as activity on the connection.
+    fn encrypt(
+        &mut self,
+        socket: PeerSocket,
+        packet: &IpPacket,
+        now: Instant,
+        allocations: &mut Allocations<RId>,
+    ) -> Result<Option<Transmit>> {
         let packet_start = if socket.send_from_relay() { 4 } else { 0 };
 
         let mut buffer = self.buffer_pool.pull();
@@ -1939,6 +2046,7 @@ where
                 dst: remote,
                 payload: buffer,
                 ecn: packet.ecn(),
+                transport: Transport::Udp,
             })),
             PeerSocket::RelayToPeer { dest: peer } | PeerSocket::RelayToRelay { dest: peer } => {
                 let Some(allocation) = allocations.get_mut_by_id(&self.relay.id) else {
@@ -1958,6 +2066,7 @@ where
                     dst: encode_ok.socket,
                     payload: buffer,
                     ecn: packet.ecn(),
+                    transport: encode_ok.transport,
                 }))
             }
         }
@@ -2066,6 +2175,14 @@ where
             }
         };
 
+        if let ControlFlow::Continue(packet) = &control_flow
+            && let Some(message) = pmtud::Message::parse(packet)
+        {
+            self.handle_pmtud_message(message, allocations, transmits, now);
+
+            return ControlFlow::Break(Ok(()));
+        }
+
         if let ControlFlow::Continue(packet) = &control_flow {
             self.state
                 .on_incoming(cid, &mut self.agent, self.default_ice_config, packet, now);
@@ -2074,6 +2191,31 @@ where
         control_flow
     }
 
+    fn handle_pmtud_message(
+        &mut self,
+        message: pmtud::Message,
+        allocations: &mut Allocations<RId>,
+        transmits: &mut VecDeque<Transmit>,
+        now: Instant,
+    ) {
+        match message {
+            pmtud::Message::Probe { id } => {
+                let Some(peer_socket) = self.socket() else {
+                    return;
+                };
+
+                match pmtud::ack(id)
+                    .and_then(|ack| self.encrypt(peer_socket, &ack, now, allocations))
+                {
+                    Ok(Some(transmit)) => transmits.push_back(transmit),
+                    Ok(None) => {}
+                    Err(e) => tracing::debug!("Failed to acknowledge PMTUD probe: {e:#}"),
+                }
+            }
+            pmtud::Message::Ack { id } => self.pmtud.handle_ack(id, now),
+        }
+    }
+
     fn initiate_wg_session(
         &mut self,
         allocations: &mut Allocations<RId>,
@@ -2143,6 +2285,32 @@ where
     fn is_idle(&self) -> bool {
         matches!(self.state, ConnectionState::Idle { .. })
     }
+
+    fn status(&self) -> ConnectionStatus {
+        match self.state {
+            ConnectionState::Connecting { .. } => ConnectionStatus::Connecting,
+            ConnectionState::Connected { peer_socket, .. } => ConnectionStatus::Connected {
+                relayed: peer_socket.is_relayed(),
+            },
+            ConnectionState::Idle { peer_socket } => ConnectionStatus::Idle {
+                relayed: peer_socket.is_relayed(),
+            },
+            ConnectionState::Failed => ConnectionStatus::Failed,
+        }
+    }
+}
+
+/// The externally visible status of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// We are still running ICE to find a working path to the remote.
+    Connecting,
+    /// A path has been nominated and we are actively exchanging packets.
+    Connected { relayed: bool },
+    /// A path has been nominated but we haven't seen application packets in a while.
+    Idle { relayed: bool },
+    /// The connection failed and will be cleaned up shortly.
+    Failed,
 }
 
 #[must_use]
@@ -2170,6 +2338,7 @@ where
             dst: remote,
             payload: buffer_pool.pull_initialised(message),
             ecn: Ecn::NonEct,
+            transport: Transport::Udp,
         },
         PeerSocket::RelayToPeer { dest: peer } | PeerSocket::RelayToRelay { dest: peer } => {
             let allocation = allocations.get_mut_by_id(&relay)?;
@@ -2182,6 +2351,7 @@ where
                 dst: encode_ok.socket,
                 payload: buffer_pool.pull_initialised(&channel_data),
                 ecn: Ecn::NonEct,
+                transport: encode_ok.transport,
             }
         }
     };
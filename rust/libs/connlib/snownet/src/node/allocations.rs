@@ -2,12 +2,12 @@ use std::{
     collections::{BTreeMap, btree_map::Entry},
     fmt,
     net::{IpAddr, SocketAddr},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bufferpool::BufferPool;
 use itertools::Itertools as _;
-use rand::{Rng, seq::IteratorRandom as _};
+use rand::{Rng, seq::SliceRandom as _};
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use str0m::Candidate;
 use stun_codec::rfc5389::attributes::{Realm, Username};
@@ -18,6 +18,21 @@ use crate::{
     node::SessionId,
 };
 
+/// The RTT we assume for relays that haven't responded yet.
+const DEFAULT_RTT: Duration = Duration::from_millis(100);
+/// The smallest RTT we consider when sampling relays, so that a single nearby relay doesn't get picked all the time.
+const MIN_RTT: Duration = Duration::from_millis(5);
+/// The spare capacity we assume for relays that don't advertise their load.
+const DEFAULT_SPARE_CAPACITY: f64 = 0.5;
+/// The smallest spare capacity we consider when sampling relays, so that fully loaded relays can still be picked if there are no others.
+const MIN_SPARE_CAPACITY: f64 = 0.01;
+/// The throughput at which we consider a relay to be busy (1 Gbit/s), halving its chance of being picked.
+const BUSY_THROUGHPUT: f64 = 125_000_000.0;
+/// How many allocations we keep at most once we know the load and RTT of all relays.
+///
+/// The portal may hand us more relays than that but each allocation costs keep-alive traffic and a port on the relay.
+const MAX_ALLOCATIONS: usize = 2;
+
 pub(crate) struct Allocations<RId> {
     inner: BTreeMap<RId, Allocation>,
     previous_relays_by_ip: AllocRingBuffer<IpAddr>,
@@ -91,6 +106,10 @@ where
             .rev()
     }
 
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&RId, &Allocation)> {
+        self.inner.iter()
+    }
+
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (&RId, &mut Allocation)> {
         self.inner.iter_mut()
     }
@@ -155,8 +174,14 @@ where
         }
     }
 
+    /// Samples an allocation, preferring relays that are less loaded and closer to us.
+    ///
+    /// We don't always pick the best relay because many clients would otherwise pile onto the same relay before its advertised load catches up.
     pub(crate) fn sample(&self, rng: &mut impl Rng) -> Option<(RId, &Allocation)> {
-        let (id, a) = self.inner.iter().choose(rng)?;
+        let allocations = self.inner.iter().collect::<Vec<_>>();
+        let &(id, a) = allocations
+            .choose_weighted(rng, |(_, a)| sampling_weight(a))
+            .ok()?;
 
         Some((*id, a))
     }
@@ -203,6 +228,47 @@ where
             });
     }
 
+    /// Drops allocations on the least preferable relays until we hold at most [`MAX_ALLOCATIONS`].
+    ///
+    /// Relays are ranked by the same weight we use for sampling, i.e. by their load and RTT.
+    /// To compare them fairly, we wait until every relay has responded.
+    /// Allocations that are still in use by a connection are never dropped.
+    pub(crate) fn drop_surplus(&mut self, in_use: impl Fn(&RId) -> bool) -> Vec<(RId, Allocation)> {
+        if self.inner.len() <= MAX_ALLOCATIONS
+            || !self.inner.values().all(Allocation::received_any_response)
+        {
+            return Vec::new();
+        }
+
+        let mut num_kept = self.inner.keys().filter(|rid| in_use(rid)).count();
+        let surplus = self
+            .inner
+            .iter()
+            .filter(|(rid, _)| !in_use(rid))
+            .sorted_by(|(_, a), (_, b)| sampling_weight(b).total_cmp(&sampling_weight(a)))
+            .filter_map(|(rid, _)| {
+                if num_kept < MAX_ALLOCATIONS {
+                    num_kept += 1;
+
+                    return None;
+                }
+
+                Some(*rid)
+            })
+            .collect::<Vec<_>>();
+
+        surplus
+            .into_iter()
+            .filter_map(|rid| {
+                let allocation = self.remove_by_id(&rid)?;
+
+                tracing::info!(%rid, load = ?allocation.load(), rtt = ?allocation.rtt(), "Dropping allocation on less preferable relay");
+
+                Some((rid, allocation))
+            })
+            .collect()
+    }
+
     fn shared_candidates(&self) -> impl Iterator<Item = Candidate> {
         self.inner
             .values()
@@ -217,6 +283,20 @@ pub(crate) enum MutAllocationRef<'a, RId> {
     Connected(RId, &'a mut Allocation),
 }
 
+/// Computes how likely we are to pick the given allocation for a new connection.
+///
+/// The weight is proportional to the relay's spare capacity and inversely proportional to its RTT and throughput.
+fn sampling_weight(allocation: &Allocation) -> f64 {
+    let rtt = allocation.rtt().unwrap_or(DEFAULT_RTT).max(MIN_RTT);
+    let (spare_capacity, throughput) = allocation
+        .load()
+        .map(|l| (1.0 - l.utilisation(), l.relayed_bytes_per_sec as f64))
+        .unwrap_or((DEFAULT_SPARE_CAPACITY, 0.0));
+
+    spare_capacity.max(MIN_SPARE_CAPACITY)
+        / (rtt.as_secs_f64() * (1.0 + throughput / BUSY_THROUGHPUT))
+}
+
 fn server_addresses(allocation: &Allocation) -> impl Iterator<Item = IpAddr> {
     std::iter::empty()
         .chain(
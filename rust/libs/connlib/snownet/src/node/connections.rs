@@ -85,6 +85,10 @@ where
         Some(connection)
     }
 
+    pub(crate) fn uses_relay(&self, rid: &RId) -> bool {
+        self.established.values().any(|c| c.relay.id == *rid)
+    }
+
     pub(crate) fn check_relays_available(
         &mut self,
         allocations: &Allocations<RId>,
@@ -124,7 +128,15 @@ where
     }
 
     pub(crate) fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+        self.established.iter().map(move |(id, c)| {
+            (
+                *id,
+                ConnectionStats {
+                    mtu: c.pmtud.mtu(),
+                    ..c.stats
+                },
+            )
+        })
     }
 
     pub(crate) fn insert_established(
@@ -168,6 +180,10 @@ where
             .map(|(id, c)| (*id, &mut c.agent))
     }
 
+    pub(crate) fn get_established(&self, id: &TId) -> Option<&Connection<RId>> {
+        self.established.get(id)
+    }
+
     pub(crate) fn get_established_mut(
         &mut self,
         id: &TId,
@@ -0,0 +1,438 @@
+//! Datagram Packetization Layer Path MTU Discovery (DPLPMTUD) for WireGuard paths, see [RFC 8899](https://www.rfc-editor.org/rfc/rfc8899).
+//!
+//! We cannot rely on ICMP "packet too big" messages to learn about the MTU of the path between two peers:
+//! They are frequently filtered and even if they do arrive, they refer to the encrypted packet, not the one we read from the TUN device.
+//! Instead, we send probe packets of a certain size through the tunnel and wait for the remote to acknowledge them.
+//!
+//! All sizes in this module refer to the size of the IP packets _within_ the tunnel.
+//! The largest one we ever need to send is [`MAX_IP_SIZE`], which is what we start our search with.
+//!
+//! Probes and their acknowledgements are encoded as Firezone p2p control protocol packets and are handled entirely within `snownet`.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use ip_packet::{FzP2pEventType, IpPacket, MAX_IP_SIZE};
+
+/// Event type of a probe packet.
+///
+/// The `0xF0..` range of the p2p control protocol is reserved for `snownet`.
+const PROBE_EVENT: FzP2pEventType = FzP2pEventType::new(0xF0);
+/// Event type of a probe acknowledgement.
+const PROBE_ACK_EVENT: FzP2pEventType = FzP2pEventType::new(0xF1);
+
+/// The size of the IPv6 header and the control protocol header in front of a probe's padding.
+const PROBE_OVERHEAD: usize = 40 + 8;
+
+/// The size we expect every path to support and confirm first.
+///
+/// This also tells us whether the remote supports probing at all.
+const BASE_MTU: usize = 1000;
+
+/// Once the gap between the largest confirmed and smallest failed probe is this small, we stop searching.
+const SEARCH_GRANULARITY: usize = 16;
+
+/// How long we wait for the acknowledgement of a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many times we send a probe of a particular size before considering it lost.
+const MAX_PROBES: usize = 3;
+
+/// How long until we search the path again after completing a search.
+///
+/// This allows us to detect if the MTU of the path increased or decreased.
+const PMTU_RAISE_TIMER: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub(crate) struct Pmtud {
+    state: State,
+    /// The largest size confirmed by the most recently completed search.
+    mtu: Option<usize>,
+
+    probe: Option<Probe>,
+    next_probe_at: Instant,
+    next_probe_id: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Confirming that the [`BASE_MTU`] works.
+    Base,
+    /// Binary search between the largest confirmed and smallest failed probe.
+    Searching {
+        confirmed: usize,
+        failed: usize,
+        loss: Loss,
+    },
+    /// The search is complete.
+    Complete { restart_at: Instant },
+    /// The remote never confirmed a probe of [`BASE_MTU`].
+    ///
+    /// Either it doesn't support probing or the path is currently broken.
+    Disabled { restart_at: Instant },
+}
+
+/// Lost probes may just be lost, not too big, thus we confirm a loss before lowering the MTU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Loss {
+    None,
+    /// A probe of this size was lost, we are checking that the path still delivers the largest confirmed size.
+    Suspected(usize),
+    /// The path still delivers the largest confirmed size, we are probing the lost size once more.
+    Rechecking(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    id: u32,
+    size: usize,
+    sent_at: Instant,
+    attempts: usize,
+}
+
+/// A probe or acknowledgement received from the remote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Message {
+    Probe { id: u32 },
+    Ack { id: u32 },
+}
+
+impl Pmtud {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            state: State::Base,
+            mtu: None,
+            probe: None,
+            next_probe_at: now,
+            next_probe_id: 0,
+        }
+    }
+
+    /// The MTU discovered for this path, if any.
+    pub(crate) fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<(Instant, &'static str)> {
+        if let Some(probe) = self.probe {
+            return Some((probe.sent_at + PROBE_TIMEOUT, "PMTUD probe timeout"));
+        }
+
+        match self.state {
+            State::Base | State::Searching { .. } => Some((self.next_probe_at, "PMTUD probe")),
+            State::Complete { restart_at } | State::Disabled { restart_at } => {
+                Some((restart_at, "PMTUD restart"))
+            }
+        }
+    }
+
+    /// Advances the search, returning the ID and size of a probe to send.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) -> Option<(u32, usize)> {
+        if let Some(probe) = self.probe.as_mut() {
+            if now < probe.sent_at + PROBE_TIMEOUT {
+                return None;
+            }
+
+            if probe.attempts < MAX_PROBES {
+                probe.attempts += 1;
+                probe.sent_at = now;
+
+                return Some((probe.id, probe.size));
+            }
+
+            let size = probe.size;
+            self.probe = None;
+            self.on_probe_lost(size, now);
+        }
+
+        match self.state {
+            State::Complete { restart_at } | State::Disabled { restart_at }
+                if now >= restart_at =>
+            {
+                tracing::debug!("Restarting path MTU discovery");
+
+                self.state = State::Base;
+                self.next_probe_at = now;
+            }
+            State::Base | State::Searching { .. } if now >= self.next_probe_at => {}
+            State::Base
+            | State::Searching { .. }
+            | State::Complete { .. }
+            | State::Disabled { .. } => return None,
+        }
+
+        let size = match self.state {
+            State::Base => BASE_MTU,
+            State::Searching {
+                confirmed,
+                loss: Loss::Suspected(_),
+                ..
+            } => confirmed,
+            State::Searching {
+                loss: Loss::Rechecking(size),
+                ..
+            } => size,
+            State::Searching { failed, .. } if failed > MAX_IP_SIZE => MAX_IP_SIZE,
+            State::Searching {
+                confirmed, failed, ..
+            } => (confirmed + failed) / 2,
+            State::Complete { .. } | State::Disabled { .. } => return None,
+        };
+        let id = self.next_probe_id;
+        self.next_probe_id = self.next_probe_id.wrapping_add(1);
+
+        self.probe = Some(Probe {
+            id,
+            size,
+            sent_at: now,
+            attempts: 1,
+        });
+
+        Some((id, size))
+    }
+
+    pub(crate) fn handle_ack(&mut self, id: u32, now: Instant) {
+        let Some(probe) = self.probe.filter(|p| p.id == id) else {
+            tracing::trace!(%id, "Ignoring acknowledgement for unknown probe");
+            return;
+        };
+
+        self.probe = None;
+        self.next_probe_at = now;
+
+        match self.state {
+            State::Base => self.update_search(probe.size, MAX_IP_SIZE + 1, now),
+            State::Searching {
+                confirmed,
+                failed,
+                loss: Loss::Suspected(size),
+            } => {
+                self.state = State::Searching {
+                    confirmed,
+                    failed,
+                    loss: Loss::Rechecking(size),
+                };
+            }
+            State::Searching { failed, .. } => self.update_search(probe.size, failed, now),
+            State::Complete { .. } | State::Disabled { .. } => {}
+        }
+    }
+
+    fn on_probe_lost(&mut self, size: usize, now: Instant) {
+        match self.state {
+            State::Base => {
+                tracing::debug!(
+                    "Remote did not confirm probe of base size; disabling path MTU discovery"
+                );
+
+                self.mtu = None;
+                self.state = State::Disabled {
+                    restart_at: now + PMTU_RAISE_TIMER,
+                };
+            }
+            State::Searching {
+                confirmed,
+                failed,
+                loss,
+            } => {
+                self.next_probe_at = now;
+
+                match loss {
+                    Loss::None => {
+                        tracing::debug!(%size, "PMTUD probe lost; confirming that the path still works");
+
+                        self.state = State::Searching {
+                            confirmed,
+                            failed,
+                            loss: Loss::Suspected(size),
+                        };
+                    }
+                    Loss::Suspected(_) => {
+                        tracing::debug!(
+                            "Path lost a probe of confirmed size; postponing path MTU discovery"
+                        );
+
+                        self.state = State::Complete {
+                            restart_at: now + PMTU_RAISE_TIMER,
+                        };
+                    }
+                    Loss::Rechecking(_) => self.update_search(confirmed, size, now),
+                }
+            }
+            State::Complete { .. } | State::Disabled { .. } => {}
+        }
+    }
+
+    fn update_search(&mut self, confirmed: usize, failed: usize, now: Instant) {
+        if confirmed == MAX_IP_SIZE || failed - confirmed <= SEARCH_GRANULARITY {
+            if self.mtu != Some(confirmed) {
+                tracing::info!(mtu = %confirmed, "Discovered path MTU");
+            }
+
+            self.mtu = Some(confirmed);
+            self.state = State::Complete {
+                restart_at: now + PMTU_RAISE_TIMER,
+            };
+
+            return;
+        }
+
+        self.state = State::Searching {
+            confirmed,
+            failed,
+            loss: Loss::None,
+        };
+    }
+}
+
+impl Message {
+    pub(crate) fn parse(packet: &IpPacket) -> Option<Self> {
+        let control = packet.as_fz_p2p_control()?;
+        let id = u32::from_be_bytes(packet.payload().get(4..8)?.try_into().ok()?); // The ID lives in bytes 4..8 of the control protocol header.
+
+        match control.event_type() {
+            PROBE_EVENT => Some(Self::Probe { id }),
+            PROBE_ACK_EVENT => Some(Self::Ack { id }),
+            _ => None,
+        }
+    }
+}
+
+/// Creates a probe packet of exactly `size` bytes.
+pub(crate) fn probe(id: u32, size: usize) -> Result<IpPacket> {
+    let padding = vec![0u8; size.saturating_sub(PROBE_OVERHEAD)];
+
+    ip_packet::make::fz_p2p_control(header(PROBE_EVENT, id), &padding)
+}
+
+/// Creates the acknowledgement for the probe with the given ID.
+pub(crate) fn ack(id: u32) -> Result<IpPacket> {
+    ip_packet::make::fz_p2p_control(header(PROBE_ACK_EVENT, id), &[])
+}
+
+fn header(event: FzP2pEventType, id: u32) -> [u8; 8] {
+    let [a, b, c, d] = id.to_be_bytes();
+
+    [event.into_u8(), 0, 0, 0, a, b, c, d]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_has_requested_size() {
+        let probe = probe(1, 1234).unwrap();
+
+        assert_eq!(probe.packet().len(), 1234);
+        assert_eq!(Message::parse(&probe), Some(Message::Probe { id: 1 }));
+    }
+
+    #[test]
+    fn ack_roundtrip() {
+        let ack = ack(42).unwrap();
+
+        assert_eq!(Message::parse(&ack), Some(Message::Ack { id: 42 }));
+    }
+
+    #[test]
+    fn confirms_max_mtu_on_unconstrained_path() {
+        let mut now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+
+        let mtu = run_search(&mut pmtud, &mut now, MAX_IP_SIZE);
+
+        assert_eq!(mtu, Some(MAX_IP_SIZE));
+    }
+
+    #[test]
+    fn finds_mtu_of_constrained_path() {
+        let mut now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+
+        let mtu = run_search(&mut pmtud, &mut now, 1200).unwrap();
+
+        assert!(mtu <= 1200);
+        assert!(1200 - mtu <= SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn disables_itself_if_remote_never_acks() {
+        let mut now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+
+        let mtu = run_search(&mut pmtud, &mut now, 0);
+
+        assert_eq!(mtu, None);
+        assert!(matches!(pmtud.state, State::Disabled { .. }));
+    }
+
+    #[test]
+    fn retransmits_lost_probes() {
+        let now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+
+        let (id, size) = pmtud.handle_timeout(now).unwrap();
+        assert_eq!(pmtud.handle_timeout(now + Duration::from_millis(100)), None);
+        assert_eq!(pmtud.handle_timeout(now + PROBE_TIMEOUT), Some((id, size)));
+    }
+
+    #[test]
+    fn single_lost_probe_does_not_lower_mtu() {
+        let mut now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+        let mut num_lost = 0;
+
+        while !matches!(pmtud.state, State::Complete { .. } | State::Disabled { .. }) {
+            let (timeout, _) = pmtud.poll_timeout().unwrap();
+            now = timeout.max(now);
+
+            let Some((id, size)) = pmtud.handle_timeout(now) else {
+                continue;
+            };
+
+            // Lose all attempts of the first probe of the maximum size, e.g. due to congestion.
+            if size == MAX_IP_SIZE && num_lost < MAX_PROBES {
+                num_lost += 1;
+                continue;
+            }
+
+            pmtud.handle_ack(id, now);
+        }
+
+        assert_eq!(num_lost, MAX_PROBES);
+        assert_eq!(pmtud.mtu(), Some(MAX_IP_SIZE));
+    }
+
+    #[test]
+    fn restarts_search_after_raise_timer() {
+        let mut now = Instant::now();
+        let mut pmtud = Pmtud::new(now);
+
+        run_search(&mut pmtud, &mut now, MAX_IP_SIZE);
+
+        now += PMTU_RAISE_TIMER;
+
+        assert_eq!(
+            pmtud.handle_timeout(now).map(|(_, size)| size),
+            Some(BASE_MTU)
+        );
+        assert_eq!(pmtud.mtu(), Some(MAX_IP_SIZE));
+    }
+
+    /// Drives the search to completion on a path that delivers probes up to `path_mtu`.
+    fn run_search(pmtud: &mut Pmtud, now: &mut Instant, path_mtu: usize) -> Option<usize> {
+        while !matches!(pmtud.state, State::Complete { .. } | State::Disabled { .. }) {
+            let (timeout, _) = pmtud.poll_timeout().unwrap();
+            *now = timeout.max(*now);
+
+            if let Some((id, size)) = pmtud.handle_timeout(*now)
+                && size <= path_mtu
+            {
+                pmtud.handle_ack(id, *now);
+            }
+        }
+
+        pmtud.mtu()
+    }
+}
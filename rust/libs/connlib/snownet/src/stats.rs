@@ -1,4 +1,6 @@
-use std::ops::AddAssign;
+use std::{ops::AddAssign, time::Duration};
+
+use crate::RelayLoad;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -12,6 +14,16 @@ pub struct ConnectionStats {
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+    /// The largest IP packet we can send through the tunnel on the current path, if discovered yet.
+    pub mtu: Option<usize>,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RelayStats {
+    /// The smoothed round-trip time of our requests to the relay.
+    pub rtt: Option<Duration>,
+    /// The load the relay most recently advertised, if it supports doing so.
+    pub load: Option<RelayLoad>,
 }
 
 #[derive(Default, Clone, Copy)]
@@ -40,6 +40,13 @@ impl Request<SetTunFlagsPayload> {
             },
         }
     }
+
+    /// Prefix every packet with a `virtio_net_hdr`, allowing offloads to be negotiated via `TUNSETOFFLOAD`.
+    pub fn with_vnet_hdr(mut self) -> Self {
+        self.payload.flags |= libc::IFF_VNET_HDR as std::ffi::c_short;
+
+        self
+    }
 }
 
 impl Request<GetInterfaceNamePayload> {
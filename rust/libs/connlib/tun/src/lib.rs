@@ -7,6 +7,7 @@ use ip_packet::IpPacket;
 
 #[cfg(target_family = "unix")]
 pub mod ioctl;
+pub mod offload;
 #[cfg(target_family = "unix")]
 pub mod unix;
 
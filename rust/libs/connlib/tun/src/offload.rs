@@ -0,0 +1,879 @@
+//! Segmentation and coalescing of "super-packets" for TUN devices opened with `IFF_VNET_HDR`.
+//!
+//! With TSO / USO enabled, the kernel hands us TCP and UDP packets of up to 64 KiB, prefixed with a [`VirtioNetHdr`] describing how they need to be split into MTU-sized segments.
+//! In the other direction, we coalesce consecutive TCP segments of the same flow into a single super-packet before writing it to the device (GRO).
+//! Both drastically reduce the number of syscalls and allow the kernel's TCP stack to process data in larger chunks.
+
+use std::{collections::BTreeMap, net::IpAddr};
+
+use anyhow::{Context as _, Result, bail, ensure};
+use ip_packet::{IpPacket, IpPacketBuf};
+
+/// The size of [`VirtioNetHdr`] on the wire.
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+
+/// The largest packet we may read from or write to the TUN device, including the [`VirtioNetHdr`].
+pub const MAX_SUPER_PACKET_SIZE: usize = VIRTIO_NET_HDR_LEN + u16::MAX as usize;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_UDP_L4: u8 = 5;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const TCP_MIN_HEADER_LEN: usize = 20;
+
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_CWR: u8 = 0x80;
+
+/// Offset of the checksum field within the TCP header.
+const TCP_CHECKSUM_OFFSET: u16 = 16;
+
+/// The header prepended to every packet on a TUN device opened with `IFF_VNET_HDR`.
+///
+/// See `struct virtio_net_hdr` in `linux/virtio_net.h`.
+/// Unless configured otherwise via `TUNSETVNETLE` / `TUNSETVNETBE`, all fields are in native endianness.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..VIRTIO_NET_HDR_LEN)?;
+
+        Some(Self {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16::from_ne_bytes([buf[2], buf[3]]),
+            gso_size: u16::from_ne_bytes([buf[4], buf[5]]),
+            csum_start: u16::from_ne_bytes([buf[6], buf[7]]),
+            csum_offset: u16::from_ne_bytes([buf[8], buf[9]]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; VIRTIO_NET_HDR_LEN] {
+        let mut buf = [0u8; VIRTIO_NET_HDR_LEN];
+
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+
+        buf
+    }
+}
+
+/// Splits a buffer read from a TUN device with `IFF_VNET_HDR` into individual IP packets.
+///
+/// All produced packets carry valid checksums.
+pub fn segment(buf: &[u8], out: &mut Vec<IpPacket>) -> Result<()> {
+    let hdr = VirtioNetHdr::decode(buf).context("Buffer is too short for virtio-net header")?;
+    let packet = &buf[VIRTIO_NET_HDR_LEN..];
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let mut ip_packet_buf = IpPacketBuf::new();
+            ip_packet_buf
+                .buf()
+                .get_mut(..packet.len())
+                .with_context(|| format!("Packet too large (len: {})", packet.len()))?
+                .copy_from_slice(packet);
+
+            let mut packet = IpPacket::new(ip_packet_buf, packet.len())?;
+
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                packet.update_checksum();
+            }
+
+            out.push(packet);
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+            segment_l4(hdr, packet, Transport::Tcp, out)?
+        }
+        VIRTIO_NET_HDR_GSO_UDP_L4 => segment_l4(hdr, packet, Transport::Udp, out)?,
+        other => bail!("Unsupported GSO type: {other}"),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Tcp,
+    Udp,
+}
+
+fn segment_l4(
+    hdr: VirtioNetHdr,
+    packet: &[u8],
+    transport: Transport,
+    out: &mut Vec<IpPacket>,
+) -> Result<()> {
+    let is_ipv4 = match packet.first().map(|b| b >> 4) {
+        Some(4) => true,
+        Some(6) => false,
+        other => bail!("Invalid IP version: {other:?}"),
+    };
+
+    let l4_start = usize::from(hdr.csum_start);
+    ensure!(
+        l4_start >= IPV4_MIN_HEADER_LEN,
+        "L4 header must not start within the IP header (csum_start: {l4_start})"
+    );
+    ensure!(
+        is_ipv4 || l4_start == IPV6_HEADER_LEN,
+        "IPv6 extension headers are not supported"
+    );
+
+    let l4_header_len = match transport {
+        Transport::Tcp => {
+            usize::from(packet.get(l4_start + 12).context("Truncated TCP header")? >> 4) * 4
+        }
+        Transport::Udp => UDP_HEADER_LEN,
+    };
+    ensure!(
+        transport == Transport::Udp || l4_header_len >= TCP_MIN_HEADER_LEN,
+        "TCP header too short (len: {l4_header_len})"
+    );
+    let headers_len = l4_start + l4_header_len;
+    let headers = packet.get(..headers_len).context("Truncated headers")?;
+    let payload = &packet[headers_len..];
+
+    let gso_size = usize::from(hdr.gso_size);
+    ensure!(gso_size > 0, "GSO size must not be 0");
+
+    let ipv4_id = u16::from_be_bytes([headers[4], headers[5]]);
+    let tcp_seq = headers
+        .get(l4_start + 4..l4_start + 8)
+        .and_then(|seq| <[u8; 4]>::try_from(seq).ok())
+        .map(u32::from_be_bytes)
+        .context("Truncated L4 header")?;
+    let num_segments = payload.len().div_ceil(gso_size);
+
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let len = headers_len + chunk.len();
+
+        let mut ip_packet_buf = IpPacketBuf::new();
+        let segment = ip_packet_buf
+            .buf()
+            .get_mut(..len)
+            .with_context(|| format!("Segment too large (len: {len})"))?;
+        segment[..headers_len].copy_from_slice(headers);
+        segment[headers_len..].copy_from_slice(chunk);
+
+        if is_ipv4 {
+            segment[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            segment[4..6].copy_from_slice(&ipv4_id.wrapping_add(i as u16).to_be_bytes());
+        } else {
+            segment[4..6].copy_from_slice(&((len - IPV6_HEADER_LEN) as u16).to_be_bytes());
+        }
+
+        match transport {
+            Transport::Tcp => {
+                let seq = tcp_seq.wrapping_add((i * gso_size) as u32);
+                segment[l4_start + 4..l4_start + 8].copy_from_slice(&seq.to_be_bytes());
+
+                // Like the kernel, only set CWR on the first and FIN / PSH on the last segment.
+                if i != 0 {
+                    segment[l4_start + 13] &= !TCP_FLAG_CWR;
+                }
+                if i + 1 != num_segments {
+                    segment[l4_start + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+                }
+            }
+            Transport::Udp => {
+                segment[l4_start + 4..l4_start + 6]
+                    .copy_from_slice(&((len - l4_start) as u16).to_be_bytes());
+            }
+        }
+
+        let mut packet = IpPacket::new(ip_packet_buf, len)?;
+        packet.update_checksum();
+
+        out.push(packet);
+    }
+
+    Ok(())
+}
+
+/// Coalesces TCP segments of the same flow into super-packets, ready to be written to a TUN device with `IFF_VNET_HDR`.
+///
+/// Packets that cannot be coalesced are emitted unchanged, prefixed with an empty [`VirtioNetHdr`].
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    batches: Vec<Batch>,
+    /// Batches that can still be extended, indexed by their flow.
+    open: BTreeMap<TcpFlow, usize>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    /// The [`VirtioNetHdr`] (yet to be filled in) followed by the IP packet.
+    buf: Vec<u8>,
+    tcp: Option<TcpBatch>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TcpBatch {
+    is_ipv4: bool,
+    ip_header_len: usize,
+    headers_len: usize,
+    gso_size: usize,
+    next_seq: u32,
+    num_segments: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TcpFlow {
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+}
+
+/// A TCP segment that is eligible for coalescing.
+struct TcpSegment {
+    flow: TcpFlow,
+    is_ipv4: bool,
+    ip_header_len: usize,
+    headers_len: usize,
+    seq: u32,
+    payload_len: usize,
+    psh: bool,
+}
+
+impl TcpSegment {
+    fn parse(packet: &IpPacket) -> Option<Self> {
+        if !packet.is_tcp() {
+            return None;
+        }
+
+        let bytes = packet.packet();
+
+        let (is_ipv4, ip_header_len) = match bytes.first()? >> 4 {
+            4 if bytes[0] & 0x0f == 5 => (true, IPV4_MIN_HEADER_LEN),
+            6 if *bytes.get(6)? == IPPROTO_TCP => (false, IPV6_HEADER_LEN),
+            _ => return None,
+        };
+
+        let tcp = bytes.get(ip_header_len..)?;
+        let tcp_header_len = usize::from(tcp.get(12)? >> 4) * 4;
+        if tcp_header_len < TCP_MIN_HEADER_LEN || tcp.len() <= tcp_header_len {
+            return None;
+        }
+
+        let flags = tcp[13];
+        if flags & !TCP_FLAG_PSH != TCP_FLAG_ACK {
+            return None;
+        }
+
+        Some(Self {
+            flow: TcpFlow {
+                src: packet.source(),
+                dst: packet.destination(),
+                src_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+                dst_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+            },
+            is_ipv4,
+            ip_header_len,
+            headers_len: ip_header_len + tcp_header_len,
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            payload_len: tcp.len() - tcp_header_len,
+            psh: flags & TCP_FLAG_PSH != 0,
+        })
+    }
+}
+
+impl Coalescer {
+    pub fn push(&mut self, packet: &IpPacket) {
+        let Some(segment) = TcpSegment::parse(packet) else {
+            // Don't let later segments of this flow overtake this packet.
+            if let Some(tcp) = packet.as_tcp() {
+                self.open.remove(&TcpFlow {
+                    src: packet.source(),
+                    dst: packet.destination(),
+                    src_port: tcp.source_port(),
+                    dst_port: tcp.destination_port(),
+                });
+            }
+
+            self.push_single(packet.packet(), None);
+            return;
+        };
+
+        if let Some(index) = self.open.get(&segment.flow).copied()
+            && let Some(batch) = self.batches.get_mut(index)
+            && let Some(tcp) = batch.tcp.as_mut()
+            && tcp.can_append(&batch.buf[VIRTIO_NET_HDR_LEN..], packet.packet(), &segment)
+        {
+            batch
+                .buf
+                .extend_from_slice(&packet.packet()[segment.headers_len..]);
+            tcp.next_seq = segment.seq.wrapping_add(segment.payload_len as u32);
+            tcp.num_segments += 1;
+
+            if segment.psh {
+                batch.buf[VIRTIO_NET_HDR_LEN + tcp.ip_header_len + 13] |= TCP_FLAG_PSH;
+            }
+
+            // A smaller segment or a PSH terminates the batch.
+            if segment.payload_len < tcp.gso_size || segment.psh {
+                self.open.remove(&segment.flow);
+            }
+
+            return;
+        }
+
+        let tcp = TcpBatch {
+            is_ipv4: segment.is_ipv4,
+            ip_header_len: segment.ip_header_len,
+            headers_len: segment.headers_len,
+            gso_size: segment.payload_len,
+            next_seq: segment.seq.wrapping_add(segment.payload_len as u32),
+            num_segments: 1,
+        };
+
+        if segment.psh {
+            self.open.remove(&segment.flow);
+        } else {
+            self.open.insert(segment.flow, self.batches.len());
+        }
+
+        self.push_single(packet.packet(), Some(tcp));
+    }
+
+    /// Finalises all batches, returning the buffers to write to the TUN device.
+    pub fn finish(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.open.clear();
+
+        self.batches.drain(..).map(|Batch { mut buf, tcp }| {
+            if let Some(tcp) = tcp
+                && tcp.num_segments > 1
+            {
+                tcp.finalise(&mut buf);
+            }
+
+            buf
+        })
+    }
+
+    fn push_single(&mut self, packet: &[u8], tcp: Option<TcpBatch>) {
+        let mut buf = Vec::with_capacity(VIRTIO_NET_HDR_LEN + packet.len());
+        buf.extend_from_slice(&VirtioNetHdr::default().encode());
+        buf.extend_from_slice(packet);
+
+        self.batches.push(Batch { buf, tcp });
+    }
+}
+
+impl TcpBatch {
+    fn can_append(&self, first: &[u8], packet: &[u8], segment: &TcpSegment) -> bool {
+        if segment.seq != self.next_seq
+            || segment.payload_len > self.gso_size
+            || segment.headers_len != self.headers_len
+            || first.len() + segment.payload_len > usize::from(u16::MAX)
+        {
+            return false;
+        }
+
+        let ip_fields_match = if self.is_ipv4 {
+            // TOS, DF / MF flags and TTL.
+            first[1] == packet[1] && first[6] & 0xe0 == packet[6] & 0xe0 && first[8] == packet[8]
+        } else {
+            // Traffic class, flow label and hop limit.
+            first[..4] == packet[..4] && first[7] == packet[7]
+        };
+
+        let first_tcp = &first[self.ip_header_len..self.headers_len];
+        let tcp = &packet[self.ip_header_len..self.headers_len];
+
+        // ACK number, data offset, window, urgent pointer and options.
+        ip_fields_match
+            && first_tcp[8..13] == tcp[8..13]
+            && first_tcp[14..16] == tcp[14..16]
+            && first_tcp[18..] == tcp[18..]
+    }
+
+    /// Writes the [`VirtioNetHdr`] and fixes up the IP and TCP headers of a coalesced super-packet.
+    ///
+    /// The TCP checksum field is set to the pseudo-header checksum, as expected for `VIRTIO_NET_HDR_F_NEEDS_CSUM`.
+    fn finalise(&self, buf: &mut [u8]) {
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if self.is_ipv4 {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            },
+            hdr_len: self.headers_len as u16,
+            gso_size: self.gso_size as u16,
+            csum_start: self.ip_header_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET,
+        };
+        buf[..VIRTIO_NET_HDR_LEN].copy_from_slice(&hdr.encode());
+
+        let packet = &mut buf[VIRTIO_NET_HDR_LEN..];
+        let len = packet.len();
+        let tcp_len = len - self.ip_header_len;
+
+        let pseudo_header_sum = if self.is_ipv4 {
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            packet[10..12].copy_from_slice(&[0, 0]);
+            let checksum = !fold(sum(&packet[..IPV4_MIN_HEADER_LEN]));
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            sum(&packet[12..20]) + u32::from(IPPROTO_TCP) + tcp_len as u32
+        } else {
+            packet[4..6].copy_from_slice(&(tcp_len as u16).to_be_bytes());
+
+            sum(&packet[8..40]) + u32::from(IPPROTO_TCP) + tcp_len as u32
+        };
+
+        let checksum_offset = self.ip_header_len + usize::from(TCP_CHECKSUM_OFFSET);
+        packet[checksum_offset..checksum_offset + 2]
+            .copy_from_slice(&fold(pseudo_header_sum).to_be_bytes());
+    }
+}
+
+/// Computes the (unfolded) one's complement sum of `data` in 16-bit words.
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or(0)])))
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+#[cfg(feature = "divan")]
+#[allow(clippy::unwrap_used)]
+mod benches {
+    use std::net::Ipv4Addr;
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    /// Payload size of a full-sized segment at our MTU of 1280.
+    const MSS: usize = 1240;
+    const NUM_SEGMENTS: usize = 50;
+
+    #[divan::bench]
+    fn read_individual_packets(bencher: divan::Bencher) {
+        bencher
+            .with_inputs(|| {
+                (0..NUM_SEGMENTS)
+                    .map(|i| tcp((i * MSS) as u32, &[0u8; MSS]))
+                    .collect::<Vec<_>>()
+            })
+            .bench_local_values(|segments| {
+                segments
+                    .into_iter()
+                    .map(|bytes| {
+                        let mut buf = IpPacketBuf::new();
+                        buf.buf()[..bytes.len()].copy_from_slice(&bytes);
+
+                        IpPacket::new(buf, bytes.len()).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            });
+    }
+
+    #[divan::bench]
+    fn segment_super_packet(bencher: divan::Bencher) {
+        bencher
+            .with_inputs(|| {
+                let hdr = VirtioNetHdr {
+                    flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                    gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+                    hdr_len: (IPV4_MIN_HEADER_LEN + TCP_MIN_HEADER_LEN) as u16,
+                    gso_size: MSS as u16,
+                    csum_start: IPV4_MIN_HEADER_LEN as u16,
+                    csum_offset: TCP_CHECKSUM_OFFSET,
+                };
+
+                [hdr.encode().as_slice(), &tcp(0, &[0u8; MSS * NUM_SEGMENTS])].concat()
+            })
+            .bench_local_refs(|buf| {
+                let mut out = Vec::with_capacity(NUM_SEGMENTS);
+                segment(buf, &mut out).unwrap();
+
+                out
+            });
+    }
+
+    #[divan::bench]
+    fn coalesce_segments(bencher: divan::Bencher) {
+        bencher
+            .with_inputs(|| {
+                (0..NUM_SEGMENTS)
+                    .map(|i| {
+                        let bytes = tcp((i * MSS) as u32, &[0u8; MSS]);
+                        let mut buf = IpPacketBuf::new();
+                        buf.buf()[..bytes.len()].copy_from_slice(&bytes);
+
+                        IpPacket::new(buf, bytes.len()).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .bench_local_refs(|segments| {
+                let mut coalescer = Coalescer::default();
+
+                for segment in segments.iter() {
+                    coalescer.push(segment);
+                }
+
+                coalescer.finish().collect::<Vec<_>>()
+            });
+    }
+
+    fn tcp(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(
+            Ipv4Addr::new(100, 64, 0, 1).octets(),
+            Ipv4Addr::new(100, 64, 0, 2).octets(),
+            64,
+        )
+        .tcp(40000, 443, seq, 64240)
+        .ack(1);
+
+        let mut buf = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut buf, payload).unwrap();
+
+        buf
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    const SRC_V4: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 1);
+    const DST_V4: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 2);
+    const SRC_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 1);
+    const DST_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn virtio_net_hdr_roundtrip() {
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV6,
+            hdr_len: 60,
+            gso_size: 1220,
+            csum_start: 40,
+            csum_offset: 16,
+        };
+
+        assert_eq!(VirtioNetHdr::decode(&hdr.encode()), Some(hdr));
+    }
+
+    #[test]
+    fn gso_none_packet_is_passed_through() {
+        let packet = tcp_v4(0, TCP_FLAG_ACK, &[1u8; 100]);
+        let mut out = Vec::new();
+
+        segment(&with_hdr(VirtioNetHdr::default(), &packet), &mut out).unwrap();
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].packet(), packet.as_slice());
+    }
+
+    #[test]
+    fn segments_tcpv4_super_packet() {
+        let payload = (0..3000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let packet = tcp_v4(1000, TCP_FLAG_ACK | TCP_FLAG_PSH, &payload);
+        let hdr = tso_hdr(VIRTIO_NET_HDR_GSO_TCPV4, IPV4_MIN_HEADER_LEN, 1200);
+        let mut out = Vec::new();
+
+        segment(&with_hdr(hdr, &packet), &mut out).unwrap();
+
+        assert_eq!(out.len(), 3);
+        assert_segments(&out, 1000, &payload, 1200);
+    }
+
+    #[test]
+    fn segments_tcpv6_super_packet() {
+        let payload = vec![0xab; 2500];
+        let packet = tcp_v6(u32::MAX - 10, TCP_FLAG_ACK | TCP_FLAG_PSH, &payload);
+        let hdr = tso_hdr(VIRTIO_NET_HDR_GSO_TCPV6, IPV6_HEADER_LEN, 1200);
+        let mut out = Vec::new();
+
+        segment(&with_hdr(hdr, &packet), &mut out).unwrap();
+
+        assert_eq!(out.len(), 3);
+        assert_segments(&out, u32::MAX - 10, &payload, 1200);
+    }
+
+    #[test]
+    fn segments_udp_super_packet() {
+        let payload = vec![0xcd; 2500];
+        let packet = udp_v4(&payload);
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_UDP_L4,
+            hdr_len: (IPV4_MIN_HEADER_LEN + UDP_HEADER_LEN) as u16,
+            gso_size: 1000,
+            csum_start: IPV4_MIN_HEADER_LEN as u16,
+            csum_offset: 6,
+        };
+        let mut out = Vec::new();
+
+        segment(&with_hdr(hdr, &packet), &mut out).unwrap();
+
+        assert_eq!(
+            out.iter().map(|p| p.payload().len()).collect::<Vec<_>>(),
+            vec![1008, 1008, 508]
+        );
+        for segment in &out {
+            let udp = segment.as_udp().unwrap();
+
+            assert_eq!(
+                usize::from(udp.length()),
+                UDP_HEADER_LEN + udp.payload().len()
+            );
+            assert_eq!(udp.checksum(), segment.calculate_udp_checksum().unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_segments_exceeding_mtu() {
+        let packet = tcp_v4(0, TCP_FLAG_ACK, &[0u8; 3000]);
+        let hdr = tso_hdr(VIRTIO_NET_HDR_GSO_TCPV4, IPV4_MIN_HEADER_LEN, 2000);
+
+        assert!(segment(&with_hdr(hdr, &packet), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_virtio_net_hdr() {
+        let packet = tcp_v4(0, TCP_FLAG_ACK, &[0u8; 3000]);
+        let mut bad_csum_start = tso_hdr(VIRTIO_NET_HDR_GSO_TCPV4, IPV4_MIN_HEADER_LEN, 1200);
+        bad_csum_start.csum_start = 2;
+        let mut bad_data_offset = packet.clone();
+        bad_data_offset[IPV4_MIN_HEADER_LEN + 12] = 0x10; // 4 bytes
+        let hdr = tso_hdr(VIRTIO_NET_HDR_GSO_TCPV4, IPV4_MIN_HEADER_LEN, 1200);
+
+        assert!(segment(&with_hdr(bad_csum_start, &packet), &mut Vec::new()).is_err());
+        assert!(segment(&with_hdr(hdr, &bad_data_offset), &mut Vec::new()).is_err());
+        assert!(segment(&with_hdr(hdr, &packet[..30]), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn coalesces_contiguous_tcp_segments() {
+        let payload = (0..3000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut coalescer = Coalescer::default();
+
+        for (i, chunk) in payload.chunks(1200).enumerate() {
+            let flags = if i == 2 {
+                TCP_FLAG_ACK | TCP_FLAG_PSH
+            } else {
+                TCP_FLAG_ACK
+            };
+
+            coalescer.push(&ip_packet(&tcp_v4(500 + i as u32 * 1200, flags, chunk)));
+        }
+
+        let buffers = coalescer.finish().collect::<Vec<_>>();
+        assert_eq!(buffers.len(), 1);
+
+        let hdr = VirtioNetHdr::decode(&buffers[0]).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 1200);
+        assert_eq!(hdr.csum_start, IPV4_MIN_HEADER_LEN as u16);
+
+        // Segmenting the super-packet again must yield the original segments.
+        let mut out = Vec::new();
+        segment(&buffers[0], &mut out).unwrap();
+
+        assert_eq!(out.len(), 3);
+        assert_segments(&out, 500, &payload, 1200);
+    }
+
+    #[test]
+    fn coalesces_ipv6_segments() {
+        let mut coalescer = Coalescer::default();
+
+        coalescer.push(&ip_packet(&tcp_v6(0, TCP_FLAG_ACK, &[1u8; 1000])));
+        coalescer.push(&ip_packet(&tcp_v6(1000, TCP_FLAG_ACK, &[2u8; 1000])));
+
+        let buffers = coalescer.finish().collect::<Vec<_>>();
+        assert_eq!(buffers.len(), 1);
+
+        let mut out = Vec::new();
+        segment(&buffers[0], &mut out).unwrap();
+
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[1].as_tcp().unwrap().payload(), &[2u8; 1000]);
+    }
+
+    #[test]
+    fn does_not_coalesce_non_contiguous_segments() {
+        let mut coalescer = Coalescer::default();
+
+        coalescer.push(&ip_packet(&tcp_v4(0, TCP_FLAG_ACK, &[0u8; 1000])));
+        coalescer.push(&ip_packet(&tcp_v4(5000, TCP_FLAG_ACK, &[0u8; 1000])));
+
+        let buffers = coalescer.finish().collect::<Vec<_>>();
+
+        assert_eq!(buffers.len(), 2);
+        assert!(
+            buffers
+                .iter()
+                .all(|b| VirtioNetHdr::decode(b) == Some(VirtioNetHdr::default()))
+        );
+    }
+
+    #[test]
+    fn does_not_coalesce_after_smaller_segment() {
+        let mut coalescer = Coalescer::default();
+
+        coalescer.push(&ip_packet(&tcp_v4(0, TCP_FLAG_ACK, &[0u8; 1000])));
+        coalescer.push(&ip_packet(&tcp_v4(1000, TCP_FLAG_ACK, &[0u8; 500])));
+        coalescer.push(&ip_packet(&tcp_v4(1500, TCP_FLAG_ACK, &[0u8; 500])));
+
+        assert_eq!(coalescer.finish().count(), 2);
+    }
+
+    #[test]
+    fn does_not_reorder_segments_around_non_coalescable_packet() {
+        let mut coalescer = Coalescer::default();
+
+        coalescer.push(&ip_packet(&tcp_v4(0, TCP_FLAG_ACK, &[0u8; 1000])));
+        coalescer.push(&ip_packet(&tcp_v4(
+            1000,
+            TCP_FLAG_ACK | TCP_FLAG_FIN,
+            &[0u8; 1000],
+        )));
+        coalescer.push(&ip_packet(&tcp_v4(2000, TCP_FLAG_ACK, &[0u8; 1000])));
+
+        assert_eq!(coalescer.finish().count(), 3);
+    }
+
+    #[test]
+    fn passes_through_non_tcp_packets() {
+        let mut coalescer = Coalescer::default();
+        let udp = udp_v4(&[0u8; 100]);
+
+        coalescer.push(&ip_packet(&udp));
+        coalescer.push(&ip_packet(&udp));
+
+        let buffers = coalescer.finish().collect::<Vec<_>>();
+
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(&buffers[0][VIRTIO_NET_HDR_LEN..], udp.as_slice());
+    }
+
+    fn assert_segments(segments: &[IpPacket], initial_seq: u32, payload: &[u8], gso_size: usize) {
+        let num_segments = segments.len();
+
+        for (i, (segment, expected)) in segments.iter().zip(payload.chunks(gso_size)).enumerate() {
+            let tcp = segment.as_tcp().unwrap();
+
+            assert_eq!(tcp.payload(), expected);
+            assert_eq!(
+                tcp.sequence_number(),
+                initial_seq.wrapping_add((i * gso_size) as u32)
+            );
+            assert_eq!(tcp.psh(), i + 1 == num_segments);
+            assert_eq!(tcp.checksum(), segment.calculate_tcp_checksum().unwrap());
+
+            if let Some(ipv4) = segment.ipv4_header() {
+                assert_eq!(ipv4.header_checksum, ipv4.calc_header_checksum());
+            }
+        }
+    }
+
+    fn tso_hdr(gso_type: u8, ip_header_len: usize, gso_size: u16) -> VirtioNetHdr {
+        VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: (ip_header_len + TCP_MIN_HEADER_LEN) as u16,
+            gso_size,
+            csum_start: ip_header_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET,
+        }
+    }
+
+    fn with_hdr(hdr: VirtioNetHdr, packet: &[u8]) -> Vec<u8> {
+        [hdr.encode().as_slice(), packet].concat()
+    }
+
+    fn ip_packet(bytes: &[u8]) -> IpPacket {
+        let mut buf = IpPacketBuf::new();
+        buf.buf()[..bytes.len()].copy_from_slice(bytes);
+
+        IpPacket::new(buf, bytes.len()).unwrap()
+    }
+
+    fn tcp_v4(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        tcp(
+            PacketBuilder::ipv4(SRC_V4.octets(), DST_V4.octets(), 64),
+            seq,
+            flags,
+            payload,
+        )
+    }
+
+    fn tcp_v6(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        tcp(
+            PacketBuilder::ipv6(SRC_V6.octets(), DST_V6.octets(), 64),
+            seq,
+            flags,
+            payload,
+        )
+    }
+
+    fn tcp(
+        builder: etherparse::PacketBuilderStep<etherparse::IpHeaders>,
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut builder = builder.tcp(40000, 443, seq, 64240).ack(1);
+        if flags & TCP_FLAG_PSH != 0 {
+            builder = builder.psh();
+        }
+        if flags & TCP_FLAG_FIN != 0 {
+            builder = builder.fin();
+        }
+
+        let mut buf = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut buf, payload).unwrap();
+
+        buf
+    }
+
+    fn udp_v4(payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(SRC_V4.octets(), DST_V4.octets(), 64).udp(40000, 443);
+
+        let mut buf = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut buf, payload).unwrap();
+
+        buf
+    }
+}
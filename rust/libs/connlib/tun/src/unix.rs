@@ -5,6 +5,8 @@ use std::os::fd::AsRawFd;
 use tokio::io::unix::AsyncFd;
 use tokio::sync::mpsc;
 
+use crate::offload;
+
 pub fn tun_send<T>(
     fd: T,
     mut outbound_rx: mpsc::Receiver<IpPacket>,
@@ -97,6 +99,119 @@ where
     anyhow::Ok(())
 }
 
+/// How many packets we try to coalesce into super-packets at once.
+const MAX_COALESCE_BATCH: usize = 128;
+
+/// Like [`tun_send`] but for TUN devices opened with `IFF_VNET_HDR`.
+///
+/// Consecutive TCP segments of the same flow are coalesced into super-packets before being written.
+pub fn tun_send_offload<T>(
+    fd: T,
+    mut outbound_rx: mpsc::Receiver<IpPacket>,
+    write: impl Fn(i32, &[u8]) -> std::result::Result<usize, io::Error>,
+) -> Result<()>
+where
+    T: AsRawFd + Clone,
+{
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create runtime")?
+        .block_on(async move {
+            let fd = AsyncFd::with_interest(fd, tokio::io::Interest::WRITABLE)?;
+
+            let mut packets = Vec::with_capacity(MAX_COALESCE_BATCH);
+            let mut coalescer = offload::Coalescer::default();
+
+            while outbound_rx
+                .recv_many(&mut packets, MAX_COALESCE_BATCH)
+                .await
+                > 0
+            {
+                for packet in packets.drain(..) {
+                    coalescer.push(&packet);
+                }
+
+                for buf in coalescer.finish() {
+                    if let Err(e) = fd
+                        .async_io(tokio::io::Interest::WRITABLE, |fd| {
+                            write(fd.as_raw_fd(), &buf)
+                        })
+                        .await
+                    {
+                        tracing::warn!("Failed to write to TUN FD: {e}");
+                    }
+                }
+            }
+
+            anyhow::Ok(())
+        })?;
+
+    anyhow::Ok(())
+}
+
+/// Like [`tun_recv`] but for TUN devices opened with `IFF_VNET_HDR`.
+///
+/// Super-packets handed to us by the kernel are split into individual IP packets.
+pub fn tun_recv_offload<T>(
+    fd: T,
+    inbound_tx: mpsc::Sender<IpPacket>,
+    read: impl Fn(i32, &mut [u8]) -> std::result::Result<usize, io::Error>,
+) -> Result<()>
+where
+    T: AsRawFd + Clone,
+{
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create runtime")?
+        .block_on(async move {
+            let fd = AsyncFd::with_interest(fd, tokio::io::Interest::READABLE)?;
+
+            let mut buf = vec![0u8; offload::MAX_SUPER_PACKET_SIZE];
+            let mut packets = Vec::new();
+
+            loop {
+                let len = fd
+                    .async_io(tokio::io::Interest::READABLE, |fd| {
+                        read(fd.as_raw_fd(), &mut buf)
+                    })
+                    .await;
+
+                let len = match len.context("Failed to read from TUN FD") {
+                    Ok(0) => bail!("TUN file descriptor is closed"),
+                    Ok(len) => len,
+                    Err(e) => {
+                        tracing::warn!("{e:#}");
+                        continue;
+                    }
+                };
+
+                match offload::segment(&buf[..len], &mut packets)
+                    .context("Failed to parse IP packet")
+                {
+                    Ok(()) => {}
+                    Err(e) if e.any_is::<ip_packet::Fragmented>() => {
+                        tracing::debug!("{e:#}"); // Log on debug to be less noisy.
+                    }
+                    Err(e) => {
+                        tracing::warn!("{e:#}");
+                    }
+                }
+
+                for packet in packets.drain(..) {
+                    if inbound_tx.send(packet).await.is_err() {
+                        tracing::debug!("Inbound packet receiver gone, shutting down task");
+
+                        return anyhow::Ok(());
+                    };
+                }
+            }
+        })?;
+
+    anyhow::Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -4,11 +4,13 @@ mod dns_resource_nat;
 mod gateway_on_client;
 mod pending_flows;
 mod resource;
+mod resource_gateways;
 mod tracked_state;
 
 use crate::client::dns_config::DnsConfig;
 pub(crate) use crate::client::gateway_on_client::GatewayOnClient;
 use crate::client::pending_flows::{ConnectionTrigger, DnsQueryForSite, PendingFlows};
+use crate::client::resource_gateways::{MAX_GATEWAYS_PER_RESOURCE, ResourceGateways};
 use crate::client::tracked_state::TrackedState;
 use boringtun::x25519;
 #[cfg(all(feature = "proptest", test))]
@@ -16,12 +18,13 @@ pub(crate) use resource::DnsResource;
 pub(crate) use resource::{CidrResource, InternetResource, Resource};
 
 use dns_resource_nat::DnsResourceNat;
-use dns_types::ResponseCode;
+use dns_types::{DomainName, ResponseCode};
 use ringbuffer::RingBuffer;
 use secrecy::ExposeSecret as _;
 use telemetry::{analytics, feature_flags};
 
 use crate::client::dns_cache::DnsCache;
+pub use crate::client::dns_cache::DnsCacheSnapshot;
 use crate::dns::{DnsResourceRecord, StubResolver};
 use crate::messages::Interface as InterfaceConfig;
 use crate::messages::{IceCredentials, SecretKey};
@@ -29,7 +32,8 @@ use crate::peer_store::PeerStore;
 use crate::{IPV4_TUNNEL, IPV6_TUNNEL, IpConfig, TunConfig, dns, is_peer, p2p_control};
 use anyhow::{Context, ErrorExt};
 use connlib_model::{
-    GatewayId, IceCandidate, PublicKey, RelayId, ResourceId, ResourceStatus, ResourceView,
+    GatewayId, GatewayStatus, GatewayView, IceCandidate, PublicKey, RelayId, ResourceId,
+    ResourceStatus, ResourceView,
 };
 use connlib_model::{Site, SiteId};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
@@ -95,10 +99,10 @@ pub struct ClientState {
     /// Tracks the flows to resources that we are currently trying to establish.
     pending_flows: PendingFlows,
     dns_resource_nat: DnsResourceNat,
-    /// Tracks the resources we have been authorized for and which Gateway to use to access them.
+    /// Tracks the resources we have been authorized for and which Gateways to use to access them.
     ///
-    /// This state persists across `reset`s so we can re-connect to the same Gateway.
-    authorized_resources: HashMap<ResourceId, GatewayId>,
+    /// This state persists across `reset`s so we can re-connect to the same Gateways.
+    resource_gateways: ResourceGateways,
     /// Tracks which gateways are in a site.
     ///
     /// This state gets populated as we connect to various Gateways.
@@ -107,6 +111,10 @@ pub struct ClientState {
     gateways_by_site: HashMap<SiteId, HashSet<GatewayId>>,
     /// The online/offline status of a site.
     sites_status: HashMap<SiteId, ResourceStatus>,
+    /// The resources (or individual domains of DNS resources) that a Gateway reported as not healthy.
+    ///
+    /// A `None` domain means the resource as a whole is not healthy.
+    unhealthy_resources: HashMap<(ResourceId, GatewayId), BTreeSet<Option<DomainName>>>,
 
     /// All CIDR resources we know about, indexed by the IP range they cover (like `1.1.0.0/8`).
     active_cidr_resources: IpNetworkTable<CidrResource>,
@@ -144,12 +152,13 @@ impl ClientState {
     pub(crate) fn new(
         seed: [u8; 32],
         records: BTreeSet<DnsResourceRecord>,
+        dns_cache: DnsCacheSnapshot,
         is_internet_resource_active: bool,
         now: Instant,
         unix_ts: Duration,
     ) -> Self {
         Self {
-            authorized_resources: Default::default(),
+            resource_gateways: Default::default(),
             active_cidr_resources: IpNetworkTable::new(),
             resources_by_id: Default::default(),
             gateways: Default::default(),
@@ -165,9 +174,10 @@ impl ClientState {
                 snownet::IceConfig::client_idle(),
             ),
             sites_status: Default::default(),
+            unhealthy_resources: Default::default(),
             gateways_by_site: Default::default(),
             stub_resolver: StubResolver::new(records),
-            dns_cache: Default::default(),
+            dns_cache: DnsCache::new(dns_cache, now, unix_ts),
             buffered_transmits: Default::default(),
             is_internet_resource_active,
             buffered_dns_queries: Default::default(),
@@ -186,6 +196,11 @@ impl ClientState {
         Some(self.tun_config.current()?.ip)
     }
 
+    #[cfg(all(test, feature = "proptest"))]
+    pub(crate) fn gateways_for_resource(&self, rid: ResourceId) -> &[GatewayId] {
+        self.resource_gateways.gateways(&rid)
+    }
+
     #[cfg(all(test, feature = "proptest"))]
     pub(crate) fn tunnel_ip_for(&self, dst: IpAddr) -> Option<IpAddr> {
         Some(match dst {
@@ -206,12 +221,46 @@ impl ClientState {
             .collect_vec()
     }
 
+    /// The status of our connections to Gateways and the resources we access through them.
+    pub fn gateways(&self) -> Vec<GatewayView> {
+        self.node
+            .connection_status()
+            .map(|(id, status)| {
+                let (status, relayed) = match status {
+                    snownet::ConnectionStatus::Connecting => (GatewayStatus::Connecting, false),
+                    snownet::ConnectionStatus::Connected { relayed } => {
+                        (GatewayStatus::Connected, relayed)
+                    }
+                    snownet::ConnectionStatus::Idle { relayed } => (GatewayStatus::Idle, relayed),
+                    snownet::ConnectionStatus::Failed => (GatewayStatus::Failed, false),
+                };
+                let resources = self
+                    .resources_by_id
+                    .keys()
+                    .filter(|rid| self.resource_gateways.contains(rid, &id))
+                    .copied()
+                    .collect();
+
+                GatewayView {
+                    id,
+                    status,
+                    relayed,
+                    resources,
+                }
+            })
+            .collect()
+    }
+
     fn resource_status(&self, resource: &Resource) -> ResourceStatus {
         if resource.sites().iter().any(|s| {
             self.sites_status
                 .get(&s.id)
                 .is_some_and(|s| *s == ResourceStatus::Online)
         }) {
+            if self.is_resource_unhealthy(resource.id()) {
+                return ResourceStatus::Degraded;
+            }
+
             return ResourceStatus::Online;
         }
 
@@ -226,16 +275,87 @@ impl ClientState {
         ResourceStatus::Unknown
     }
 
+    /// Whether any of the Gateways we use for this resource reported it or one of its domains as not healthy.
+    fn is_resource_unhealthy(&self, rid: ResourceId) -> bool {
+        self.resource_gateways
+            .gateways(&rid)
+            .iter()
+            .any(|gid| self.unhealthy_resources.contains_key(&(rid, *gid)))
+    }
+
+    fn handle_resource_health(
+        &mut self,
+        gid: GatewayId,
+        health: p2p_control::resource_health::ResourceHealth,
+    ) {
+        use p2p_control::resource_health::HealthStatus;
+
+        let rid = health.resource;
+
+        tracing::debug!(%gid, %rid, domain = ?health.domain, status = ?health.status, reason = ?health.reason, "Gateway reported resource health");
+
+        let changed = match health.status {
+            HealthStatus::Degraded | HealthStatus::Unreachable => self
+                .unhealthy_resources
+                .entry((rid, gid))
+                .or_default()
+                .insert(health.domain),
+            HealthStatus::Healthy | HealthStatus::Unknown => {
+                let Some(unhealthy) = self.unhealthy_resources.get_mut(&(rid, gid)) else {
+                    return;
+                };
+                let removed = unhealthy.remove(&health.domain);
+
+                if unhealthy.is_empty() {
+                    self.unhealthy_resources.remove(&(rid, gid));
+                }
+
+                removed
+            }
+        };
+
+        if !changed {
+            return;
+        }
+
+        self.resource_list.update(self.resources());
+    }
+
+    /// Moves new flows of all resources we access through this Gateway to other Gateways of the site.
+    ///
+    /// Existing flows stay with the Gateway until it says `goodbye`.
+    fn handle_gateway_draining(&mut self, gid: GatewayId, now: Instant) {
+        if !self.resource_gateways.set_draining(gid) {
+            return; // Gateways repeat the event until they shut down.
+        }
+
+        tracing::info!(%gid, "Gateway is draining; moving new flows to other Gateways");
+
+        for rid in self.resource_gateways.resources(&gid) {
+            if self.resource_gateways.has_non_draining_gateway(&rid) {
+                continue;
+            }
+
+            self.on_not_connected_resource(rid, ConnectionTrigger::GatewayDraining, now);
+        }
+    }
+
     pub fn set_resource_offline(&mut self, id: ResourceId) {
         let Some(resource) = self.resources_by_id.get(&id).cloned() else {
             return;
         };
 
+        self.on_connection_failed(id);
+
+        // The resource is still reachable through the Gateways we already use.
+        if self.resource_gateways.primary(&id).is_some() {
+            return;
+        }
+
         for Site { id, .. } in resource.sites() {
             self.sites_status.insert(*id, ResourceStatus::Offline);
         }
 
-        self.on_connection_failed(id);
         self.resource_list.update(self.resources());
     }
 
@@ -275,12 +395,19 @@ impl ClientState {
                     .stub_resolver
                     .resolve_resource_by_ip(&packet.destination())
                     .context("IP is not associated with a DNS resource domain")?;
+                // Pin the flow so its later packets go to the Gateway that we release these to.
                 let gateway_id = self
-                    .authorized_resources
-                    .get(resource)
+                    .resource_gateways
+                    .gateway_for_flow(
+                        *resource,
+                        &packet,
+                        |gid| self.gateways.get(gid).is_some(),
+                        now,
+                    )
+                    .or_else(|| self.resource_gateways.primary(resource))
                     .context("No gateway for resource")?;
 
-                anyhow::Ok((*gateway_id, domain, packet))
+                anyhow::Ok((gateway_id, domain, packet))
             })
             .filter_map(|res| {
                 res.inspect_err(|e| tracing::debug!("Dropping buffered packet: {e}"))
@@ -295,53 +422,55 @@ impl ClientState {
                 },
             );
 
-        for (domain, rid, proxy_ips, gid) in
+        // Each Gateway we use for a resource needs its own NAT.
+        for (domain, rid, proxy_ips, gateways) in
             self.stub_resolver
                 .resolved_resources()
                 .map(|(domain, resource, proxy_ips)| {
-                    let gateway = self.authorized_resources.get(resource);
+                    let gateways = self.resource_gateways.gateways(resource);
 
-                    (domain, resource, proxy_ips, gateway)
+                    (domain, resource, proxy_ips, gateways)
                 })
         {
-            let Some(gid) = gid else {
+            if gateways.is_empty() {
                 tracing::trace!(
                     %domain, %rid,
                     "No gateway connected for resource, skipping DNS resource NAT setup"
                 );
                 continue;
-            };
-
-            let packets_for_domain = buffered_packets_by_gateway_and_domain
-                .remove(&(*gid, domain))
-                .unwrap_or_default();
+            }
 
-            match self.dns_resource_nat.update(
-                domain.clone(),
-                *gid,
-                *rid,
-                proxy_ips,
-                packets_for_domain,
-                now,
-            ) {
-                Ok(()) => {}
-                Err(e) => {
-                    tracing::warn!("Failed to update DNS resource NAT state: {e:#}");
-                    continue;
+            for gid in gateways {
+                let packets_for_domain = buffered_packets_by_gateway_and_domain
+                    .remove(&(*gid, domain))
+                    .unwrap_or_default();
+
+                match self.dns_resource_nat.update(
+                    domain.clone(),
+                    *gid,
+                    *rid,
+                    proxy_ips,
+                    packets_for_domain,
+                    now,
+                ) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        tracing::warn!("Failed to update DNS resource NAT state: {e:#}");
+                        continue;
+                    }
                 }
-            }
 
-            self.gateways
-                .add_ips_with_resource(gid, proxy_ips.clone(), rid);
+                self.gateways
+                    .add_ips_with_resource(gid, proxy_ips.clone(), rid);
+            }
         }
     }
 
     fn is_cidr_resource_connected(&self, resource: &ResourceId) -> bool {
-        let Some(gateway_id) = self.authorized_resources.get(resource) else {
-            return false;
-        };
-
-        self.gateways.get(gateway_id).is_some()
+        self.resource_gateways
+            .gateways(resource)
+            .iter()
+            .any(|gid| self.gateways.get(gid).is_some())
     }
 
     /// Handles packets received on the TUN device.
@@ -429,10 +558,20 @@ impl ClientState {
                         );
                     }
                 }
+                p2p_control::RESOURCE_HEALTH_EVENT => {
+                    let res = p2p_control::resource_health::decode_resource_health(fz_p2p_control)
+                        .inspect_err(|e| tracing::debug!("{e:#}"))
+                        .ok()?;
+
+                    self.handle_resource_health(gid, res);
+                }
                 p2p_control::GOODBYE_EVENT => {
                     self.node.remove_connection(gid, "received `goodbye`", now);
                     self.cleanup_connected_gateway(&gid);
                 }
+                p2p_control::GATEWAY_DRAINING_EVENT => {
+                    self.handle_gateway_draining(gid, now);
+                }
                 code => {
                     tracing::debug!(code = %code.into_u8(), "Unknown control protocol");
                 }
@@ -460,7 +599,7 @@ impl ClientState {
 
             self.on_not_connected_resource(
                 resource,
-                ConnectionTrigger::IcmpDestinationUnreachableProhibited,
+                ConnectionTrigger::IcmpDestinationUnreachableProhibited(gid),
                 now,
             );
         }
@@ -476,37 +615,50 @@ impl ClientState {
         let _span = tracing::debug_span!("handle_dns_response", %qid, %server, local = %response.local, %domain).entered();
 
         let message = match response.message {
-            Ok(response) => {
+            Ok(message) if message.response_code() != ResponseCode::SERVFAIL => {
                 tracing::trace!("Received recursive DNS response");
 
-                if response.truncated() {
+                if message.truncated() {
                     tracing::debug!("Upstream DNS server had to truncate response");
                 }
 
-                response
-            }
-            Err(e)
-                if response.transport == dns::Transport::Udp
-                    && e.any_downcast_ref::<io::Error>()
-                        .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) =>
-            {
-                tracing::debug!("Recursive UDP DNS query timed out");
+                // Ensure the response we are sending back has the original query ID.
+                // Recursive DoH queries set the ID to 0.
+                let message = message.with_id(qid);
 
-                return; // Our UDP DNS query timeout is likely longer than the one from the OS, so don't bother sending a response.
-            }
-            Err(e) => {
-                tracing::debug!("Recursive DNS query failed: {e:#}");
+                self.dns_cache.insert(domain, &message, now);
 
-                dns_types::Response::servfail(&response.query)
+                message
+            }
+            result => {
+                if let Some(stale) = self.dns_cache.try_answer_stale(&response.query) {
+                    stale
+                } else {
+                    match result {
+                        Ok(message) => {
+                            tracing::trace!("Upstream DNS server failed to answer query");
+
+                            message.with_id(qid)
+                        }
+                        Err(e)
+                            if response.transport == dns::Transport::Udp
+                                && e.any_downcast_ref::<io::Error>()
+                                    .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) =>
+                        {
+                            tracing::debug!("Recursive UDP DNS query timed out");
+
+                            return; // Our UDP DNS query timeout is likely longer than the one from the OS, so don't bother sending a response.
+                        }
+                        Err(e) => {
+                            tracing::debug!("Recursive DNS query failed: {e:#}");
+
+                            dns_types::Response::servfail(&response.query)
+                        }
+                    }
+                }
             }
         };
 
-        // Ensure the response we are sending back has the original query ID.
-        // Recursive DoH queries set the ID to 0.
-        let message = message.with_id(qid);
-
-        self.dns_cache.insert(domain, &message, now);
-
         match response.transport {
             dns::Transport::Udp => {
                 self.buffered_packets.extend(into_udp_dns_packet(
@@ -541,8 +693,15 @@ impl ClientState {
                 return None;
             };
 
-            let Some(peer) =
-                peer_by_resource_mut(&self.authorized_resources, &mut self.gateways, resource)
+            let Some(peer) = self
+                .resource_gateways
+                .gateway_for_flow(
+                    resource,
+                    &packet,
+                    |gid| self.gateways.get(gid).is_some(),
+                    now,
+                )
+                .and_then(|gid| self.gateways.get_mut(&gid))
             else {
                 self.on_not_connected_resource(resource, packet, now);
                 return None;
@@ -551,17 +710,26 @@ impl ClientState {
             peer
         };
 
+        let gid = peer.id();
+
+        if let Some(icmp_error) = crate::packet_too_big(&packet, self.node.path_mtu(gid))
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to create ICMP error: {e:#}"))
+            .ok()?
+        {
+            self.buffered_packets.push_back(icmp_error);
+
+            return None;
+        }
+
         // TODO: Check DNS resource NAT state for the domain that the destination IP belongs to.
         // Re-send if older than X.
 
         if let Some((domain, _)) = self.stub_resolver.resolve_resource_by_ip(&dst) {
             packet = self
                 .dns_resource_nat
-                .handle_outgoing(peer.id(), domain, packet, now)?;
+                .handle_outgoing(gid, domain, packet, now)?;
         }
 
-        let gid = peer.id();
-
         let transmit = self
             .node
             .encapsulate(gid, &packet, now)
@@ -636,7 +804,10 @@ impl ClientState {
             Ok(()) => {}
             Err(e) => return Ok(Err(e)),
         };
-        self.authorized_resources.insert(rid, gid);
+        // Forget about Gateways we are no longer connected to, e.g. after a `reset`.
+        self.resource_gateways
+            .retain(&rid, |g| g == &gid || self.gateways.get(g).is_some());
+        let is_new_gateway = self.resource_gateways.insert(rid, gid);
         self.gateways_by_site
             .entry(site_id)
             .or_default()
@@ -707,9 +878,33 @@ impl ClientState {
             );
         }
 
+        // 3. Spread flows across more Gateways of the site, if we know of any.
+        if is_new_gateway && self.has_unused_gateways_in_site(rid, site_id) {
+            tracing::debug!(%site_id, "Requesting additional Gateway for resource");
+
+            self.on_not_connected_resource(rid, ConnectionTrigger::AdditionalGateway, now);
+        }
+
         Ok(Ok(()))
     }
 
+    /// Whether we know of Gateways in the given site that we are not yet using for this resource.
+    fn has_unused_gateways_in_site(&self, rid: ResourceId, site_id: SiteId) -> bool {
+        let used = self.resource_gateways.gateways(&rid);
+
+        if used.len() >= MAX_GATEWAYS_PER_RESOURCE {
+            return false;
+        }
+
+        let Some(site_gateways) = self.gateways_by_site.get(&site_id) else {
+            return false;
+        };
+
+        let used_in_site = used.iter().filter(|g| site_gateways.contains(g)).count();
+
+        site_gateways.len() > used_in_site
+    }
+
     /// For DNS queries to IPs that are a CIDR resources we want to mangle and forward to the gateway that handles that resource.
     ///
     /// We only want to do this if the upstream DNS server is set by the portal, otherwise, the server might be a local IP.
@@ -724,16 +919,20 @@ impl ClientState {
         let server = match dns_server {
             dns::Upstream::Do53 { server } => server,
             dns::Upstream::DoH { .. } => return None, // If DoH upstreams are in effect, we never forward queries to upstreams.
+            dns::Upstream::DoT { .. } => return None, // Like DoH, DoT queries are never forwarded through the tunnel.
         };
 
+        self.is_routed_through_tunnel(server.ip())
+            .then_some(*server)
+    }
+
+    /// Whether traffic to the given IP is routed through the tunnel, either via a CIDR resource or the Internet resource.
+    fn is_routed_through_tunnel(&self, ip: IpAddr) -> bool {
         if self.active_internet_resource().is_some() {
-            return Some(*server);
+            return true;
         }
 
-        self.active_cidr_resources
-            .longest_match(server.ip())
-            .is_some()
-            .then_some(*server)
+        self.active_cidr_resources.longest_match(ip).is_some()
     }
 
     /// Handles UDP & TCP packets targeted at our stub resolver.
@@ -760,14 +959,40 @@ impl ClientState {
     }
 
     pub fn on_connection_failed(&mut self, resource: ResourceId) {
-        self.pending_flows.remove(&resource);
-        let Some(disconnected_gateway) = self.authorized_resources.remove(&resource) else {
+        let pending_flow = self.pending_flows.remove(&resource);
+
+        // Failing to get an additional Gateway doesn't affect the ones we already use.
+        if pending_flow
+            .as_ref()
+            .is_some_and(|f| f.is_for_additional_gateway())
+        {
+            tracing::debug!(%resource, "Failed to connect to additional Gateway");
             return;
-        };
-        self.cleanup_connected_gateway(&disconnected_gateway);
+        }
+
+        // Only the Gateway that rejected our traffic failed, its siblings are still fine.
+        if let Some(rejected_by) = pending_flow.and_then(|f| f.rejected_by()) {
+            self.cleanup_connected_gateway(&rejected_by);
+
+            // Flows pinned to the failed Gateway get re-assigned to the remaining ones.
+            if let Some(sibling) = self.resource_gateways.primary(&resource) {
+                tracing::debug!(%resource, failed = %rejected_by, %sibling, "Failing over to sibling Gateway");
+            }
+
+            return;
+        }
+
+        // Otherwise, we only ask for a new flow if none of the Gateways for this resource are usable anymore.
+        for disconnected_gateway in self.resource_gateways.gateways(&resource).to_vec() {
+            self.cleanup_connected_gateway(&disconnected_gateway);
+        }
     }
 
     fn preferred_gateways(&self, resource: ResourceId) -> Vec<GatewayId> {
+        let authorized = self.resource_gateways.gateways(&resource);
+
+        let wants_additional_gateway = self.pending_flows.is_for_additional_gateway(&resource);
+
         #[expect(clippy::disallowed_methods, reason = "We are sorting anyway")]
         self.gateways_by_site
             .values()
@@ -775,15 +1000,25 @@ impl ClientState {
             .copied()
             .unique()
             .sorted_by(|left, right| {
-                let prefer_authorized = self
-                    .authorized_resources
-                    .get(&resource)
-                    .map(|g| match g {
-                        g if g == left => Ordering::Less,
-                        g if g == right => Ordering::Greater,
-                        _ => Ordering::Equal,
-                    })
-                    .unwrap_or(Ordering::Equal);
+                let prefer_authorized = match (
+                    authorized.iter().position(|g| g == left),
+                    authorized.iter().position(|g| g == right),
+                ) {
+                    (Some(left), Some(right)) => left.cmp(&right),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                };
+                // For an additional Gateway, we want one that we don't use for this resource yet.
+                let prefer_authorized = if wants_additional_gateway {
+                    prefer_authorized.reverse()
+                } else {
+                    prefer_authorized
+                };
+                let prefer_not_draining = self
+                    .resource_gateways
+                    .is_draining(left)
+                    .cmp(&self.resource_gateways.is_draining(right));
                 let prefer_connected = match (self.gateways.get(left), self.gateways.get(right)) {
                     (None, None) => Ordering::Equal,
                     (Some(_), Some(_)) => Ordering::Equal,
@@ -793,7 +1028,8 @@ impl ClientState {
 
                 let default_ordering = left.cmp(right);
 
-                prefer_authorized
+                prefer_not_draining
+                    .then(prefer_authorized)
                     .then(prefer_connected)
                     .then(default_ordering) // This makes it deterministic, even though we are using `HashSets
             })
@@ -801,7 +1037,7 @@ impl ClientState {
     }
 
     pub fn gateway_by_resource(&self, resource: &ResourceId) -> Option<GatewayId> {
-        self.authorized_resources.get(resource).copied()
+        self.resource_gateways.primary(resource)
     }
 
     fn initialise_tcp_dns_client(&mut self) {
@@ -816,6 +1052,17 @@ impl ClientState {
         self.tcp_dns_client.reset();
     }
 
+    /// Takes a snapshot of the DNS cache to be restored in a future session.
+    ///
+    /// Entries for DNS resources are never included: Their IPs are allocated by us and only valid for the current session.
+    pub(crate) fn dns_cache_snapshot(&self, now: Instant, unix_ts: Duration) -> DnsCacheSnapshot {
+        self.dns_cache.snapshot(
+            |domain| !self.stub_resolver.is_resource(domain),
+            now,
+            unix_ts,
+        )
+    }
+
     fn initialise_tcp_dns_server(&mut self) {
         let sentinel_sockets = self
             .dns_config
@@ -861,10 +1108,11 @@ impl ClientState {
 
     #[tracing::instrument(level = "debug", skip_all, fields(gateway = %disconnected_gateway))]
     fn cleanup_connected_gateway(&mut self, disconnected_gateway: &GatewayId) {
+        self.unhealthy_resources
+            .retain(|(_, gid), _| gid != disconnected_gateway);
         self.update_site_status_by_gateway(disconnected_gateway, ResourceStatus::Unknown);
         self.gateways.remove(disconnected_gateway);
-        self.authorized_resources
-            .retain(|_, g| g != disconnected_gateway);
+        self.resource_gateways.remove_gateway(disconnected_gateway);
         self.dns_resource_nat.clear_by_gateway(disconnected_gateway);
     }
 
@@ -966,7 +1214,7 @@ impl ClientState {
     }
 
     pub fn update_interface_config(&mut self, config: InterfaceConfig) {
-        tracing::trace!(upstream_do53 = ?config.upstream_do53(), upstream_doh = ?config.upstream_doh(), search_domain = ?config.search_domain, ipv4 = %config.ipv4, ipv6 = %config.ipv6, "Received interface configuration from portal");
+        tracing::trace!(upstream_do53 = ?config.upstream_do53(), upstream_doh = ?config.upstream_doh(), upstream_dot = ?config.upstream_dot(), search_domain = ?config.search_domain, conditional_forwarders = ?config.conditional_forwarders, ipv4 = %config.ipv4, ipv6 = %config.ipv6, "Received interface configuration from portal");
 
         let changed_do53 = self
             .dns_config
@@ -974,8 +1222,14 @@ impl ClientState {
         let changed_doh = self
             .dns_config
             .update_upstream_doh_resolvers(config.upstream_doh());
+        let changed_dot = self
+            .dns_config
+            .update_upstream_dot_resolvers(config.upstream_dot());
+        let changed_forwarders = self
+            .stub_resolver
+            .set_conditional_forwarders(config.conditional_forwarders.clone());
 
-        if changed_do53 || changed_doh {
+        if changed_do53 || changed_doh || changed_dot || changed_forwarders {
             self.dns_cache.flush("DNS servers changed");
         }
 
@@ -1046,6 +1300,7 @@ impl ClientState {
         self.send_dns_resource_nat_packets(now);
 
         self.dns_cache.handle_timeout(now);
+        self.resource_gateways.handle_timeout(now);
     }
 
     /// Advance the DNS server and client state machines.
@@ -1263,6 +1518,9 @@ impl ClientState {
             dns::ResolveStrategy::RecurseSite(_) => {
                 tracing::trace!("LLMNR queries are not forwarded to upstream resolvers");
             }
+            dns::ResolveStrategy::RecurseForwarder(_) => {
+                tracing::trace!("LLMNR queries are not forwarded to upstream resolvers");
+            }
         }
     }
 
@@ -1290,6 +1548,10 @@ impl ClientState {
                 return Some(response);
             }
             dns::ResolveStrategy::RecurseLocal => {
+                if let Some(response) = self.dns_cache.try_answer_restored(&message, now) {
+                    return Some(response);
+                }
+
                 if let Some(upstream) = self.should_forward_dns_query_to_gateway(&upstream) {
                     self.forward_dns_query_to_new_upstream_via_tunnel(
                         local, remote, upstream, message, transport, now,
@@ -1310,7 +1572,7 @@ impl ClientState {
             }
             dns::ResolveStrategy::RecurseSite(resource) => {
                 let Some(gateway) =
-                    peer_by_resource_mut(&self.authorized_resources, &mut self.gateways, resource)
+                    peer_by_resource_mut(&self.resource_gateways, &mut self.gateways, resource)
                 else {
                     self.on_not_connected_resource(
                         resource,
@@ -1331,6 +1593,29 @@ impl ClientState {
                     local, remote, server, message, transport, now,
                 );
             }
+            dns::ResolveStrategy::RecurseForwarder(server) => {
+                if let Some(response) = self.dns_cache.try_answer_restored(&message, now) {
+                    return Some(response);
+                }
+
+                if self.is_routed_through_tunnel(server.ip()) {
+                    self.forward_dns_query_to_new_upstream_via_tunnel(
+                        local, remote, server, message, transport, now,
+                    );
+
+                    return None;
+                }
+
+                tracing::trace!(%server, %query_id, "Forwarding {transport} DNS query to conditional forwarder");
+
+                self.buffered_dns_queries.push_back(dns::RecursiveQuery {
+                    server: dns::Upstream::Do53 { server },
+                    local,
+                    remote,
+                    message,
+                    transport,
+                });
+            }
         };
 
         None
@@ -1545,6 +1830,7 @@ impl ClientState {
         self.gateways.clear(); // Clear all state associated with Gateways.
 
         self.dns_resource_nat.clear(); // Clear all state related to DNS resource NATs.
+        self.unhealthy_resources.clear(); // Gateways will report the health again once we are connected.
         self.drain_node_events();
 
         // Resetting the client will trigger a failed `QueryResult` for each one that is in-progress.
@@ -1633,10 +1919,12 @@ impl ClientState {
             .insert(new_resource.id(), new_resource.clone());
 
         let activated = match &new_resource {
-            Resource::Dns(dns) => {
-                self.stub_resolver
-                    .add_resource(dns.id, dns.address.clone(), dns.ip_stack)
-            }
+            Resource::Dns(dns) => self.stub_resolver.add_resource(
+                dns.id,
+                dns.address.clone(),
+                &dns.exclusions,
+                dns.ip_stack,
+            ),
             Resource::Cidr(cidr) => {
                 let existing = self.active_cidr_resources.exact_match(cidr.address);
 
@@ -1715,28 +2003,35 @@ impl ClientState {
 
         self.pending_flows.remove(&id);
 
-        let Some(peer) = peer_by_resource_mut(&self.authorized_resources, &mut self.gateways, id)
-        else {
-            return;
-        };
+        let gateways = self.resource_gateways.gateways(&id);
 
-        // First we remove the id from all allowed ips
-        for (_, resources) in peer
-            .allowed_ips
-            .iter_mut()
-            .filter(|(_, resources)| resources.contains(&id))
-        {
-            resources.remove(&id);
+        if !gateways.iter().any(|gid| self.gateways.get(gid).is_some()) {
+            return;
+        }
 
-            if !resources.is_empty() {
+        for gid in gateways {
+            let Some(peer) = self.gateways.get_mut(gid) else {
                 continue;
+            };
+
+            // First we remove the id from all allowed ips
+            for (_, resources) in peer
+                .allowed_ips
+                .iter_mut()
+                .filter(|(_, resources)| resources.contains(&id))
+            {
+                resources.remove(&id);
+
+                if !resources.is_empty() {
+                    continue;
+                }
             }
-        }
 
-        // We remove all empty allowed ips entry since there's no resource that corresponds to it
-        peer.allowed_ips.retain(|_, r| !r.is_empty());
+            // We remove all empty allowed ips entry since there's no resource that corresponds to it
+            peer.allowed_ips.retain(|_, r| !r.is_empty());
+        }
 
-        self.authorized_resources.remove(&id);
+        self.resource_gateways.remove_resource(&id);
 
         // Clear DNS resource NAT state for all domains resolved for this DNS resource.
         for domain in self
@@ -1806,11 +2101,14 @@ fn encapsulate_and_buffer(
 }
 
 fn peer_by_resource_mut<'p>(
-    resources_gateways: &HashMap<ResourceId, GatewayId>,
+    resource_gateways: &ResourceGateways,
     peers: &'p mut PeerStore<GatewayId, GatewayOnClient>,
     resource: ResourceId,
 ) -> Option<&'p mut GatewayOnClient> {
-    let gateway_id = resources_gateways.get(&resource)?;
+    let gateway_id = resource_gateways
+        .gateways(&resource)
+        .iter()
+        .find(|gid| peers.get(gid).is_some())?;
     let peer = peers.get_mut(gateway_id)?;
 
     Some(peer)
@@ -1960,6 +2258,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn only_requests_additional_gateway_if_site_has_unused_ones() {
+        let mut state = ClientState::for_test();
+        let site = SiteId::from_u128(1);
+        let resource = ResourceId::from_u128(100);
+        state
+            .gateways_by_site
+            .insert(site, HashSet::from([GatewayId::from_u128(10)]));
+        state
+            .resource_gateways
+            .insert(resource, GatewayId::from_u128(10));
+
+        assert!(!state.has_unused_gateways_in_site(resource, site));
+
+        state.gateways_by_site.insert(
+            site,
+            HashSet::from([GatewayId::from_u128(10), GatewayId::from_u128(20)]),
+        );
+
+        assert!(state.has_unused_gateways_in_site(resource, site));
+
+        state
+            .resource_gateways
+            .insert(resource, GatewayId::from_u128(20));
+
+        assert!(!state.has_unused_gateways_in_site(resource, site));
+    }
+
+    #[test]
+    fn prefers_gateways_that_are_not_draining() {
+        let mut state = ClientState::for_test();
+        state.gateways_by_site.insert(
+            SiteId::from_u128(1),
+            HashSet::from([GatewayId::from_u128(10), GatewayId::from_u128(20)]),
+        );
+        state.gateways.insert(peer(GatewayId::from_u128(10)), &[]);
+        state
+            .resource_gateways
+            .insert(ResourceId::from_u128(100), GatewayId::from_u128(10));
+
+        state.handle_gateway_draining(GatewayId::from_u128(10), Instant::now());
+        let preferred_gateways = state.preferred_gateways(ResourceId::from_u128(100));
+
+        assert_eq!(
+            preferred_gateways,
+            vec![GatewayId::from_u128(20), GatewayId::from_u128(10)]
+        );
+    }
+
     #[test]
     fn remembers_preference_for_authorized_resource_after_reset() {
         let mut state = ClientState::for_test();
@@ -1973,7 +2320,7 @@ mod tests {
         );
         state.gateways.insert(peer(GatewayId::from_u128(30)), &[]);
         state
-            .authorized_resources
+            .resource_gateways
             .insert(ResourceId::from_u128(100), GatewayId::from_u128(30));
 
         state.reset(Instant::now(), "test");
@@ -1990,11 +2337,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn failed_connection_only_removes_rejecting_gateway() {
+        let mut state = ClientState::for_test();
+        let now = Instant::now();
+        let resource = Resource::Cidr(CidrResource {
+            id: ResourceId::from_u128(100),
+            address: IpNetwork::from(Ipv4Addr::new(10, 0, 0, 1)),
+            name: "resource".to_owned(),
+            address_description: None,
+            sites: vec![Site {
+                id: SiteId::from_u128(1),
+                name: "site".to_owned(),
+            }],
+        });
+        let rid = resource.id();
+        state.add_resource(resource.clone(), now);
+        state
+            .resource_gateways
+            .insert(rid, GatewayId::from_u128(10));
+        state
+            .resource_gateways
+            .insert(rid, GatewayId::from_u128(20));
+
+        state.on_not_connected_resource(
+            rid,
+            ConnectionTrigger::IcmpDestinationUnreachableProhibited(GatewayId::from_u128(20)),
+            now,
+        );
+        state.set_resource_offline(rid);
+
+        assert_eq!(
+            state.resource_gateways.gateways(&rid),
+            &[GatewayId::from_u128(10)]
+        );
+        assert_ne!(state.resource_status(&resource), ResourceStatus::Offline);
+    }
+
     impl ClientState {
         pub fn for_test() -> ClientState {
             ClientState::new(
                 rand::random(),
                 Default::default(),
+                Default::default(),
                 false,
                 Instant::now(),
                 Duration::ZERO,
@@ -2206,7 +2591,7 @@ mod proptests {
 
         let first_resource = resources_online.first().unwrap();
         client_state
-            .authorized_resources
+            .resource_gateways
             .insert(first_resource.id(), gateway);
         client_state.gateways_by_site.insert(
             first_resource.sites().iter().next().unwrap().id,
@@ -2241,7 +2626,7 @@ mod proptests {
         }
         let first_resources = resources.first().unwrap();
         client_state
-            .authorized_resources
+            .resource_gateways
             .insert(first_resources.id(), gateway);
         client_state.gateways_by_site.insert(
             first_resources.sites().iter().next().unwrap().id,
@@ -1,35 +1,158 @@
 use std::time::Duration;
-use std::{fmt, net::IpAddr, time::Instant};
+use std::{cmp, fmt, iter, net::IpAddr, time::Instant};
 
 use dns_types::{DomainName, RecordType, ResponseBuilder, ResponseCode, Ttl};
 use dns_types::{OwnedRecord, Query};
 use dns_types::{Response, prelude::*};
+use serde::{Deserialize, Serialize};
 
 use crate::expiring_map::{self, ExpiringMap};
 
+/// For how long we keep entries around after their TTL expired, in case our upstream resolvers become unreachable.
+///
+/// RFC 8767 recommends between 1 and 3 days.
+const MAX_STALE_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// The TTL of records in stale answers, as recommended by RFC 8767.
+const STALE_ANSWER_TTL: Ttl = Ttl::from_secs(30);
+
+/// The maximum number of entries we cache, beyond which we evict the least recently used one.
+///
+/// Together with [`MAX_STALE_DURATION`], the cache would otherwise grow with every domain queried within a day.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The maximum size of all responses in a [`DnsCacheSnapshot`], in bytes.
+const MAX_SNAPSHOT_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, Default)]
 pub struct DnsCache {
-    inner: ExpiringMap<(DomainName, RecordType), Response>,
+    inner: ExpiringMap<(DomainName, RecordType), CachedResponse>,
+
+    /// Entries restored from a [`DnsCacheSnapshot`] of a previous session.
+    ///
+    /// We only ever answer queries from these that we'd otherwise forward to an upstream resolver.
+    /// Thus, they are not affected by flushes which mostly happen as part of the initial configuration.
+    restored: ExpiringMap<(DomainName, RecordType), Response>,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    response: Response,
+    stale_at: Instant,
+    last_used: Instant,
+}
+
+/// A snapshot of the DNS cache that can be persisted across restarts.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsCacheSnapshot {
+    /// When the snapshot was taken, in seconds since the UNIX epoch.
+    taken_at: u64,
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SnapshotEntry {
+    /// When the entry expires, in seconds since the UNIX epoch.
+    expires_at: u64,
+    /// The response in wire-format, with its TTLs relative to [`DnsCacheSnapshot::taken_at`].
+    response: Vec<u8>,
 }
 
 impl DnsCache {
-    pub fn try_answer(&self, query: &Query, now: Instant) -> Option<Response> {
+    /// Creates a new cache, restoring all entries from the given snapshot that have not yet expired.
+    pub fn new(snapshot: DnsCacheSnapshot, now: Instant, unix_now: Duration) -> Self {
+        let mut cache = Self::default();
+        let unix_now = unix_now.as_secs();
+        let elapsed = Duration::from_secs(unix_now.saturating_sub(snapshot.taken_at));
+
+        for entry in snapshot.entries.into_iter().take(MAX_ENTRIES) {
+            if entry.expires_at <= unix_now {
+                continue;
+            }
+
+            let response = match Response::parse(&entry.response) {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::debug!("Failed to parse cached DNS response: {e}");
+                    continue;
+                }
+            };
+
+            let domain = response.domain();
+            let qtype = response.qtype();
+            let query = Query::new(domain.clone(), qtype);
+
+            let response = ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+                .with_records(decrement_ttls(&response, elapsed))
+                .build();
+            let ttl = Duration::from_secs(entry.expires_at - unix_now);
+
+            cache.restored.insert((domain, qtype), response, now, ttl);
+        }
+
+        tracing::debug!(
+            num_entries = cache.restored.iter().count(),
+            "Restored DNS cache"
+        );
+
+        cache
+    }
+
+    pub fn try_answer(&mut self, query: &Query, now: Instant) -> Option<Response> {
         let domain = query.domain();
         let qtype = query.qtype();
 
-        let entry = self.inner.get(&(domain.clone(), qtype))?;
+        let entry = self.inner.get_mut(&(domain.clone(), qtype))?;
+
+        if now >= entry.value.stale_at {
+            return None;
+        }
+
+        entry.value.last_used = now;
+
+        let elapsed = now.saturating_duration_since(entry.inserted_at);
+        let response = ResponseBuilder::for_query(query, ResponseCode::NOERROR)
+            .with_records(decrement_ttls(&entry.value.response, elapsed))
+            .build();
+
+        tracing::trace!(%domain, records = ?fmt_friendly_records(&response), remaining_ttl = ?response.ttl(qtype), "Cache hit");
+
+        Some(response)
+    }
+
+    /// Answers a query from the entries restored from a previous session.
+    ///
+    /// Must only be called for queries that are going to be forwarded to an upstream resolver.
+    pub fn try_answer_restored(&self, query: &Query, now: Instant) -> Option<Response> {
+        let domain = query.domain();
+        let qtype = query.qtype();
+
+        let entry = self.restored.get(&(domain.clone(), qtype))?;
+
+        let elapsed = now.saturating_duration_since(entry.inserted_at);
+        let response = ResponseBuilder::for_query(query, ResponseCode::NOERROR)
+            .with_records(decrement_ttls(&entry.value, elapsed))
+            .build();
+
+        tracing::trace!(%domain, records = ?fmt_friendly_records(&response), remaining_ttl = ?response.ttl(qtype), "Restored cache hit");
+
+        Some(response)
+    }
 
-        let records = entry.value.records().map(|r| {
-            let original_ttl = r.ttl();
-            let elapsed = now.saturating_duration_since(entry.inserted_at);
-            let elapsed_secs = elapsed.as_secs().min(original_ttl.as_secs() as u64);
-            let new_ttl =
-                Ttl::from_secs(original_ttl.as_secs().saturating_sub(elapsed_secs as u32));
+    /// Answers a query with an expired entry, as per RFC 8767.
+    ///
+    /// Must only be used if our upstream resolvers fail to answer.
+    pub fn try_answer_stale(&self, query: &Query) -> Option<Response> {
+        let domain = query.domain();
+        let qtype = query.qtype();
+
+        let entry = self.inner.get(&(domain.clone(), qtype))?;
 
+        let records = entry.value.response.records().map(|r| {
             OwnedRecord::new(
                 r.owner().flatten_into(),
                 r.class(),
-                new_ttl,
+                STALE_ANSWER_TTL,
                 r.into_data().flatten_into(),
             )
         });
@@ -38,11 +161,82 @@ impl DnsCache {
             .with_records(records)
             .build();
 
-        tracing::trace!(%domain, records = ?fmt_friendly_records(&response), remaining_ttl = ?response.ttl(qtype), "Cache hit");
+        tracing::debug!(%domain, %qtype, records = ?fmt_friendly_records(&response), "Serving stale DNS answer");
 
         Some(response)
     }
 
+    /// Takes a snapshot of all fresh entries for which `is_persistable` returns `true`.
+    ///
+    /// If they don't all fit into [`MAX_SNAPSHOT_SIZE`], the most recently used ones are kept.
+    pub fn snapshot(
+        &self,
+        is_persistable: impl Fn(&DomainName) -> bool,
+        now: Instant,
+        unix_now: Duration,
+    ) -> DnsCacheSnapshot {
+        let taken_at = unix_now.as_secs();
+
+        let fresh = self
+            .inner
+            .iter()
+            .filter(|(_, entry)| now < entry.value.stale_at)
+            .map(|(key, entry)| {
+                (
+                    key,
+                    &entry.value.response,
+                    entry.inserted_at,
+                    entry.value.stale_at,
+                    entry.value.last_used,
+                )
+            });
+        let restored = self
+            .restored
+            .iter()
+            .filter(|(key, _)| self.inner.get(key).is_none_or(|e| now >= e.value.stale_at))
+            .filter(|(_, entry)| now < entry.expires_at)
+            .map(|(key, entry)| {
+                (
+                    key,
+                    &entry.value,
+                    entry.inserted_at,
+                    entry.expires_at,
+                    entry.inserted_at,
+                )
+            });
+
+        let mut candidates = fresh
+            .chain(restored)
+            .filter(|((domain, _), ..)| is_persistable(domain))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(.., last_used)| cmp::Reverse(*last_used));
+
+        let mut size = 0;
+        let entries = candidates
+            .into_iter()
+            .take(MAX_ENTRIES)
+            .map(|((domain, qtype), response, inserted_at, expires_at, _)| {
+                let query = Query::new(domain.clone(), *qtype);
+                let elapsed = now.saturating_duration_since(inserted_at);
+                let response = ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+                    .with_records(decrement_ttls(response, elapsed))
+                    .build();
+
+                SnapshotEntry {
+                    expires_at: taken_at + expires_at.duration_since(now).as_secs(),
+                    response: response.into_bytes(u16::MAX),
+                }
+            })
+            .take_while(|entry| {
+                size += entry.response.len();
+
+                size <= MAX_SNAPSHOT_SIZE
+            })
+            .collect();
+
+        DnsCacheSnapshot { taken_at, entries }
+    }
+
     pub fn flush(&mut self, reason: &'static str) {
         tracing::trace!("Flushing DNS cache ({reason})");
 
@@ -77,30 +271,90 @@ impl DnsCache {
             return;
         }
 
+        let key = (domain, qtype);
+
+        if self.inner.get(&key).is_none() && self.inner.len() >= MAX_ENTRIES {
+            self.evict_least_recently_used();
+        }
+
+        let (domain, qtype) = &key;
         tracing::trace!(%domain, %qtype, records = ?fmt_friendly_records(response), ?ttl, "New entry");
 
-        self.inner
-            .insert((domain, qtype), response.clone(), now, ttl);
+        self.inner.insert(
+            key,
+            CachedResponse {
+                response: response.clone(),
+                stale_at: now + ttl,
+                last_used: now,
+            },
+            now,
+            ttl + MAX_STALE_DURATION,
+        );
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let Some(key) = self
+            .inner
+            .iter()
+            .min_by_key(|(_, entry)| entry.value.last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+
+        self.inner.remove(&key);
+
+        let (domain, qtype) = key;
+        tracing::trace!(%domain, %qtype, "Evicted least recently used entry");
     }
 
     pub fn handle_timeout(&mut self, now: Instant) {
         self.inner.handle_timeout(now);
+        self.restored.handle_timeout(now);
 
         while let Some(event) = self.inner.poll_event() {
             let expiring_map::Event::EntryExpired {
                 key: (domain, qtype),
-                value: response,
+                value: CachedResponse { response, .. },
             } = event;
 
             tracing::trace!(%domain, %qtype, records = ?fmt_friendly_records(&response), "Entry expired");
         }
+
+        while let Some(event) = self.restored.poll_event() {
+            let expiring_map::Event::EntryExpired {
+                key: (domain, qtype),
+                value: response,
+            } = event;
+
+            tracing::trace!(%domain, %qtype, records = ?fmt_friendly_records(&response), "Restored entry expired");
+        }
     }
 
     pub fn poll_timeout(&self) -> Option<Instant> {
-        self.inner.poll_timeout()
+        iter::empty()
+            .chain(self.inner.poll_timeout())
+            .chain(self.restored.poll_timeout())
+            .min()
     }
 }
 
+/// Decrements the TTL of all records in the response by the given duration.
+fn decrement_ttls(response: &Response, elapsed: Duration) -> impl Iterator<Item = OwnedRecord> {
+    response.records().map(move |r| {
+        let original_ttl = r.ttl();
+        let elapsed_secs = elapsed.as_secs().min(original_ttl.as_secs() as u64);
+        let new_ttl = Ttl::from_secs(original_ttl.as_secs().saturating_sub(elapsed_secs as u32));
+
+        OwnedRecord::new(
+            r.owner().flatten_into(),
+            r.class(),
+            new_ttl,
+            r.into_data().flatten_into(),
+        )
+    })
+}
+
 #[expect(
     clippy::wildcard_enum_match_arm,
     reason = "We don't want to enumerate all record types."
@@ -215,6 +469,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Dual,
         );
 
@@ -229,4 +484,146 @@ mod tests {
 
         assert!(result.is_none());
     }
+
+    #[test]
+    fn serves_stale_answer_after_ttl_expired() {
+        let mut cache = DnsCache::default();
+        let mut now = Instant::now();
+
+        let domain = DomainName::vec_from_str("example.com").unwrap();
+        let query = Query::new(domain.clone(), RecordType::A);
+        let response = dns_types::ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+            .with_records(iter::once((
+                domain.clone(),
+                60,
+                records::a(Ipv4Addr::LOCALHOST),
+            )))
+            .build();
+
+        cache.insert(domain, &response, now);
+
+        now += Duration::from_secs(100);
+        cache.handle_timeout(now);
+
+        assert!(cache.try_answer(&query, now).is_none());
+        assert_eq!(
+            cache.try_answer_stale(&query).unwrap().ttl(RecordType::A),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entry_when_full() {
+        let mut cache = DnsCache::default();
+        let mut now = Instant::now();
+
+        let queries = (0..=MAX_ENTRIES)
+            .map(|i| {
+                let domain = DomainName::vec_from_str(&format!("{i}.example.com")).unwrap();
+
+                Query::new(domain, RecordType::A)
+            })
+            .collect::<Vec<_>>();
+        let (last, queries) = queries.split_last().unwrap();
+
+        for query in queries {
+            cache.insert(query.domain(), &a_response(query), now);
+            now += Duration::from_millis(1);
+        }
+        cache.try_answer(&queries[0], now).unwrap();
+
+        cache.insert(last.domain(), &a_response(last), now);
+
+        assert!(cache.try_answer(&queries[0], now).is_some());
+        assert!(cache.try_answer(&queries[1], now).is_none());
+        assert!(cache.try_answer(last, now).is_some());
+    }
+
+    #[test]
+    fn restored_snapshot_honors_remaining_ttl() {
+        let mut cache = DnsCache::default();
+        let mut now = Instant::now();
+        let unix_now = Duration::from_secs(1_700_000_000);
+
+        let domain = DomainName::vec_from_str("example.com").unwrap();
+        let query = Query::new(domain.clone(), RecordType::A);
+        let response = dns_types::ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+            .with_records(iter::once((
+                domain.clone(),
+                3600,
+                records::a(Ipv4Addr::LOCALHOST),
+            )))
+            .build();
+
+        cache.insert(domain, &response, now);
+        now += Duration::from_secs(100);
+
+        let snapshot = cache.snapshot(|_| true, now, unix_now);
+        let mut cache = DnsCache::new(snapshot, now, unix_now + Duration::from_secs(200));
+
+        assert!(cache.try_answer(&query, now).is_none());
+        assert_eq!(
+            cache
+                .try_answer_restored(&query, now)
+                .unwrap()
+                .ttl(RecordType::A),
+            Some(Duration::from_secs(3300))
+        );
+    }
+
+    #[test]
+    fn does_not_restore_expired_entries() {
+        let mut cache = DnsCache::default();
+        let now = Instant::now();
+        let unix_now = Duration::from_secs(1_700_000_000);
+
+        let domain = DomainName::vec_from_str("example.com").unwrap();
+        let query = Query::new(domain.clone(), RecordType::A);
+        let response = dns_types::ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+            .with_records(iter::once((
+                domain.clone(),
+                60,
+                records::a(Ipv4Addr::LOCALHOST),
+            )))
+            .build();
+
+        cache.insert(domain, &response, now);
+
+        let snapshot = cache.snapshot(|_| true, now, unix_now);
+        let cache = DnsCache::new(snapshot, now, unix_now + Duration::from_secs(60));
+
+        assert!(cache.try_answer_restored(&query, now).is_none());
+    }
+
+    #[test]
+    fn snapshot_skips_filtered_entries() {
+        let mut cache = DnsCache::default();
+        let now = Instant::now();
+
+        let domain = DomainName::vec_from_str("example.com").unwrap();
+        let query = Query::new(domain.clone(), RecordType::A);
+        let response = dns_types::ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+            .with_records(iter::once((
+                domain.clone(),
+                3600,
+                records::a(Ipv4Addr::LOCALHOST),
+            )))
+            .build();
+
+        cache.insert(domain, &response, now);
+
+        let snapshot = cache.snapshot(|_| false, now, Duration::ZERO);
+
+        assert_eq!(snapshot, DnsCacheSnapshot::default());
+    }
+
+    fn a_response(query: &Query) -> Response {
+        dns_types::ResponseBuilder::for_query(query, ResponseCode::NOERROR)
+            .with_records(iter::once((
+                query.domain(),
+                3600,
+                records::a(Ipv4Addr::LOCALHOST),
+            )))
+            .build()
+    }
 }
@@ -8,7 +8,8 @@ use ip_network::IpNetwork;
 
 use crate::{
     client::{DNS_SENTINELS_V4, DNS_SENTINELS_V6, IpProvider},
-    dns::{self, DNS_PORT},
+    dns::{self, DNS_PORT, DOT_PORT},
+    messages::UpstreamDoT,
 };
 
 #[derive(Debug, Default)]
@@ -18,8 +19,13 @@ pub(crate) struct DnsConfig {
     /// The Do53 resolvers configured in the portal.
     ///
     /// Has priority over system-configured DNS servers.
-    /// Has priority over DoH resolvers.
+    /// Has priority over DoT and DoH resolvers.
     upstream_do53: Vec<IpAddr>,
+    /// The DoT resolvers configured in the portal.
+    ///
+    /// Has priority over system-configured DNS servers.
+    /// Has priority over DoH resolvers.
+    upstream_dot: Vec<UpstreamDoT>,
     /// The DoH resolvers configured in the portal.
     ///
     /// Has priority over system-configured DNS servers.
@@ -104,8 +110,19 @@ impl DnsConfig {
         self.update_dns_mapping()
     }
 
+    #[must_use = "Check if the DNS mapping has changed"]
+    pub(crate) fn update_upstream_dot_resolvers(&mut self, servers: Vec<UpstreamDoT>) -> bool {
+        tracing::debug!(?servers, "Received upstream-defined DoT servers");
+
+        self.upstream_dot = servers;
+
+        self.update_dns_mapping()
+    }
+
     pub(crate) fn has_custom_upstream(&self) -> bool {
-        !self.upstream_do53.is_empty() || !self.upstream_doh.is_empty()
+        !self.upstream_do53.is_empty()
+            || !self.upstream_dot.is_empty()
+            || !self.upstream_doh.is_empty()
     }
 
     pub(crate) fn mapping(&mut self) -> DnsMapping {
@@ -119,6 +136,7 @@ impl DnsConfig {
     fn update_dns_mapping(&mut self) -> bool {
         let effective_dns_servers = effective_dns_servers(
             self.upstream_do53.clone(),
+            self.upstream_dot.clone(),
             self.upstream_doh.clone(),
             self.system_resolvers.clone(),
         );
@@ -139,6 +157,7 @@ impl DnsConfig {
 
 fn effective_dns_servers(
     upstream_do53: Vec<IpAddr>,
+    upstream_dot: Vec<UpstreamDoT>,
     upstream_doh: Vec<DoHUrl>,
     default_resolvers: Vec<IpAddr>,
 ) -> Vec<dns::Upstream> {
@@ -151,6 +170,16 @@ fn effective_dns_servers(
             .collect();
     }
 
+    if !upstream_dot.is_empty() {
+        return upstream_dot
+            .into_iter()
+            .map(|UpstreamDoT { ip, tls_name }| dns::Upstream::DoT {
+                server: SocketAddr::new(ip, DOT_PORT),
+                tls_name,
+            })
+            .collect();
+    }
+
     if !upstream_doh.is_empty() {
         return upstream_doh
             .into_iter()
@@ -180,7 +209,7 @@ fn sentinel_dns_mapping(dns: &[dns::Upstream], old_sentinels: Vec<IpAddr>) -> Dn
         .iter()
         .map(|u| {
             let ip_addr = match u {
-                dns::Upstream::Do53 { server } => server.ip(),
+                dns::Upstream::Do53 { server } | dns::Upstream::DoT { server, .. } => server.ip(),
                 dns::Upstream::DoH { .. } => IpAddr::V4(Ipv4Addr::UNSPECIFIED), // DoH servers are always mapped to IPv4 servers.
             };
 
@@ -278,6 +307,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn prefers_upstream_dot_over_doh() {
+        let mut config = DnsConfig::default();
+
+        let changed = config.update_upstream_doh_resolvers(vec![DoHUrl::cloudflare()]);
+        assert!(changed);
+        let changed = config.update_upstream_dot_resolvers(vec![UpstreamDoT {
+            ip: ip("1.1.1.1"),
+            tls_name: "one.one.one.one".to_owned(),
+        }]);
+        assert!(changed);
+
+        assert_eq!(config.mapping().sentinel_ips().len(), 1);
+        assert_eq!(
+            config.mapping().upstream_servers(),
+            vec![dns::Upstream::DoT {
+                server: "1.1.1.1:853".parse().unwrap(),
+                tls_name: "one.one.one.one".to_owned(),
+            }]
+        );
+    }
+
     fn ip(address: &str) -> IpAddr {
         address.parse().unwrap()
     }
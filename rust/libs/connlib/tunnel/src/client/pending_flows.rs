@@ -4,7 +4,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use connlib_model::ResourceId;
+use connlib_model::{GatewayId, ResourceId};
 use ip_packet::IpPacket;
 use ringbuffer::{AllocRingBuffer, RingBuffer as _};
 
@@ -34,10 +34,12 @@ impl PendingFlows {
             return;
         };
 
-        let pending_flow = self
-            .inner
-            .entry(rid)
-            .or_insert_with(|| PendingFlow::new(now - Duration::from_secs(10))); // Insert with a negative time to ensure we instantly send an intent.
+        let pending_flow = self.inner.entry(rid).or_insert_with(|| {
+            PendingFlow::new(
+                now - Duration::from_secs(10), // Insert with a negative time to ensure we instantly send an intent.
+                trigger.is_for_additional_gateway(),
+            )
+        });
 
         pending_flow.push(trigger);
 
@@ -58,6 +60,13 @@ impl PendingFlows {
         self.inner.remove(rid)
     }
 
+    /// Whether the pending flow for this resource is only about connecting to an additional Gateway.
+    pub fn is_for_additional_gateway(&self, rid: &ResourceId) -> bool {
+        self.inner
+            .get(rid)
+            .is_some_and(PendingFlow::is_for_additional_gateway)
+    }
+
     pub fn poll_connection_intents(&mut self) -> Option<ResourceId> {
         self.connection_intents.pop_front()
     }
@@ -65,6 +74,8 @@ impl PendingFlows {
 
 pub struct PendingFlow {
     last_intent_sent_at: Instant,
+    for_additional_gateway: bool,
+    rejected_by: Option<GatewayId>,
     resource_packets: UniquePacketBuffer,
     dns_queries: AllocRingBuffer<DnsQueryForSite>,
 }
@@ -76,9 +87,11 @@ impl PendingFlow {
     /// Thus, we may receive a fair few packets before we can send them.
     const CAPACITY_POW_2: usize = 7; // 2^7 = 128
 
-    fn new(now: Instant) -> Self {
+    fn new(now: Instant, for_additional_gateway: bool) -> Self {
         Self {
             last_intent_sent_at: now,
+            for_additional_gateway,
+            rejected_by: None,
             resource_packets: UniquePacketBuffer::with_capacity_power_of_2(
                 Self::CAPACITY_POW_2,
                 "pending-flow-resources",
@@ -88,15 +101,31 @@ impl PendingFlow {
     }
 
     fn push(&mut self, trigger: ConnectionTrigger) {
+        // As soon as anything else needs this connection, we can no longer treat it as optional.
+        self.for_additional_gateway &= trigger.is_for_additional_gateway();
+
         match trigger {
             ConnectionTrigger::PacketForResource(packet) => self.resource_packets.push(packet),
             ConnectionTrigger::DnsQueryForSite(query) => {
                 self.dns_queries.enqueue(query);
             }
-            ConnectionTrigger::IcmpDestinationUnreachableProhibited => {}
+            ConnectionTrigger::IcmpDestinationUnreachableProhibited(gid) => {
+                self.rejected_by = Some(gid);
+            }
+            ConnectionTrigger::AdditionalGateway => {}
+            ConnectionTrigger::GatewayDraining => {}
         }
     }
 
+    pub fn is_for_additional_gateway(&self) -> bool {
+        self.for_additional_gateway
+    }
+
+    /// The Gateway that rejected our traffic for this resource, if that is what triggered the connection.
+    pub fn rejected_by(&self) -> Option<GatewayId> {
+        self.rejected_by
+    }
+
     pub fn into_buffered_packets(self) -> (UniquePacketBuffer, AllocRingBuffer<DnsQueryForSite>) {
         let Self {
             resource_packets,
@@ -117,7 +146,11 @@ pub enum ConnectionTrigger {
     /// We have received an ICMP error that is marked as "access prohibited".
     ///
     /// Most likely, the Gateway is filtering these packets because the Client doesn't have access (anymore).
-    IcmpDestinationUnreachableProhibited,
+    IcmpDestinationUnreachableProhibited(GatewayId),
+    /// We are already connected to a Gateway for this resource and want to spread flows across another Gateway of the site.
+    AdditionalGateway,
+    /// The Gateway we use for this resource is about to shut down and we need another Gateway of the site for new flows.
+    GatewayDraining,
 }
 
 pub struct DnsQueryForSite {
@@ -128,13 +161,22 @@ pub struct DnsQueryForSite {
 }
 
 impl ConnectionTrigger {
+    fn is_for_additional_gateway(&self) -> bool {
+        matches!(
+            self,
+            ConnectionTrigger::AdditionalGateway | ConnectionTrigger::GatewayDraining
+        )
+    }
+
     fn name(&self) -> &'static str {
         match self {
             ConnectionTrigger::PacketForResource(_) => "packet-for-resource",
             ConnectionTrigger::DnsQueryForSite(_) => "dns-query-for-site",
-            ConnectionTrigger::IcmpDestinationUnreachableProhibited => {
+            ConnectionTrigger::IcmpDestinationUnreachableProhibited(_) => {
                 "icmp-destination-unreachable-prohibited"
             }
+            ConnectionTrigger::AdditionalGateway => "additional-gateway",
+            ConnectionTrigger::GatewayDraining => "gateway-draining",
         }
     }
 }
@@ -213,6 +255,33 @@ mod tests {
         assert_eq!(pending_flows.poll_connection_intents(), Some(rid2));
     }
 
+    #[test]
+    fn other_triggers_make_additional_gateway_flow_mandatory() {
+        let mut pending_flows = PendingFlows::default();
+        let now = Instant::now();
+        let rid = ipv4_localhost_resource().id();
+        let resources = BTreeMap::from([(rid, ipv4_localhost_resource())]);
+
+        pending_flows.on_not_connected_resource(
+            rid,
+            ConnectionTrigger::AdditionalGateway,
+            &resources,
+            now,
+        );
+        assert!(pending_flows.is_for_additional_gateway(&rid));
+
+        pending_flows.on_not_connected_resource(rid, trigger(1), &resources, now);
+        assert!(!pending_flows.is_for_additional_gateway(&rid));
+
+        pending_flows.on_not_connected_resource(
+            rid,
+            ConnectionTrigger::GatewayDraining,
+            &resources,
+            now,
+        );
+        assert!(!pending_flows.is_for_additional_gateway(&rid));
+    }
+
     fn trigger(payload: u8) -> IpPacket {
         ip_packet::make::udp_packet(
             Ipv4Addr::LOCALHOST,
@@ -37,6 +37,11 @@ pub struct DnsResource {
     pub sites: Vec<Site>,
 
     pub ip_stack: IpStack,
+
+    /// Patterns of sub-domains that are not part of this resource.
+    ///
+    /// Queries for these are resolved by the system's resolvers instead.
+    pub exclusions: Vec<String>,
 }
 
 /// Description of a resource that maps to a CIDR.
@@ -164,7 +169,9 @@ impl Resource {
 
     pub fn has_different_address(&self, other: &Resource) -> bool {
         match (self, other) {
-            (Resource::Dns(dns_a), Resource::Dns(dns_b)) => dns_a.address != dns_b.address,
+            (Resource::Dns(dns_a), Resource::Dns(dns_b)) => {
+                dns_a.address != dns_b.address || dns_a.exclusions != dns_b.exclusions
+            }
             (Resource::Cidr(cidr_a), Resource::Cidr(cidr_b)) => cidr_a.address != cidr_b.address,
             (Resource::Internet(_), Resource::Internet(_)) => false,
             _ => true,
@@ -283,6 +290,7 @@ impl DnsResource {
             address_description: resource.address_description,
             sites: resource.sites,
             ip_stack: resource.ip_stack.unwrap_or(IpStack::Dual),
+            exclusions: resource.exclusions,
         }
     }
 
@@ -377,6 +385,25 @@ mod tests {
         assert_eq!(dns.ip_stack, IpStack::Dual)
     }
 
+    #[test]
+    fn can_deserialize_dns_resource_with_exclusions() {
+        let resource = Resource::from_description(ResourceDescription::Dns(serde_json::json!({
+            "address": "*.corp.example.com",
+            "id": "03000143-e25e-45c7-aafb-144990e57dce",
+            "name": "corp.example.com",
+            "gateway_groups": [{"name": "test", "id": "bf56f32d-7b2c-4f5d-a784-788977d014a4"}],
+            "type": "dns",
+            "exclusions": ["public.corp.example.com"]
+        })))
+        .unwrap();
+
+        let Resource::Dns(dns) = resource else {
+            panic!("Unexpected resource")
+        };
+
+        assert_eq!(dns.exclusions, vec!["public.corp.example.com".to_owned()])
+    }
+
     #[test]
     fn name_changes_of_site_doesnt_matter() {
         let resource1 = Resource::from_description(ResourceDescription::Dns(serde_json::json!({
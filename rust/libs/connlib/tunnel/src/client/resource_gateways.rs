@@ -0,0 +1,479 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher as _},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use connlib_model::{GatewayId, ResourceId};
+use ip_packet::{IpPacket, Protocol};
+
+/// How many Gateways of a site we at most keep connections to for a single resource.
+pub const MAX_GATEWAYS_PER_RESOURCE: usize = 2;
+
+/// After how long of not seeing any packets we forget which Gateway a flow is pinned to.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Tracks the resources we have been authorized for and which Gateways to use to access them.
+///
+/// A resource may be authorized on more than one Gateway of a site.
+/// New flows are spread across all connected Gateways by hashing their 5-tuple.
+/// Once a flow has been assigned to a Gateway, it stays pinned to it until the flow is idle or the Gateway goes away.
+/// Draining Gateways keep their pinned flows but don't get any new ones, unless there is no other Gateway for the resource.
+///
+/// While there is only one Gateway to choose from, which is by far the most common case, we don't pin flows.
+/// Instead, we remember that Gateway and keep sending flows without a pin to it until they must have gone idle.
+#[derive(Default)]
+pub struct ResourceGateways {
+    /// The Gateways per resource, in the order we have been authorized on them.
+    ///
+    /// The first Gateway is the "primary" one, i.e. the one we use for things that are not flows, like DNS queries.
+    inner: HashMap<ResourceId, Vec<GatewayId>>,
+
+    pinned_flows: HashMap<(ResourceId, FlowKey), PinnedFlow>,
+
+    /// The Gateway we last used without pinning because it was the only one, per resource.
+    sole_gateways: HashMap<ResourceId, PinnedFlow>,
+
+    /// Gateways that are about to shut down.
+    draining: HashSet<GatewayId>,
+}
+
+impl ResourceGateways {
+    /// The Gateway we have first been authorized on for this resource.
+    pub fn primary(&self, rid: &ResourceId) -> Option<GatewayId> {
+        self.inner.get(rid)?.first().copied()
+    }
+
+    pub fn gateways(&self, rid: &ResourceId) -> &[GatewayId] {
+        self.inner.get(rid).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn contains(&self, rid: &ResourceId, gid: &GatewayId) -> bool {
+        self.gateways(rid).contains(gid)
+    }
+
+    /// All resources we access through the given Gateway.
+    pub fn resources(&self, gid: &GatewayId) -> BTreeSet<ResourceId> {
+        #[expect(
+            clippy::disallowed_methods,
+            reason = "We are collecting into a sorted set"
+        )]
+        self.inner
+            .iter()
+            .filter(|(_, gateways)| gateways.contains(gid))
+            .map(|(rid, _)| *rid)
+            .collect()
+    }
+
+    /// Records that the Gateway is about to shut down.
+    ///
+    /// Returns `true` if we didn't know about this yet.
+    pub fn set_draining(&mut self, gid: GatewayId) -> bool {
+        self.draining.insert(gid)
+    }
+
+    pub fn is_draining(&self, gid: &GatewayId) -> bool {
+        self.draining.contains(gid)
+    }
+
+    /// Whether we can access the resource through a Gateway that is not draining.
+    pub fn has_non_draining_gateway(&self, rid: &ResourceId) -> bool {
+        self.gateways(rid)
+            .iter()
+            .any(|gid| !self.draining.contains(gid))
+    }
+
+    /// Records that we are authorized to access the resource through the given Gateway.
+    ///
+    /// Returns `true` if this is a new Gateway for this resource.
+    pub fn insert(&mut self, rid: ResourceId, gid: GatewayId) -> bool {
+        let gateways = self.inner.entry(rid).or_default();
+
+        if gateways.contains(&gid) {
+            return false;
+        }
+
+        gateways.push(gid);
+
+        true
+    }
+
+    /// Only retains the Gateways of the given resource for which `f` returns `true`.
+    pub fn retain(&mut self, rid: &ResourceId, mut f: impl FnMut(&GatewayId) -> bool) {
+        let Some(gateways) = self.inner.get_mut(rid) else {
+            return;
+        };
+
+        gateways.retain(|gid| f(gid));
+
+        if gateways.is_empty() {
+            self.inner.remove(rid);
+        }
+
+        self.pinned_flows
+            .retain(|(r, _), flow| r != rid || f(&flow.gateway));
+        self.sole_gateways
+            .retain(|r, sole| r != rid || f(&sole.gateway));
+    }
+
+    pub fn remove_resource(&mut self, rid: &ResourceId) {
+        self.inner.remove(rid);
+        self.pinned_flows.retain(|(r, _), _| r != rid);
+        self.sole_gateways.remove(rid);
+    }
+
+    /// Removes the Gateway from all resources.
+    ///
+    /// Flows pinned to this Gateway will be re-assigned to one of the remaining Gateways of the resource.
+    pub fn remove_gateway(&mut self, gid: &GatewayId) {
+        self.inner.retain(|_, gateways| {
+            gateways.retain(|g| g != gid);
+
+            !gateways.is_empty()
+        });
+        self.pinned_flows.retain(|_, flow| &flow.gateway != gid);
+        self.sole_gateways.retain(|_, sole| &sole.gateway != gid);
+        self.draining.remove(gid);
+    }
+
+    /// Selects the Gateway to use for the flow of the given packet.
+    ///
+    /// Only Gateways for which `is_connected` returns `true` are considered.
+    /// This runs for every packet, thus it must not allocate.
+    pub fn gateway_for_flow(
+        &mut self,
+        rid: ResourceId,
+        packet: &IpPacket,
+        is_connected: impl Fn(&GatewayId) -> bool,
+        now: Instant,
+    ) -> Option<GatewayId> {
+        let gateways = self.inner.get(&rid)?;
+        let key = FlowKey::new(packet);
+
+        if let Some(flow) = self.pinned_flows.get_mut(&(rid, key))
+            && gateways.contains(&flow.gateway)
+            && is_connected(&flow.gateway)
+        {
+            flow.last_used = now;
+
+            return Some(flow.gateway);
+        }
+
+        let draining = &self.draining;
+        let is_candidate = |gid: &GatewayId| is_connected(gid) && !draining.contains(gid);
+        let num_candidates = gateways.iter().filter(|gid| is_candidate(gid)).count();
+
+        if num_candidates == 1 {
+            let gateway = *gateways.iter().find(|gid| is_candidate(gid))?;
+
+            self.sole_gateways.insert(
+                rid,
+                PinnedFlow {
+                    gateway,
+                    last_used: now,
+                },
+            );
+
+            return Some(gateway);
+        }
+
+        // Connecting to another Gateway must not move flows that started while there was only one.
+        let sole_gateway = self
+            .sole_gateways
+            .get(&rid)
+            .filter(|sole| {
+                now.duration_since(sole.last_used) < FLOW_IDLE_TIMEOUT
+                    && gateways.contains(&sole.gateway)
+                    && is_connected(&sole.gateway)
+            })
+            .map(|sole| sole.gateway);
+
+        let gateway = match sole_gateway {
+            Some(gateway) => gateway,
+            None if num_candidates > 0 => select_by_hash(
+                &key,
+                gateways.iter().filter(|gid| is_candidate(gid)),
+                num_candidates,
+            )?,
+            None => {
+                // All connected Gateways are draining, we have no choice but to use one of them.
+                let num_connected = gateways.iter().filter(|gid| is_connected(gid)).count();
+
+                select_by_hash(
+                    &key,
+                    gateways.iter().filter(|gid| is_connected(gid)),
+                    num_connected,
+                )?
+            }
+        };
+
+        tracing::trace!(%rid, %gateway, ?key, "Pinning new flow to Gateway");
+
+        self.pinned_flows.insert(
+            (rid, key),
+            PinnedFlow {
+                gateway,
+                last_used: now,
+            },
+        );
+
+        Some(gateway)
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.pinned_flows
+            .retain(|_, flow| now.duration_since(flow.last_used) < FLOW_IDLE_TIMEOUT);
+        self.sole_gateways
+            .retain(|_, sole| now.duration_since(sole.last_used) < FLOW_IDLE_TIMEOUT);
+    }
+}
+
+fn select_by_hash<'a>(
+    key: &FlowKey,
+    mut candidates: impl Iterator<Item = &'a GatewayId>,
+    num_candidates: usize,
+) -> Option<GatewayId> {
+    if num_candidates == 0 {
+        return None;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let index = (hasher.finish() % num_candidates as u64) as usize;
+
+    candidates.nth(index).copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    src: IpAddr,
+    dst: IpAddr,
+    src_proto: Option<Protocol>,
+    dst_proto: Option<Protocol>,
+}
+
+impl FlowKey {
+    fn new(packet: &IpPacket) -> Self {
+        Self {
+            src: packet.source(),
+            dst: packet.destination(),
+            src_proto: packet.source_protocol().ok(),
+            dst_proto: packet.destination_protocol().ok(),
+        }
+    }
+}
+
+struct PinnedFlow {
+    gateway: GatewayId,
+    last_used: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn single_gateway_is_used_for_all_flows() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+
+        for port in 1..100 {
+            let gateway = gateways.gateway_for_flow(RID, &packet(port), |_| true, Instant::now());
+
+            assert_eq!(gateway, Some(GID_A));
+        }
+    }
+
+    #[test]
+    fn flows_are_spread_across_gateways() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.insert(RID, GID_B);
+
+        let selected = (1..100)
+            .filter_map(|port| {
+                gateways.gateway_for_flow(RID, &packet(port), |_| true, Instant::now())
+            })
+            .collect::<Vec<_>>();
+
+        assert!(selected.contains(&GID_A));
+        assert!(selected.contains(&GID_B));
+    }
+
+    #[test]
+    fn existing_flows_stay_pinned_when_new_gateway_is_added() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        let now = Instant::now();
+
+        let before = (1..100)
+            .map(|port| gateways.gateway_for_flow(RID, &packet(port), |_| true, now))
+            .collect::<Vec<_>>();
+        assert!(before.iter().all(|g| g == &Some(GID_A)));
+
+        gateways.insert(RID, GID_B);
+
+        let after = (1..100)
+            .map(|port| gateways.gateway_for_flow(RID, &packet(port), |_| true, now))
+            .collect::<Vec<_>>();
+        assert_eq!(before, after);
+
+        gateways.insert(RID, GID_C);
+
+        let after = (1..100)
+            .map(|port| gateways.gateway_for_flow(RID, &packet(port), |_| true, now))
+            .collect::<Vec<_>>();
+        assert_eq!(before, after);
+
+        // Flows that we haven't seen until all flows of the single-Gateway era must have gone idle are new.
+        let later = now + FLOW_IDLE_TIMEOUT;
+        let new_flows = (100..200)
+            .filter_map(|port| gateways.gateway_for_flow(RID, &packet(port), |_| true, later))
+            .collect::<Vec<_>>();
+        assert!(new_flows.contains(&GID_B));
+    }
+
+    #[test]
+    fn flows_are_not_pinned_with_single_gateway() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        let now = Instant::now();
+
+        for port in 1..100 {
+            gateways.gateway_for_flow(RID, &packet(port), |_| true, now);
+        }
+
+        assert!(gateways.pinned_flows.is_empty());
+    }
+
+    #[test]
+    fn flows_fail_over_to_sibling_gateway() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.insert(RID, GID_B);
+        let now = Instant::now();
+
+        for port in 1..100 {
+            gateways.gateway_for_flow(RID, &packet(port), |_| true, now);
+        }
+
+        gateways.remove_gateway(&GID_A);
+
+        for port in 1..100 {
+            let gateway = gateways.gateway_for_flow(RID, &packet(port), |_| true, now);
+
+            assert_eq!(gateway, Some(GID_B));
+        }
+    }
+
+    #[test]
+    fn disconnected_gateways_are_not_selected() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.insert(RID, GID_B);
+
+        for port in 1..100 {
+            let gateway =
+                gateways.gateway_for_flow(RID, &packet(port), |g| g == &GID_B, Instant::now());
+
+            assert_eq!(gateway, Some(GID_B));
+        }
+    }
+
+    #[test]
+    fn new_flows_avoid_draining_gateway() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.insert(RID, GID_B);
+        let now = Instant::now();
+
+        let before = (1..100)
+            .map(|port| gateways.gateway_for_flow(RID, &packet(port), |_| true, now))
+            .collect::<Vec<_>>();
+
+        gateways.set_draining(GID_A);
+
+        for (port, gateway) in (1..100).zip(before) {
+            assert_eq!(
+                gateways.gateway_for_flow(RID, &packet(port), |_| true, now),
+                gateway,
+                "existing flows should stay pinned"
+            );
+        }
+        for port in 100..200 {
+            assert_eq!(
+                gateways.gateway_for_flow(RID, &packet(port), |_| true, now),
+                Some(GID_B)
+            );
+        }
+    }
+
+    #[test]
+    fn flows_stay_on_draining_gateway_when_sibling_connects() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.set_draining(GID_A);
+        let now = Instant::now();
+
+        for port in 1..100 {
+            gateways.gateway_for_flow(RID, &packet(port), |_| true, now);
+        }
+
+        gateways.insert(RID, GID_B);
+
+        for port in 1..100 {
+            assert_eq!(
+                gateways.gateway_for_flow(RID, &packet(port), |_| true, now),
+                Some(GID_A)
+            );
+        }
+        assert_eq!(
+            gateways.gateway_for_flow(RID, &packet(100), |_| true, now),
+            Some(GID_B)
+        );
+    }
+
+    #[test]
+    fn removing_last_gateway_removes_resource() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+
+        gateways.remove_gateway(&GID_A);
+
+        assert_eq!(gateways.primary(&RID), None);
+        assert!(gateways.inner.is_empty());
+    }
+
+    #[test]
+    fn idle_flows_are_unpinned() {
+        let mut gateways = ResourceGateways::default();
+        gateways.insert(RID, GID_A);
+        gateways.insert(RID, GID_B);
+        let now = Instant::now();
+
+        gateways.gateway_for_flow(RID, &packet(1), |_| true, now);
+        assert_eq!(gateways.pinned_flows.len(), 1);
+
+        gateways.handle_timeout(now + FLOW_IDLE_TIMEOUT);
+        assert!(gateways.pinned_flows.is_empty());
+    }
+
+    fn packet(sport: u16) -> IpPacket {
+        ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 1),
+            sport,
+            443,
+            vec![],
+        )
+        .unwrap()
+    }
+
+    const RID: ResourceId = ResourceId::from_u128(1);
+    const GID_A: GatewayId = GatewayId::from_u128(10);
+    const GID_B: GatewayId = GatewayId::from_u128(20);
+    const GID_C: GatewayId = GatewayId::from_u128(30);
+}
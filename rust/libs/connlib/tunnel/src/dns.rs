@@ -1,4 +1,5 @@
 use crate::client::IpProvider;
+use crate::messages::ConditionalForwarder;
 use anyhow::Result;
 use connlib_model::{IpStack, ResourceId};
 use dns_types::{
@@ -20,6 +21,7 @@ const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
 const REVERSE_DNS_ADDRESS_V6: &str = "ip6";
 pub(crate) const DNS_PORT: u16 = 53;
+pub(crate) const DOT_PORT: u16 = 853;
 
 /// The DNS over HTTPS canary domain used by Firefox to check whether DoH can be enabled by default.
 ///
@@ -39,7 +41,11 @@ pub struct StubResolver {
     ip_provider: IpProvider,
     /// All DNS resources we know about, indexed by the glob pattern they match against.
     dns_resources: BTreeMap<Pattern, Resource>,
+    /// Patterns of domains that are excluded from a DNS resource, indexed by the resource.
+    dns_resource_exclusions: BTreeMap<ResourceId, Vec<Pattern>>,
     search_domain: Option<DomainName>,
+    /// Zones that are resolved by a dedicated DNS server, ordered from most to least specific.
+    conditional_forwarders: Vec<(DomainName, SocketAddr)>,
 
     events: VecDeque<Event>,
 }
@@ -105,6 +111,12 @@ pub enum Upstream {
     Do53 { server: SocketAddr },
     #[display("DoH({server})")]
     DoH { server: DoHUrl },
+    #[display("DoT({server}, {tls_name})")]
+    DoT {
+        server: SocketAddr,
+        /// The name we expect in the server's TLS certificate.
+        tls_name: String,
+    },
 }
 
 /// Tells the Client how to reply to a single DNS query
@@ -114,6 +126,8 @@ pub(crate) enum ResolveStrategy {
     LocalResponse(Response),
     /// The query is for a non-Resource, forward it locally to an upstream or system resolver.
     RecurseLocal,
+    /// The query is for a non-Resource within a zone that has a conditional forwarder, forward it to the given server.
+    RecurseForwarder(SocketAddr),
     /// The query is for a DNS resource but for a type that we don't intercept (i.e. SRV, TXT, ...), forward it to the site that hosts the DNS resource and resolve it there.
     RecurseSite(ResourceId),
 }
@@ -162,7 +176,9 @@ impl StubResolver {
             ips_to_fqdn,
             ip_provider,
             dns_resources: Default::default(),
+            dns_resource_exclusions: Default::default(),
             search_domain: Default::default(),
+            conditional_forwarders: Default::default(),
             events: Default::default(),
         }
     }
@@ -186,10 +202,16 @@ impl StubResolver {
             .map(|((domain, resource), ips)| (domain, resource, ips))
     }
 
+    /// Whether the given domain is handled by one of our DNS resources.
+    pub(crate) fn is_resource(&self, domain: &dns_types::DomainName) -> bool {
+        self.match_resource_linear(domain).is_some()
+    }
+
     pub(crate) fn add_resource(
         &mut self,
         id: ResourceId,
         pattern: String,
+        exclusions: &[String],
         ip_stack: IpStack,
     ) -> bool {
         let parsed_pattern = match Pattern::new(&pattern) {
@@ -200,6 +222,23 @@ impl StubResolver {
             }
         };
 
+        let exclusions = exclusions
+            .iter()
+            .filter_map(|exclusion| {
+                Pattern::new(exclusion)
+                    .inspect_err(|e| {
+                        tracing::warn!(%pattern, %exclusion, "Exclusion pattern is not valid: {}", err_with_src(e))
+                    })
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+
+        if exclusions.is_empty() {
+            self.dns_resource_exclusions.remove(&id);
+        } else {
+            self.dns_resource_exclusions.insert(id, exclusions);
+        }
+
         let existing = self
             .dns_resources
             .insert(parsed_pattern, Resource { id, ip_stack });
@@ -209,6 +248,7 @@ impl StubResolver {
 
     pub(crate) fn remove_resource(&mut self, id: ResourceId) {
         self.dns_resources.retain(|_, r| r.id != id);
+        self.dns_resource_exclusions.remove(&id);
     }
 
     fn get_or_assign_a_records(
@@ -273,15 +313,29 @@ impl StubResolver {
     /// Attempts to match the given domain against our list of possible patterns.
     ///
     /// This performs a linear search and is thus O(N) and **must not** be called in the hot-path of packet routing.
+    ///
+    /// A domain that is excluded from a resource continues to be matched against the remaining, less specific patterns.
     fn match_resource_linear(&self, domain: &dns_types::DomainName) -> Option<Resource> {
         let name = Candidate::from_domain(domain);
 
         for (pattern, r) in &self.dns_resources {
-            if pattern.matches(&name) {
-                tracing::trace!(id = %r.id, %pattern, %domain, "Matched resource");
+            if !pattern.matches(&name) {
+                continue;
+            }
 
-                return Some(*r);
+            if let Some(exclusion) = self
+                .dns_resource_exclusions
+                .get(&r.id)
+                .and_then(|exclusions| exclusions.iter().find(|e| e.matches(&name)))
+            {
+                tracing::trace!(id = %r.id, %pattern, %exclusion, %domain, "Domain is excluded from resource");
+
+                continue;
             }
+
+            tracing::trace!(id = %r.id, %pattern, %domain, "Matched resource");
+
+            return Some(*r);
         }
 
         if tracing::enabled!(tracing::Level::TRACE) {
@@ -332,7 +386,7 @@ impl StubResolver {
             }
             (RecordType::PTR, _) => {
                 let Some(fqdn) = self.resource_address_name_by_reservse_dns(&domain) else {
-                    return ResolveStrategy::RecurseLocal;
+                    return self.recurse(&domain);
                 };
 
                 vec![dns_types::records::ptr(fqdn)]
@@ -343,7 +397,7 @@ impl StubResolver {
 
                 return ResolveStrategy::LocalResponse(Response::no_error(query));
             }
-            _ => return ResolveStrategy::RecurseLocal,
+            _ => return self.recurse(&domain),
         };
 
         tracing::trace!(%qtype, %domain, records = ?records, "Forming DNS response");
@@ -355,6 +409,42 @@ impl StubResolver {
         ResolveStrategy::LocalResponse(response)
     }
 
+    /// Selects where to recurse to for a query that is not for one of our resources.
+    fn recurse(&self, domain: &DomainName) -> ResolveStrategy {
+        let Some((zone, server)) = self
+            .conditional_forwarders
+            .iter()
+            .find(|(zone, _)| domain.ends_with(zone))
+        else {
+            return ResolveStrategy::RecurseLocal;
+        };
+
+        tracing::trace!(%domain, %zone, %server, "Matched conditional forwarder");
+
+        ResolveStrategy::RecurseForwarder(*server)
+    }
+
+    pub(crate) fn set_conditional_forwarders(
+        &mut self,
+        forwarders: Vec<ConditionalForwarder>,
+    ) -> bool {
+        let mut new = forwarders
+            .into_iter()
+            .map(|f| (f.zone, SocketAddr::new(f.upstream, DNS_PORT)))
+            .collect::<Vec<_>>();
+        new.sort_by(|(left, _), (right, _)| right.len().cmp(&left.len()).then(left.cmp(right))); // Longer zones are more specific.
+
+        if self.conditional_forwarders == new {
+            return false;
+        }
+
+        tracing::debug!(current = ?self.conditional_forwarders, ?new, "Setting new conditional forwarders");
+
+        self.conditional_forwarders = new;
+
+        true
+    }
+
     pub(crate) fn set_search_domain(&mut self, new_search_domain: Option<DomainName>) {
         if self.search_domain == new_search_domain {
             return;
@@ -729,8 +819,8 @@ mod tests {
         let wc = ResourceId::from_u128(0);
         let non_wc = ResourceId::from_u128(1);
 
-        resolver.add_resource(wc, "**.example.com".to_owned(), IpStack::Dual);
-        resolver.add_resource(non_wc, "foo.example.com".to_owned(), IpStack::Dual);
+        resolver.add_resource(wc, "**.example.com".to_owned(), &[], IpStack::Dual);
+        resolver.add_resource(non_wc, "foo.example.com".to_owned(), &[], IpStack::Dual);
 
         let resource = resolver
             .match_resource_linear(&"foo.example.com".parse().unwrap())
@@ -739,6 +829,55 @@ mod tests {
         assert_eq!(resource.id, non_wc);
     }
 
+    #[test]
+    fn excluded_domain_recurses_locally() {
+        let mut resolver = StubResolver::default();
+
+        resolver.add_resource(
+            ResourceId::from_u128(1),
+            "*.corp.example.com".to_owned(),
+            &["public.corp.example.com".to_owned()],
+            IpStack::Dual,
+        );
+
+        let excluded = resolver.handle(&Query::new(
+            "public.corp.example.com"
+                .parse::<dns_types::DomainName>()
+                .unwrap(),
+            RecordType::A,
+        ));
+        let included = resolver.handle(&Query::new(
+            "app.corp.example.com"
+                .parse::<dns_types::DomainName>()
+                .unwrap(),
+            RecordType::A,
+        ));
+
+        assert!(matches!(excluded, ResolveStrategy::RecurseLocal));
+        assert!(matches!(included, ResolveStrategy::LocalResponse(_)));
+    }
+
+    #[test]
+    fn excluded_domain_falls_through_to_less_specific_resource() {
+        let mut resolver = StubResolver::default();
+        let specific = ResourceId::from_u128(0);
+        let fallback = ResourceId::from_u128(1);
+
+        resolver.add_resource(
+            specific,
+            "*.corp.example.com".to_owned(),
+            &["public.corp.example.com".to_owned()],
+            IpStack::Dual,
+        );
+        resolver.add_resource(fallback, "**.example.com".to_owned(), &[], IpStack::Dual);
+
+        let resource = resolver
+            .match_resource_linear(&"public.corp.example.com".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(resource.id, fallback);
+    }
+
     #[test]
     fn doh_canary_domain_parses_correctly() {
         assert_eq!(DOH_CANARY_DOMAIN.to_string(), "use-application-dns.net")
@@ -770,6 +909,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Ipv6Only,
         );
 
@@ -793,6 +933,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Ipv4Only,
         );
 
@@ -816,6 +957,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Dual,
         );
 
@@ -829,6 +971,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Ipv4Only,
         );
 
@@ -860,6 +1003,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Ipv4Only,
         );
 
@@ -883,6 +1027,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Dual,
         );
 
@@ -914,6 +1059,85 @@ mod tests {
         )
     }
 
+    #[test]
+    fn queries_within_zone_are_sent_to_conditional_forwarder() {
+        let mut resolver = StubResolver::default();
+        resolver.set_conditional_forwarders(vec![
+            forwarder("corp.internal", [10, 0, 0, 53]),
+            forwarder("eu.corp.internal", [10, 1, 0, 53]),
+        ]);
+
+        let strategy = resolver.handle(&Query::new(
+            "gitlab.corp.internal"
+                .parse::<dns_types::DomainName>()
+                .unwrap(),
+            RecordType::A,
+        ));
+        assert!(matches!(
+            strategy,
+            ResolveStrategy::RecurseForwarder(server) if server == SocketAddr::from(([10, 0, 0, 53], 53))
+        ));
+
+        let strategy = resolver.handle(&Query::new(
+            "gitlab.eu.corp.internal"
+                .parse::<dns_types::DomainName>()
+                .unwrap(),
+            RecordType::A,
+        ));
+        assert!(matches!(
+            strategy,
+            ResolveStrategy::RecurseForwarder(server) if server == SocketAddr::from(([10, 1, 0, 53], 53))
+        ));
+
+        let strategy = resolver.handle(&Query::new(
+            "notcorp.internal".parse::<dns_types::DomainName>().unwrap(),
+            RecordType::A,
+        ));
+        assert!(matches!(strategy, ResolveStrategy::RecurseLocal));
+    }
+
+    #[test]
+    fn resources_take_precedence_over_conditional_forwarders() {
+        let mut resolver = StubResolver::default();
+        resolver.set_conditional_forwarders(vec![forwarder("corp.internal", [10, 0, 0, 53])]);
+        resolver.add_resource(
+            ResourceId::from_u128(1),
+            "gitlab.corp.internal".to_owned(),
+            &[],
+            IpStack::Dual,
+        );
+
+        let strategy = resolver.handle(&Query::new(
+            "gitlab.corp.internal"
+                .parse::<dns_types::DomainName>()
+                .unwrap(),
+            RecordType::A,
+        ));
+
+        assert!(matches!(strategy, ResolveStrategy::LocalResponse(_)));
+    }
+
+    #[test]
+    fn setting_same_conditional_forwarders_is_no_change() {
+        let mut resolver = StubResolver::default();
+
+        assert!(resolver.set_conditional_forwarders(vec![
+            forwarder("a.internal", [10, 0, 0, 53]),
+            forwarder("b.internal", [10, 0, 0, 53]),
+        ]));
+        assert!(!resolver.set_conditional_forwarders(vec![
+            forwarder("b.internal", [10, 0, 0, 53]),
+            forwarder("a.internal", [10, 0, 0, 53]),
+        ]));
+    }
+
+    fn forwarder(zone: &str, upstream: [u8; 4]) -> ConditionalForwarder {
+        ConditionalForwarder {
+            zone: zone.parse().unwrap(),
+            upstream: IpAddr::from(upstream),
+        }
+    }
+
     #[test]
     fn repeated_queries_dont_emit_events() {
         let mut resolver = StubResolver::default();
@@ -921,6 +1145,7 @@ mod tests {
         resolver.add_resource(
             ResourceId::from_u128(1),
             "example.com".to_owned(),
+            &[],
             IpStack::Dual,
         );
 
@@ -963,6 +1188,7 @@ mod benches {
                     resolver.add_resource(
                         ResourceId::from_u128(n),
                         make_domain(&mut rng),
+                        &[],
                         IpStack::Dual,
                     );
                 }
@@ -53,7 +53,26 @@ where
         self.inner.get(key)
     }
 
-    #[cfg(test)]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Entry<V>> {
+        self.inner.get_mut(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Iterates over all entries in the order of their expiration.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Entry<V>)> {
+        self.expiration.iter().flat_map(move |(expires_at, keys)| {
+            keys.iter().filter_map(move |key| {
+                let entry = self.inner.get(key)?;
+
+                // Re-inserted keys are also listed under their previous expiration.
+                (entry.expires_at == *expires_at).then_some((key, entry))
+            })
+        })
+    }
+
     pub fn remove(&mut self, key: &K) -> Option<Entry<V>> {
         self.expiration.retain(|_, keys| {
             keys.retain(|k| k != key);
@@ -84,6 +103,11 @@ where
             .chain(now_entry)
             .flatten()
         {
+            // The key may have been re-inserted with a later expiration.
+            if self.inner.get(&key).is_none_or(|e| e.expires_at > now) {
+                continue;
+            }
+
             let Some(entry) = self.inner.remove(&key) else {
                 continue;
             };
@@ -187,4 +211,18 @@ mod tests {
 
         assert!(map.is_empty())
     }
+
+    #[test]
+    fn reinserted_item_expires_at_new_expiration() {
+        let mut map = ExpiringMap::default();
+        let now = Instant::now();
+
+        map.insert("key1", "value1", now, Duration::from_secs(1));
+        map.insert("key1", "value2", now, Duration::from_secs(2));
+
+        map.handle_timeout(now + Duration::from_secs(1));
+
+        assert_eq!(map.get(&"key1").unwrap().value, "value2");
+        assert_eq!(map.iter().count(), 1);
+    }
 }
@@ -1,18 +1,27 @@
 mod client_on_gateway;
+mod dns_query_log;
 mod filter_engine;
 mod flow_tracker;
 mod nat_table;
+mod resource_health;
+mod resource_probes;
 mod unroutable_packet;
 
+pub use crate::gateway::dns_query_log::{CompletedDnsQuery, DnsQueryKind};
+pub use crate::gateway::flow_tracker::{CompletedFlow, CompletedTcpFlow, CompletedUdpFlow};
+pub use crate::gateway::resource_probes::ProbeTarget;
 pub use crate::gateway::unroutable_packet::UnroutablePacket;
 
 pub(crate) use crate::gateway::client_on_gateway::ClientOnGateway;
 pub(crate) use crate::gateway::unroutable_packet::RoutingError;
 
 use crate::gateway::client_on_gateway::TranslateOutboundResult;
+use crate::gateway::dns_query_log::DnsQueryLog;
 use crate::gateway::flow_tracker::FlowTracker;
+use crate::gateway::resource_health::ResourceHealthTracker;
 use crate::messages::gateway::{Client, ResourceDescription, Subject};
 use crate::messages::{IceCredentials, ResolveRequest};
+use crate::p2p_control::resource_health::HealthStatus;
 use crate::peer_store::PeerStore;
 use crate::{FailedToDecapsulate, GatewayEvent, IpConfig, p2p_control, packet_kind};
 use anyhow::{Context, ErrorExt, Result};
@@ -22,7 +31,7 @@ use connlib_model::{ClientId, IceCandidate, RelayId, ResourceId};
 use dns_types::DomainName;
 use ip_packet::{FzP2pControlSlice, IpPacket};
 use secrecy::ExposeSecret as _;
-use snownet::{Credentials, IceConfig, IceRole, NoTurnServers, Node, RelaySocket, Transmit};
+use snownet::{Credentials, IceConfig, IceRole, Node, RelaySocket, Transmit};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::iter;
 use std::net::{IpAddr, SocketAddr};
@@ -33,6 +42,11 @@ pub const TUN_DNS_PORT: u16 = 53535;
 
 const EXPIRE_RESOURCES_INTERVAL: Duration = Duration::from_secs(1);
 
+/// How often we remind clients that we are draining.
+///
+/// The p2p control protocol is unreliable, hence we need to re-send the event.
+const DRAINING_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(10);
+
 /// A SANS-IO implementation of a gateway's functionality.
 ///
 /// Internally, this composes a [`snownet::Node`] with firezone's policy engine around resources.
@@ -43,14 +57,22 @@ pub struct GatewayState {
     peers: PeerStore<ClientId, ClientOnGateway>,
 
     flow_tracker: FlowTracker,
+    dns_query_log: DnsQueryLog,
 
     /// When to next check whether a resource-access policy has expired.
     next_expiry_resources_check: Option<Instant>,
 
+    /// The health of the resources we serve, as determined by probing them and observing their traffic.
+    resource_health: ResourceHealthTracker,
+
     tun_ip_config: Option<IpConfig>,
 
+    /// When to next tell clients that we are draining, set once we started draining.
+    next_draining_notification: Option<Instant>,
+
     buffered_events: VecDeque<GatewayEvent>,
     buffered_transmits: VecDeque<Transmit>,
+    buffered_packets: VecDeque<IpPacket>,
 }
 
 #[derive(Debug)]
@@ -71,7 +93,13 @@ impl DnsResourceNatEntry {
 }
 
 impl GatewayState {
-    pub(crate) fn new(flow_logs: bool, seed: [u8; 32], now: Instant, unix_ts: Duration) -> Self {
+    pub(crate) fn new(
+        flow_logs: bool,
+        dns_query_logs: bool,
+        seed: [u8; 32],
+        now: Instant,
+        unix_ts: Duration,
+    ) -> Self {
         Self {
             peers: Default::default(),
             node: Node::new(
@@ -82,10 +110,14 @@ impl GatewayState {
                 IceConfig::server_idle(),
             ),
             next_expiry_resources_check: Default::default(),
+            resource_health: Default::default(),
             buffered_events: VecDeque::default(),
             buffered_transmits: VecDeque::default(),
+            buffered_packets: VecDeque::default(),
             flow_tracker: FlowTracker::new(flow_logs, now),
+            dns_query_log: DnsQueryLog::new(dns_query_logs),
             tun_ip_config: None,
+            next_draining_notification: None,
         }
     }
 
@@ -105,6 +137,50 @@ impl GatewayState {
         self.node.close_all(p2p_control::goodbye(), now);
     }
 
+    /// Stops accepting new clients and tells the connected ones to use other gateways of the site for new flows.
+    ///
+    /// Existing flows continue to work.
+    /// Use [`GatewayState::is_drained`] to learn when we can shut down without interrupting any flows.
+    pub fn start_draining(&mut self, now: Instant) {
+        if self.is_draining() {
+            return;
+        }
+
+        tracing::info!(num_clients = %self.peers.iter().count(), num_active_flows = %self.flow_tracker.num_active_flows(), "Draining");
+
+        self.flow_tracker.enable(now);
+        self.notify_clients_of_draining(now);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.next_draining_notification.is_some()
+    }
+
+    /// Whether we are draining and none of our clients have any active flows anymore.
+    pub fn is_drained(&self, now: Instant) -> bool {
+        self.is_draining() && (self.peers.iter().next().is_none() || self.flow_tracker.is_idle(now))
+    }
+
+    pub fn num_active_flows(&self) -> usize {
+        self.flow_tracker.num_active_flows()
+    }
+
+    fn notify_clients_of_draining(&mut self, now: Instant) {
+        for peer in self.peers.iter() {
+            let cid = peer.id();
+
+            match encrypt_packet(p2p_control::gateway_draining(), cid, &mut self.node, now) {
+                Ok(Some(transmit)) => self.buffered_transmits.push_back(transmit),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!(%cid, "Failed to notify client of draining: {e:#}");
+                }
+            }
+        }
+
+        self.next_draining_notification = Some(now + DRAINING_NOTIFICATION_INTERVAL);
+    }
+
     /// Handles packets received on the TUN device.
     pub(crate) fn handle_tun_input(
         &mut self,
@@ -130,10 +206,22 @@ impl GatewayState {
 
         flow_tracker::inbound_tun::record_client(cid);
 
+        if let Some((key, signal)) = peer.health_signal(&packet) {
+            self.resource_health.on_signal(key, signal, now);
+        }
+
+        if let Some(icmp_error) = crate::packet_too_big(&packet, self.node.path_mtu(cid))? {
+            self.buffered_packets.push_back(icmp_error);
+
+            return Ok(None);
+        }
+
         let packet = peer
             .translate_inbound(packet, now)
             .context("Failed to translate inbound packet")?;
 
+        self.drain_resource_health_updates(now);
+
         let encrypted_packet = match self.node.encapsulate(cid, &packet, now) {
             Ok(Some(encrypted_packet)) => encrypted_packet,
             Ok(None) => return Ok(None),
@@ -186,7 +274,7 @@ impl GatewayState {
         if let Some(fz_p2p_control) = packet.as_fz_p2p_control() {
             let immediate_response = match fz_p2p_control.event_type() {
                 p2p_control::ASSIGNED_IPS_EVENT => {
-                    handle_assigned_ips_event(fz_p2p_control, peer, &mut self.buffered_events)
+                    handle_assigned_ips_event(fz_p2p_control, peer, &mut self.buffered_events, now)
                 }
                 p2p_control::GOODBYE_EVENT => {
                     self.peers.remove(&cid);
@@ -300,7 +388,9 @@ impl GatewayState {
         expires_at: Option<DateTime<Utc>>,
         resource: ResourceDescription,
         now: Instant,
-    ) -> Result<(), NoTurnServers> {
+    ) -> Result<()> {
+        anyhow::ensure!(self.accepts_client(&client.id), GatewayDraining);
+
         self.node.upsert_connection(
             client.id,
             client.public_key.into(),
@@ -357,6 +447,8 @@ impl GatewayState {
         resource: ResourceDescription,
         dns_resource_nat: Option<DnsResourceNatEntry>,
     ) -> anyhow::Result<()> {
+        anyhow::ensure!(self.accepts_client(&client), GatewayDraining);
+
         let gateway_tun = self.tun_ip_config.context("TUN device not configured")?;
 
         let peer = self
@@ -381,6 +473,11 @@ impl GatewayState {
         Ok(())
     }
 
+    /// While draining, we only serve clients we are already connected to.
+    fn accepts_client(&self, cid: &ClientId) -> bool {
+        !self.is_draining() || self.peers.get(cid).is_some()
+    }
+
     pub fn update_access_authorization_expiry(
         &mut self,
         client: ClientId,
@@ -403,6 +500,31 @@ impl GatewayState {
     ) -> anyhow::Result<()> {
         use p2p_control::dns_resource_nat;
 
+        if self.dns_query_log.is_enabled()
+            && let Some(peer) = self.peers.get(&req.client)
+        {
+            self.dns_query_log.on_resource_nat_resolved(
+                req.client,
+                peer.client_flow_properties(),
+                req.resource,
+                req.domain.clone(),
+                resolve_result.as_deref().ok(),
+                now.duration_since(req.requested_at),
+                Utc::now(),
+            );
+            self.drain_completed_dns_queries();
+        }
+
+        self.resource_health.on_domain_resolved(
+            req.resource,
+            req.domain.clone(),
+            resolve_result
+                .as_ref()
+                .is_ok_and(|addresses| !addresses.is_empty()),
+            now,
+        );
+        self.drain_resource_health_updates(now);
+
         let nat_status = resolve_result
             .and_then(|addresses| {
                 self.peers
@@ -434,6 +556,53 @@ impl GatewayState {
         Ok(())
     }
 
+    /// The probes for all resources that we currently serve to at least one client.
+    pub fn resource_probe_targets(&self) -> BTreeSet<(ResourceId, ProbeTarget)> {
+        self.peers
+            .iter()
+            .flat_map(|peer| peer.probe_targets())
+            .collect()
+    }
+
+    /// Updates the health of a resource as determined by probing it.
+    ///
+    /// Clients that have access to the resource are notified if the health changes.
+    pub fn update_resource_health(&mut self, rid: ResourceId, status: HealthStatus, now: Instant) {
+        self.resource_health.on_probe_result(rid, status);
+        self.drain_resource_health_updates(now);
+    }
+
+    /// Sends all pending health updates to the clients that have access to the respective resource.
+    fn drain_resource_health_updates(&mut self, now: Instant) {
+        while let Some(update) = self.resource_health.poll_update() {
+            let rid = update.resource;
+            let clients = self
+                .peers
+                .iter()
+                .filter(|peer| peer.is_allowed(rid))
+                .map(|peer| peer.id())
+                .collect::<Vec<_>>();
+
+            for cid in clients {
+                let result = p2p_control::resource_health::resource_health(
+                    rid,
+                    update.domain.clone(),
+                    update.status,
+                    update.reason,
+                )
+                .and_then(|packet| encrypt_packet(packet, cid, &mut self.node, now));
+
+                match result {
+                    Ok(Some(transmit)) => self.buffered_transmits.push_back(transmit),
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::debug!(%cid, %rid, "Failed to send resource health: {e:#}");
+                    }
+                }
+            }
+        }
+    }
+
     pub fn poll_timeout(&mut self) -> Option<(Instant, &'static str)> {
         iter::empty()
             // TODO: This should check when the next resource actually expires instead of doing it at a fixed interval.
@@ -441,6 +610,15 @@ impl GatewayState {
                 self.next_expiry_resources_check
                     .map(|instant| (instant, "resource expiry")),
             )
+            .chain(
+                self.resource_health
+                    .poll_timeout()
+                    .map(|instant| (instant, "resource health")),
+            )
+            .chain(
+                self.next_draining_notification
+                    .map(|instant| (instant, "draining notification")),
+            )
             .chain(self.node.poll_timeout())
             .min_by_key(|(instant, _)| *instant)
     }
@@ -449,6 +627,7 @@ impl GatewayState {
         self.node.handle_timeout(now);
         self.drain_node_events();
         self.flow_tracker.handle_timeout(now);
+        self.dns_query_log.handle_timeout(now);
 
         match self.next_expiry_resources_check {
             Some(next_expiry_resources_check) if now >= next_expiry_resources_check => {
@@ -470,26 +649,38 @@ impl GatewayState {
             Some(_) => {}
         }
 
+        if self
+            .next_draining_notification
+            .is_some_and(|next_draining_notification| now >= next_draining_notification)
+        {
+            self.notify_clients_of_draining(now);
+        }
+
+        self.resource_health
+            .retain_resources(|rid| self.peers.iter().any(|peer| peer.is_allowed(*rid)));
+        self.resource_health.handle_timeout(now);
+        self.drain_resource_health_updates(now);
+
         while let Some(flow) = self.flow_tracker.poll_completed_flow() {
-            match flow {
-                flow_tracker::CompletedFlow::Tcp(flow) => {
+            match &flow {
+                CompletedFlow::Tcp(flow) => {
                     tracing::trace!(
                         target: "flow_logs::tcp",
 
                         client_id = %flow.client_id,
-                        client_version = flow.client_version.map(tracing::field::display),
+                        client_version = flow.client_version.as_ref().map(tracing::field::display),
 
-                        device_os_name = flow.device_os_name.map(tracing::field::display),
-                        device_os_version = flow.device_os_version.map(tracing::field::display),
-                        device_serial = flow.device_serial.map(tracing::field::display),
-                        device_uuid = flow.device_uuid.map(tracing::field::display),
-                        device_identifier_for_vendor = flow.device_identifier_for_vendor.map(tracing::field::display),
-                        device_firebase_installation_id = flow.device_firebase_installation_id.map(tracing::field::display),
+                        device_os_name = flow.device_os_name.as_ref().map(tracing::field::display),
+                        device_os_version = flow.device_os_version.as_ref().map(tracing::field::display),
+                        device_serial = flow.device_serial.as_ref().map(tracing::field::display),
+                        device_uuid = flow.device_uuid.as_ref().map(tracing::field::display),
+                        device_identifier_for_vendor = flow.device_identifier_for_vendor.as_ref().map(tracing::field::display),
+                        device_firebase_installation_id = flow.device_firebase_installation_id.as_ref().map(tracing::field::display),
 
-                        auth_provider_id = flow.auth_provider_id.map(tracing::field::display),
-                        actor_name = flow.actor_name.map(tracing::field::display),
-                        actor_id = flow.actor_id.map(tracing::field::display),
-                        actor_email = flow.actor_email.map(tracing::field::display),
+                        auth_provider_id = flow.auth_provider_id.as_ref().map(tracing::field::display),
+                        actor_name = flow.actor_name.as_ref().map(tracing::field::display),
+                        actor_id = flow.actor_id.as_ref().map(tracing::field::display),
+                        actor_email = flow.actor_email.as_ref().map(tracing::field::display),
 
                         resource_id = %flow.resource_id,
                         resource_name = %flow.resource_name,
@@ -502,7 +693,7 @@ impl GatewayState {
                         inner_dst_ip = %flow.inner_dst_ip,
                         inner_src_port = %flow.inner_src_port,
                         inner_dst_port = %flow.inner_dst_port,
-                        inner_domain = flow.inner_domain.map(tracing::field::display),
+                        inner_domain = flow.inner_domain.as_ref().map(tracing::field::display),
 
                         outer_src_ip = %flow.outer_src_ip,
                         outer_dst_ip = %flow.outer_dst_ip,
@@ -516,24 +707,24 @@ impl GatewayState {
                         "TCP flow completed"
                     );
                 }
-                flow_tracker::CompletedFlow::Udp(flow) => {
+                CompletedFlow::Udp(flow) => {
                     tracing::trace!(
                         target: "flow_logs::udp",
 
                         client_id = %flow.client_id,
-                        client_version = flow.client_version.map(tracing::field::display),
+                        client_version = flow.client_version.as_ref().map(tracing::field::display),
 
-                        device_os_name = flow.device_os_name.map(tracing::field::display),
-                        device_os_version = flow.device_os_version.map(tracing::field::display),
-                        device_serial = flow.device_serial.map(tracing::field::display),
-                        device_uuid = flow.device_uuid.map(tracing::field::display),
-                        device_identifier_for_vendor = flow.device_identifier_for_vendor.map(tracing::field::display),
-                        device_firebase_installation_id = flow.device_firebase_installation_id.map(tracing::field::display),
+                        device_os_name = flow.device_os_name.as_ref().map(tracing::field::display),
+                        device_os_version = flow.device_os_version.as_ref().map(tracing::field::display),
+                        device_serial = flow.device_serial.as_ref().map(tracing::field::display),
+                        device_uuid = flow.device_uuid.as_ref().map(tracing::field::display),
+                        device_identifier_for_vendor = flow.device_identifier_for_vendor.as_ref().map(tracing::field::display),
+                        device_firebase_installation_id = flow.device_firebase_installation_id.as_ref().map(tracing::field::display),
 
-                        auth_provider_id = flow.auth_provider_id.map(tracing::field::display),
-                        actor_name = flow.actor_name.map(tracing::field::display),
-                        actor_id = flow.actor_id.map(tracing::field::display),
-                        actor_email = flow.actor_email.map(tracing::field::display),
+                        auth_provider_id = flow.auth_provider_id.as_ref().map(tracing::field::display),
+                        actor_name = flow.actor_name.as_ref().map(tracing::field::display),
+                        actor_id = flow.actor_id.as_ref().map(tracing::field::display),
+                        actor_email = flow.actor_email.as_ref().map(tracing::field::display),
 
                         resource_id = %flow.resource_id,
                         resource_name = %flow.resource_name,
@@ -546,7 +737,7 @@ impl GatewayState {
                         inner_dst_ip = %flow.inner_dst_ip,
                         inner_src_port = %flow.inner_src_port,
                         inner_dst_port = %flow.inner_dst_port,
-                        inner_domain = flow.inner_domain.map(tracing::field::display),
+                        inner_domain = flow.inner_domain.as_ref().map(tracing::field::display),
 
                         outer_src_ip = %flow.outer_src_ip,
                         outer_dst_ip = %flow.outer_dst_ip,
@@ -561,6 +752,83 @@ impl GatewayState {
                     );
                 }
             }
+
+            self.buffered_events
+                .push_back(GatewayEvent::FlowCompleted(Box::new(flow)));
+        }
+    }
+
+    /// Records a DNS query that a client sent to our TUN DNS server and which we forward to our upstream resolvers.
+    pub(crate) fn on_forwarded_dns_query(
+        &mut self,
+        client: SocketAddr,
+        query: &dns_types::Query,
+        now: Instant,
+    ) {
+        self.dns_query_log.on_forwarded_query(client, query, now);
+    }
+
+    /// Records the response to a DNS query that we forwarded on behalf of a client.
+    pub(crate) fn on_forwarded_dns_response(
+        &mut self,
+        client: SocketAddr,
+        response: &dns_types::Response,
+        now: Instant,
+        now_utc: DateTime<Utc>,
+    ) {
+        if !self.dns_query_log.is_enabled() {
+            return;
+        }
+
+        let Some(peer) = self.peers.peer_by_ip(client.ip()) else {
+            tracing::debug!(%client, "Unknown client for DNS response");
+            return;
+        };
+
+        self.dns_query_log.on_forwarded_response(
+            client,
+            peer.id(),
+            peer.client_flow_properties(),
+            response,
+            now,
+            now_utc,
+        );
+        self.drain_completed_dns_queries();
+    }
+
+    fn drain_completed_dns_queries(&mut self) {
+        while let Some(query) = self.dns_query_log.poll_completed_query() {
+            tracing::trace!(
+                target: "flow_logs::dns",
+
+                kind = ?query.kind,
+
+                client_id = %query.client_id,
+                client_version = query.client_version.as_ref().map(tracing::field::display),
+
+                device_os_name = query.device_os_name.as_ref().map(tracing::field::display),
+                device_os_version = query.device_os_version.as_ref().map(tracing::field::display),
+                device_serial = query.device_serial.as_ref().map(tracing::field::display),
+                device_uuid = query.device_uuid.as_ref().map(tracing::field::display),
+                device_identifier_for_vendor = query.device_identifier_for_vendor.as_ref().map(tracing::field::display),
+                device_firebase_installation_id = query.device_firebase_installation_id.as_ref().map(tracing::field::display),
+
+                auth_provider_id = query.auth_provider_id.as_ref().map(tracing::field::display),
+                actor_name = query.actor_name.as_ref().map(tracing::field::display),
+                actor_id = query.actor_id.as_ref().map(tracing::field::display),
+                actor_email = query.actor_email.as_ref().map(tracing::field::display),
+
+                resource_id = query.resource_id.as_ref().map(tracing::field::display),
+                domain = %query.domain,
+                qtype = query.qtype.as_ref().map(tracing::field::display),
+                answers = ?query.answers,
+                rcode = %query.rcode,
+                latency = ?query.latency,
+                "DNS query completed"
+            );
+
+            self.buffered_events
+                .push_back(GatewayEvent::DnsQueryCompleted(Box::new(query)));
         }
     }
 
@@ -614,6 +882,10 @@ impl GatewayState {
         }
     }
 
+    pub(crate) fn poll_packets(&mut self) -> Option<IpPacket> {
+        self.buffered_packets.pop_front()
+    }
+
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit> {
         self.buffered_transmits
             .pop_front()
@@ -666,6 +938,7 @@ fn handle_assigned_ips_event(
     fz_p2p_control: FzP2pControlSlice,
     peer: &ClientOnGateway,
     buffered_events: &mut VecDeque<GatewayEvent>,
+    now: Instant,
 ) -> Option<IpPacket> {
     use p2p_control::dns_resource_nat;
 
@@ -696,11 +969,17 @@ fn handle_assigned_ips_event(
         client: peer.id(),
         resource: req.resource,
         proxy_ips: req.proxy_ips,
+        requested_at: now,
     }));
 
     None
 }
 
+/// We are draining and therefore don't accept new clients.
+#[derive(Debug, thiserror::Error)]
+#[error("Not accepting new clients while draining")]
+pub struct GatewayDraining;
+
 fn encrypt_packet(
     packet: IpPacket,
     cid: ClientId,
@@ -721,6 +1000,7 @@ pub struct ResolveDnsRequest {
     client: ClientId,
     resource: ResourceId,
     proxy_ips: Vec<IpAddr>,
+    requested_at: Instant,
 }
 
 impl ResolveDnsRequest {
@@ -10,11 +10,14 @@ use dns_types::DomainName;
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_network_table::IpNetworkTable;
 use ip_packet::{IpPacket, Protocol, UnsupportedProtocol};
+use telemetry::feature_flags;
 
 use crate::client::{IPV4_RESOURCES, IPV6_RESOURCES};
 use crate::gateway::filter_engine::FilterEngine;
 use crate::gateway::flow_tracker;
 use crate::gateway::nat_table::{NatTable, TranslateIncomingResult};
+use crate::gateway::resource_health::{HealthKey, HealthSignal};
+use crate::gateway::resource_probes::{self, ProbeTarget};
 use crate::gateway::unroutable_packet::UnroutablePacket;
 use crate::messages::gateway::Filters;
 use crate::messages::gateway::ResourceDescription;
@@ -117,9 +120,10 @@ impl ClientOnGateway {
         tracing::debug!(domain = %name, ?resolved_ips, ?proxy_ips, "Setting up DNS resource NAT");
 
         for proxy_ip in proxy_ips {
+            // Prefer IPs of the same version and fall back to NAT64 / NAT46 for single-stack domains.
             let maybe_real_ip = match proxy_ip {
-                IpAddr::V4(_) => resolved_ipv4.next(),
-                IpAddr::V6(_) => resolved_ipv6.next(),
+                IpAddr::V4(_) => resolved_ipv4.next().or_else(|| resolved_ipv6.next()),
+                IpAddr::V6(_) => resolved_ipv6.next().or_else(|| resolved_ipv4.next()),
             };
 
             tracing::debug!(%name, %proxy_ip, real_ip = ?maybe_real_ip);
@@ -350,7 +354,7 @@ impl ClientOnGateway {
 
     fn transform_network_to_tun(
         &mut self,
-        mut packet: IpPacket,
+        packet: IpPacket,
         now: Instant,
     ) -> anyhow::Result<TranslateOutboundResult> {
         let dst = packet.destination();
@@ -379,11 +383,13 @@ impl ClientOnGateway {
             ));
         };
 
-        if resolved_ip.is_ipv4() != dst.is_ipv4() {
+        if resolved_ip.is_ipv4() != dst.is_ipv4()
+            && feature_flags::icmp_unreachable_instead_of_nat64()
+        {
             tracing::debug!(
                 %dst,
                 resolved = %resolved_ip,
-                "Cannot translate between IP versions"
+                "Not translating between IP versions"
             );
 
             return Ok(TranslateOutboundResult::DestinationUnreachable(
@@ -397,6 +403,16 @@ impl ClientOnGateway {
             self.nat_table
                 .translate_outgoing(&packet, resolved_ip, now)?;
 
+        let mut packet = match (dst, real_ip) {
+            (IpAddr::V6(_), IpAddr::V4(real_ip)) => packet
+                .consume_to_ipv4(self.client_tun.v4, real_ip)
+                .context("Failed to translate packet to IPv4")?,
+            (IpAddr::V4(_), IpAddr::V6(real_ip)) => packet
+                .consume_to_ipv6(self.client_tun.v6, real_ip)
+                .context("Failed to translate packet to IPv6")?,
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => packet,
+        };
+
         packet
             .translate_destination(source_protocol, real_ip)
             .context("Failed to translate packet to new destination")?;
@@ -407,7 +423,7 @@ impl ClientOnGateway {
 
     fn transform_tun_to_network(
         &mut self,
-        mut packet: IpPacket,
+        packet: IpPacket,
         now: Instant,
     ) -> anyhow::Result<IpPacket> {
         let (proto, ip) = match self.nat_table.translate_incoming(&packet, now)? {
@@ -431,6 +447,16 @@ impl ClientOnGateway {
             }
         };
 
+        let mut packet = match (packet.source(), ip) {
+            (IpAddr::V4(_), IpAddr::V6(proxy_ip)) => packet
+                .consume_to_ipv6(proxy_ip, self.client_tun.v6)
+                .context("Failed to translate packet to IPv6")?,
+            (IpAddr::V6(_), IpAddr::V4(proxy_ip)) => packet
+                .consume_to_ipv4(proxy_ip, self.client_tun.v4)
+                .context("Failed to translate packet to IPv4")?,
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => packet,
+        };
+
         packet
             .translate_source(proto, ip)
             .context("Failed to translate packet to new source")?;
@@ -443,6 +469,54 @@ impl ClientOnGateway {
         self.resources.contains_key(&resource)
     }
 
+    /// The probes for all resources this client has access to.
+    pub(crate) fn probe_targets(&self) -> impl Iterator<Item = (ResourceId, ProbeTarget)> + '_ {
+        self.resources
+            .iter()
+            .flat_map(|(rid, r)| r.probe_targets().into_iter().map(move |t| (*rid, t)))
+    }
+
+    /// Inspects a packet received from the network for signs that the resource it came from is not healthy.
+    ///
+    /// The packet must not have been translated yet, i.e. it must still carry the resource's real IP.
+    pub(crate) fn health_signal(&self, packet: &IpPacket) -> Option<(HealthKey, HealthSignal)> {
+        let (resource_ip, signal) = match packet.icmp_error() {
+            Ok(Some((failed_packet, error))) if error.is_unreachable() => {
+                (failed_packet.dst(), HealthSignal::IcmpUnreachable)
+            }
+            Ok(Some(_)) | Err(_) => return None,
+            Ok(None) if packet.as_tcp().is_some_and(|tcp| tcp.rst()) => {
+                (packet.source(), HealthSignal::TcpReset)
+            }
+            Ok(None) => return None,
+        };
+
+        let key = self.resource_by_real_ip(resource_ip)?;
+
+        Some((key, signal))
+    }
+
+    /// Finds the resource (and domain for DNS resources) a real IP belongs to.
+    ///
+    /// The Internet resource is never returned as its health cannot be judged by individual IPs.
+    fn resource_by_real_ip(&self, ip: IpAddr) -> Option<HealthKey> {
+        if let Some(state) = self
+            .permanent_translations
+            .values()
+            .find(|state| state.resolved_ip == Some(ip))
+        {
+            return Some((state.resource_id, Some(state.domain.clone())));
+        }
+
+        let (_, (_, rid)) = self.filters.longest_match(ip)?;
+
+        if self.internet_resource_enabled == Some(*rid) {
+            return None;
+        }
+
+        Some((*rid, None))
+    }
+
     fn ensure_allowed_outbound(&self, packet: &IpPacket) -> anyhow::Result<()> {
         self.ensure_client_ip(packet.source())?;
 
@@ -497,7 +571,7 @@ impl ClientOnGateway {
             .context(NotAllowedResource(resource_ip))?;
 
         filter
-            .apply(protocol)
+            .apply(resource_ip, protocol)
             .context(NotAllowedResource(resource_ip))?;
 
         Ok(*rid)
@@ -586,6 +660,21 @@ impl ResourceOnGateway {
         }
     }
 
+    fn probe_targets(&self) -> Vec<ProbeTarget> {
+        match self {
+            ResourceOnGateway::Cidr {
+                network, filters, ..
+            } => resource_probes::cidr_targets(*network, filters),
+            ResourceOnGateway::Dns {
+                domains, filters, ..
+            } => domains
+                .iter()
+                .flat_map(|(domain, ips)| resource_probes::domain_targets(domain, ips, filters))
+                .collect(),
+            ResourceOnGateway::Internet { .. } => Vec::new(),
+        }
+    }
+
     fn filters(&self) -> &Filters {
         const EMPTY: &Filters = &Filters::new();
 
@@ -695,7 +784,7 @@ mod tests {
 
     use crate::{
         gateway::{RoutingError, nat_table},
-        messages::gateway::{Filter, PortRange, ResourceDescriptionCidr},
+        messages::gateway::{Filter, PortRange, ProtocolFilter, ResourceDescriptionCidr},
     };
 
     #[test]
@@ -714,10 +803,10 @@ mod tests {
                 id: foo_resource_id(),
                 address: cidr_v4_resource().into(),
                 name: "cidr1".to_owned(),
-                filters: vec![Filter::Tcp(PortRange {
+                filters: vec![Filter::from(ProtocolFilter::Tcp(PortRange {
                     port_range_start: 20,
                     port_range_end: 100,
-                })],
+                }))],
             }),
             Some(then),
         );
@@ -726,10 +815,10 @@ mod tests {
                 id: bar_resource_id(),
                 address: cidr_v4_resource().into(),
                 name: "cidr2".to_owned(),
-                filters: vec![Filter::Udp(PortRange {
+                filters: vec![Filter::from(ProtocolFilter::Udp(PortRange {
                     port_range_start: 20,
                     port_range_end: 100,
-                })],
+                }))],
             }),
             Some(after_then),
         );
@@ -1172,7 +1261,7 @@ mod tests {
     }
 
     #[test]
-    fn setup_dns_resource_nat_ipv4_only_adds_ipv6_translation_state_entries() {
+    fn setup_dns_resource_nat_ipv4_only_translates_ipv6_packets() {
         let _guard = logging::test("trace");
 
         let now = Instant::now();
@@ -1201,13 +1290,29 @@ mod tests {
         )
         .unwrap();
 
-        let TranslateOutboundResult::DestinationUnreachable(packet) =
-            peer.translate_outbound(request, now).unwrap()
+        let TranslateOutboundResult::Send(packet) = peer.translate_outbound(request, now).unwrap()
         else {
             panic!("Bad translation result")
         };
 
-        assert_eq!(packet.destination(), client_tun_ipv6());
+        assert_eq!(packet.source(), client_tun_ipv4());
+        assert_eq!(packet.destination(), foo_real_ip1());
+        assert_eq!(packet.as_udp().unwrap().source_port(), 1);
+
+        let response = ip_packet::make::udp_packet(
+            foo_real_ip1(),
+            client_tun_ipv4(),
+            foo_allowed_port(),
+            1,
+            vec![0, 0, 0, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+
+        let response = peer.translate_inbound(response, now).unwrap();
+
+        assert_eq!(response.source(), proxy_ip6_1());
+        assert_eq!(response.destination(), client_tun_ipv6());
+        assert_eq!(response.as_udp().unwrap().destination_port(), 1);
 
         assert!(peer.permanent_translations.contains_key(&proxy_ip4_1()));
         assert!(peer.permanent_translations.contains_key(&proxy_ip4_2()));
@@ -1215,6 +1320,62 @@ mod tests {
         assert!(peer.permanent_translations.contains_key(&proxy_ip6_2()));
     }
 
+    #[test]
+    fn icmp_error_for_nat64_packet_is_translated_to_icmpv6() {
+        let _guard = logging::test("trace");
+
+        let now = Instant::now();
+
+        let mut peer = ClientOnGateway::new(
+            client_id(),
+            client_tun(),
+            gateway_tun(),
+            flow_tracker::ClientProperties::default(),
+        );
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            foo_resource_id(),
+            BTreeSet::from([foo_real_ip1().into()]),
+            BTreeSet::from([proxy_ip6_1()]),
+        )
+        .unwrap();
+
+        let request = ip_packet::make::udp_packet(
+            client_tun_ipv6(),
+            proxy_ip6_1(),
+            1,
+            foo_allowed_port(),
+            vec![0, 0, 0, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+
+        let TranslateOutboundResult::Send(packet) = peer.translate_outbound(request, now).unwrap()
+        else {
+            panic!("Bad translation result")
+        };
+
+        let icmp_error = ip_packet::make::icmp_dest_unreachable_network(&packet).unwrap();
+
+        let icmp_error = peer.translate_inbound(icmp_error, now).unwrap();
+
+        assert_eq!(icmp_error.source(), proxy_ip6_1());
+        assert_eq!(icmp_error.destination(), client_tun_ipv6());
+
+        let (failed_packet, error) = icmp_error.icmp_error().unwrap().unwrap();
+
+        assert!(error.is_unreachable());
+        assert_eq!(failed_packet.src(), client_tun_ipv6());
+        assert_eq!(failed_packet.dst(), proxy_ip6_1());
+        assert_eq!(
+            failed_packet.layer4_protocol(),
+            ip_packet::Layer4Protocol::Udp {
+                src: 1,
+                dst: foo_allowed_port()
+            }
+        );
+    }
+
     #[test]
     fn no_translate_outbound_icmp_error() {
         let _guard = logging::test("trace");
@@ -1243,16 +1404,52 @@ mod tests {
         assert_eq!(error.proto().to_string(), "ICMP");
     }
 
+    #[test]
+    fn tcp_reset_from_cidr_resource_is_health_signal() {
+        let mut peer = ClientOnGateway::new(
+            client_id(),
+            client_tun(),
+            gateway_tun(),
+            flow_tracker::ClientProperties::default(),
+        );
+        peer.add_resource(bar_cidr_resource(), None);
+
+        let rst = ip_packet::make::tcp_packet(
+            bar_contained_ip(),
+            client_tun_ipv4(),
+            bar_allowed_port(),
+            50000,
+            TcpFlags { rst: true },
+            vec![],
+        )
+        .unwrap();
+        let no_rst = ip_packet::make::tcp_packet(
+            bar_contained_ip(),
+            client_tun_ipv4(),
+            bar_allowed_port(),
+            50000,
+            TcpFlags { rst: false },
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            peer.health_signal(&rst),
+            Some(((bar_resource_id(), None), HealthSignal::TcpReset))
+        );
+        assert_eq!(peer.health_signal(&no_rst), None);
+    }
+
     fn foo_dns_resource() -> crate::messages::gateway::ResourceDescription {
         crate::messages::gateway::ResourceDescription::Dns(
             crate::messages::gateway::ResourceDescriptionDns {
                 id: foo_resource_id(),
                 address: foo_name(),
                 name: "foo".to_string(),
-                filters: vec![Filter::Udp(PortRange {
+                filters: vec![Filter::from(ProtocolFilter::Udp(PortRange {
                     port_range_end: foo_allowed_port(),
                     port_range_start: foo_allowed_port(),
-                })],
+                }))],
             },
         )
     }
@@ -1274,10 +1471,10 @@ mod tests {
                 id: bar_resource_id(),
                 address: bar_address(),
                 name: "foo".to_string(),
-                filters: vec![Filter::Udp(PortRange {
+                filters: vec![Filter::from(ProtocolFilter::Udp(PortRange {
                     port_range_end: bar_allowed_port(),
                     port_range_start: bar_allowed_port(),
-                })],
+                }))],
             },
         )
     }
@@ -1398,7 +1595,7 @@ mod proptests {
     use super::tests::*;
     use super::*;
     use crate::messages::gateway::{
-        Filter, PortRange, ResourceDescription, ResourceDescriptionCidr,
+        Filter, PortRange, ProtocolFilter, ResourceDescription, ResourceDescriptionCidr,
     };
     use crate::proptest::*;
     use ip_packet::make::{TcpFlags, icmp_request_packet, tcp_packet, udp_packet};
@@ -1754,9 +1951,7 @@ mod proptests {
                     .prop_filter_map("If ICMP is contained there is no way to generate gaps", {
                         let f = f.clone();
 
-                        move |p| {
-                            (p != ProtocolKind::Icmp || !f.contains(&Filter::Icmp)).then_some(p)
-                        }
+                        move |p| (p != ProtocolKind::Icmp || !contains_icmp_filter(&f)).then_some(p)
                     })
                     .prop_filter("no gaps in port ranges", {
                         let f = f.clone();
@@ -1788,11 +1983,11 @@ mod proptests {
     fn gaps(filters: Filters, protocol: ProtocolKind) -> Vec<RangeInclusive<u16>> {
         filters
             .into_iter()
-            .filter_map(|f| match (f, protocol) {
-                (Filter::Udp(inner), ProtocolKind::Udp) => {
+            .filter_map(|f| match (f.protocol, protocol) {
+                (ProtocolFilter::Udp(inner), ProtocolKind::Udp) => {
                     Some(inner.port_range_start..=inner.port_range_end)
                 }
-                (Filter::Tcp(inner), ProtocolKind::Tcp) => {
+                (ProtocolFilter::Tcp(inner), ProtocolKind::Tcp) => {
                     Some(inner.port_range_start..=inner.port_range_end)
                 }
                 (_, _) => None,
@@ -1803,25 +1998,30 @@ mod proptests {
     }
 
     fn protocol_from_filter(f: Filter) -> impl Strategy<Value = Protocol> {
-        match f {
-            Filter::Udp(PortRange {
+        match f.protocol {
+            ProtocolFilter::Udp(PortRange {
                 port_range_end,
                 port_range_start,
             }) => (port_range_start..=port_range_end)
                 .prop_map(|dport| Protocol::Udp { dport })
                 .boxed(),
-            Filter::Tcp(PortRange {
+            ProtocolFilter::Tcp(PortRange {
                 port_range_end,
                 port_range_start,
             }) => (port_range_start..=port_range_end)
                 .prop_map(|dport| Protocol::Tcp { dport })
                 .boxed(),
-            Filter::Icmp => Just(Protocol::Icmp).boxed(),
+            ProtocolFilter::Icmp => Just(Protocol::Icmp).boxed(),
+            ProtocolFilter::All => any::<Protocol>().boxed(),
         }
     }
 
+    fn contains_icmp_filter(filters: &Filters) -> bool {
+        filters.iter().any(|f| f.protocol == ProtocolFilter::Icmp)
+    }
+
     fn filters_in_gaps(filters: Filters) -> impl Strategy<Value = Filters> {
-        let contains_icmp_filter = filters.contains(&Filter::Icmp);
+        let contains_icmp_filter = contains_icmp_filter(&filters);
 
         let ranges_without_tcp_filter = gaps(filters.clone(), ProtocolKind::Tcp);
         let tcp_filters = filter_from_vec(ranges_without_tcp_filter, ProtocolKind::Tcp);
@@ -1832,7 +2032,7 @@ mod proptests {
         let icmp_filter = if contains_icmp_filter {
             Just(vec![])
         } else {
-            Just(vec![Filter::Icmp])
+            Just(vec![Filter::from(ProtocolFilter::Icmp)])
         };
 
         (tcp_filters, udp_filters, icmp_filter)
@@ -1862,9 +2062,9 @@ mod proptests {
     fn filters() -> impl Strategy<Value = Filters> {
         collection::vec(
             prop_oneof![
-                Just(Filter::Icmp),
-                port_range().prop_map(Filter::Udp),
-                port_range().prop_map(Filter::Tcp),
+                Just(Filter::from(ProtocolFilter::Icmp)),
+                port_range().prop_map(|r| Filter::from(ProtocolFilter::Udp(r))),
+                port_range().prop_map(|r| Filter::from(ProtocolFilter::Tcp(r))),
             ],
             0..=100,
         )
@@ -1893,16 +2093,6 @@ mod proptests {
         Icmp,
     }
 
-    impl From<&Filter> for ProtocolKind {
-        fn from(value: &Filter) -> Self {
-            match value {
-                Filter::Udp(_) => ProtocolKind::Udp,
-                Filter::Tcp(_) => ProtocolKind::Tcp,
-                Filter::Icmp => ProtocolKind::Icmp,
-            }
-        }
-    }
-
     #[derive(Debug, Clone, Copy, Arbitrary, PartialEq, Eq)]
     enum ProtocolKind {
         Tcp,
@@ -1921,15 +2111,15 @@ mod proptests {
 
         fn into_filter(self, range: RangeInclusive<u16>) -> Filter {
             match self {
-                ProtocolKind::Tcp => Filter::Tcp(PortRange {
+                ProtocolKind::Tcp => Filter::from(ProtocolFilter::Tcp(PortRange {
                     port_range_start: *range.start(),
                     port_range_end: *range.end(),
-                }),
-                ProtocolKind::Udp => Filter::Udp(PortRange {
+                })),
+                ProtocolKind::Udp => Filter::from(ProtocolFilter::Udp(PortRange {
                     port_range_start: *range.start(),
                     port_range_end: *range.end(),
-                }),
-                ProtocolKind::Icmp => Filter::Icmp,
+                })),
+                ProtocolKind::Icmp => Filter::from(ProtocolFilter::Icmp),
             }
         }
     }
@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use connlib_model::{ClientId, ResourceId};
+use dns_types::{DomainName, RecordType, ResponseCode};
+
+use crate::gateway::flow_tracker::ClientProperties;
+
+/// After how long we forget about a forwarded query that never received a response.
+const MAX_QUERY_AGE: Duration = Duration::from_secs(60);
+
+/// Records the DNS queries we resolve on behalf of clients.
+///
+/// Clients send us DNS queries in two ways:
+/// - Queries for domains within a site are forwarded to our TUN DNS server and from there to our upstream resolvers.
+/// - Domains of DNS resources get resolved by us in order to set up the DNS resource NAT.
+#[derive(Debug)]
+pub struct DnsQueryLog {
+    enabled: bool,
+
+    /// Queries we have forwarded to an upstream resolver, keyed by the client's socket and the query ID.
+    forwarded_queries: HashMap<(SocketAddr, u16), Instant>,
+
+    completed_queries: VecDeque<CompletedDnsQuery>,
+}
+
+/// A DNS query that we resolved on behalf of a client.
+#[derive(Debug)]
+pub struct CompletedDnsQuery {
+    pub kind: DnsQueryKind,
+
+    pub client_id: ClientId,
+    pub client_version: Option<String>,
+
+    pub device_os_name: Option<String>,
+    pub device_os_version: Option<String>,
+    pub device_serial: Option<String>,
+    pub device_uuid: Option<String>,
+    pub device_identifier_for_vendor: Option<String>,
+    pub device_firebase_installation_id: Option<String>,
+
+    pub auth_provider_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub actor_id: Option<String>,
+    pub actor_email: Option<String>,
+
+    /// The resource the query was made for.
+    ///
+    /// Only known for [`DnsQueryKind::ResourceNat`].
+    pub resource_id: Option<ResourceId>,
+
+    pub domain: DomainName,
+    /// The type of the query.
+    ///
+    /// `None` for [`DnsQueryKind::ResourceNat`] where we always look up both A and AAAA records.
+    pub qtype: Option<RecordType>,
+    pub answers: Vec<String>,
+    pub rcode: ResponseCode,
+
+    pub timestamp: DateTime<Utc>,
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsQueryKind {
+    /// The client forwarded the query to our TUN DNS server.
+    Forwarded,
+    /// We resolved a DNS resource's domain in order to set up the NAT for it.
+    ResourceNat,
+}
+
+impl DnsQueryLog {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            forwarded_queries: Default::default(),
+            completed_queries: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn on_forwarded_query(
+        &mut self,
+        client: SocketAddr,
+        query: &dns_types::Query,
+        now: Instant,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        self.forwarded_queries.insert((client, query.id()), now);
+    }
+
+    pub fn on_forwarded_response(
+        &mut self,
+        client: SocketAddr,
+        cid: ClientId,
+        props: ClientProperties,
+        response: &dns_types::Response,
+        now: Instant,
+        now_utc: DateTime<Utc>,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        let Some(sent_at) = self.forwarded_queries.remove(&(client, response.id())) else {
+            tracing::debug!(%client, id = %response.id(), "Unknown DNS query");
+            return;
+        };
+
+        self.completed_queries.push_back(CompletedDnsQuery::new(
+            DnsQueryKind::Forwarded,
+            cid,
+            props,
+            None,
+            response.domain(),
+            Some(response.qtype()),
+            response.records().map(|r| r.data().to_string()).collect(),
+            response.response_code(),
+            now_utc,
+            now.duration_since(sent_at),
+        ));
+    }
+
+    #[expect(
+        clippy::too_many_arguments,
+        reason = "We are just passing through the data."
+    )]
+    pub fn on_resource_nat_resolved(
+        &mut self,
+        cid: ClientId,
+        props: ClientProperties,
+        rid: ResourceId,
+        domain: DomainName,
+        answers: Option<&[IpAddr]>,
+        latency: Duration,
+        now_utc: DateTime<Utc>,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        let (answers, rcode) = match answers {
+            Some(answers) => (
+                answers.iter().map(|ip| ip.to_string()).collect(),
+                ResponseCode::NOERROR,
+            ),
+            None => (Vec::new(), ResponseCode::SERVFAIL),
+        };
+
+        self.completed_queries.push_back(CompletedDnsQuery::new(
+            DnsQueryKind::ResourceNat,
+            cid,
+            props,
+            Some(rid),
+            domain,
+            None,
+            answers,
+            rcode,
+            now_utc,
+            latency,
+        ));
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.forwarded_queries
+            .retain(|_, sent_at| now.duration_since(*sent_at) < MAX_QUERY_AGE);
+    }
+
+    pub fn poll_completed_query(&mut self) -> Option<CompletedDnsQuery> {
+        self.completed_queries.pop_front()
+    }
+}
+
+impl CompletedDnsQuery {
+    #[expect(clippy::too_many_arguments, reason = "Private constructor.")]
+    fn new(
+        kind: DnsQueryKind,
+        client_id: ClientId,
+        props: ClientProperties,
+        resource_id: Option<ResourceId>,
+        domain: DomainName,
+        qtype: Option<RecordType>,
+        answers: Vec<String>,
+        rcode: ResponseCode,
+        timestamp: DateTime<Utc>,
+        latency: Duration,
+    ) -> Self {
+        Self {
+            kind,
+            client_id,
+            client_version: props.version,
+            device_os_name: props.device_os_name,
+            device_os_version: props.device_os_version,
+            device_serial: props.device_serial,
+            device_uuid: props.device_uuid,
+            device_identifier_for_vendor: props.identifier_for_vendor,
+            device_firebase_installation_id: props.firebase_installation_id,
+            auth_provider_id: props.auth_provider_id,
+            actor_name: props.actor_name,
+            actor_id: props.actor_id,
+            actor_email: props.actor_email,
+            resource_id,
+            domain,
+            qtype,
+            answers,
+            rcode,
+            timestamp,
+            latency,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use dns_types::{ResponseBuilder, records};
+
+    use super::*;
+
+    #[test]
+    fn records_forwarded_query() {
+        let mut log = DnsQueryLog::new(true);
+        let now = Instant::now();
+        let query = dns_types::Query::new(domain(), RecordType::A).with_id(42);
+        let response = ResponseBuilder::for_query(&query, ResponseCode::NOERROR)
+            .with_records([(domain(), 300, records::a(Ipv4Addr::new(10, 0, 0, 1)))])
+            .build();
+
+        log.on_forwarded_query(CLIENT, &query, now);
+        log.on_forwarded_response(
+            CLIENT,
+            CID,
+            ClientProperties::default(),
+            &response,
+            now + Duration::from_millis(20),
+            Utc::now(),
+        );
+
+        let completed = log.poll_completed_query().unwrap();
+
+        assert_eq!(completed.kind, DnsQueryKind::Forwarded);
+        assert_eq!(completed.client_id, CID);
+        assert_eq!(completed.domain, domain());
+        assert_eq!(completed.qtype, Some(RecordType::A));
+        assert_eq!(completed.answers, vec!["10.0.0.1".to_owned()]);
+        assert_eq!(completed.rcode, ResponseCode::NOERROR);
+        assert_eq!(completed.latency, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn ignores_response_without_query() {
+        let mut log = DnsQueryLog::new(true);
+        let query = dns_types::Query::new(domain(), RecordType::A);
+
+        log.on_forwarded_response(
+            CLIENT,
+            CID,
+            ClientProperties::default(),
+            &dns_types::Response::servfail(&query),
+            Instant::now(),
+            Utc::now(),
+        );
+
+        assert!(log.poll_completed_query().is_none());
+    }
+
+    #[test]
+    fn records_nothing_when_disabled() {
+        let mut log = DnsQueryLog::new(false);
+        let now = Instant::now();
+        let query = dns_types::Query::new(domain(), RecordType::A);
+
+        log.on_forwarded_query(CLIENT, &query, now);
+        log.on_forwarded_response(
+            CLIENT,
+            CID,
+            ClientProperties::default(),
+            &dns_types::Response::no_error(&query),
+            now,
+            Utc::now(),
+        );
+        log.on_resource_nat_resolved(
+            CID,
+            ClientProperties::default(),
+            ResourceId::from_u128(1),
+            domain(),
+            None,
+            Duration::ZERO,
+            Utc::now(),
+        );
+
+        assert!(log.poll_completed_query().is_none());
+    }
+
+    #[test]
+    fn failed_resource_nat_resolution_is_servfail() {
+        let mut log = DnsQueryLog::new(true);
+
+        log.on_resource_nat_resolved(
+            CID,
+            ClientProperties::default(),
+            ResourceId::from_u128(1),
+            domain(),
+            None,
+            Duration::from_secs(10),
+            Utc::now(),
+        );
+
+        let completed = log.poll_completed_query().unwrap();
+
+        assert_eq!(completed.kind, DnsQueryKind::ResourceNat);
+        assert_eq!(completed.qtype, None);
+        assert_eq!(completed.rcode, ResponseCode::SERVFAIL);
+    }
+
+    fn domain() -> DomainName {
+        DomainName::vec_from_str("gitlab.example.com").unwrap()
+    }
+
+    const CID: ClientId = ClientId::from_u128(1);
+    const CLIENT: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 64, 0, 1), 5353));
+}
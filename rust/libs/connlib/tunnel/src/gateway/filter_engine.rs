@@ -1,19 +1,43 @@
+use std::net::IpAddr;
+
+use ip_network_table::IpNetworkTable;
 use ip_packet::{Protocol, UnsupportedProtocol};
 use rangemap::RangeInclusiveSet;
 
-use crate::messages::gateway::{Filter, Filters};
+use crate::messages::gateway::{Filter, FilterAction, Filters, ProtocolFilter};
 
 #[derive(Debug)]
 pub(crate) enum FilterEngine {
     PermitAll,
-    PermitSome(AllowRules),
+    /// The rules of each resource that covers an IP.
+    ///
+    /// A packet is permitted if the rules of any resource permit it and none of them explicitly deny it.
+    PermitSome(Vec<AllowRules>),
 }
 
+/// The filter rules of a single resource.
 #[derive(Debug)]
 pub(crate) struct AllowRules {
+    /// Rules that apply to all IPs of the resource.
+    unscoped: Rules,
+    /// Rules that only apply to a sub-prefix of the resource.
+    ///
+    /// These are evaluated in longest-prefix-match order before the unscoped rules.
+    scoped: IpNetworkTable<Rules>,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    allow: ProtocolSet,
+    deny: ProtocolSet,
+}
+
+#[derive(Debug, Default)]
+struct ProtocolSet {
     udp: RangeInclusiveSet<u16>,
     tcp: RangeInclusiveSet<u16>,
     icmp: bool,
+    other: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,6 +48,8 @@ pub(crate) enum Filtered {
     Udp,
     #[error("ICMP not allowed")]
     Icmp,
+    #[error("Traffic to {0} is denied")]
+    Denied(IpAddr),
     #[error("Failed to evaluate filter")]
     UnsupportedProtocol(#[from] UnsupportedProtocol),
 }
@@ -31,11 +57,33 @@ pub(crate) enum Filtered {
 impl FilterEngine {
     pub(crate) fn apply(
         &self,
+        dst: IpAddr,
         protocol: Result<Protocol, UnsupportedProtocol>,
     ) -> Result<(), Filtered> {
-        match self {
-            FilterEngine::PermitAll => Ok(()),
-            FilterEngine::PermitSome(filter_engine) => filter_engine.apply(protocol),
+        let rules = match self {
+            FilterEngine::PermitAll => return Ok(()),
+            FilterEngine::PermitSome(rules) => rules,
+        };
+
+        let verdicts = rules
+            .iter()
+            .filter_map(|rules| rules.evaluate(dst, &protocol))
+            .collect::<Vec<_>>();
+
+        // An explicit deny of one resource cannot be overridden by another resource covering the same IP.
+        if verdicts.contains(&FilterAction::Deny) {
+            return Err(Filtered::Denied(dst));
+        }
+
+        if verdicts.contains(&FilterAction::Allow) {
+            return Ok(());
+        }
+
+        match protocol {
+            Ok(Protocol::Tcp(_)) => Err(Filtered::Tcp),
+            Ok(Protocol::Udp(_)) => Err(Filtered::Udp),
+            Ok(Protocol::IcmpEcho(_)) => Err(Filtered::Icmp),
+            Err(e) => Err(Filtered::UnsupportedProtocol(e)),
         }
     }
 
@@ -47,110 +95,383 @@ impl FilterEngine {
             return Self::PermitAll;
         }
 
-        let mut allow_rules = AllowRules::new();
-        allow_rules.add_filters(filters.flatten());
-
-        Self::PermitSome(allow_rules)
+        Self::PermitSome(filters.map(AllowRules::new).collect())
     }
 }
 
 impl AllowRules {
-    fn new() -> AllowRules {
-        AllowRules {
-            udp: RangeInclusiveSet::new(),
-            tcp: RangeInclusiveSet::new(),
-            icmp: false,
+    fn new<'a>(filters: impl IntoIterator<Item = &'a Filter>) -> AllowRules {
+        let mut allow_rules = AllowRules {
+            unscoped: Rules::default(),
+            scoped: IpNetworkTable::new(),
+        };
+
+        for filter in filters {
+            let rules = match filter.destination {
+                Some(destination) => {
+                    if allow_rules.scoped.exact_match(destination).is_none() {
+                        allow_rules.scoped.insert(destination, Rules::default());
+                    }
+
+                    allow_rules
+                        .scoped
+                        .exact_match_mut(destination)
+                        .expect("we just inserted it")
+                }
+                None => &mut allow_rules.unscoped,
+            };
+
+            match filter.action {
+                FilterAction::Allow => rules.allow.insert(filter.protocol),
+                FilterAction::Deny => rules.deny.insert(filter.protocol),
+            }
         }
+
+        allow_rules
     }
 
-    fn apply(&self, protocol: Result<Protocol, UnsupportedProtocol>) -> Result<(), Filtered> {
+    /// Evaluates the most specific rule matching the given packet.
+    ///
+    /// Returns `None` if no rule matches.
+    fn evaluate(
+        &self,
+        dst: IpAddr,
+        protocol: &Result<Protocol, UnsupportedProtocol>,
+    ) -> Option<FilterAction> {
+        // The most specific prefix with a matching rule wins.
+        let scoped = self
+            .scoped
+            .matches(dst)
+            .filter_map(|(network, rules)| Some((network.netmask(), rules.verdict(protocol)?)))
+            .max_by_key(|(netmask, _)| *netmask)
+            .map(|(_, action)| action);
+
+        scoped.or_else(|| self.unscoped.verdict(protocol))
+    }
+}
+
+impl Rules {
+    fn verdict(&self, protocol: &Result<Protocol, UnsupportedProtocol>) -> Option<FilterAction> {
+        // On the same prefix, deny rules take precedence.
+        if self.deny.contains(protocol) {
+            return Some(FilterAction::Deny);
+        }
+
+        if self.allow.contains(protocol) {
+            return Some(FilterAction::Allow);
+        }
+
+        None
+    }
+}
+
+impl ProtocolSet {
+    fn insert(&mut self, protocol: ProtocolFilter) {
         match protocol {
-            Ok(Protocol::Tcp(port)) if self.tcp.contains(&port) => Ok(()),
-            Ok(Protocol::Udp(port)) if self.udp.contains(&port) => Ok(()),
-            Ok(Protocol::IcmpEcho(_)) if self.icmp => Ok(()),
+            ProtocolFilter::Udp(range) => {
+                self.udp
+                    .insert(range.port_range_start..=range.port_range_end);
+            }
+            ProtocolFilter::Tcp(range) => {
+                self.tcp
+                    .insert(range.port_range_start..=range.port_range_end);
+            }
+            ProtocolFilter::Icmp => {
+                self.icmp = true;
+            }
+            ProtocolFilter::All => {
+                self.udp.insert(0..=u16::MAX);
+                self.tcp.insert(0..=u16::MAX);
+                self.icmp = true;
+                self.other = true;
+            }
+        }
+    }
+
+    fn contains(&self, protocol: &Result<Protocol, UnsupportedProtocol>) -> bool {
+        match protocol {
+            Ok(Protocol::Tcp(port)) => self.tcp.contains(port),
+            Ok(Protocol::Udp(port)) => self.udp.contains(port),
+            Ok(Protocol::IcmpEcho(_)) => self.icmp,
 
             // If ICMP is allowed, we don't care about the specific ICMP type.
             // i.e. it doesn't have to be an echo request / reply.
             Err(
                 UnsupportedProtocol::UnsupportedIcmpv4Type(_)
                 | UnsupportedProtocol::UnsupportedIcmpv6Type(_),
-            ) if self.icmp => Ok(()),
-
-            Ok(Protocol::Tcp(_)) => Err(Filtered::Tcp),
-            Ok(Protocol::Udp(_)) => Err(Filtered::Udp),
-            Ok(Protocol::IcmpEcho(_)) => Err(Filtered::Icmp),
-
-            Err(e) => Err(Filtered::UnsupportedProtocol(e)),
-        }
-    }
+            ) => self.icmp,
 
-    fn add_filters<'a>(&mut self, filters: impl IntoIterator<Item = &'a Filter>) {
-        for filter in filters {
-            match filter {
-                Filter::Udp(range) => {
-                    self.udp
-                        .insert(range.port_range_start..=range.port_range_end);
-                }
-                Filter::Tcp(range) => {
-                    self.tcp
-                        .insert(range.port_range_start..=range.port_range_end);
-                }
-                Filter::Icmp => {
-                    self.icmp = true;
-                }
-            }
+            Err(UnsupportedProtocol::UnsupportedIpPayload(_)) => self.other,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv4Addr;
+
     use ip_packet::{Icmpv4Type, Icmpv6Type, icmpv4, icmpv6};
+    use itertools::Itertools as _;
+
+    use crate::messages::gateway::PortRange;
 
     use super::*;
 
     #[test]
     fn allows_icmpv4_destination_unreachable() {
-        let filter = FilterEngine::PermitSome(AllowRules {
-            udp: RangeInclusiveSet::default(),
-            tcp: RangeInclusiveSet::default(),
-            icmp: true,
-        });
+        let filter = engine([ProtocolFilter::Icmp.into()]);
 
-        let result = filter.apply(Err(UnsupportedProtocol::UnsupportedIcmpv4Type(
-            Icmpv4Type::DestinationUnreachable(icmpv4::DestUnreachableHeader::Host),
-        )));
+        let result = filter.apply(
+            resource_ip(),
+            Err(UnsupportedProtocol::UnsupportedIcmpv4Type(
+                Icmpv4Type::DestinationUnreachable(icmpv4::DestUnreachableHeader::Host),
+            )),
+        );
 
         assert!(result.is_ok())
     }
 
     #[test]
     fn allows_icmpv6_destination_unreachable() {
-        let filter = FilterEngine::PermitSome(AllowRules {
-            udp: RangeInclusiveSet::default(),
-            tcp: RangeInclusiveSet::default(),
-            icmp: true,
-        });
+        let filter = engine([ProtocolFilter::Icmp.into()]);
 
-        let result = filter.apply(Err(UnsupportedProtocol::UnsupportedIcmpv6Type(
-            Icmpv6Type::DestinationUnreachable(icmpv6::DestUnreachableCode::Address),
-        )));
+        let result = filter.apply(
+            resource_ip(),
+            Err(UnsupportedProtocol::UnsupportedIcmpv6Type(
+                Icmpv6Type::DestinationUnreachable(icmpv6::DestUnreachableCode::Address),
+            )),
+        );
 
         assert!(result.is_ok())
     }
 
     #[test]
     fn icmp_false_blocks_other_icmp_messages() {
-        let filter = FilterEngine::PermitSome(AllowRules {
-            udp: RangeInclusiveSet::default(),
-            tcp: RangeInclusiveSet::default(),
-            icmp: false,
-        });
+        let filter = engine([ProtocolFilter::Udp(port(53)).into()]);
 
-        let result = filter.apply(Err(UnsupportedProtocol::UnsupportedIcmpv4Type(
-            Icmpv4Type::TimestampRequest(icmpv4::TimestampMessage::from_bytes([0u8; 16])),
-        )));
+        let result = filter.apply(
+            resource_ip(),
+            Err(UnsupportedProtocol::UnsupportedIcmpv4Type(
+                Icmpv4Type::TimestampRequest(icmpv4::TimestampMessage::from_bytes([0u8; 16])),
+            )),
+        );
 
         assert!(result.is_err())
     }
+
+    #[test]
+    fn scoped_allow_only_applies_to_destination() {
+        let filter = engine([Filter {
+            protocol: ProtocolFilter::Tcp(port(443)),
+            destination: Some("10.0.1.0/24".parse().unwrap()),
+            action: FilterAction::Allow,
+        }]);
+
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 1), Ok(Protocol::Tcp(443)))
+                .is_ok()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 1), Ok(Protocol::Tcp(80)))
+                .is_err()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 2, 1), Ok(Protocol::Tcp(443)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn longest_prefix_deny_overrides_allow() {
+        let filter = engine([
+            Filter {
+                protocol: ProtocolFilter::Tcp(port(443)),
+                destination: Some("10.0.1.0/24".parse().unwrap()),
+                action: FilterAction::Allow,
+            },
+            Filter {
+                protocol: ProtocolFilter::All,
+                destination: Some("10.0.1.5/32".parse().unwrap()),
+                action: FilterAction::Deny,
+            },
+        ]);
+
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 4), Ok(Protocol::Tcp(443)))
+                .is_ok()
+        );
+        assert!(matches!(
+            filter.apply(ip(10, 0, 1, 5), Ok(Protocol::Tcp(443))),
+            Err(Filtered::Denied(_))
+        ));
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 5), Ok(Protocol::IcmpEcho(1)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn longest_prefix_allow_overrides_deny() {
+        let filter = engine([
+            Filter {
+                protocol: ProtocolFilter::Udp(port(53)),
+                destination: Some("10.0.0.0/16".parse().unwrap()),
+                action: FilterAction::Deny,
+            },
+            Filter {
+                protocol: ProtocolFilter::Udp(port(53)),
+                destination: Some("10.0.0.53/32".parse().unwrap()),
+                action: FilterAction::Allow,
+            },
+        ]);
+
+        assert!(
+            filter
+                .apply(ip(10, 0, 0, 53), Ok(Protocol::Udp(53)))
+                .is_ok()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 0, 54), Ok(Protocol::Udp(53)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn more_specific_rule_only_applies_to_its_protocol() {
+        let filter = engine([
+            ProtocolFilter::Tcp(port(22)).into(),
+            Filter {
+                protocol: ProtocolFilter::Udp(port(53)),
+                destination: Some("10.0.0.53/32".parse().unwrap()),
+                action: FilterAction::Allow,
+            },
+        ]);
+
+        assert!(
+            filter
+                .apply(ip(10, 0, 0, 53), Ok(Protocol::Tcp(22)))
+                .is_ok()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 0, 53), Ok(Protocol::Udp(53)))
+                .is_ok()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 0, 54), Ok(Protocol::Udp(53)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn deny_wins_on_same_prefix() {
+        let filter = engine([
+            ProtocolFilter::All.into(),
+            Filter {
+                protocol: ProtocolFilter::Tcp(port(22)),
+                destination: None,
+                action: FilterAction::Deny,
+            },
+        ]);
+
+        assert!(filter.apply(resource_ip(), Ok(Protocol::Tcp(22))).is_err());
+        assert!(filter.apply(resource_ip(), Ok(Protocol::Tcp(23))).is_ok());
+    }
+
+    #[test]
+    fn only_deny_rules_allow_nothing() {
+        let filter = engine([Filter {
+            protocol: ProtocolFilter::All,
+            destination: Some("10.0.1.5/32".parse().unwrap()),
+            action: FilterAction::Deny,
+        }]);
+
+        assert!(matches!(
+            filter.apply(ip(10, 0, 1, 4), Ok(Protocol::Tcp(443))),
+            Err(Filtered::Tcp)
+        ));
+        assert!(matches!(
+            filter.apply(ip(10, 0, 1, 4), Ok(Protocol::IcmpEcho(1))),
+            Err(Filtered::Icmp)
+        ));
+        assert!(matches!(
+            filter.apply(ip(10, 0, 1, 5), Ok(Protocol::Udp(53))),
+            Err(Filtered::Denied(_))
+        ));
+    }
+
+    #[test]
+    fn deny_of_one_resource_overrides_allow_of_another() {
+        let resource_a = vec![Filter {
+            protocol: ProtocolFilter::All,
+            destination: Some("10.0.1.5/32".parse().unwrap()),
+            action: FilterAction::Deny,
+        }];
+        let resource_b = vec![ProtocolFilter::Tcp(port(443)).into()];
+
+        let filter = FilterEngine::with_filters([&resource_a, &resource_b].into_iter());
+
+        assert!(matches!(
+            filter.apply(ip(10, 0, 1, 5), Ok(Protocol::Tcp(443))),
+            Err(Filtered::Denied(_))
+        ));
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 4), Ok(Protocol::Tcp(443)))
+                .is_ok()
+        );
+        assert!(
+            filter
+                .apply(ip(10, 0, 1, 4), Ok(Protocol::Tcp(80)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn deny_only_resource_does_not_widen_another() {
+        let resource_a = vec![Filter {
+            protocol: ProtocolFilter::Udp(port(53)),
+            destination: None,
+            action: FilterAction::Deny,
+        }];
+        let resource_b = vec![ProtocolFilter::Tcp(port(443)).into()];
+
+        let filter = FilterEngine::with_filters([&resource_a, &resource_b].into_iter());
+
+        assert!(filter.apply(resource_ip(), Ok(Protocol::Tcp(443))).is_ok());
+        assert!(matches!(
+            filter.apply(resource_ip(), Ok(Protocol::Tcp(80))),
+            Err(Filtered::Tcp)
+        ));
+        assert!(matches!(
+            filter.apply(resource_ip(), Ok(Protocol::Udp(53))),
+            Err(Filtered::Denied(_))
+        ));
+    }
+
+    fn engine(filters: impl IntoIterator<Item = Filter>) -> FilterEngine {
+        FilterEngine::PermitSome(vec![AllowRules::new(&filters.into_iter().collect_vec())])
+    }
+
+    fn port(port: u16) -> PortRange {
+        PortRange {
+            port_range_start: port,
+            port_range_end: port,
+        }
+    }
+
+    fn ip(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn resource_ip() -> IpAddr {
+        ip(10, 0, 0, 1)
+    }
 }
@@ -24,6 +24,8 @@ pub struct FlowTracker {
     completed_flows: VecDeque<CompletedFlow>,
 
     enabled: bool,
+    /// Since when we are tracking flows.
+    enabled_at: Instant,
     created_at: Instant,
     created_at_utc: DateTime<Utc>,
 }
@@ -51,6 +53,7 @@ impl FlowTracker {
             active_udp_flows: Default::default(),
             completed_flows: Default::default(),
             enabled,
+            enabled_at: now,
             created_at: now,
             created_at_utc: Utc::now(),
         }
@@ -117,6 +120,34 @@ impl FlowTracker {
         }
     }
 
+    /// Starts tracking flows, if we aren't already.
+    ///
+    /// Flows that are already active are picked up with their next packet.
+    pub fn enable(&mut self, now: Instant) {
+        if self.enabled {
+            return;
+        }
+
+        self.enabled = true;
+        self.enabled_at = now;
+    }
+
+    pub fn num_active_flows(&self) -> usize {
+        self.active_tcp_flows.len() + self.active_udp_flows.len()
+    }
+
+    /// Whether there are no active TCP or UDP flows.
+    ///
+    /// We only learn about a flow with its next packet.
+    /// To not miss any, we need to have been tracking flows for at least as long as it takes for an idle flow to time out.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        let tracked_for = TimeDelta::from_std(now.duration_since(self.enabled_at));
+
+        self.enabled
+            && tracked_for.is_ok_and(|tracked_for| tracked_for > FLOW_TIMEOUT)
+            && self.num_active_flows() == 0
+    }
+
     pub fn poll_completed_flow(&mut self) -> Option<CompletedFlow> {
         self.completed_flows.pop_front()
     }
@@ -1048,9 +1079,22 @@ impl From<&IpPacket> for InnerFlow {
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
+    use std::time::Duration;
 
     use super::*;
 
+    #[test]
+    fn is_only_idle_after_tracking_for_flow_timeout() {
+        let now = Instant::now();
+        let mut tracker = FlowTracker::new(false, now);
+
+        assert!(!tracker.is_idle(now));
+
+        tracker.enable(now);
+        assert!(!tracker.is_idle(now + Duration::from_secs(60)));
+        assert!(tracker.is_idle(now + Duration::from_secs(121)));
+    }
+
     #[test]
     fn flow_context_diff_rendering() {
         let old = FlowContext {
@@ -289,28 +289,46 @@ pub struct IcmpErrorPrototype {
 impl IcmpErrorPrototype {
     /// Turns this prototype into an actual ICMP error IP packet, targeting the given IPv4/IPv6 address, depending on the original Resource address.
     pub fn into_packet(self, dst_v4: Ipv4Addr, dst_v6: Ipv6Addr) -> Result<IpPacket> {
+        // If we translated between IP versions, the ICMP error is first created in the IP version of the Resource.
+        let original_dst = if self.failed_packet.dst().is_ipv4() == self.inside_dst.is_ipv4() {
+            self.inside_dst
+        } else {
+            self.failed_packet.dst()
+        };
+
         // First, translate the failed packet as if it would have directly originated from the client (without our NAT applied).
         let original_packet = self
             .failed_packet
-            .translate_destination(self.inside_dst, self.inside_proto)
+            .translate_destination(original_dst, self.inside_proto)
             .context("Failed to translate unroutable packet within ICMP error")?;
 
         // Second, generate an ICMP error that originates from the originally addressed Resource.
-        match self.inside_dst {
-            IpAddr::V4(inside_dst) => {
+        let icmp_error = match original_dst {
+            IpAddr::V4(original_dst) => {
                 let icmp_type = self.icmp_error.into_icmp_v4_type()?;
-                let icmpv4 =
-                    PacketBuilder::ipv4(inside_dst.octets(), dst_v4.octets(), 20).icmpv4(icmp_type);
+                let icmpv4 = PacketBuilder::ipv4(original_dst.octets(), dst_v4.octets(), 20)
+                    .icmpv4(icmp_type);
 
-                ip_packet::build!(icmpv4, original_packet)
+                ip_packet::build!(icmpv4, original_packet)?
             }
-            IpAddr::V6(inside_dst) => {
+            IpAddr::V6(original_dst) => {
                 let icmp_type = self.icmp_error.into_icmp_v6_type()?;
-                let icmpv6 =
-                    PacketBuilder::ipv6(inside_dst.octets(), dst_v6.octets(), 20).icmpv6(icmp_type);
+                let icmpv6 = PacketBuilder::ipv6(original_dst.octets(), dst_v6.octets(), 20)
+                    .icmpv6(icmp_type);
 
-                ip_packet::build!(icmpv6, original_packet)
+                ip_packet::build!(icmpv6, original_packet)?
             }
+        };
+
+        // Third, translate the ICMP error to the IP version the client used (NAT64 / NAT46).
+        match (original_dst, self.inside_dst) {
+            (IpAddr::V4(_), IpAddr::V6(inside_dst)) => icmp_error
+                .consume_to_ipv6(inside_dst, dst_v6)
+                .context("Failed to translate ICMP error to IPv6"),
+            (IpAddr::V6(_), IpAddr::V4(inside_dst)) => icmp_error
+                .consume_to_ipv4(inside_dst, dst_v4)
+                .context("Failed to translate ICMP error to IPv4"),
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => Ok(icmp_error),
         }
     }
 
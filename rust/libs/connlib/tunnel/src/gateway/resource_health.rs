@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use connlib_model::ResourceId;
+use dns_types::DomainName;
+
+use crate::p2p_control::resource_health::{HealthReason, HealthStatus};
+
+/// How often we re-send the health of resources that are not healthy to the clients accessing them.
+///
+/// The p2p control protocol is unreliable, re-sending the status ensures clients eventually learn about it.
+const BROADCAST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// For how long a signal observed in the traffic of a resource affects its health.
+const SIGNAL_TTL: Duration = Duration::from_secs(60);
+
+/// The window in which we count TCP resets of a resource.
+const TCP_RESET_WINDOW: Duration = Duration::from_secs(10);
+
+/// How many TCP resets within [`TCP_RESET_WINDOW`] we consider a "reset storm".
+const TCP_RESET_THRESHOLD: usize = 10;
+
+/// The resource, or the particular domain of a DNS resource, a health applies to.
+pub(crate) type HealthKey = (ResourceId, Option<DomainName>);
+
+/// Something we observed in the traffic of a resource that indicates it may not be healthy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HealthSignal {
+    TcpReset,
+    IcmpUnreachable,
+}
+
+/// Combines active probes and passive signals into the health of resources and domains.
+///
+/// The health of a resource is the worst of all findings about it.
+/// Passive findings expire after [`SIGNAL_TTL`] unless they are observed again.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceHealthTracker {
+    findings: BTreeMap<HealthKey, BTreeMap<Reason, Finding>>,
+    tcp_resets: BTreeMap<HealthKey, VecDeque<Instant>>,
+
+    /// The health we last reported for a resource or domain.
+    ///
+    /// Only contains entries that are not healthy.
+    reported: BTreeMap<HealthKey, (HealthStatus, HealthReason)>,
+    next_broadcast: Option<Instant>,
+
+    pending_updates: VecDeque<HealthUpdate>,
+}
+
+/// The health of a resource or domain that needs to be sent to the clients accessing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HealthUpdate {
+    pub resource: ResourceId,
+    pub domain: Option<DomainName>,
+    pub status: HealthStatus,
+    pub reason: Option<HealthReason>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Reason {
+    Probe,
+    DnsFailure,
+    TcpResets,
+    IcmpUnreachable,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Finding {
+    status: HealthStatus,
+    expires_at: Option<Instant>,
+}
+
+impl ResourceHealthTracker {
+    /// Records the health of a resource as determined by probing it.
+    pub(crate) fn on_probe_result(&mut self, rid: ResourceId, status: HealthStatus) {
+        let key = (rid, None);
+
+        match status {
+            HealthStatus::Healthy | HealthStatus::Unknown => self.clear(&key, Reason::Probe),
+            HealthStatus::Degraded | HealthStatus::Unreachable => self.insert(
+                key.clone(),
+                Reason::Probe,
+                Finding {
+                    status,
+                    expires_at: None,
+                },
+            ),
+        }
+
+        self.update(key);
+    }
+
+    /// Records the outcome of resolving a domain of a DNS resource.
+    ///
+    /// Failing to resolve a domain or not getting any IPs for it renders the domain unreachable.
+    pub(crate) fn on_domain_resolved(
+        &mut self,
+        rid: ResourceId,
+        domain: DomainName,
+        resolved: bool,
+        now: Instant,
+    ) {
+        let key = (rid, Some(domain));
+
+        if resolved {
+            self.clear(&key, Reason::DnsFailure);
+        } else {
+            self.insert(
+                key.clone(),
+                Reason::DnsFailure,
+                Finding {
+                    status: HealthStatus::Unreachable,
+                    expires_at: Some(now + SIGNAL_TTL),
+                },
+            );
+        }
+
+        self.update(key);
+    }
+
+    /// Records a signal we observed in the traffic of a resource.
+    pub(crate) fn on_signal(&mut self, key: HealthKey, signal: HealthSignal, now: Instant) {
+        match signal {
+            HealthSignal::TcpReset => {
+                let resets = self.tcp_resets.entry(key.clone()).or_default();
+                resets.push_back(now);
+                resets.retain(|at| now.duration_since(*at) < TCP_RESET_WINDOW);
+
+                if resets.len() < TCP_RESET_THRESHOLD {
+                    return;
+                }
+
+                self.insert(
+                    key.clone(),
+                    Reason::TcpResets,
+                    Finding {
+                        status: HealthStatus::Degraded,
+                        expires_at: Some(now + SIGNAL_TTL),
+                    },
+                );
+            }
+            HealthSignal::IcmpUnreachable => {
+                self.insert(
+                    key.clone(),
+                    Reason::IcmpUnreachable,
+                    Finding {
+                        status: HealthStatus::Degraded,
+                        expires_at: Some(now + SIGNAL_TTL),
+                    },
+                );
+            }
+        }
+
+        self.update(key);
+    }
+
+    /// Only retains the health of resources for which `f` returns `true`.
+    pub(crate) fn retain_resources(&mut self, mut f: impl FnMut(&ResourceId) -> bool) {
+        self.findings.retain(|(rid, _), _| f(rid));
+        self.tcp_resets.retain(|(rid, _), _| f(rid));
+        self.reported.retain(|(rid, _), _| f(rid));
+    }
+
+    pub(crate) fn poll_update(&mut self) -> Option<HealthUpdate> {
+        self.pending_updates.pop_front()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        let next_expiry = self
+            .findings
+            .values()
+            .flat_map(|findings| findings.values())
+            .filter_map(|f| f.expires_at)
+            .min();
+
+        self.next_broadcast.into_iter().chain(next_expiry).min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+
+        for (key, findings) in self.findings.iter_mut() {
+            let num_findings = findings.len();
+            findings.retain(|_, f| f.expires_at.is_none_or(|at| at > now));
+
+            if findings.len() != num_findings {
+                expired.push(key.clone());
+            }
+        }
+
+        self.findings.retain(|_, findings| !findings.is_empty());
+        self.tcp_resets.retain(|_, resets| {
+            resets.retain(|at| now.duration_since(*at) < TCP_RESET_WINDOW);
+
+            !resets.is_empty()
+        });
+
+        for key in expired {
+            self.update(key);
+        }
+
+        match self.next_broadcast {
+            Some(next_broadcast) if now >= next_broadcast => {
+                self.pending_updates.extend(self.reported.iter().map(
+                    |((rid, domain), (status, reason))| HealthUpdate {
+                        resource: *rid,
+                        domain: domain.clone(),
+                        status: *status,
+                        reason: Some(*reason),
+                    },
+                ));
+
+                self.next_broadcast = Some(now + BROADCAST_INTERVAL);
+            }
+            None => self.next_broadcast = Some(now + BROADCAST_INTERVAL),
+            Some(_) => {}
+        }
+    }
+
+    fn insert(&mut self, key: HealthKey, reason: Reason, finding: Finding) {
+        self.findings
+            .entry(key)
+            .or_default()
+            .insert(reason, finding);
+    }
+
+    fn clear(&mut self, key: &HealthKey, reason: Reason) {
+        let Some(findings) = self.findings.get_mut(key) else {
+            return;
+        };
+
+        findings.remove(&reason);
+
+        if findings.is_empty() {
+            self.findings.remove(key);
+        }
+    }
+
+    /// Re-computes the health of a resource or domain and queues an update if it changed.
+    fn update(&mut self, key: HealthKey) {
+        let current = self.findings.get(&key).and_then(|findings| {
+            findings
+                .iter()
+                .max_by_key(|(_, f)| severity(f.status))
+                .map(|(reason, f)| (f.status, reason.into_health_reason()))
+        });
+        let previous = match current {
+            Some(current) => self.reported.insert(key.clone(), current),
+            None => self.reported.remove(&key),
+        };
+
+        if previous == current {
+            return;
+        }
+
+        let (resource, domain) = key;
+        let (status, reason) = match current {
+            Some((status, reason)) => (status, Some(reason)),
+            None => (HealthStatus::Healthy, None),
+        };
+
+        tracing::info!(%resource, domain = domain.as_ref().map(tracing::field::display), ?previous, ?status, ?reason, "Resource health changed");
+
+        self.pending_updates.push_back(HealthUpdate {
+            resource,
+            domain,
+            status,
+            reason,
+        });
+    }
+}
+
+impl Reason {
+    fn into_health_reason(self) -> HealthReason {
+        match self {
+            Reason::Probe => HealthReason::Probe,
+            Reason::DnsFailure => HealthReason::DnsFailure,
+            Reason::TcpResets => HealthReason::TcpResets,
+            Reason::IcmpUnreachable => HealthReason::IcmpUnreachable,
+        }
+    }
+}
+
+fn severity(status: HealthStatus) -> u8 {
+    match status {
+        HealthStatus::Healthy | HealthStatus::Unknown => 0,
+        HealthStatus::Degraded => 1,
+        HealthStatus::Unreachable => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_probe_is_reported() {
+        let mut tracker = ResourceHealthTracker::default();
+
+        tracker.on_probe_result(RID, HealthStatus::Unreachable);
+
+        assert_eq!(
+            tracker.poll_update(),
+            Some(update(None, HealthStatus::Unreachable, HealthReason::Probe))
+        );
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    #[test]
+    fn unchanged_health_is_not_reported_again() {
+        let mut tracker = ResourceHealthTracker::default();
+
+        tracker.on_probe_result(RID, HealthStatus::Healthy);
+        tracker.on_probe_result(RID, HealthStatus::Degraded);
+        tracker.poll_update();
+        tracker.on_probe_result(RID, HealthStatus::Degraded);
+
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    #[test]
+    fn recovery_is_reported() {
+        let mut tracker = ResourceHealthTracker::default();
+
+        tracker.on_probe_result(RID, HealthStatus::Degraded);
+        tracker.poll_update();
+        tracker.on_probe_result(RID, HealthStatus::Healthy);
+
+        assert_eq!(
+            tracker.poll_update(),
+            Some(HealthUpdate {
+                resource: RID,
+                domain: None,
+                status: HealthStatus::Healthy,
+                reason: None,
+            })
+        );
+    }
+
+    #[test]
+    fn failed_resolution_makes_domain_unreachable_until_resolved() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+
+        tracker.on_domain_resolved(RID, domain(), false, now);
+
+        assert_eq!(
+            tracker.poll_update(),
+            Some(update(
+                Some(domain()),
+                HealthStatus::Unreachable,
+                HealthReason::DnsFailure
+            ))
+        );
+
+        tracker.on_domain_resolved(RID, domain(), true, now);
+
+        assert_eq!(
+            tracker.poll_update().map(|u| (u.domain, u.status)),
+            Some((Some(domain()), HealthStatus::Healthy))
+        );
+    }
+
+    #[test]
+    fn single_tcp_reset_is_not_reported() {
+        let mut tracker = ResourceHealthTracker::default();
+
+        tracker.on_signal((RID, None), HealthSignal::TcpReset, Instant::now());
+
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    #[test]
+    fn tcp_reset_storm_degrades_resource() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+
+        for i in 0..TCP_RESET_THRESHOLD {
+            tracker.on_signal(
+                (RID, None),
+                HealthSignal::TcpReset,
+                now + Duration::from_millis(i as u64 * 100),
+            );
+        }
+
+        assert_eq!(
+            tracker.poll_update(),
+            Some(update(
+                None,
+                HealthStatus::Degraded,
+                HealthReason::TcpResets
+            ))
+        );
+    }
+
+    #[test]
+    fn spread_out_tcp_resets_are_not_reported() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+
+        for i in 0..(TCP_RESET_THRESHOLD * 2) {
+            tracker.on_signal(
+                (RID, None),
+                HealthSignal::TcpReset,
+                now + TCP_RESET_WINDOW * i as u32,
+            );
+        }
+
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    #[test]
+    fn passive_signals_expire() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+
+        tracker.on_signal((RID, None), HealthSignal::IcmpUnreachable, now);
+        tracker.poll_update();
+
+        assert_eq!(tracker.poll_timeout(), Some(now + SIGNAL_TTL));
+
+        tracker.handle_timeout(now + SIGNAL_TTL);
+
+        assert_eq!(
+            tracker.poll_update().map(|u| u.status),
+            Some(HealthStatus::Healthy)
+        );
+    }
+
+    #[test]
+    fn worst_finding_wins() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+
+        tracker.on_signal((RID, None), HealthSignal::IcmpUnreachable, now);
+        tracker.on_probe_result(RID, HealthStatus::Unreachable);
+
+        assert_eq!(
+            tracker.poll_update().map(|u| u.status),
+            Some(HealthStatus::Degraded)
+        );
+        assert_eq!(
+            tracker.poll_update(),
+            Some(update(None, HealthStatus::Unreachable, HealthReason::Probe))
+        );
+
+        tracker.handle_timeout(now + SIGNAL_TTL);
+
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    #[test]
+    fn unhealthy_resources_are_periodically_reported() {
+        let mut tracker = ResourceHealthTracker::default();
+        let now = Instant::now();
+        tracker.handle_timeout(now);
+
+        tracker.on_probe_result(RID, HealthStatus::Degraded);
+        tracker.on_probe_result(ResourceId::from_u128(2), HealthStatus::Healthy);
+        tracker.poll_update();
+
+        tracker.handle_timeout(now + BROADCAST_INTERVAL);
+
+        assert_eq!(
+            tracker.poll_update(),
+            Some(update(None, HealthStatus::Degraded, HealthReason::Probe))
+        );
+        assert_eq!(tracker.poll_update(), None);
+    }
+
+    fn update(
+        domain: Option<DomainName>,
+        status: HealthStatus,
+        reason: HealthReason,
+    ) -> HealthUpdate {
+        HealthUpdate {
+            resource: RID,
+            domain,
+            status,
+            reason: Some(reason),
+        }
+    }
+
+    fn domain() -> DomainName {
+        DomainName::vec_from_str("gitlab.example.com").unwrap()
+    }
+
+    const RID: ResourceId = ResourceId::from_u128(1);
+}
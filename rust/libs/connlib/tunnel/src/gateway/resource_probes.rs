@@ -0,0 +1,198 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use dns_types::DomainName;
+use ip_network::IpNetwork;
+
+use crate::messages::gateway::{Filter, FilterAction, ProtocolFilter};
+
+/// Something the Gateway can probe to determine whether a resource is reachable.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProbeTarget {
+    /// Open a TCP connection to the given socket.
+    TcpConnect(SocketAddr),
+    /// Send an ICMP echo request to the given IP.
+    IcmpEcho(IpAddr),
+    /// Resolve the given domain.
+    Dns(DomainName),
+}
+
+impl ProbeTarget {
+    /// The kind of probe, without its target.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProbeTarget::TcpConnect(_) => "tcp",
+            ProbeTarget::IcmpEcho(_) => "icmp",
+            ProbeTarget::Dns(_) => "dns",
+        }
+    }
+}
+
+impl fmt::Display for ProbeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeTarget::TcpConnect(socket) => write!(f, "tcp:{socket}"),
+            ProbeTarget::IcmpEcho(ip) => write!(f, "icmp:{ip}"),
+            ProbeTarget::Dns(domain) => write!(f, "dns:{domain}"),
+        }
+    }
+}
+
+/// Derives the probes for a CIDR resource.
+///
+/// Only resources that point to a single host can be probed, larger networks are skipped.
+pub(crate) fn cidr_targets(network: IpNetwork, filters: &[Filter]) -> Vec<ProbeTarget> {
+    if !is_single_host(network) {
+        return Vec::new();
+    }
+
+    ip_targets(network.network_address(), filters)
+}
+
+/// Derives the probes for a domain of a DNS resource that we have resolved.
+pub(crate) fn domain_targets<'a>(
+    domain: &DomainName,
+    resolved_ips: impl IntoIterator<Item = &'a IpAddr>,
+    filters: &[Filter],
+) -> Vec<ProbeTarget> {
+    std::iter::once(ProbeTarget::Dns(domain.clone()))
+        .chain(
+            resolved_ips
+                .into_iter()
+                .flat_map(|ip| ip_targets(*ip, filters)),
+        )
+        .collect()
+}
+
+/// Derives the probes for a single IP of a resource from the filters that allow traffic to it.
+///
+/// - TCP filters are probed by connecting to the first port of their range.
+/// - ICMP filters and resources without filters are probed with an ICMP echo request.
+/// - UDP filters cannot be probed reliably and are therefore skipped.
+fn ip_targets(ip: IpAddr, filters: &[Filter]) -> Vec<ProbeTarget> {
+    if filters.is_empty() {
+        return vec![ProbeTarget::IcmpEcho(ip)];
+    }
+
+    let mut targets = filters
+        .iter()
+        .filter(|f| f.action == FilterAction::Allow)
+        .filter(|f| f.destination.is_none_or(|d| d.contains(ip)))
+        .filter_map(|f| match f.protocol {
+            ProtocolFilter::Tcp(range) if range.port_range_start != 0 => Some(
+                ProbeTarget::TcpConnect(SocketAddr::new(ip, range.port_range_start)),
+            ),
+            ProtocolFilter::Tcp(_) | ProtocolFilter::Icmp | ProtocolFilter::All => {
+                Some(ProbeTarget::IcmpEcho(ip))
+            }
+            ProtocolFilter::Udp(_) => None,
+        })
+        .collect::<Vec<_>>();
+    targets.sort();
+    targets.dedup();
+
+    targets
+}
+
+fn is_single_host(network: IpNetwork) -> bool {
+    match network {
+        IpNetwork::V4(n) => n.netmask() == 32,
+        IpNetwork::V6(n) => n.netmask() == 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use ip_network::Ipv4Network;
+
+    use super::*;
+    use crate::messages::gateway::PortRange;
+
+    #[test]
+    fn resource_without_filters_is_pinged() {
+        let targets = cidr_targets(host(), &[]);
+
+        assert_eq!(targets, vec![ProbeTarget::IcmpEcho(IP.into())]);
+    }
+
+    #[test]
+    fn tcp_filters_are_probed_with_connect() {
+        let targets = cidr_targets(host(), &[tcp(443, 443), tcp(22, 22)]);
+
+        assert_eq!(
+            targets,
+            vec![
+                ProbeTarget::TcpConnect(SocketAddr::new(IP.into(), 22)),
+                ProbeTarget::TcpConnect(SocketAddr::new(IP.into(), 443)),
+            ]
+        );
+    }
+
+    #[test]
+    fn udp_filters_are_not_probed() {
+        let targets = cidr_targets(
+            host(),
+            &[Filter::from(ProtocolFilter::Udp(PortRange {
+                port_range_start: 53,
+                port_range_end: 53,
+            }))],
+        );
+
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn denied_traffic_is_not_probed() {
+        let targets = cidr_targets(
+            host(),
+            &[Filter {
+                action: FilterAction::Deny,
+                ..tcp(443, 443)
+            }],
+        );
+
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn networks_are_not_probed() {
+        let targets = cidr_targets(
+            Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 24)
+                .unwrap()
+                .into(),
+            &[],
+        );
+
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn domains_are_resolved_and_probed() {
+        let domain = "example.com".parse::<DomainName>().unwrap();
+
+        let targets = domain_targets(&domain, &[IpAddr::from(IP)], &[tcp(443, 8443)]);
+
+        assert_eq!(
+            targets,
+            vec![
+                ProbeTarget::Dns(domain),
+                ProbeTarget::TcpConnect(SocketAddr::new(IP.into(), 443)),
+            ]
+        );
+    }
+
+    fn tcp(start: u16, end: u16) -> Filter {
+        Filter::from(ProtocolFilter::Tcp(PortRange {
+            port_range_start: start,
+            port_range_end: end,
+        }))
+    }
+
+    fn host() -> IpNetwork {
+        Ipv4Network::new(IP, 32).unwrap().into()
+    }
+
+    const IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+}
@@ -1,6 +1,8 @@
 mod doh;
+mod dot;
 mod gso_queue;
 mod nameserver_set;
+mod relay_streams;
 mod tcp_dns;
 mod udp_dns;
 
@@ -8,6 +10,7 @@ use crate::{TunnelError, device_channel::Device, dns, otel, sockets::Sockets};
 use anyhow::{Context as _, ErrorExt, Result};
 use chrono::{DateTime, Utc};
 use dns_types::DoHUrl;
+use dot::DoTClient;
 use futures::FutureExt as _;
 use futures_bounded::{FuturesMap, FuturesTupleSet};
 use gat_lending_iterator::LendingIterator;
@@ -15,6 +18,7 @@ use gso_queue::GsoQueue;
 use http_client::HttpClient;
 use ip_packet::{Ecn, IpPacket, MAX_FZ_PAYLOAD};
 use nameserver_set::NameserverSet;
+use relay_streams::RelayStreams;
 use socket_factory::{DatagramIn, SocketFactory, TcpSocket, UdpSocket};
 use std::{
     collections::{BTreeMap, BTreeSet, VecDeque},
@@ -48,6 +52,8 @@ pub struct Io {
     /// The UDP sockets used to send & receive packets from the network.
     sockets: Sockets,
     gso_queue: GsoQueue,
+    /// The TCP and TLS connections to relays that we can't reach via UDP.
+    relay_streams: RelayStreams,
 
     nameservers: NameserverSet,
     reval_nameserver_interval: tokio::time::Interval,
@@ -63,6 +69,8 @@ pub struct Io {
     udp_dns_client: l4_udp_dns_client::UdpDnsClient,
     doh_clients: BTreeMap<DoHUrl, HttpClient>,
     doh_clients_bootstrap: FuturesMap<DoHUrl, Result<HttpClient>>,
+    dot_clients: BTreeMap<DoTServer, DoTClient>,
+    dot_clients_bootstrap: FuturesMap<DoTServer, Result<DoTClient>>,
 
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
 
@@ -72,6 +80,9 @@ pub struct Io {
     dropped_packets: opentelemetry::metrics::Counter<u64>,
 }
 
+/// A DoT server is identified by its address and the name we validate its certificate against.
+type DoTServer = (SocketAddr, String);
+
 #[derive(Debug, Clone)]
 struct DnsQueryMetaData {
     query: dns_types::Query,
@@ -103,6 +114,7 @@ pub struct Input<D, I> {
     pub timeout: bool,
     pub device: Option<D>,
     pub network: Option<I>,
+    pub relay_messages: Vec<relay_streams::Received>,
     pub tcp_dns_queries: Vec<l4_tcp_dns_server::Query>,
     pub udp_dns_queries: Vec<l4_udp_dns_server::Query>,
     pub dns_response: Option<dns::RecursiveResponse>,
@@ -117,6 +129,7 @@ impl<D, I> Input<D, I> {
             timeout: false,
             device: None,
             network: None,
+            relay_messages: Vec::new(),
             tcp_dns_queries: Vec::new(),
             udp_dns_queries: Vec::new(),
             dns_response: None,
@@ -166,6 +179,7 @@ impl Io {
             outbound_packet_buffer: VecDeque::default(),
             timeout: None,
             sockets,
+            relay_streams: RelayStreams::new(tcp_socket_factory.clone()),
             nameservers: NameserverSet::new(
                 nameservers,
                 tcp_socket_factory.clone(),
@@ -187,6 +201,11 @@ impl Io {
                 || futures_bounded::Delay::tokio(DNS_QUERY_TIMEOUT),
                 10,
             ),
+            dot_clients: Default::default(),
+            dot_clients_bootstrap: FuturesMap::new(
+                || futures_bounded::Delay::tokio(DNS_QUERY_TIMEOUT),
+                10,
+            ),
             gso_queue: GsoQueue::new(),
             tun: Device::new(),
             udp_dns_server: Default::default(),
@@ -287,6 +306,22 @@ impl Io {
             }
         }
 
+        while let Poll::Ready(((server, tls_name), result)) =
+            self.dot_clients_bootstrap.poll_unpin(cx)
+        {
+            match result {
+                Ok(Ok(client)) => {
+                    self.dot_clients.insert((server, tls_name), client);
+                }
+                Ok(Err(e)) => {
+                    tracing::debug!(%server, %tls_name, "Failed to bootstrap DoT client: {e:#}")
+                }
+                Err(e) => {
+                    tracing::debug!(%server, %tls_name, "Failed to bootstrap DoT client: {e:#}")
+                }
+            }
+        }
+
         let network = self.sockets.poll_recv_from(cx).map(|network| {
             anyhow::Ok(
                 network
@@ -295,6 +330,11 @@ impl Io {
             )
         });
 
+        let relay_messages =
+            std::iter::from_fn(|| poll_to_option(self.relay_streams.poll_recv(cx)))
+                .take(MAX_INBOUND_PACKET_BATCH)
+                .collect::<Vec<_>>();
+
         let device = self
             .tun
             .poll_read_many(cx, &mut buffers.ip, MAX_INBOUND_PACKET_BATCH)
@@ -388,6 +428,17 @@ impl Io {
             self.doh_clients.remove(server);
         }
 
+        // Same for DoT clients.
+        if let Poll::Ready(response) = &dns_response
+            && let dns::Upstream::DoT { server, tls_name } = &response.server
+            && let Err(e) = &response.message
+            && e.any_is::<dot::Closed>()
+        {
+            tracing::debug!(%server, %tls_name, "Connection of DoT client failed");
+
+            self.dot_clients.remove(&(*server, tls_name.clone()));
+        }
+
         let timeout = self
             .timeout
             .as_mut()
@@ -401,6 +452,7 @@ impl Io {
         if !timeout
             && device.is_pending()
             && network.is_pending()
+            && relay_messages.is_empty()
             && tcp_dns_queries.is_empty()
             && udp_dns_queries.is_empty()
             && dns_response.is_pending()
@@ -415,6 +467,7 @@ impl Io {
             timeout,
             device: poll_to_option(device),
             network: poll_result_to_option(network, &mut error),
+            relay_messages,
             tcp_dns_queries,
             udp_dns_queries,
             dns_response: poll_to_option(dns_response),
@@ -485,6 +538,7 @@ impl Io {
         self.udp_socket_factory.reset();
         self.sockets.rebind(self.udp_socket_factory.clone());
         self.gso_queue.clear();
+        self.relay_streams.reset();
         self.dns_queries =
             FuturesTupleSet::new(|| futures_bounded::Delay::tokio(DNS_QUERY_TIMEOUT), 1000);
         self.nameservers.evaluate();
@@ -492,6 +546,10 @@ impl Io {
         for (server, _) in std::mem::take(&mut self.doh_clients) {
             self.bootstrap_doh_client(server);
         }
+
+        for ((server, tls_name), _) in std::mem::take(&mut self.dot_clients) {
+            self.bootstrap_dot_client(server, tls_name);
+        }
     }
 
     pub fn reset_timeout(&mut self, timeout: Instant, reason: &'static str) {
@@ -523,14 +581,26 @@ impl Io {
         dst: SocketAddr,
         payload: &[u8],
         ecn: Ecn,
+        transport: snownet::Transport,
     ) {
-        self.gso_queue.enqueue(src, dst, payload, ecn);
+        let transport_attr = match transport {
+            snownet::Transport::Udp => {
+                self.gso_queue.enqueue(src, dst, payload, ecn);
+
+                otel::attr::network_transport_udp()
+            }
+            snownet::Transport::Tcp | snownet::Transport::Tls => {
+                self.relay_streams.send(dst, transport, payload);
+
+                otel::attr::network_transport_tcp()
+            }
+        };
 
         self.packet_counter.add(
             1,
             &[
                 otel::attr::network_protocol_name(payload),
-                otel::attr::network_transport_udp(),
+                transport_attr,
                 otel::attr::network_io_direction_transmit(),
             ],
         );
@@ -571,6 +641,20 @@ impl Io {
 
                 self.queue_dns_query(doh::send(http_client, server, query.message), meta);
             }
+            (_, dns::Upstream::DoT { server, tls_name }) => {
+                let Some(dot_client) = self.dot_clients.get(&(server, tls_name.clone())).cloned()
+                else {
+                    self.bootstrap_dot_client(server, tls_name);
+
+                    // Queue a dummy "query" that instantly fails to ensure we don't let the application run into a timeout.
+                    // This will trigger a SERVFAIL response.
+                    self.queue_dns_query(async { anyhow::bail!("Bootstrapping DoT client") }, meta);
+
+                    return;
+                };
+
+                self.queue_dns_query(dot::send(dot_client, server, query.message), meta);
+            }
         }
     }
 
@@ -602,6 +686,31 @@ impl Io {
             });
     }
 
+    pub(crate) fn bootstrap_dot_client(&mut self, server: SocketAddr, tls_name: String) {
+        let key = (server, tls_name);
+
+        if self.dot_clients.contains_key(&key) {
+            return;
+        }
+
+        if self.dot_clients_bootstrap.contains(key.clone()) {
+            return; // Already bootstrapping.
+        }
+
+        let socket_factory = self.tcp_socket_factory.clone();
+        let tls_name = key.1.clone();
+
+        let _ = self.dot_clients_bootstrap.try_push(key, async move {
+            tracing::debug!(%server, %tls_name, "Bootstrapping DoT client");
+
+            let dot_client = DoTClient::new(server, tls_name.clone(), socket_factory).await?;
+
+            tracing::debug!(%server, %tls_name, "Bootstrapped DoT client");
+
+            Ok(dot_client)
+        });
+    }
+
     pub(crate) fn send_udp_dns_response(
         &mut self,
         to: SocketAddr,
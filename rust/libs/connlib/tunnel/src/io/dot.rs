@@ -0,0 +1,289 @@
+//! DNS over TLS, see <https://datatracker.ietf.org/doc/html/rfc7858>.
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context as _, ErrorExt as _, Result};
+use bytes::{Buf as _, BytesMut};
+use futures::future::{BoxFuture, FutureExt as _, Shared};
+use socket_factory::{SocketFactory, TcpSocket, TcpStream};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    sync::{Mutex, mpsc, oneshot},
+};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+use tokio_util::task::AbortOnDropHandle;
+
+/// How many queries we buffer before they are written to the TLS stream and how many may await their response.
+const MAX_BUFFERED_QUERIES: usize = 100;
+
+type PendingQuery = (
+    dns_types::Query,
+    oneshot::Sender<Result<dns_types::Response>>,
+);
+type Connecting = Shared<BoxFuture<'static, Result<Arc<Connection>, Arc<anyhow::Error>>>>;
+
+/// A DNS over TLS client.
+///
+/// One instance of this client is tied to a given server.
+/// It maintains a single TLS connection across which multiple queries are pipelined.
+/// Servers close idle connections (RFC 7858, section 3.4), thus we reconnect on demand if the connection is closed.
+/// A query that fails because the connection closed is retried once on a new connection.
+/// If that fails too, [`Closed`] is returned and the client should be discarded.
+#[derive(Clone)]
+pub struct DoTClient {
+    server: SocketAddr,
+    tls_name: rustls_pki_types::ServerName<'static>,
+    connector: TlsConnector,
+    socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+
+    connection: Arc<Mutex<ConnectionState>>,
+}
+
+enum ConnectionState {
+    Disconnected,
+    /// A connection attempt that all concurrent queries wait for.
+    Connecting(Connecting),
+    Connected(Arc<Connection>),
+}
+
+struct Connection {
+    queries: mpsc::Sender<PendingQuery>,
+
+    #[expect(dead_code, reason = "We only need to keep it around.")]
+    task: AbortOnDropHandle<()>,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("The connection is closed")]
+pub struct Closed;
+
+impl DoTClient {
+    pub async fn new(
+        server: SocketAddr,
+        tls_name: String,
+        socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    ) -> Result<Self> {
+        // TODO: Use `rustls-platform-verifier` instead.
+        let mut root_cert_store = rustls::RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+
+        let tls_name =
+            rustls_pki_types::ServerName::try_from(tls_name).context("Invalid TLS server name")?;
+
+        let client = Self {
+            server,
+            tls_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            socket_factory,
+            connection: Arc::new(Mutex::new(ConnectionState::Disconnected)),
+        };
+
+        // Connect right away so a misconfigured server fails the bootstrap.
+        let connection = client.connect().await?;
+        *client.connection.lock().await = ConnectionState::Connected(Arc::new(connection));
+
+        Ok(client)
+    }
+
+    pub async fn send_query(&self, query: dns_types::Query) -> Result<dns_types::Response> {
+        match self.try_send_query(query.clone()).await {
+            Err(e) if e.any_is::<Closed>() => {
+                tracing::debug!(server = %self.server, "DoT connection closed, retrying query on a new connection");
+
+                self.try_send_query(query).await
+            }
+            result => result,
+        }
+    }
+
+    async fn try_send_query(&self, query: dns_types::Query) -> Result<dns_types::Response> {
+        let (tx, rx) = oneshot::channel();
+
+        self.queries()
+            .await?
+            .try_send((query, tx))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => {
+                    anyhow::anyhow!("Too many buffered DoT queries")
+                }
+                mpsc::error::TrySendError::Closed(_) => anyhow::Error::new(Closed),
+            })?;
+
+        rx.await.map_err(|_| anyhow::Error::new(Closed))?
+    }
+
+    /// Returns the channel for sending queries on the current connection, connecting first if necessary.
+    ///
+    /// The lock is never held while connecting so queries on an established connection are not held up by a reconnect.
+    /// Concurrent queries share the same connection attempt.
+    async fn queries(&self) -> Result<mpsc::Sender<PendingQuery>> {
+        let connecting = {
+            let mut state = self.connection.lock().await;
+
+            match &*state {
+                ConnectionState::Connected(connection) if !connection.queries.is_closed() => {
+                    return Ok(connection.queries.clone());
+                }
+                ConnectionState::Connecting(connecting) => connecting.clone(),
+                ConnectionState::Connected(_) | ConnectionState::Disconnected => {
+                    let connecting = self
+                        .connect()
+                        .map(|result| result.map(Arc::new).map_err(Arc::new))
+                        .boxed()
+                        .shared();
+                    *state = ConnectionState::Connecting(connecting.clone());
+
+                    connecting
+                }
+            }
+        };
+
+        let result = connecting.clone().await;
+
+        {
+            let mut state = self.connection.lock().await;
+
+            // Only the first query to see the result of this attempt updates the state.
+            if let ConnectionState::Connecting(current) = &*state
+                && current.ptr_eq(&connecting)
+            {
+                *state = match &result {
+                    Ok(connection) => ConnectionState::Connected(connection.clone()),
+                    Err(_) => ConnectionState::Disconnected,
+                };
+            }
+        }
+
+        let connection = result.map_err(|e| anyhow::anyhow!("{e:#}"))?;
+
+        Ok(connection.queries.clone())
+    }
+
+    /// Connects to the server.
+    ///
+    /// The returned future does not borrow `self` so it can be shared across queries.
+    fn connect(&self) -> impl Future<Output = Result<Connection>> + Send + use<> {
+        let server = self.server;
+        let tls_name = self.tls_name.clone();
+        let connector = self.connector.clone();
+        let socket_factory = self.socket_factory.clone();
+
+        async move {
+            let stream = socket_factory
+                .bind(server)
+                .context("Failed to create TCP socket")?
+                .connect(server)
+                .await
+                .context("Failed to connect TCP stream")?;
+            let stream = connector
+                .connect(tls_name, stream)
+                .await
+                .context("Failed to perform TLS handshake")?;
+
+            let (queries_tx, queries_rx) = mpsc::channel(MAX_BUFFERED_QUERIES);
+
+            let task = tokio::spawn(async move {
+                match run_connection(stream, queries_rx).await {
+                    Ok(()) => tracing::debug!(%server, "DoT connection finished"),
+                    Err(e) => tracing::debug!(%server, "DoT connection failed: {e:#}"),
+                }
+            });
+
+            Ok(Connection {
+                queries: queries_tx,
+                task: AbortOnDropHandle::new(task),
+            })
+        }
+    }
+}
+
+pub async fn send(
+    client: DoTClient,
+    server: SocketAddr,
+    query: dns_types::Query,
+) -> Result<dns_types::Response> {
+    let domain = query.domain();
+    let qtype = query.qtype();
+
+    tracing::trace!(target: "wire::dns::recursive::qry", %server, "{qtype} {domain}");
+
+    let response = client.send_query(query).await?;
+
+    tracing::trace!(target: "wire::dns::recursive::res", %server, "{qtype} {domain} => {}", response.response_code());
+
+    Ok(response)
+}
+
+/// Drives a single DoT connection until it fails or all [`DoTClient`]s are dropped.
+///
+/// Queries are written as soon as they arrive, without waiting for previous responses (RFC 7858, section 3.3).
+/// Each query is assigned an ID that is unique for this connection so we can match the out-of-order responses.
+async fn run_connection(
+    stream: TlsStream<TcpStream>,
+    mut queries: mpsc::Receiver<PendingQuery>,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut pending = BTreeMap::<u16, (u16, oneshot::Sender<Result<dns_types::Response>>)>::new();
+    let mut next_id = 0_u16;
+    let mut read_buffer = BytesMut::new();
+
+    loop {
+        tokio::select! {
+            query = queries.recv() => {
+                let Some((query, response_tx)) = query else {
+                    return Ok(());
+                };
+
+                // Forget about queries that have been abandoned by the caller, i.e. due to a timeout.
+                pending.retain(|_, (_, tx)| !tx.is_closed());
+
+                // Also ensures that there is always a free ID below.
+                if pending.len() >= MAX_BUFFERED_QUERIES {
+                    let _ = response_tx.send(Err(anyhow::anyhow!("Too many pending DoT queries")));
+                    continue;
+                }
+
+                while pending.contains_key(&next_id) {
+                    next_id = next_id.wrapping_add(1);
+                }
+                let id = next_id;
+                next_id = next_id.wrapping_add(1);
+
+                pending.insert(id, (query.id(), response_tx));
+
+                writer
+                    .write_all(&dns_over_tcp::encode(&query.with_id(id).into_bytes()))
+                    .await
+                    .context("Failed to write DNS query")?;
+            }
+            read = reader.read_buf(&mut read_buffer) => {
+                let read = read.context("Failed to read from TLS stream")?;
+                anyhow::ensure!(read != 0, "Connection closed by remote");
+
+                while let Some((message, consumed)) = dns_over_tcp::decode(&read_buffer) {
+                    let response = dns_types::Response::parse(message);
+                    read_buffer.advance(consumed);
+
+                    let response = match response {
+                        Ok(response) => response,
+                        Err(e) => {
+                            tracing::debug!("Failed to parse DNS response: {e:#}");
+                            continue;
+                        }
+                    };
+
+                    let Some((original_id, response_tx)) = pending.remove(&response.id()) else {
+                        tracing::debug!(id = %response.id(), "Received DNS response for unknown query");
+                        continue;
+                    };
+
+                    let _ = response_tx.send(Ok(response.with_id(original_id)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! TURN over TCP and TLS, for networks that block UDP traffic to our relays.
+//!
+//! snownet decides when to fall back to a stream-based transport, see [`snownet::Transport`].
+//! We maintain one connection per relay socket and transport and feed all messages received on it back as if they came from the relay's UDP socket.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use bytes::{Buf as _, BytesMut};
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use snownet::Transport;
+use socket_factory::{SocketFactory, TcpSocket};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    sync::mpsc,
+};
+use tokio_rustls::TlsConnector;
+use tokio_util::task::AbortOnDropHandle;
+
+/// How many messages we buffer per connection before they are written to the stream.
+const MAX_BUFFERED_MESSAGES: usize = 1_000;
+
+/// A message received from a relay via TCP or TLS.
+#[derive(Debug)]
+pub struct Received {
+    pub local: SocketAddr,
+    pub from: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+pub struct RelayStreams {
+    connections: BTreeMap<(SocketAddr, Transport), Connection>,
+
+    received_tx: mpsc::Sender<Received>,
+    received_rx: mpsc::Receiver<Received>,
+
+    tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+}
+
+struct Connection {
+    outbound: mpsc::Sender<Vec<u8>>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl RelayStreams {
+    pub fn new(tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>) -> Self {
+        let (received_tx, received_rx) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+        Self {
+            connections: Default::default(),
+            received_tx,
+            received_rx,
+            tcp_socket_factory,
+        }
+    }
+
+    /// Sends a message to the relay at `dst`, connecting to it first if necessary.
+    ///
+    /// Messages are dropped if the connection is congested, the same as UDP would.
+    pub fn send(&mut self, dst: SocketAddr, transport: Transport, message: &[u8]) {
+        let key = (dst, transport);
+
+        if self
+            .connections
+            .get(&key)
+            .is_none_or(|c| c.outbound.is_closed())
+        {
+            let connection = self.connect(dst, transport);
+            self.connections.insert(key, connection);
+        }
+
+        let Some(connection) = self.connections.get(&key) else {
+            return;
+        };
+
+        if let Err(e) = connection
+            .outbound
+            .try_send(snownet::stream::encode(message))
+        {
+            tracing::debug!(%dst, %transport, "Failed to queue message for relay: {e}");
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        self.received_rx
+            .poll_recv(cx)
+            .map(|r| r.expect("we hold a sender ourselves"))
+    }
+
+    /// Closes all connections.
+    pub fn reset(&mut self) {
+        self.connections.clear();
+    }
+
+    fn connect(&self, dst: SocketAddr, transport: Transport) -> Connection {
+        let (outbound_tx, outbound_rx) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+        let task = tokio::spawn({
+            let socket_factory = self.tcp_socket_factory.clone();
+            let received_tx = self.received_tx.clone();
+
+            async move {
+                match run_connection(dst, transport, socket_factory, outbound_rx, received_tx).await
+                {
+                    Ok(()) => tracing::debug!(%dst, %transport, "Relay connection finished"),
+                    Err(e) => tracing::debug!(%dst, %transport, "Relay connection failed: {e:#}"),
+                }
+            }
+        });
+
+        Connection {
+            outbound: outbound_tx,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+}
+
+async fn run_connection(
+    dst: SocketAddr,
+    transport: Transport,
+    socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    outbound: mpsc::Receiver<Vec<u8>>,
+    received: mpsc::Sender<Received>,
+) -> Result<()> {
+    let remote = match transport {
+        Transport::Udp => anyhow::bail!("UDP is not a stream transport"),
+        Transport::Tcp => dst,
+        Transport::Tls => SocketAddr::new(dst.ip(), snownet::stream::RELAY_TLS_PORT),
+    };
+
+    let stream = socket_factory
+        .bind(remote)
+        .context("Failed to create TCP socket")?
+        .connect(remote)
+        .await
+        .context("Failed to connect TCP stream")?;
+    let local = stream
+        .local_addr()
+        .context("Failed to get local address of TCP stream")?;
+
+    tracing::debug!(%dst, %remote, %transport, "Connected to relay");
+
+    match transport {
+        Transport::Udp => unreachable!("checked above"),
+        Transport::Tcp => run_stream(stream, local, dst, outbound, received).await,
+        Transport::Tls => {
+            let config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate::new()))
+                .with_no_client_auth();
+
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::IpAddress(dst.ip().into()), stream)
+                .await
+                .context("Failed to perform TLS handshake")?;
+
+            run_stream(stream, local, dst, outbound, received).await
+        }
+    }
+}
+
+/// Writes outbound messages to the stream and forwards all received messages until either side is closed.
+async fn run_stream(
+    stream: impl AsyncRead + AsyncWrite,
+    local: SocketAddr,
+    from: SocketAddr,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    received: mpsc::Sender<Received>,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut read_buffer = BytesMut::new();
+
+    loop {
+        tokio::select! {
+            frame = outbound.recv() => {
+                let Some(frame) = frame else {
+                    return Ok(());
+                };
+
+                writer.write_all(&frame).await.context("Failed to write to stream")?;
+            }
+            read = reader.read_buf(&mut read_buffer) => {
+                let read = read.context("Failed to read from stream")?;
+                anyhow::ensure!(read != 0, "Connection closed by relay");
+
+                while let Some((message, consumed)) = snownet::stream::decode(&read_buffer)? {
+                    let message = Received {
+                        local,
+                        from,
+                        payload: message.to_vec(),
+                    };
+                    read_buffer.advance(consumed);
+
+                    if received.try_send(message).is_err() {
+                        tracing::debug!(%from, "Dropping message from relay because the receive buffer is full");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accepts any certificate presented by the relay.
+///
+/// We only use TLS to get through firewalls that don't allow anything but HTTPS.
+/// Relays are authenticated by the message integrity of their TURN responses and all relayed traffic is encrypted by WireGuard.
+/// Our relays are addressed by IP and don't have certificates that we could verify anyway.
+#[derive(Debug)]
+struct AcceptAnyCertificate {
+    provider: Arc<CryptoProvider>,
+}
+
+impl AcceptAnyCertificate {
+    fn new() -> Self {
+        Self {
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -15,6 +15,7 @@ use futures::{FutureExt, future::BoxFuture};
 use gat_lending_iterator::LendingIterator;
 use io::{Buffers, Io};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
+use ip_packet::IpPacket;
 use logging::DisplayBTreeSet;
 use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::{
@@ -70,10 +71,15 @@ pub const IPV6_TUNNEL: Ipv6Network =
 pub type GatewayTunnel = Tunnel<GatewayState>;
 pub type ClientTunnel = Tunnel<ClientState>;
 
-pub use client::ClientState;
 pub use client::dns_config::DnsMapping;
+pub use client::{ClientState, DnsCacheSnapshot};
 pub use dns::DnsResourceRecord;
-pub use gateway::{DnsResourceNatEntry, GatewayState, ResolveDnsRequest, UnroutablePacket};
+pub use gateway::{
+    CompletedDnsQuery, CompletedFlow, CompletedTcpFlow, CompletedUdpFlow, DnsQueryKind,
+    DnsResourceNatEntry, GatewayDraining, GatewayState, ProbeTarget, ResolveDnsRequest,
+    UnroutablePacket,
+};
+pub use p2p_control::resource_health::HealthStatus;
 pub use sockets::UdpSocketThreadStopped;
 pub use utils::turn;
 
@@ -95,6 +101,10 @@ pub struct Tunnel<TRoleState> {
 }
 
 impl<TRoleState> Tunnel<TRoleState> {
+    pub fn state(&self) -> &TRoleState {
+        &self.role_state
+    }
+
     pub fn state_mut(&mut self) -> &mut TRoleState {
         &mut self.role_state
     }
@@ -113,6 +123,7 @@ impl ClientTunnel {
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
         records: BTreeSet<DnsResourceRecord>,
+        dns_cache: DnsCacheSnapshot,
         is_internet_resource_active: bool,
     ) -> Self {
         Self {
@@ -124,6 +135,7 @@ impl ClientTunnel {
             role_state: ClientState::new(
                 rand::random(),
                 records,
+                dns_cache,
                 is_internet_resource_active,
                 Instant::now(),
                 SystemTime::now()
@@ -147,6 +159,15 @@ impl ClientTunnel {
         self.io.reset();
     }
 
+    pub fn dns_cache_snapshot(&self) -> DnsCacheSnapshot {
+        self.role_state.dns_cache_snapshot(
+            Instant::now(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Should be able to compute UNIX timestamp"),
+        )
+    }
+
     pub fn update_system_resolvers(&mut self, resolvers: Vec<IpAddr>) -> Vec<IpAddr> {
         let resolvers = self.role_state.update_system_resolvers(resolvers);
         self.io.update_system_resolvers(resolvers.clone()); // IO needs the system resolvers to bootstrap DoH upstream.
@@ -161,8 +182,13 @@ impl ClientTunnel {
 
         // Drain all UDP packets that need to be sent.
         while let Some(trans) = self.role_state.poll_transmit() {
-            self.io
-                .send_network(trans.src, trans.dst, &trans.payload, trans.ecn);
+            self.io.send_network(
+                trans.src,
+                trans.dst,
+                &trans.payload,
+                trans.ecn,
+                trans.transport,
+            );
         }
 
         // Return a future that "owns" our IO, polling it until all packets have been flushed.
@@ -188,12 +214,14 @@ impl ClientTunnel {
             // Pass up existing events.
             if let Some(event) = self.role_state.poll_event() {
                 if let ClientEvent::TunInterfaceUpdated(config) = &event {
-                    for url in &config.dns_by_sentinel.upstream_servers() {
-                        let dns::Upstream::DoH { server } = url else {
-                            continue;
-                        };
-
-                        self.io.bootstrap_doh_client(server.clone());
+                    for upstream in config.dns_by_sentinel.upstream_servers() {
+                        match upstream {
+                            dns::Upstream::DoH { server } => self.io.bootstrap_doh_client(server),
+                            dns::Upstream::DoT { server, tls_name } => {
+                                self.io.bootstrap_dot_client(server, tls_name)
+                            }
+                            dns::Upstream::Do53 { .. } => {}
+                        }
                     }
                 }
 
@@ -208,8 +236,13 @@ impl ClientTunnel {
 
             // Drain all buffered transmits.
             while let Some(trans) = self.role_state.poll_transmit() {
-                self.io
-                    .send_network(trans.src, trans.dst, &trans.payload, trans.ecn);
+                self.io.send_network(
+                    trans.src,
+                    trans.dst,
+                    &trans.payload,
+                    trans.ecn,
+                    trans.transport,
+                );
                 ready = true;
             }
 
@@ -229,6 +262,7 @@ impl ClientTunnel {
                 udp_dns_queries: _,
                 device,
                 network,
+                relay_messages,
                 error,
             }) = self.io.poll(cx, &mut self.buffers)
             {
@@ -253,6 +287,7 @@ impl ClientTunnel {
                                     transmit.dst,
                                     &transmit.payload,
                                     transmit.ecn,
+                                    transmit.transport,
                                 );
                             }
                             None => {
@@ -291,6 +326,20 @@ impl ClientTunnel {
                     ready = true;
                 }
 
+                for received in relay_messages {
+                    match self.role_state.handle_network_input(
+                        received.local,
+                        received.from,
+                        &received.payload,
+                        now,
+                    ) {
+                        Some(packet) => self.io.send_tun(packet),
+                        None => self.role_state.handle_timeout(now),
+                    };
+
+                    ready = true;
+                }
+
                 // Reset timer for time-based wakeup.
                 if let Some((timeout, reason)) = self.role_state.poll_timeout() {
                     self.io.reset_timeout(timeout, reason);
@@ -320,11 +369,13 @@ impl GatewayTunnel {
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
         nameservers: BTreeSet<IpAddr>,
         flow_logs: bool,
+        dns_query_logs: bool,
     ) -> Self {
         Self {
             io: Io::new(tcp_socket_factory, udp_socket_factory.clone(), nameservers),
             role_state: GatewayState::new(
                 flow_logs,
+                dns_query_logs,
                 rand::random(),
                 Instant::now(),
                 SystemTime::now()
@@ -350,8 +401,13 @@ impl GatewayTunnel {
 
         // Drain all UDP packets that need to be sent.
         while let Some(trans) = self.role_state.poll_transmit() {
-            self.io
-                .send_network(trans.src, trans.dst, &trans.payload, trans.ecn);
+            self.io.send_network(
+                trans.src,
+                trans.dst,
+                &trans.payload,
+                trans.ecn,
+                trans.transport,
+            );
         }
 
         // Return a future that "owns" our IO, polling it until all packets have been flushed.
@@ -379,10 +435,21 @@ impl GatewayTunnel {
                 return Poll::Ready(other);
             }
 
+            // Drain all buffered IP packets.
+            while let Some(packet) = self.role_state.poll_packets() {
+                self.io.send_tun(packet);
+                ready = true;
+            }
+
             // Drain all buffered transmits.
             while let Some(trans) = self.role_state.poll_transmit() {
-                self.io
-                    .send_network(trans.src, trans.dst, &trans.payload, trans.ecn);
+                self.io.send_network(
+                    trans.src,
+                    trans.dst,
+                    &trans.payload,
+                    trans.ecn,
+                    trans.transport,
+                );
 
                 ready = true;
             }
@@ -397,6 +464,7 @@ impl GatewayTunnel {
                 udp_dns_queries,
                 device,
                 network,
+                relay_messages,
                 mut error,
             }) = self.io.poll(cx, &mut self.buffers)
             {
@@ -407,6 +475,13 @@ impl GatewayTunnel {
                         dns_types::Response::servfail(&response.query)
                     });
 
+                    self.role_state.on_forwarded_dns_response(
+                        response.remote,
+                        &message,
+                        now,
+                        now_utc,
+                    );
+
                     match response.transport {
                         dns::Transport::Udp => {
                             if let Err(e) = self.io.send_udp_dns_response(
@@ -445,6 +520,7 @@ impl GatewayTunnel {
                                     transmit.dst,
                                     &transmit.payload,
                                     transmit.ecn,
+                                    transmit.transport,
                                 );
                             }
                             Ok(None) => {
@@ -498,8 +574,25 @@ impl GatewayTunnel {
                     ready = true;
                 }
 
+                for received in relay_messages {
+                    match self.role_state.handle_network_input(
+                        received.local,
+                        received.from,
+                        &received.payload,
+                        now,
+                    ) {
+                        Ok(Some(packet)) => self.io.send_tun(packet),
+                        Ok(None) => self.role_state.handle_timeout(now, now_utc),
+                        Err(e) => error.push(e),
+                    };
+
+                    ready = true;
+                }
+
                 for query in udp_dns_queries {
                     if let Some(nameserver) = self.io.fastest_nameserver() {
+                        self.role_state
+                            .on_forwarded_dns_query(query.remote, &query.message, now);
                         self.io.send_dns_query(dns::RecursiveQuery {
                             server: dns::Upstream::Do53 {
                                 server: SocketAddr::new(nameserver, dns::DNS_PORT),
@@ -526,6 +619,8 @@ impl GatewayTunnel {
 
                 for query in tcp_dns_queries {
                     if let Some(nameserver) = self.io.fastest_nameserver() {
+                        self.role_state
+                            .on_forwarded_dns_query(query.remote, &query.message, now);
                         self.io.send_dns_query(dns::RecursiveQuery {
                             server: dns::Upstream::Do53 {
                                 server: SocketAddr::new(nameserver, dns::DNS_PORT),
@@ -640,6 +735,14 @@ pub enum GatewayEvent {
         candidates: BTreeSet<IceCandidate>,
     },
     ResolveDns(ResolveDnsRequest),
+    /// A TCP or UDP flow has completed.
+    ///
+    /// Only emitted if flow logs are enabled.
+    FlowCompleted(Box<CompletedFlow>),
+    /// A DNS query resolved on behalf of a client has completed.
+    ///
+    /// Only emitted if DNS query logs are enabled.
+    DnsQueryCompleted(Box<CompletedDnsQuery>),
     Error(TunnelError),
 }
 
@@ -704,10 +807,109 @@ pub fn is_peer(dst: IpAddr) -> bool {
     }
 }
 
+/// Creates an ICMP "packet too big" error for the given packet if it exceeds the discovered MTU of the path to the peer.
+///
+/// Returns `Ok(None)` if the packet should be sent anyway:
+/// - IPv4 packets may be fragmented unless they have the DF bit set.
+/// - IPv6 hosts ignore MTUs below 1280 (RFC 8200, section 5), thus we never enforce those.
+pub(crate) fn packet_too_big(
+    packet: &IpPacket,
+    path_mtu: Option<usize>,
+) -> anyhow::Result<Option<IpPacket>> {
+    let Some(mtu) = path_mtu.filter(|mtu| packet.packet().len() > *mtu) else {
+        return Ok(None);
+    };
+
+    let enforce = match packet.ipv4_header() {
+        Some(header) => header.dont_fragment,
+        None => mtu >= IPV6_MIN_MTU,
+    };
+
+    if !enforce {
+        return Ok(None);
+    }
+
+    tracing::debug!(%mtu, ?packet, "Packet exceeds path MTU");
+
+    let icmp_error = ip_packet::make::icmp_packet_too_big(packet, mtu as u16)?;
+
+    Ok(Some(icmp_error))
+}
+
+/// The minimum MTU of IPv6 links, see RFC 8200, section 5.
+const IPV6_MIN_MTU: usize = 1280;
+
 #[cfg(test)]
 mod unittests {
     use super::*;
 
+    #[test]
+    fn packet_within_path_mtu_is_not_too_big() {
+        let packet = ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(100, 64, 0, 2),
+            1,
+            2,
+            vec![0; 100],
+        )
+        .unwrap();
+
+        assert!(packet_too_big(&packet, None).unwrap().is_none());
+        assert!(packet_too_big(&packet, Some(1000)).unwrap().is_none());
+    }
+
+    #[test]
+    fn packet_exceeding_path_mtu_yields_icmp_error() {
+        let packet = ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(100, 64, 0, 2),
+            1,
+            2,
+            vec![0; 1100],
+        )
+        .unwrap();
+
+        let icmp_error = packet_too_big(&packet, Some(1000)).unwrap().unwrap();
+
+        assert_eq!(icmp_error.destination(), packet.source());
+        assert_eq!(icmp_error.source(), packet.destination());
+        assert!(icmp_error.as_icmpv4().is_some());
+    }
+
+    #[test]
+    fn ipv4_packet_without_df_is_not_too_big() {
+        let mut header = ip_packet::Ipv4Header::new(
+            0,
+            64,
+            ip_packet::IpNumber::UDP,
+            [100, 64, 0, 1],
+            [100, 64, 0, 2],
+        )
+        .unwrap();
+        header.dont_fragment = false;
+        let builder =
+            ip_packet::PacketBuilder::ip(ip_packet::IpHeaders::Ipv4(header, Default::default()))
+                .udp(1, 2);
+        let payload = vec![0; 1100];
+        let packet = (|| ip_packet::build!(builder, payload))().unwrap();
+
+        assert!(packet_too_big(&packet, Some(1000)).unwrap().is_none());
+    }
+
+    #[test]
+    fn ipv6_packet_is_not_too_big_for_mtu_below_minimum() {
+        let packet = ip_packet::make::udp_packet(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            1,
+            2,
+            vec![0; 1100],
+        )
+        .unwrap();
+
+        assert!(packet_too_big(&packet, Some(1000)).unwrap().is_none());
+    }
+
     #[test]
     fn mldv2_routers_are_not_peers() {
         assert!(!is_peer("ff02::16".parse().unwrap()))
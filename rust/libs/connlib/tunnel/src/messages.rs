@@ -126,8 +126,15 @@ pub struct Interface {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub upstream_doh: Vec<UpstreamDoH>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub upstream_dot: Vec<UpstreamDoT>,
     #[serde(default)]
     pub search_domain: Option<DomainName>,
+    /// Zones that must be resolved by a dedicated DNS server instead of the regular upstreams.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub conditional_forwarders: Vec<ConditionalForwarder>,
 }
 
 impl Interface {
@@ -148,6 +155,17 @@ impl Interface {
     pub fn upstream_doh(&self) -> Vec<DoHUrl> {
         self.upstream_doh.iter().map(|u| u.url.clone()).collect()
     }
+
+    pub fn upstream_dot(&self) -> Vec<UpstreamDoT> {
+        self.upstream_dot.clone()
+    }
+}
+
+/// Forwards all queries for domains within `zone` to the DNS server at `upstream`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ConditionalForwarder {
+    pub zone: DomainName,
+    pub upstream: IpAddr,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -160,6 +178,13 @@ pub struct UpstreamDoH {
     pub url: DoHUrl,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct UpstreamDoT {
+    pub ip: IpAddr,
+    /// The name to validate the server's TLS certificate against.
+    pub tls_name: String,
+}
+
 /// A single relay
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
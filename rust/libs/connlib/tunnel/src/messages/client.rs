@@ -28,6 +28,10 @@ pub struct ResourceDescriptionDns {
     /// The IP stack supported by this resource.
     #[serde(default)]
     pub ip_stack: Option<IpStack>,
+
+    /// Patterns of sub-domains that are not part of this resource.
+    #[serde(default)]
+    pub exclusions: Vec<String>,
 }
 
 /// Description of a resource that maps to a CIDR.
@@ -194,6 +198,8 @@ pub enum EgressMessages {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::messages::ConditionalForwarder;
+    use std::net::IpAddr;
 
     #[test]
     fn can_deserialize_internet_resource() {
@@ -323,6 +329,42 @@ mod tests {
         assert!(matches!(message, IngressMessages::ConfigChanged(_)))
     }
 
+    #[test]
+    fn can_deserialize_config_changed_message_with_conditional_forwarders() {
+        let json = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "ipv4": "100.67.138.25",
+                "conditional_forwarders": [
+                  {
+                    "zone": "corp.internal",
+                    "upstream": "10.0.0.53"
+                  }
+                ]
+              }
+            }
+          }
+        "#;
+
+        let message = serde_json::from_str::<IngressMessages>(json).unwrap();
+
+        let IngressMessages::ConfigChanged(config) = message else {
+            panic!("Unexpected message")
+        };
+        assert_eq!(
+            config.interface.conditional_forwarders,
+            vec![ConditionalForwarder {
+                zone: "corp.internal".parse().unwrap(),
+                upstream: IpAddr::from([10, 0, 0, 53]),
+            }]
+        );
+    }
+
     #[test]
     fn can_deserialize_init_message() {
         let json = r#"{
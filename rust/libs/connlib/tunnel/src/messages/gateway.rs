@@ -59,12 +59,48 @@ pub enum ResourceDescription {
     Internet(ResourceDescriptionInternet),
 }
 
+/// A single filter rule of a resource.
+///
+/// Without a `destination`, a filter applies to every IP of the resource.
+/// If several filters match a packet, the one with the longest `destination` prefix decides.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Filter {
+    #[serde(flatten)]
+    pub protocol: ProtocolFilter,
+    /// Restricts this filter to a sub-prefix of the resource.
+    ///
+    /// Only meaningful for CIDR resources.
+    #[serde(default)]
+    pub destination: Option<IpNetwork>,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
-pub enum Filter {
+pub enum ProtocolFilter {
     Udp(PortRange),
     Tcp(PortRange),
     Icmp,
+    All,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl From<ProtocolFilter> for Filter {
+    fn from(protocol: ProtocolFilter) -> Self {
+        Self {
+            protocol,
+            destination: None,
+            action: FilterAction::Allow,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
@@ -248,6 +284,24 @@ pub enum EgressMessages {
         #[serde(rename = "ref")]
         reference: String,
     },
+    /// We did not authorize the flow, the portal should tell the client to pick another gateway.
+    FlowRejected {
+        #[serde(rename = "ref")]
+        reference: String,
+        reason: FlowRejectionReason,
+    },
+    /// We no longer accept new clients and will shut down once the flows of existing ones have ended.
+    Draining {
+        /// The maximum time in seconds until we shut down.
+        max_drain_secs: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowRejectionReason {
+    /// We are draining and don't accept new clients.
+    Draining,
 }
 
 #[cfg(test)]
@@ -257,10 +311,10 @@ mod tests {
     #[test]
     fn can_deserialize_udp_filter() {
         let msg = r#"{ "protocol": "udp", "port_range_start": 10, "port_range_end": 20 }"#;
-        let expected_filter = Filter::Udp(PortRange {
+        let expected_filter = Filter::from(ProtocolFilter::Udp(PortRange {
             port_range_start: 10,
             port_range_end: 20,
-        });
+        }));
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -270,10 +324,10 @@ mod tests {
     #[test]
     fn can_deserialize_empty_udp_filter() {
         let msg = r#"{ "protocol": "udp" }"#;
-        let expected_filter = Filter::Udp(PortRange {
+        let expected_filter = Filter::from(ProtocolFilter::Udp(PortRange {
             port_range_start: 0,
             port_range_end: u16::MAX,
-        });
+        }));
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -283,10 +337,10 @@ mod tests {
     #[test]
     fn can_deserialize_tcp_filter() {
         let msg = r#"{ "protocol": "tcp", "port_range_start": 10, "port_range_end": 20 }"#;
-        let expected_filter = Filter::Tcp(PortRange {
+        let expected_filter = Filter::from(ProtocolFilter::Tcp(PortRange {
             port_range_start: 10,
             port_range_end: 20,
-        });
+        }));
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -296,10 +350,10 @@ mod tests {
     #[test]
     fn can_deserialize_empty_tcp_filter() {
         let msg = r#"{ "protocol": "tcp" }"#;
-        let expected_filter = Filter::Tcp(PortRange {
+        let expected_filter = Filter::from(ProtocolFilter::Tcp(PortRange {
             port_range_start: 0,
             port_range_end: u16::MAX,
-        });
+        }));
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -309,7 +363,38 @@ mod tests {
     #[test]
     fn can_deserialize_icmp_filter() {
         let msg = r#"{ "protocol": "icmp" }"#;
-        let expected_filter = Filter::Icmp;
+        let expected_filter = Filter::from(ProtocolFilter::Icmp);
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_filter_with_destination() {
+        let msg = r#"{ "protocol": "tcp", "port_range_start": 443, "port_range_end": 443, "destination": "10.0.1.0/24" }"#;
+        let expected_filter = Filter {
+            protocol: ProtocolFilter::Tcp(PortRange {
+                port_range_start: 443,
+                port_range_end: 443,
+            }),
+            destination: Some("10.0.1.0/24".parse().unwrap()),
+            action: FilterAction::Allow,
+        };
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_deny_all_filter() {
+        let msg = r#"{ "protocol": "all", "destination": "10.0.1.5/32", "action": "deny" }"#;
+        let expected_filter = Filter {
+            protocol: ProtocolFilter::All,
+            destination: Some("10.0.1.5/32".parse().unwrap()),
+            action: FilterAction::Deny,
+        };
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -424,4 +509,31 @@ mod tests {
 
         assert_eq!(client_candidates.candidates.len(), 1);
     }
+
+    #[test]
+    fn can_serialize_draining() {
+        let json = serde_json::to_string(&EgressMessages::Draining {
+            max_drain_secs: 3600,
+        })
+        .unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"event":"draining","payload":{"max_drain_secs":3600}}"#
+        );
+    }
+
+    #[test]
+    fn can_serialize_flow_rejected() {
+        let json = serde_json::to_string(&EgressMessages::FlowRejected {
+            reference: "foo".to_owned(),
+            reason: FlowRejectionReason::Draining,
+        })
+        .unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"event":"flow_rejected","payload":{"ref":"foo","reason":"draining"}}"#
+        );
+    }
 }
@@ -15,6 +15,9 @@ use ip_packet::{FzP2pEventType, IpPacket};
 pub const ASSIGNED_IPS_EVENT: FzP2pEventType = FzP2pEventType::new(0);
 pub const DOMAIN_STATUS_EVENT: FzP2pEventType = FzP2pEventType::new(1);
 pub const GOODBYE_EVENT: FzP2pEventType = FzP2pEventType::new(2);
+pub const RESOURCE_HEALTH_EVENT: FzP2pEventType = FzP2pEventType::new(3);
+pub const GATEWAY_DRAINING_EVENT: FzP2pEventType = FzP2pEventType::new(4);
+// Event types from `0xF0` onwards are reserved for `snownet`'s path MTU probes.
 
 pub mod dns_resource_nat {
     use super::*;
@@ -216,7 +219,183 @@ pub mod dns_resource_nat {
     }
 }
 
+pub mod resource_health {
+    use super::*;
+    use anyhow::{Context as _, Result};
+    use connlib_model::ResourceId;
+    use dns_types::DomainName;
+    use ip_packet::{FzP2pControlSlice, IpPacket};
+
+    /// Construct a new [`ResourceHealth`] event.
+    ///
+    /// The event always carries the current health of the resource, i.e. receiving it multiple times is harmless.
+    /// If `domain` is set, the health only applies to this particular domain of a DNS resource.
+    pub fn resource_health(
+        resource: ResourceId,
+        domain: Option<DomainName>,
+        status: HealthStatus,
+        reason: Option<HealthReason>,
+    ) -> Result<IpPacket> {
+        let payload = serde_json::to_vec(&ResourceHealth {
+            resource,
+            domain,
+            status,
+            reason,
+        })
+        .context("Failed to serialize `ResourceHealth` event")?;
+
+        let ip_packet = ip_packet::make::fz_p2p_control(
+            [RESOURCE_HEALTH_EVENT.into_u8(), 0, 0, 0, 0, 0, 0, 0],
+            &payload,
+        )
+        .context("Failed to create p2p control protocol packet")?;
+
+        Ok(ip_packet)
+    }
+
+    pub fn decode_resource_health(packet: FzP2pControlSlice) -> Result<ResourceHealth> {
+        anyhow::ensure!(
+            packet.event_type() == RESOURCE_HEALTH_EVENT,
+            "Control protocol packet is not a `resource_health::ResourceHealth` event"
+        );
+
+        serde_json::from_slice::<ResourceHealth>(packet.payload())
+            .context("Failed to deserialize `resource_health::ResourceHealth`")
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct ResourceHealth {
+        pub resource: ResourceId,
+        /// The domain of a DNS resource this health applies to.
+        ///
+        /// `None` means the health applies to the resource as a whole.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub domain: Option<DomainName>,
+        pub status: HealthStatus,
+        /// Why the Gateway considers the resource not healthy.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reason: Option<HealthReason>,
+    }
+
+    /// The health of a resource as seen from the Gateway.
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+    #[serde(rename_all = "snake_case")]
+    pub enum HealthStatus {
+        /// All probes of the resource succeed.
+        Healthy,
+        /// Some probes of the resource fail.
+        Degraded,
+        /// All probes of the resource fail.
+        Unreachable,
+        /// The Gateway knows a status that we don't.
+        #[serde(other)] // For forwards-compatibility with future versions of this enum.
+        Unknown,
+    }
+
+    /// Why a resource is not healthy.
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+    #[serde(rename_all = "snake_case")]
+    pub enum HealthReason {
+        /// The Gateway's probes of the resource fail.
+        Probe,
+        /// The Gateway's upstream resolvers failed to resolve the domain.
+        DnsFailure,
+        /// The resource keeps resetting TCP connections.
+        TcpResets,
+        /// The Gateway received ICMP "destination unreachable" errors for the resource.
+        IcmpUnreachable,
+        /// The Gateway knows a reason that we don't.
+        #[serde(other)] // For forwards-compatibility with future versions of this enum.
+        Unknown,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn resource_health_serde_roundtrip() {
+            let packet = resource_health(
+                ResourceId::from_u128(101),
+                None,
+                HealthStatus::Degraded,
+                Some(HealthReason::Probe),
+            )
+            .unwrap();
+
+            let slice = packet.as_fz_p2p_control().unwrap();
+            let resource_health = decode_resource_health(slice).unwrap();
+
+            assert_eq!(resource_health.resource, ResourceId::from_u128(101));
+            assert_eq!(resource_health.domain, None);
+            assert_eq!(resource_health.status, HealthStatus::Degraded);
+            assert_eq!(resource_health.reason, Some(HealthReason::Probe));
+        }
+
+        #[test]
+        fn domain_health_serde_roundtrip() {
+            let domain = DomainName::vec_from_str("gitlab.example.com").unwrap();
+            let packet = resource_health(
+                ResourceId::from_u128(101),
+                Some(domain.clone()),
+                HealthStatus::Unreachable,
+                Some(HealthReason::DnsFailure),
+            )
+            .unwrap();
+
+            let slice = packet.as_fz_p2p_control().unwrap();
+            let resource_health = decode_resource_health(slice).unwrap();
+
+            assert_eq!(resource_health.domain, Some(domain));
+            assert_eq!(resource_health.status, HealthStatus::Unreachable);
+            assert_eq!(resource_health.reason, Some(HealthReason::DnsFailure));
+        }
+
+        #[test]
+        fn resource_health_ignores_unknown_status() {
+            let payload =
+                r#"{"resource":"00000000-0000-0000-0000-000000000065","status":"what_is_this"}"#;
+            let packet = ip_packet::make::fz_p2p_control(
+                [RESOURCE_HEALTH_EVENT.into_u8(), 0, 0, 0, 0, 0, 0, 0],
+                payload.as_bytes(),
+            )
+            .expect("payload is less than max packet size");
+
+            let slice = packet.as_fz_p2p_control().unwrap();
+            let resource_health = decode_resource_health(slice).unwrap();
+
+            assert_eq!(resource_health.resource, ResourceId::from_u128(101));
+            assert_eq!(resource_health.domain, None);
+            assert_eq!(resource_health.status, HealthStatus::Unknown);
+            assert_eq!(resource_health.reason, None);
+        }
+
+        #[test]
+        fn resource_health_ignores_unknown_reason() {
+            let payload = r#"{"resource":"00000000-0000-0000-0000-000000000065","status":"degraded","reason":"what_is_this"}"#;
+            let packet = ip_packet::make::fz_p2p_control(
+                [RESOURCE_HEALTH_EVENT.into_u8(), 0, 0, 0, 0, 0, 0, 0],
+                payload.as_bytes(),
+            )
+            .expect("payload is less than max packet size");
+
+            let slice = packet.as_fz_p2p_control().unwrap();
+            let resource_health = decode_resource_health(slice).unwrap();
+
+            assert_eq!(resource_health.reason, Some(HealthReason::Unknown));
+        }
+    }
+}
+
 pub fn goodbye() -> IpPacket {
     ip_packet::make::fz_p2p_control([GOODBYE_EVENT.into_u8(), 0, 0, 0, 0, 0, 0, 0], &[])
         .expect("should always be able to make a `goodbye` packet")
 }
+
+/// Tells a client that this gateway is about to shut down and that new flows should go to another gateway of the site.
+///
+/// Existing flows may continue until the gateway sends [`goodbye`].
+pub fn gateway_draining() -> IpPacket {
+    ip_packet::make::fz_p2p_control([GATEWAY_DRAINING_EVENT.into_u8(), 0, 0, 0, 0, 0, 0, 0], &[])
+        .expect("should always be able to make a `gateway_draining` packet")
+}
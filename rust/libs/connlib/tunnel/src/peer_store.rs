@@ -111,6 +111,14 @@ where
         self.peer_by_id.get_mut(id)
     }
 
+    #[expect(
+        clippy::disallowed_methods,
+        reason = "Callers must not depend on the iteration order."
+    )]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &P> {
+        self.peer_by_id.values()
+    }
+
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut P> {
         self.peer_by_id.values_mut()
     }
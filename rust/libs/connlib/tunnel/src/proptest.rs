@@ -44,6 +44,7 @@ pub fn dns_resource(sites: impl Strategy<Value = Vec<Site>>) -> impl Strategy<Va
                 sites,
                 address_description,
                 ip_stack,
+                exclusions: Vec::new(),
             },
         )
 }
@@ -10,7 +10,7 @@ use dns_types::DomainName;
 use ip_packet::IpPacket;
 use itertools::Itertools;
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque, hash_map::Entry},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque, btree_map, hash_map::Entry},
     hash::Hash,
     marker::PhantomData,
     net::{IpAddr, SocketAddr},
@@ -30,16 +30,24 @@ pub(crate) fn assert_icmp_packets_properties(
     ref_client: &RefClient,
     sim_client: &SimClient,
     sim_gateways: &BTreeMap<GatewayId, &SimGateway>,
+    portal: &StubPortal,
     global_dns_records: &DnsRecords,
 ) {
-    let received_icmp_requests = sim_gateways
-        .iter()
-        .map(|(g, s)| (*g, &s.received_icmp_requests))
-        .collect();
-    let dns_query_timestamps = sim_gateways
-        .iter()
-        .map(|(g, s)| (*g, &s.dns_query_timestamps))
-        .collect();
+    let received_icmp_requests = merge_by_site(
+        sim_gateways,
+        portal,
+        |g| &g.received_icmp_requests,
+        |_, _| {}, // Payloads are unique across all gateways.
+    );
+    let dns_query_timestamps = merge_by_site(
+        sim_gateways,
+        portal,
+        |g| &g.dns_query_timestamps,
+        |timestamps, other| {
+            timestamps.extend(other);
+            timestamps.sort();
+        },
+    );
 
     assert_packets_properties(
         ref_client,
@@ -47,6 +55,7 @@ pub(crate) fn assert_icmp_packets_properties(
         &dns_query_timestamps,
         &received_icmp_requests,
         &ref_client.expected_icmp_handshakes,
+        &ref_client.filtered_icmp_packets,
         &sim_client.received_icmp_replies,
         "ICMP",
         global_dns_records,
@@ -64,16 +73,24 @@ pub(crate) fn assert_udp_packets_properties(
     ref_client: &RefClient,
     sim_client: &SimClient,
     sim_gateways: &BTreeMap<GatewayId, &SimGateway>,
+    portal: &StubPortal,
     global_dns_records: &DnsRecords,
 ) {
-    let received_udp_requests = sim_gateways
-        .iter()
-        .map(|(g, s)| (*g, &s.received_udp_requests))
-        .collect();
-    let dns_query_timestamps = sim_gateways
-        .iter()
-        .map(|(g, s)| (*g, &s.dns_query_timestamps))
-        .collect();
+    let received_udp_requests = merge_by_site(
+        sim_gateways,
+        portal,
+        |g| &g.received_udp_requests,
+        |_, _| {}, // Payloads are unique across all gateways.
+    );
+    let dns_query_timestamps = merge_by_site(
+        sim_gateways,
+        portal,
+        |g| &g.dns_query_timestamps,
+        |timestamps, other| {
+            timestamps.extend(other);
+            timestamps.sort();
+        },
+    );
 
     assert_packets_properties(
         ref_client,
@@ -81,6 +98,7 @@ pub(crate) fn assert_udp_packets_properties(
         &dns_query_timestamps,
         &received_udp_requests,
         &ref_client.expected_udp_handshakes,
+        &ref_client.filtered_udp_packets,
         &sim_client.received_udp_replies,
         "UDP",
         global_dns_records,
@@ -151,6 +169,8 @@ pub(crate) fn assert_resource_status(ref_client: &RefClient, sim_client: &SimCli
                 }
                 Some(&Unknown)
                     if expected_status == &Online && maybe_online_resources.contains(resource) => {}
+                // The Gateway reports resources as degraded based on the traffic it observes, which we don't model.
+                Some(&Degraded) if expected_status == &Online => {}
 
                 Some(actual_status) if actual_status != expected_status => {
                     tracing::error!(target: "assertions", %expected_status, %actual_status, %resource, ?maybe_online_resources, "Resource status doesn't match");
@@ -173,9 +193,10 @@ pub(crate) fn assert_resource_status(ref_client: &RefClient, sim_client: &SimCli
 fn assert_packets_properties<T, U>(
     ref_client: &RefClient,
     sent_requests: &HashMap<(T, U), IpPacket>,
-    dns_query_timestamps: &BTreeMap<GatewayId, &BTreeMap<DomainName, Vec<Instant>>>,
-    received_requests: &BTreeMap<GatewayId, &BTreeMap<u64, (Instant, IpPacket)>>,
+    dns_query_timestamps: &BTreeMap<GatewayId, BTreeMap<DomainName, Vec<Instant>>>,
+    received_requests: &BTreeMap<GatewayId, BTreeMap<u64, (Instant, IpPacket)>>,
     expected_handshakes: &BTreeMap<GatewayId, BTreeMap<u64, (Destination, T, U)>>,
+    filtered_packets: &BTreeSet<u64>,
     received_replies: &BTreeMap<(T, U), IpPacket>,
     packet_protocol: &str,
     global_dns_records: &DnsRecords,
@@ -219,6 +240,23 @@ fn assert_packets_properties<T, U>(
             };
             assert_correct_src_and_dst_ips(client_sent_request, client_received_reply);
 
+            if filtered_packets.contains(payload) {
+                if received_requests.contains_key(payload) {
+                    tracing::error!(target: "assertions", "❌ Filtered {packet_protocol} request arrived at resource");
+                }
+
+                if client_received_reply
+                    .icmp_error()
+                    .ok()
+                    .is_none_or(|icmp| icmp.is_none())
+                {
+                    tracing::error!(target: "assertions", "❌ Filtered {packet_protocol} request did not result in an ICMP error");
+                }
+
+                num_expected_handshakes -= 1;
+                continue;
+            }
+
             let Some((packet_sent_at, gateway_received_request)) = received_requests.get(payload)
             else {
                 if client_received_reply
@@ -226,6 +264,18 @@ fn assert_packets_properties<T, U>(
                     .ok()
                     .is_some_and(|icmp| icmp.is_some())
                 {
+                    if let Destination::DomainName { name, .. } = resource_dst
+                        && should_have_been_translated(
+                            client_sent_request,
+                            client_received_reply,
+                            dns_query_timestamps.get(name),
+                            global_dns_records,
+                            name,
+                        )
+                    {
+                        tracing::error!(target: "assertions", %name, "❌ Request to DNS resource was not translated between IP versions");
+                    }
+
                     // If the received reply is an ICMP unreachable error, it is ok to have a missing request.
                     num_expected_handshakes -= 1;
                     continue;
@@ -281,7 +331,16 @@ fn assert_packets_properties<T, U>(
                         client_sent_request,
                         gateway_received_request,
                         mapping,
-                    )
+                    );
+
+                    assert_ip_version_translation(
+                        client_sent_request,
+                        gateway_received_request,
+                        client_received_reply,
+                        global_dns_records,
+                        name,
+                        *dns_record_snapshot,
+                    );
                 }
             }
         }
@@ -296,12 +355,153 @@ fn assert_packets_properties<T, U>(
     }
 }
 
+/// Merges the given state of each gateway with the one of all other gateways in its site.
+///
+/// The client spreads flows for a resource across all gateways of its site.
+/// Thus, the reference model cannot know upfront which of them receives a particular packet.
+fn merge_by_site<K, V>(
+    sim_gateways: &BTreeMap<GatewayId, &SimGateway>,
+    portal: &StubPortal,
+    state: impl Fn(&SimGateway) -> &BTreeMap<K, V>,
+    merge: impl Fn(&mut V, &V),
+) -> BTreeMap<GatewayId, BTreeMap<K, V>>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    sim_gateways
+        .keys()
+        .map(|gid| {
+            let mut merged = BTreeMap::<K, V>::new();
+
+            for sibling in portal.sibling_gateways(*gid) {
+                let Some(sim_gateway) = sim_gateways.get(&sibling) else {
+                    continue;
+                };
+
+                for (key, value) in state(sim_gateway) {
+                    match merged.entry(key.clone()) {
+                        btree_map::Entry::Vacant(entry) => {
+                            entry.insert(value.clone());
+                        }
+                        btree_map::Entry::Occupied(mut entry) => merge(entry.get_mut(), value),
+                    }
+                }
+            }
+
+            (*gid, merged)
+        })
+        .collect()
+}
+
+/// Whether the client received an ICMP error for a request that the gateway should have translated via NAT64 / NAT46.
+///
+/// That is the case if the domain only ever resolved to IPs of the other IP version.
+fn should_have_been_translated(
+    client_sent_request: &IpPacket,
+    client_received_reply: &IpPacket,
+    query_timestamps: Option<&Vec<Instant>>,
+    global_dns_records: &DnsRecords,
+    name: &DomainName,
+) -> bool {
+    let Some(query_timestamps) = query_timestamps.filter(|t| !t.is_empty()) else {
+        return false;
+    };
+
+    // Prohibited errors stem from the gateway's filters and have nothing to do with the IP version.
+    let is_error = client_received_reply
+        .icmp_error()
+        .ok()
+        .flatten()
+        .is_some_and(|(_, error)| !error.is_unreachable_prohibited());
+
+    if !is_error {
+        return false;
+    }
+
+    let request_is_ipv4 = client_sent_request.destination().is_ipv4();
+
+    query_timestamps
+        .iter()
+        .all(|at| only_resolves_to_other_ip_version(global_dns_records, name, *at, request_is_ipv4))
+}
+
+/// Asserts that a request to a DNS resource is translated between IP versions if and only if the domain only resolved to IPs of the other version.
+///
+/// The reply to a translated request must arrive in the IP version of the request and with the ports of the request flipped.
+fn assert_ip_version_translation(
+    client_sent_request: &IpPacket,
+    gateway_received_request: &IpPacket,
+    client_received_reply: &IpPacket,
+    global_dns_records: &DnsRecords,
+    domain: &DomainName,
+    at: Instant,
+) {
+    let request_is_ipv4 = client_sent_request.destination().is_ipv4();
+    let expected =
+        only_resolves_to_other_ip_version(global_dns_records, domain, at, request_is_ipv4);
+    let actual = gateway_received_request.destination().is_ipv4() != request_is_ipv4;
+
+    if expected != actual {
+        tracing::error!(target: "assertions", %domain, %expected, %actual, "❌ Translation between IP versions does not match resolved IPs");
+        return;
+    }
+
+    if !actual {
+        return;
+    }
+
+    if client_received_reply.icmp_error().ok().flatten().is_some() {
+        tracing::error!(target: "assertions", %domain, "❌ Translated request resulted in an ICMP error");
+        return;
+    }
+
+    if client_received_reply.source().is_ipv4() != request_is_ipv4 {
+        tracing::error!(target: "assertions", %domain, "❌ Reply to translated request has a different IP version than the request");
+        return;
+    }
+
+    if let (Some(client_udp), Some(gateway_udp)) = (
+        client_sent_request.as_udp(),
+        gateway_received_request.as_udp(),
+    ) {
+        let client_dport = client_udp.destination_port();
+        let gateway_dport = gateway_udp.destination_port();
+
+        if client_dport != gateway_dport {
+            tracing::error!(target: "assertions", %domain, %client_dport, %gateway_dport, "❌ Translated request has a different dst port");
+        }
+
+        assert_correct_src_and_dst_udp_ports(client_sent_request, client_received_reply);
+    }
+
+    tracing::info!(target: "assertions", %domain, "✅ Request was translated between IP versions");
+}
+
+/// Whether the domain only resolved to IPs of the other IP version at the given time.
+fn only_resolves_to_other_ip_version(
+    global_dns_records: &DnsRecords,
+    name: &DomainName,
+    at: Instant,
+    request_is_ipv4: bool,
+) -> bool {
+    let (same_version, other_version): (Vec<_>, Vec<_>) = global_dns_records
+        .domain_ips_iter(name, at)
+        .partition(|ip| ip.is_ipv4() == request_is_ipv4);
+
+    same_version.is_empty() && !other_version.is_empty()
+}
+
 pub(crate) fn assert_dns_servers_are_valid(
     ref_client: &RefClient,
     sim_client: &SimClient,
     portal: &StubPortal,
 ) {
-    let expected = ref_client.expected_dns_servers(portal.upstream_do53(), portal.upstream_doh());
+    let expected = ref_client.expected_dns_servers(
+        portal.upstream_do53(),
+        portal.upstream_doh(),
+        portal.upstream_dot(),
+    );
     let actual = sim_client.effective_dns_servers();
 
     if actual != expected {
@@ -4,12 +4,14 @@ use super::{
     composite_strategy::CompositeStrategy, sim_client::*, sim_gateway::*, sim_net::*,
     strategies::*, stub_portal::StubPortal, transition::*,
 };
+use crate::messages::gateway::FilterAction;
 use crate::proptest::domain_label;
 use crate::{client, dns};
 use crate::{dns::is_subdomain, proptest::relay_id};
 use connlib_model::{GatewayId, RelayId, Site, StaticSecret};
 use dns_types::{DomainName, RecordType};
 use ip_network::{Ipv4Network, Ipv6Network};
+use ip_packet::Protocol;
 use itertools::Itertools;
 use prop::sample::select;
 use proptest::collection::btree_set;
@@ -225,6 +227,10 @@ impl ReferenceState {
                 1,
                 upstream_doh_servers().prop_map(Transition::UpdateUpstreamDoHServers),
             )
+            .with(
+                1,
+                upstream_dot_servers().prop_map(Transition::UpdateUpstreamDoTServers),
+            )
             .with(
                 1,
                 search_domain(state.portal.dns_resources())
@@ -407,6 +413,27 @@ impl ReferenceState {
                     .prop_map(Transition::SendDnsQueries)
                 },
             )
+            .with_if_not_empty(
+                2,
+                (
+                    state.excluded_domains_on_client(),
+                    state.reachable_dns_servers(),
+                ),
+                |(excluded_domains, dns_servers)| {
+                    dns_queries(
+                        (
+                            sample::select(excluded_domains),
+                            prop_oneof![
+                                Just(vec![RecordType::A]),
+                                Just(vec![RecordType::AAAA]),
+                                Just(vec![RecordType::A, RecordType::AAAA])
+                            ],
+                        ),
+                        sample::select(dns_servers),
+                    )
+                    .prop_map(Transition::SendDnsQueries)
+                },
+            )
             .with_if_not_empty(
                 1,
                 state
@@ -537,16 +564,21 @@ impl ReferenceState {
                 identifier,
                 payload,
                 ..
-            } => state.client.exec_mut(|client| {
-                client.on_icmp_packet(
-                    dst.clone(),
-                    *seq,
-                    *identifier,
-                    *payload,
-                    |r| state.portal.gateway_for_resource(r).copied(),
-                    |ip| state.portal.gateway_by_ip(ip),
-                )
-            }),
+            } => {
+                let filtered = state.is_filtered_by_gateway(dst, Protocol::IcmpEcho(identifier.0));
+
+                state.client.exec_mut(|client| {
+                    client.on_icmp_packet(
+                        dst.clone(),
+                        *seq,
+                        *identifier,
+                        *payload,
+                        filtered,
+                        |r| state.portal.gateway_for_resource(r).copied(),
+                        |ip| state.portal.gateway_by_ip(ip),
+                    )
+                })
+            }
             Transition::SendUdpPacket {
                 dst,
                 sport,
@@ -554,12 +586,15 @@ impl ReferenceState {
                 payload,
                 ..
             } => {
+                let filtered = state.is_filtered_by_gateway(dst, Protocol::Udp(dport.0));
+
                 state.client.exec_mut(|client| {
                     client.on_udp_packet(
                         dst.clone(),
                         *sport,
                         *dport,
                         *payload,
+                        filtered,
                         |r| state.portal.gateway_for_resource(r).copied(),
                         |ip| state.portal.gateway_by_ip(ip),
                     )
@@ -584,6 +619,9 @@ impl ReferenceState {
             Transition::UpdateUpstreamDoHServers(servers) => {
                 state.portal.set_upstream_doh(servers.clone());
             }
+            Transition::UpdateUpstreamDoTServers(servers) => {
+                state.portal.set_upstream_dot(servers.clone());
+            }
             Transition::UpdateUpstreamSearchDomain(domain) => {
                 state.portal.set_search_domain(domain.clone());
             }
@@ -701,6 +739,9 @@ impl ReferenceState {
 
                 ref_client.is_valid_icmp_packet(seq, identifier, payload)
                     && state.is_valid_dst_ip(*dst)
+                    && state
+                        .gateway_filter_verdict(*dst, Protocol::IcmpEcho(identifier.0))
+                        .is_some()
             }
             Transition::SendUdpPacket {
                 dst: Destination::IpAddr(dst),
@@ -711,7 +752,11 @@ impl ReferenceState {
             } => {
                 let ref_client = state.client.inner();
 
-                ref_client.is_valid_udp_packet(sport, dport, payload) && state.is_valid_dst_ip(*dst)
+                ref_client.is_valid_udp_packet(sport, dport, payload)
+                    && state.is_valid_dst_ip(*dst)
+                    && state
+                        .gateway_filter_verdict(*dst, Protocol::Udp(dport.0))
+                        .is_some()
             }
             Transition::ConnectTcp {
                 src,
@@ -744,21 +789,35 @@ impl ReferenceState {
                     .any(|dns_server| state.client.sending_socket_for(dns_server.ip).is_some())
             }
             Transition::UpdateUpstreamDoHServers(_) => true,
+            Transition::UpdateUpstreamDoTServers(_) => true,
             Transition::UpdateUpstreamSearchDomain(_) => true,
             Transition::SendDnsQueries(queries) => queries.iter().all(|query| {
                 let has_socket_for_server = match query.dns_server {
                     crate::dns::Upstream::Do53 { server } => {
                         state.client.sending_socket_for(server.ip()).is_some()
                     }
-                    crate::dns::Upstream::DoH { .. } => true,
+                    crate::dns::Upstream::DoH { .. } | crate::dns::Upstream::DoT { .. } => true,
+                };
+                let dns_server_is_not_filtered = match query.dns_server {
+                    crate::dns::Upstream::Do53 { server } => {
+                        let protocol = match query.transport {
+                            DnsTransport::Udp { .. } => Protocol::Udp(server.port()),
+                            DnsTransport::Tcp => Protocol::Tcp(server.port()),
+                        };
+
+                        state.gateway_filter_verdict(server.ip(), protocol)
+                            == Some(FilterAction::Allow)
+                    }
+                    crate::dns::Upstream::DoH { .. } | crate::dns::Upstream::DoT { .. } => true,
                 };
                 let upstream_do53 = state.portal.upstream_do53();
                 let upstream_doh = state.portal.upstream_doh();
+                let upstream_dot = state.portal.upstream_dot();
 
                 let has_dns_server = state
                     .client
                     .inner()
-                    .expected_dns_servers(upstream_do53, upstream_doh)
+                    .expected_dns_servers(upstream_do53, upstream_doh, upstream_dot)
                     .contains(&query.dns_server);
                 let gateway_is_present_in_case_dns_server_is_cidr_resource = match state
                     .client
@@ -777,6 +836,7 @@ impl ReferenceState {
 
                 has_socket_for_server
                     && has_dns_server
+                    && dns_server_is_not_filtered
                     && gateway_is_present_in_case_dns_server_is_cidr_resource
             }),
             Transition::RoamClient { ip4, ip6 } => {
@@ -857,6 +917,25 @@ impl ReferenceState {
         self.gateways.contains_key(gateway)
     }
 
+    /// Predicts whether the gateway's filters allow a packet to the given IP.
+    ///
+    /// Returns `None` if the verdict depends on which resources the gateway is authorized for.
+    fn gateway_filter_verdict(&self, dst: IpAddr, protocol: Protocol) -> Option<FilterAction> {
+        let Some(rid) = self.client.inner().cidr_resource_by_ip(dst) else {
+            return Some(FilterAction::Allow); // Only CIDR resources have filters in our tests.
+        };
+
+        self.portal.gateway_filter_verdict(rid, dst, protocol)
+    }
+
+    fn is_filtered_by_gateway(&self, dst: &Destination, protocol: Protocol) -> bool {
+        let Some(dst) = dst.ip_addr() else {
+            return false;
+        };
+
+        self.gateway_filter_verdict(dst, protocol) == Some(FilterAction::Deny)
+    }
+
     fn is_valid_dst_domain(&self, name: &DomainName, src: &IpAddr) -> bool {
         let Some(resource) = self.client.inner().dns_resource_by_domain(name) else {
             return false;
@@ -919,7 +998,11 @@ impl ReferenceState {
     fn reachable_dns_servers(&self) -> Vec<dns::Upstream> {
         self.client
             .inner()
-            .expected_dns_servers(self.portal.upstream_do53(), self.portal.upstream_doh())
+            .expected_dns_servers(
+                self.portal.upstream_do53(),
+                self.portal.upstream_doh(),
+                self.portal.upstream_dot(),
+            )
             .into_iter()
             .filter(|s| match s {
                 crate::dns::Upstream::Do53 {
@@ -928,7 +1011,7 @@ impl ReferenceState {
                 crate::dns::Upstream::Do53 {
                     server: SocketAddr::V6(_),
                 } => self.client.ip6.is_some(),
-                crate::dns::Upstream::DoH { .. } => true,
+                crate::dns::Upstream::DoH { .. } | crate::dns::Upstream::DoT { .. } => true,
             })
             .collect()
     }
@@ -975,6 +1058,19 @@ impl ReferenceState {
             .collect()
     }
 
+    /// Domains that are excluded from a DNS resource on the client.
+    ///
+    /// Unless a less specific resource matches them, these must be resolved outside of the tunnel.
+    fn excluded_domains_on_client(&self) -> Vec<DomainName> {
+        self.portal
+            .dns_resources()
+            .into_iter()
+            .filter(|r| self.client.inner().has_resource(r.id))
+            .flat_map(|r| r.exclusions)
+            .map(|e| e.parse().unwrap())
+            .collect()
+    }
+
     fn regular_sites(&self) -> Vec<Site> {
         let all_sites = self
             .portal
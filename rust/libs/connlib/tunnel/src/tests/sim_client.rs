@@ -9,7 +9,7 @@ use super::{
 };
 use crate::{
     ClientState, DnsMapping, DnsResourceRecord, dns,
-    messages::{UpstreamDo53, UpstreamDoH},
+    messages::{UpstreamDo53, UpstreamDoH, UpstreamDoT},
     proptest::*,
 };
 use crate::{
@@ -121,6 +121,7 @@ impl SimClient {
         self.sut = ClientState::new(
             key.0,
             dns_resource_records,
+            Default::default(),
             is_internet_resource_active,
             now,
             utc_now
@@ -472,6 +473,13 @@ pub struct RefClient {
     pub(crate) expected_udp_handshakes:
         BTreeMap<GatewayId, BTreeMap<u64, (Destination, SPort, DPort)>>,
 
+    /// The payloads of ICMP packets we expect the gateway's filters to reject.
+    #[debug(skip)]
+    pub(crate) filtered_icmp_packets: BTreeSet<u64>,
+    /// The payloads of UDP packets we expect the gateway's filters to reject.
+    #[debug(skip)]
+    pub(crate) filtered_udp_packets: BTreeSet<u64>,
+
     /// The expected TCP connections.
     #[debug(skip)]
     pub(crate) expected_tcp_connections: BTreeMap<(IpAddr, Destination, SPort, DPort), ResourceId>,
@@ -492,6 +500,7 @@ impl RefClient {
         self,
         upstream_do53: Vec<UpstreamDo53>,
         upstream_doh: Vec<UpstreamDoH>,
+        upstream_dot: Vec<UpstreamDoT>,
         search_domain: Option<DomainName>,
         now: Instant,
         utc_now: DateTime<Utc>,
@@ -499,6 +508,7 @@ impl RefClient {
         let mut client_state = ClientState::new(
             self.key.0,
             Default::default(),
+            Default::default(),
             self.internet_resource_active,
             now,
             utc_now
@@ -510,8 +520,10 @@ impl RefClient {
             ipv4: self.tunnel_ip4,
             ipv6: self.tunnel_ip6,
             upstream_dns: Vec::new(),
+            conditional_forwarders: Vec::new(),
             upstream_do53,
             upstream_doh,
+            upstream_dot,
             search_domain,
         });
         client_state.update_system_resolvers(self.system_dns_resolvers.clone());
@@ -753,9 +765,14 @@ impl RefClient {
         seq: Seq,
         identifier: Identifier,
         payload: u64,
+        filtered: bool,
         gateway_by_resource: impl Fn(ResourceId) -> Option<GatewayId>,
         gateway_by_ip: impl Fn(IpAddr) -> Option<GatewayId>,
     ) {
+        if filtered {
+            self.filtered_icmp_packets.insert(payload);
+        }
+
         self.on_packet(
             dst.clone(),
             (dst, seq, identifier),
@@ -772,9 +789,14 @@ impl RefClient {
         sport: SPort,
         dport: DPort,
         payload: u64,
+        filtered: bool,
         gateway_by_resource: impl Fn(ResourceId) -> Option<GatewayId>,
         gateway_by_ip: impl Fn(IpAddr) -> Option<GatewayId>,
     ) {
+        if filtered {
+            self.filtered_udp_packets.insert(payload);
+        }
+
         self.on_packet(
             dst.clone(),
             (dst, sport, dport),
@@ -983,12 +1005,18 @@ impl RefClient {
         self.active_internet_resource()
     }
 
+    /// Finds the most specific DNS resource for the given domain.
+    ///
+    /// Resources that the domain is excluded from don't match, leaving it to a less specific one or none at all.
     pub(crate) fn dns_resource_by_domain(&self, domain: &DomainName) -> Option<DnsResource> {
+        let domain = domain.to_string();
+
         self.resources
             .iter()
             .cloned()
             .filter_map(|r| r.into_dns())
-            .filter(|r| is_subdomain(&domain.to_string(), &r.address))
+            .filter(|r| is_subdomain(&domain, &r.address))
+            .filter(|r| !r.exclusions.iter().any(|e| is_subdomain(&domain, e)))
             .sorted_by_key(|r| r.address.len())
             .next_back()
     }
@@ -1058,7 +1086,7 @@ impl RefClient {
     /// Returns the DNS servers that we expect connlib to use.
     ///
     /// If there are upstream Do53 servers configured in the portal, it should use those.
-    /// If there are no custom servers defined, it should use the DoH servers specified in the portal.
+    /// If there are no Do53 servers defined, it should use the DoT servers specified in the portal, followed by the DoH servers.
     /// Otherwise it should use whatever was configured on the system prior to connlib starting.
     ///
     /// This purposely returns a `Vec` so we also assert the order!
@@ -1066,6 +1094,7 @@ impl RefClient {
         &self,
         upstream_do53: &[UpstreamDo53],
         upstream_doh: &[UpstreamDoH],
+        upstream_dot: &[UpstreamDoT],
     ) -> Vec<dns::Upstream> {
         if !upstream_do53.is_empty() {
             return upstream_do53
@@ -1076,6 +1105,16 @@ impl RefClient {
                 .collect();
         }
 
+        if !upstream_dot.is_empty() {
+            return upstream_dot
+                .iter()
+                .map(|u| dns::Upstream::DoT {
+                    server: SocketAddr::new(u.ip, 853),
+                    tls_name: u.tls_name.clone(),
+                })
+                .collect();
+        }
+
         if !upstream_doh.is_empty() {
             return upstream_doh
                 .iter()
@@ -1178,7 +1217,7 @@ impl RefClient {
 
         let server = match query.dns_server {
             dns::Upstream::Do53 { server } => server,
-            dns::Upstream::DoH { .. } => return None,
+            dns::Upstream::DoH { .. } | dns::Upstream::DoT { .. } => return None,
         };
 
         let maybe_active_cidr_resource = self.cidr_resource_by_ip(server.ip());
@@ -1314,6 +1353,8 @@ fn ref_client(
                     connected_internet_resource: Default::default(),
                     expected_icmp_handshakes: Default::default(),
                     expected_udp_handshakes: Default::default(),
+                    filtered_icmp_packets: Default::default(),
+                    filtered_udp_packets: Default::default(),
                     expected_tcp_connections: Default::default(),
                     expected_udp_dns_handshakes: Default::default(),
                     expected_tcp_dns_handshakes: Default::default(),
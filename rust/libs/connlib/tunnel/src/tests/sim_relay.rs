@@ -9,10 +9,11 @@ use ip_packet::Ecn;
 use proptest::prelude::*;
 use rand::{SeedableRng as _, rngs::StdRng};
 use secrecy::SecretString;
-use snownet::{RelaySocket, Transmit};
+use snownet::{RelaySocket, Transmit, Transport};
 use std::{
     collections::HashSet,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    ops::RangeInclusive,
     time::{Duration, Instant, SystemTime},
 };
 
@@ -42,11 +43,20 @@ pub(crate) fn map_explode<'a>(
 
 impl SimRelay {
     pub(crate) fn new(seed: u64, ip4: Option<Ipv4Addr>, ip6: Option<Ipv6Addr>) -> Self {
+        Self::with_ports(seed, ip4, ip6, 49152..=65535)
+    }
+
+    fn with_ports(
+        seed: u64,
+        ip4: Option<Ipv4Addr>,
+        ip6: Option<Ipv6Addr>,
+        ports: RangeInclusive<u16>,
+    ) -> Self {
         let sut = firezone_relay::Server::new(
             IpStack::from((ip4, ip6)),
             rand::rngs::StdRng::seed_from_u64(seed),
             3478,
-            49152..=65535,
+            ports,
         );
 
         Self {
@@ -105,6 +115,7 @@ impl SimRelay {
             payload,
             PeerSocket::new(sender),
             AllocationPort::new(dst.port()),
+            now,
         )
     }
 
@@ -153,6 +164,7 @@ impl SimRelay {
             dst,
             payload,
             ecn: Ecn::NonEct,
+            transport: Transport::Udp,
         })
     }
 
@@ -161,8 +173,9 @@ impl SimRelay {
         mut payload: Buffer<Vec<u8>>,
         peer: PeerSocket,
         port: AllocationPort,
+        now: Instant,
     ) -> Option<Transmit> {
-        let (client, channel) = self.sut.handle_peer_traffic(&payload, peer, port)?;
+        let (client, channel) = self.sut.handle_peer_traffic(&payload, peer, port, now)?;
 
         let data_len = payload.len() as u16;
         let header = payload.shift_start_left(4);
@@ -179,6 +192,7 @@ impl SimRelay {
             dst: receiving_socket,
             payload,
             ecn: Ecn::NonEct,
+            transport: Transport::Udp,
         })
     }
 
@@ -206,3 +220,318 @@ pub(crate) fn ref_relay_host() -> impl Strategy<Value = Host<u64>> {
         latency(50), // We assume our relays have a good Internet connection.
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boringtun::x25519::{PublicKey, StaticSecret};
+    use snownet::{Credentials, Event, IceConfig, IceRole, Node};
+
+    const IDLE_RELAY: RelayId = RelayId::from_u128(1);
+    const BUSY_RELAY: RelayId = RelayId::from_u128(2);
+    const OTHER_IDLE_RELAY: RelayId = RelayId::from_u128(3);
+    const DISTANT_RELAY: RelayId = RelayId::from_u128(4);
+
+    /// How many ports each of our relays has available.
+    const CAPACITY: u16 = 10;
+
+    #[test]
+    fn clients_learn_load_of_relays() {
+        let now = Instant::now();
+        let mut network = Network::new();
+
+        network.allocate_on_busy_relay(8, now);
+        let client = network.add_node([0; 32], &[IDLE_RELAY, BUSY_RELAY], now);
+        network.run(now);
+
+        let stats = network.nodes[client]
+            .node
+            .relay_stats()
+            .map(|(rid, stats)| (rid, stats.load.unwrap().allocations))
+            .collect::<Vec<_>>();
+
+        assert_eq!(stats, vec![(IDLE_RELAY, 1), (BUSY_RELAY, 9)]);
+    }
+
+    #[test]
+    fn new_connections_prefer_less_loaded_relay() {
+        let now = Instant::now();
+        let mut network = Network::new();
+
+        network.allocate_on_busy_relay(8, now);
+        let client = network.add_node([0; 32], &[IDLE_RELAY, BUSY_RELAY], now);
+        network.run(now);
+
+        let mut num_idle = 0;
+        let mut num_busy = 0;
+
+        for cid in 0..200 {
+            match network.relay_for_new_connection(client, cid, now) {
+                IDLE_RELAY => num_idle += 1,
+                BUSY_RELAY => num_busy += 1,
+                other => panic!("Unknown relay {other}"),
+            }
+        }
+
+        assert!(num_busy > 0, "Busy relay should still be used occasionally");
+        assert!(
+            num_idle > num_busy * 3,
+            "Idle relay should be preferred: idle = {num_idle}, busy = {num_busy}"
+        );
+    }
+
+    #[test]
+    fn drops_allocation_on_busiest_relay() {
+        let now = Instant::now();
+        let mut network = Network::new();
+
+        network.allocate_on_busy_relay(8, now);
+        let client = network.add_node([0; 32], &[IDLE_RELAY, BUSY_RELAY, OTHER_IDLE_RELAY], now);
+        network.run(now);
+        network.nodes[client].node.handle_timeout(now);
+
+        assert_eq!(
+            network.relays_of(client),
+            vec![IDLE_RELAY, OTHER_IDLE_RELAY]
+        );
+    }
+
+    #[test]
+    fn drops_allocation_on_most_distant_relay() {
+        let now = Instant::now();
+        let mut network = Network::new();
+
+        let client = network.add_node([0; 32], &[IDLE_RELAY, OTHER_IDLE_RELAY, DISTANT_RELAY], now);
+        network.run(now);
+        network.nodes[client]
+            .node
+            .handle_timeout(now + Duration::from_secs(1)); // After the responses from the distant relay arrived.
+
+        assert_eq!(
+            network.relays_of(client),
+            vec![IDLE_RELAY, OTHER_IDLE_RELAY]
+        );
+    }
+
+    #[test]
+    fn keeps_allocations_until_all_relays_responded() {
+        let now = Instant::now();
+        let mut network = Network::new();
+
+        let client = network.add_node([0; 32], &[IDLE_RELAY, OTHER_IDLE_RELAY, DISTANT_RELAY], now);
+        network.nodes[client].node.handle_timeout(now);
+
+        assert_eq!(
+            network.relays_of(client),
+            vec![IDLE_RELAY, OTHER_IDLE_RELAY, DISTANT_RELAY]
+        );
+    }
+
+    /// A minimal network of [`SimRelay`]s and [`Node`]s without packet loss.
+    ///
+    /// Messages are exchanged instantly, except for responses from a relay, which arrive after the relay's latency.
+    struct Network {
+        relays: Vec<(RelayId, SimRelay, Duration)>,
+        nodes: Vec<SimNode>,
+    }
+
+    struct SimNode {
+        node: Node<u64, RelayId>,
+        socket: SocketAddr,
+    }
+
+    impl Network {
+        fn new() -> Self {
+            Self {
+                relays: vec![
+                    (
+                        IDLE_RELAY,
+                        relay(1, Ipv4Addr::new(10, 0, 0, 1)),
+                        Duration::ZERO,
+                    ),
+                    (
+                        BUSY_RELAY,
+                        relay(2, Ipv4Addr::new(10, 0, 0, 2)),
+                        Duration::ZERO,
+                    ),
+                    (
+                        OTHER_IDLE_RELAY,
+                        relay(3, Ipv4Addr::new(10, 0, 0, 3)),
+                        Duration::ZERO,
+                    ),
+                    (
+                        DISTANT_RELAY,
+                        relay(4, Ipv4Addr::new(10, 0, 0, 4)),
+                        Duration::from_millis(300),
+                    ),
+                ],
+                nodes: Vec::new(),
+            }
+        }
+
+        /// Creates `num` allocations from other nodes on the busy relay.
+        fn allocate_on_busy_relay(&mut self, num: u8, now: Instant) {
+            for i in 0..num {
+                self.add_node([i + 1; 32], &[BUSY_RELAY], now);
+            }
+
+            self.run(now);
+        }
+
+        fn add_node(&mut self, seed: [u8; 32], relays: &[RelayId], now: Instant) -> usize {
+            let index = self.nodes.len();
+            let mut node = Node::new(
+                seed,
+                now,
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap(),
+                IceConfig::client_default(),
+                IceConfig::client_idle(),
+            );
+
+            let to_add = self
+                .relays
+                .iter()
+                .filter(|(rid, _, _)| relays.contains(rid))
+                .map(|(rid, relay, _)| {
+                    let (socket, username, password, realm) = relay.explode(
+                        &format!("node{index}"),
+                        relay.sut.auth_secret(),
+                        relay.sut.public_address(),
+                    );
+
+                    (*rid, socket, username, password, realm)
+                })
+                .collect();
+            node.update_relays(Default::default(), &to_add, now);
+
+            self.nodes.push(SimNode {
+                node,
+                socket: SocketAddr::new(Ipv4Addr::new(192, 168, 0, index as u8 + 1).into(), 1000),
+            });
+
+            index
+        }
+
+        /// Creates a new connection on the given node and returns which relay it sampled for it.
+        fn relay_for_new_connection(&mut self, node: usize, cid: u64, now: Instant) -> RelayId {
+            let node = &mut self.nodes[node].node;
+
+            node.upsert_connection(
+                cid,
+                PublicKey::from(&StaticSecret::from([1; 32])),
+                StaticSecret::from([2; 32]),
+                Credentials {
+                    username: "foo".to_owned(),
+                    password: "foo".to_owned(),
+                },
+                Credentials {
+                    username: "bar".to_owned(),
+                    password: "bar".to_owned(),
+                },
+                IceRole::Controlling,
+                now,
+            )
+            .unwrap();
+
+            // The relayed candidate is the only one that carries the relay's IP.
+            let relay = std::iter::from_fn(|| node.poll_event())
+                .find_map(|event| match event {
+                    Event::NewIceCandidate { candidate, .. } => self
+                        .relays
+                        .iter()
+                        .find(|(_, r, _)| r.sut.public_ip4() == Some(candidate.addr().ip()))
+                        .map(|(rid, _, _)| *rid),
+                    Event::InvalidateIceCandidate { .. }
+                    | Event::ConnectionEstablished(_)
+                    | Event::ConnectionFailed(_)
+                    | Event::ConnectionClosed(_) => None,
+                })
+                .unwrap();
+
+            while node.poll_event().is_some() {}
+
+            relay
+        }
+
+        /// Returns the relays the given node holds allocations on.
+        fn relays_of(&self, node: usize) -> Vec<RelayId> {
+            self.nodes[node]
+                .node
+                .relay_stats()
+                .map(|(rid, _)| rid)
+                .collect()
+        }
+
+        /// Exchanges messages between nodes and relays until there are none left.
+        fn run(&mut self, now: Instant) {
+            loop {
+                let mut progress = false;
+
+                for SimNode { node, socket } in &mut self.nodes {
+                    while let Some(transmit) = node.poll_transmit() {
+                        progress = true;
+
+                        let Some((_, relay, _)) = self
+                            .relays
+                            .iter_mut()
+                            .find(|(_, r, _)| r.sut.public_ip4() == Some(transmit.dst.ip()))
+                        else {
+                            continue;
+                        };
+
+                        let _relayed = relay.receive(
+                            Transmit {
+                                src: Some(*socket),
+                                ..transmit
+                            },
+                            now,
+                        );
+                    }
+                }
+
+                for (_, relay, latency) in &mut self.relays {
+                    while let Some(command) = relay.sut.next_command() {
+                        progress = true;
+
+                        match command {
+                            firezone_relay::Command::SendMessage { payload, recipient } => {
+                                let from = relay
+                                    .matching_listen_socket(
+                                        recipient.into_socket(),
+                                        relay.sut.public_address(),
+                                    )
+                                    .unwrap();
+                                let SimNode { node, socket } = self
+                                    .nodes
+                                    .iter_mut()
+                                    .find(|n| n.socket == recipient.into_socket())
+                                    .unwrap();
+
+                                node.decapsulate(*socket, from, &payload, now + *latency)
+                                    .unwrap();
+                            }
+                            firezone_relay::Command::CreateAllocation { port, family } => {
+                                relay.allocations.insert((family, port));
+                            }
+                            firezone_relay::Command::FreeAllocation { port, family } => {
+                                relay.allocations.remove(&(family, port));
+                            }
+                            firezone_relay::Command::CreateChannelBinding { .. }
+                            | firezone_relay::Command::DeleteChannelBinding { .. } => {}
+                        }
+                    }
+                }
+
+                if !progress {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn relay(seed: u64, ip4: Ipv4Addr) -> SimRelay {
+        SimRelay::with_ports(seed, Some(ip4), None, 49152..=49152 + CAPACITY - 1)
+    }
+}
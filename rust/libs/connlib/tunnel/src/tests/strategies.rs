@@ -5,9 +5,12 @@ use crate::client::{
     CidrResource, DNS_SENTINELS_V4, DNS_SENTINELS_V6, DnsResource, IPV4_RESOURCES, IPV6_RESOURCES,
     InternetResource,
 };
-use crate::messages::{UpstreamDo53, UpstreamDoH};
+use crate::messages::{
+    UpstreamDo53, UpstreamDoH, UpstreamDoT,
+    gateway::{Filter, FilterAction, Filters, PortRange, ProtocolFilter},
+};
 use crate::{IPV4_TUNNEL, IPV6_TUNNEL, proptest::*};
-use connlib_model::{RelayId, Site};
+use connlib_model::{RelayId, ResourceId, Site};
 use dns_types::{DoHUrl, DomainName, OwnedRecordData};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use itertools::Itertools;
@@ -108,6 +111,7 @@ pub(crate) fn stub_portal() -> impl Strategy<Value = StubPortal> {
 
             let upstream_do53_servers = upstream_do53_servers();
             let upstream_doh_servers = upstream_doh_servers();
+            let upstream_dot_servers = upstream_dot_servers();
 
             (
                 gateways_by_site,
@@ -117,6 +121,7 @@ pub(crate) fn stub_portal() -> impl Strategy<Value = StubPortal> {
                 gateway_selector,
                 upstream_do53_servers,
                 upstream_doh_servers,
+                upstream_dot_servers,
             )
         })
         .prop_flat_map(
@@ -128,9 +133,11 @@ pub(crate) fn stub_portal() -> impl Strategy<Value = StubPortal> {
                 gateway_selector,
                 upstream_do53_servers,
                 upstream_doh_servers,
+                upstream_dot_servers,
             )| {
                 (
                     Just(gateways_by_site),
+                    cidr_resource_filters(cidr_resources.clone()),
                     Just(cidr_resources),
                     search_domain(dns_resources.clone()),
                     Just(dns_resources),
@@ -138,12 +145,14 @@ pub(crate) fn stub_portal() -> impl Strategy<Value = StubPortal> {
                     Just(gateway_selector),
                     Just(upstream_do53_servers),
                     Just(upstream_doh_servers),
+                    Just(upstream_dot_servers),
                 )
             },
         )
         .prop_map(
             |(
                 gateways_by_site,
+                cidr_filters,
                 cidr_resources,
                 search_domain,
                 dns_resources,
@@ -151,21 +160,80 @@ pub(crate) fn stub_portal() -> impl Strategy<Value = StubPortal> {
                 gateway_selector,
                 upstream_do53_servers,
                 upstream_doh_servers,
+                upstream_dot_servers,
             )| {
                 StubPortal::new(
                     gateways_by_site,
                     gateway_selector,
                     cidr_resources,
+                    cidr_filters,
                     dns_resources,
                     internet_resource,
                     search_domain,
                     upstream_do53_servers,
                     upstream_doh_servers,
+                    upstream_dot_servers,
                 )
             },
         )
 }
 
+/// Samples the filters the gateway applies to each CIDR resource.
+///
+/// Most resources don't have any filters.
+/// Scoped filters always target a sub-prefix of the resource they belong to.
+fn cidr_resource_filters(
+    resources: BTreeSet<CidrResource>,
+) -> impl Strategy<Value = BTreeMap<ResourceId, Filters>> {
+    resources
+        .into_iter()
+        .map(|r| {
+            (
+                Just(r.id),
+                prop_oneof![
+                    3 => Just(Filters::new()),
+                    1 => collection::vec(gateway_filter(r.address), 1..4),
+                ],
+            )
+        })
+        .collect::<Vec<_>>()
+        .prop_map(BTreeMap::from_iter)
+}
+
+fn gateway_filter(resource: IpNetwork) -> impl Strategy<Value = Filter> {
+    let protocol = prop_oneof![
+        Just(ProtocolFilter::Icmp),
+        Just(ProtocolFilter::All),
+        gateway_filter_port_range().prop_map(ProtocolFilter::Udp),
+        gateway_filter_port_range().prop_map(ProtocolFilter::Tcp),
+    ];
+    let action = prop_oneof![Just(FilterAction::Allow), Just(FilterAction::Deny)];
+
+    (protocol, proptest::option::of(sub_prefix(resource)), action).prop_map(
+        |(protocol, destination, action)| Filter {
+            protocol,
+            destination,
+            action,
+        },
+    )
+}
+
+fn gateway_filter_port_range() -> impl Strategy<Value = PortRange> {
+    any::<u16>().prop_flat_map(|start| {
+        (start..=u16::MAX).prop_map(move |end| PortRange {
+            port_range_start: start,
+            port_range_end: end,
+        })
+    })
+}
+
+fn sub_prefix(network: IpNetwork) -> impl Strategy<Value = IpNetwork> {
+    let max_netmask = if network.is_ipv4() { 32 } else { 128 };
+    let host_mask_bits = usize::from(max_netmask - network.netmask()) + 1;
+
+    host(network).prop_flat_map(move |ip| ip_network(ip, host_mask_bits))
+}
+
 /// Samples a list of TCP resource addresses from the given DNS records.
 ///
 /// We sample at most 1 domain from the given records and create a [`SocketAddr`]
@@ -343,19 +411,40 @@ fn non_wildcard_dns_resource(
 fn star_wildcard_dns_resource(
     site: impl Strategy<Value = Site>,
 ) -> impl Strategy<Value = DnsResource> {
-    dns_resource(site.prop_map(|s| vec![s])).prop_map(|r| DnsResource {
-        address: format!("*.{}", r.address),
-        ..r
-    })
+    (
+        dns_resource(site.prop_map(|s| vec![s])),
+        excluded_subdomains(),
+    )
+        .prop_map(|(r, excluded)| DnsResource {
+            address: format!("*.{}", r.address),
+            exclusions: excluded
+                .into_iter()
+                .map(|label| format!("{label}.{}", r.address))
+                .collect(),
+            ..r
+        })
 }
 
 fn double_star_wildcard_dns_resource(
     site: impl Strategy<Value = Site>,
 ) -> impl Strategy<Value = DnsResource> {
-    dns_resource(site.prop_map(|s| vec![s])).prop_map(|r| DnsResource {
-        address: format!("**.{}", r.address),
-        ..r
-    })
+    (
+        dns_resource(site.prop_map(|s| vec![s])),
+        excluded_subdomains(),
+    )
+        .prop_map(|(r, excluded)| DnsResource {
+            address: format!("**.{}", r.address),
+            exclusions: excluded
+                .into_iter()
+                .map(|label| format!("{label}.{}", r.address))
+                .collect(),
+            ..r
+        })
+}
+
+/// Labels of sub-domains to exclude from a wildcard DNS resource.
+fn excluded_subdomains() -> impl Strategy<Value = BTreeSet<String>> {
+    collection::btree_set(domain_label(), 0..2)
 }
 
 pub(crate) fn resolved_ips() -> impl Strategy<Value = BTreeSet<OwnedRecordData>> {
@@ -440,3 +529,17 @@ pub(crate) fn upstream_doh_servers() -> impl Strategy<Value = Vec<UpstreamDoH>>
     btree_set(doh_server(), 0..2)
         .prop_map(|servers| servers.into_iter().map(|url| UpstreamDoH { url }).collect())
 }
+
+pub(crate) fn upstream_dot_servers() -> impl Strategy<Value = Vec<UpstreamDoT>> {
+    let servers = [
+        ("1.1.1.1", "one.one.one.one"),
+        ("9.9.9.9", "dns.quad9.net"),
+        ("2606:4700:4700::1111", "one.one.one.one"),
+    ]
+    .map(|(ip, tls_name)| UpstreamDoT {
+        ip: ip.parse().unwrap(),
+        tls_name: tls_name.to_owned(),
+    });
+
+    sample::subsequence(Vec::from(servers), 0..=2)
+}
@@ -7,14 +7,18 @@ use super::{
 };
 use crate::{
     client,
-    messages::{UpstreamDo53, UpstreamDoH},
+    messages::{UpstreamDo53, UpstreamDoH, UpstreamDoT},
     proptest::*,
 };
-use crate::{client::DnsResource, messages::gateway};
+use crate::{
+    client::DnsResource,
+    messages::gateway::{self, FilterAction, ProtocolFilter},
+};
 use connlib_model::{GatewayId, Site};
 use connlib_model::{ResourceId, SiteId};
 use dns_types::DomainName;
 use ip_network::IpNetwork;
+use ip_packet::Protocol;
 use itertools::Itertools;
 use proptest::{
     collection,
@@ -44,9 +48,13 @@ pub(crate) struct StubPortal {
     dns_resources: BTreeMap<ResourceId, client::DnsResource>,
     internet_resource: client::InternetResource,
 
+    /// The filters the gateway applies to traffic for a CIDR resource.
+    cidr_filters: BTreeMap<ResourceId, gateway::Filters>,
+
     search_domain: Option<DomainName>,
     upstream_do53: Vec<UpstreamDo53>,
     upstream_doh: Vec<UpstreamDoH>,
+    upstream_dot: Vec<UpstreamDoT>,
 
     #[debug(skip)]
     gateway_selector: Selector,
@@ -57,11 +65,13 @@ impl StubPortal {
         gateways_by_site: BTreeMap<SiteId, BTreeSet<GatewayId>>,
         gateway_selector: Selector,
         cidr_resources: BTreeSet<client::CidrResource>,
+        cidr_filters: BTreeMap<ResourceId, gateway::Filters>,
         dns_resources: BTreeSet<client::DnsResource>,
         internet_resource: client::InternetResource,
         search_domain: Option<DomainName>,
         upstream_do53: Vec<UpstreamDo53>,
         upstream_doh: Vec<UpstreamDoH>,
+        upstream_dot: Vec<UpstreamDoT>,
     ) -> Self {
         let cidr_resources = cidr_resources
             .into_iter()
@@ -136,9 +146,11 @@ impl StubPortal {
             cidr_resources,
             dns_resources,
             internet_resource,
+            cidr_filters,
             search_domain,
             upstream_do53,
             upstream_doh,
+            upstream_dot,
         }
     }
 
@@ -187,11 +199,23 @@ impl StubPortal {
         self.upstream_doh = upstream_doh;
     }
 
+    pub(crate) fn upstream_dot(&self) -> &[UpstreamDoT] {
+        &self.upstream_dot
+    }
+
+    pub(crate) fn set_upstream_dot(&mut self, upstream_dot: Vec<UpstreamDoT>) {
+        self.upstream_dot = upstream_dot;
+    }
+
     /// Picks, which gateway and site we should connect to for the given resource.
+    ///
+    /// The first connection always goes to the gateway selected for the resource's site.
+    /// Once the client uses that one, it asks for additional gateways by listing the ones it doesn't use yet first.
     pub(crate) fn handle_connection_intent(
         &self,
         resource: ResourceId,
-        _connected_gateway_ids: Vec<GatewayId>,
+        gateways_in_use: &[GatewayId],
+        preferred_gateways: Vec<GatewayId>,
     ) -> (GatewayId, SiteId) {
         let site_id = self
             .sites_by_resource
@@ -199,9 +223,42 @@ impl StubPortal {
             .expect("resource to be known");
 
         let gateways = self.gateways_by_site.get(site_id).unwrap();
-        let (gateway, _, _) = self.gateway_selector.select(gateways);
+        let (selected, _, _) = self.gateway_selector.select(gateways);
 
-        (*gateway, *site_id)
+        if !gateways_in_use.contains(selected) {
+            return (*selected, *site_id);
+        }
+
+        let additional = preferred_gateways
+            .into_iter()
+            .find(|preferred| gateways.iter().any(|(gid, _, _)| gid == preferred))
+            .filter(|preferred| !gateways_in_use.contains(preferred));
+
+        (additional.unwrap_or(*selected), *site_id)
+    }
+
+    /// All gateways of the site serving the given resource.
+    ///
+    /// The client may spread flows for the resource across any of them.
+    pub(crate) fn gateways_for_resource(&self, rid: ResourceId) -> Vec<GatewayId> {
+        self.sites_by_resource
+            .get(&rid)
+            .and_then(|sid| self.gateways_by_site.get(sid))
+            .into_iter()
+            .flatten()
+            .map(|(gid, _, _)| *gid)
+            .collect()
+    }
+
+    /// All gateways in the same site as the given one, including itself.
+    pub(crate) fn sibling_gateways(&self, gid: GatewayId) -> Vec<GatewayId> {
+        self.gateways_by_site
+            .values()
+            .find(|gateways| gateways.iter().any(|(g, _, _)| *g == gid))
+            .into_iter()
+            .flatten()
+            .map(|(gid, _, _)| *gid)
+            .collect()
     }
 
     pub(crate) fn map_client_resource_to_gateway_resource(
@@ -214,7 +271,7 @@ impl StubPortal {
                     id: r.id,
                     address: r.address,
                     name: r.name.clone(),
-                    filters: Vec::new(),
+                    filters: self.cidr_filters.get(&r.id).cloned().unwrap_or_default(),
                 },
             ))
         });
@@ -260,6 +317,32 @@ impl StubPortal {
         Some(gid)
     }
 
+    /// Computes whether the gateway allows a packet to a CIDR resource.
+    ///
+    /// The gateway merges the filters of all CIDR resources in the site that contain the destination.
+    /// Which of those the gateway is authorized for depends on what the client connected to before.
+    /// Thus, we only return a verdict if all these resources agree.
+    pub(crate) fn gateway_filter_verdict(
+        &self,
+        rid: ResourceId,
+        dst: IpAddr,
+        protocol: Protocol,
+    ) -> Option<FilterAction> {
+        let site = self.sites_by_resource.get(&rid)?;
+
+        self.cidr_resources
+            .values()
+            .filter(|r| r.address.contains(dst))
+            .filter(|r| self.sites_by_resource.get(&r.id) == Some(site))
+            .map(|r| {
+                let filters = self.cidr_filters.get(&r.id).map(Vec::as_slice);
+
+                evaluate_filters(filters.unwrap_or_default(), dst, protocol)
+            })
+            .all_equal_value()
+            .ok()
+    }
+
     pub(crate) fn gateway_by_ip(&self, ip: IpAddr) -> Option<GatewayId> {
         self.gateways_by_site
             .values()
@@ -345,6 +428,42 @@ impl StubPortal {
     }
 }
 
+/// Reference implementation of the gateway's filter engine for a single resource.
+///
+/// Out of all filters matching the packet, the one with the longest destination prefix wins.
+/// On the same prefix, deny wins over allow.
+/// Packets that don't match any filter are denied, even if the resource only has deny filters.
+fn evaluate_filters(filters: &[gateway::Filter], dst: IpAddr, protocol: Protocol) -> FilterAction {
+    if filters.is_empty() {
+        return FilterAction::Allow;
+    }
+
+    filters
+        .iter()
+        .filter(|f| f.destination.is_none_or(|d| d.contains(dst)))
+        .filter(|f| filter_matches_protocol(f.protocol, protocol))
+        .max_by_key(|f| {
+            (
+                f.destination.map(|d| d.netmask()),
+                f.action == FilterAction::Deny,
+            )
+        })
+        .map(|f| f.action)
+        .unwrap_or(FilterAction::Deny)
+}
+
+fn filter_matches_protocol(filter: ProtocolFilter, protocol: Protocol) -> bool {
+    match (filter, protocol) {
+        (ProtocolFilter::All, _) => true,
+        (ProtocolFilter::Icmp, Protocol::IcmpEcho(_)) => true,
+        (ProtocolFilter::Udp(range), Protocol::Udp(port))
+        | (ProtocolFilter::Tcp(range), Protocol::Tcp(port)) => {
+            (range.port_range_start..=range.port_range_end).contains(&port)
+        }
+        (ProtocolFilter::Icmp | ProtocolFilter::Udp(_) | ProtocolFilter::Tcp(_), _) => false,
+    }
+}
+
 /// Generates site-specific DNS records for a particular site.
 fn site_specific_dns_records(
     dns_resources: BTreeMap<ResourceId, client::DnsResource>,
@@ -378,15 +497,30 @@ fn dns_resource_records(
     dns_resources
         .map(|resource| {
             let address = resource.address;
+            let exclusions = resource.exclusions;
 
             // Only generate simple wildcard domains for these tests.
             // The matching logic is extensively unit-tested so we don't need to cover all cases here.
             // What we do want to cover is multiple domains pointing to the same resource.
             // For example, `*.example.com` and `app.example.com`.
+            //
+            // Excluded sub-domains get records too so we actually resolve them (outside of the resource).
             match address.split_once('.') {
-                Some(("*" | "**", base)) => {
-                    subdomain_records(base.to_owned(), domain_label(), at).boxed()
-                }
+                Some(("*" | "**", base)) => (
+                    subdomain_records(base.to_owned(), domain_label(), at),
+                    collection::vec(resolved_ips(), exclusions.len()),
+                )
+                    .prop_map(move |(mut records, excluded_ips)| {
+                        for (excluded, ips) in exclusions.iter().zip(excluded_ips) {
+                            records.merge(DnsRecords::from([(
+                                excluded.parse().unwrap(),
+                                BTreeMap::from([(at, ips)]),
+                            )]));
+                        }
+
+                        records
+                    })
+                    .boxed(),
                 _ => resolved_ips()
                     .prop_map(move |resolved_ips| {
                         DnsRecords::from([(
@@ -24,7 +24,7 @@ use ip_packet::Ecn;
 use rand::SeedableRng;
 use rand::distributions::DistString;
 use sha2::Digest;
-use snownet::{NoTurnServers, Transmit};
+use snownet::{NoTurnServers, Transmit, Transport};
 use std::collections::BTreeSet;
 use std::iter;
 use std::net::SocketAddr;
@@ -60,6 +60,7 @@ impl TunnelTest {
                 ref_client.init(
                     ref_state.portal.upstream_do53().to_vec(),
                     ref_state.portal.upstream_doh().to_vec(),
+                    ref_state.portal.upstream_dot().to_vec(),
                     ref_state.portal.search_domain(),
                     flux_capacitor.now(),
                     flux_capacitor.now(),
@@ -181,11 +182,11 @@ impl TunnelTest {
                     ..resource
                 });
 
-                if let Some(gateway) = ref_state
-                    .portal
-                    .gateway_for_resource(new_resource.id())
-                    .and_then(|gid| state.gateways.get_mut(gid))
-                {
+                for gid in ref_state.portal.gateways_for_resource(new_resource.id()) {
+                    let Some(gateway) = state.gateways.get_mut(&gid) else {
+                        continue;
+                    };
+
                     gateway.exec_mut(|g| {
                         g.sut
                             .remove_access(&state.client.inner().id, &new_resource.id(), now)
@@ -205,11 +206,11 @@ impl TunnelTest {
             Transition::RemoveResource(rid) => {
                 state.client.exec_mut(|c| c.sut.remove_resource(rid, now));
 
-                if let Some(gateway) = ref_state
-                    .portal
-                    .gateway_for_resource(rid)
-                    .and_then(|gid| state.gateways.get_mut(gid))
-                {
+                for gid in ref_state.portal.gateways_for_resource(rid) {
+                    let Some(gateway) = state.gateways.get_mut(&gid) else {
+                        continue;
+                    };
+
                     gateway.exec_mut(|g| g.sut.remove_access(&state.client.inner().id, &rid, now));
                 }
             }
@@ -300,9 +301,11 @@ impl TunnelTest {
                         ipv4: c.sut.tunnel_ip_config().unwrap().v4,
                         ipv6: c.sut.tunnel_ip_config().unwrap().v6,
                         upstream_dns: vec![],
+                        conditional_forwarders: Vec::new(),
                         upstream_do53: upstream_do53.clone(),
                         search_domain: ref_state.portal.search_domain(),
                         upstream_doh: ref_state.portal.upstream_doh().to_vec(),
+                        upstream_dot: ref_state.portal.upstream_dot().to_vec(),
                     })
                 });
 
@@ -323,9 +326,25 @@ impl TunnelTest {
                         ipv4: c.sut.tunnel_ip_config().unwrap().v4,
                         ipv6: c.sut.tunnel_ip_config().unwrap().v6,
                         upstream_dns: vec![],
+                        conditional_forwarders: Vec::new(),
                         upstream_do53: ref_state.portal.upstream_do53().to_vec(),
                         search_domain: ref_state.portal.search_domain(),
                         upstream_doh,
+                        upstream_dot: ref_state.portal.upstream_dot().to_vec(),
+                    })
+                });
+            }
+            Transition::UpdateUpstreamDoTServers(upstream_dot) => {
+                state.client.exec_mut(|c| {
+                    c.sut.update_interface_config(Interface {
+                        ipv4: c.sut.tunnel_ip_config().unwrap().v4,
+                        ipv6: c.sut.tunnel_ip_config().unwrap().v6,
+                        upstream_dns: vec![],
+                        conditional_forwarders: Vec::new(),
+                        upstream_do53: ref_state.portal.upstream_do53().to_vec(),
+                        upstream_doh: ref_state.portal.upstream_doh().to_vec(),
+                        search_domain: ref_state.portal.search_domain(),
+                        upstream_dot,
                     })
                 });
             }
@@ -335,8 +354,10 @@ impl TunnelTest {
                         ipv4: c.sut.tunnel_ip_config().unwrap().v4,
                         ipv6: c.sut.tunnel_ip_config().unwrap().v6,
                         upstream_dns: vec![],
+                        conditional_forwarders: Vec::new(),
                         upstream_do53: ref_state.portal.upstream_do53().to_vec(),
                         upstream_doh: ref_state.portal.upstream_doh().to_vec(),
+                        upstream_dot: ref_state.portal.upstream_dot().to_vec(),
                         search_domain,
                     })
                 });
@@ -370,8 +391,10 @@ impl TunnelTest {
                         ipv4,
                         ipv6,
                         upstream_dns: Vec::new(),
+                        conditional_forwarders: Vec::new(),
                         upstream_do53: ref_state.portal.upstream_do53().to_vec(),
                         upstream_doh: ref_state.portal.upstream_doh().to_vec(),
+                        upstream_dot: ref_state.portal.upstream_dot().to_vec(),
                         search_domain: ref_state.portal.search_domain(),
                     });
                     c.update_relays(iter::empty(), state.relays.iter(), now);
@@ -466,8 +489,10 @@ impl TunnelTest {
                         ipv4,
                         ipv6,
                         upstream_dns: Vec::new(),
+                        conditional_forwarders: Vec::new(),
                         upstream_do53: ref_state.portal.upstream_do53().to_vec(),
                         upstream_doh: ref_state.portal.upstream_doh().to_vec(),
+                        upstream_dot: ref_state.portal.upstream_dot().to_vec(),
                         search_domain: ref_state.portal.search_domain(),
                     });
                     c.sut.update_system_resolvers(system_dns);
@@ -498,12 +523,14 @@ impl TunnelTest {
             ref_client,
             sim_client,
             &sim_gateways,
+            &ref_state.portal,
             &ref_state.global_dns_records,
         );
         assert_udp_packets_properties(
             ref_client,
             sim_client,
             &sim_gateways,
+            &ref_state.portal,
             &ref_state.global_dns_records,
         );
         assert_tcp_connections(ref_client, sim_client);
@@ -632,6 +659,7 @@ impl TunnelTest {
                                 dst,
                                 payload: self.buffer_pool.pull_initialised(&payload),
                                 ecn: Ecn::NonEct,
+                                transport: Transport::Udp,
                             },
                             relay,
                             now,
@@ -882,8 +910,11 @@ impl TunnelTest {
                 resource: resource_id,
                 preferred_gateways,
             } => {
-                let (gateway_id, site_id) =
-                    portal.handle_connection_intent(resource_id, preferred_gateways);
+                let (gateway_id, site_id) = portal.handle_connection_intent(
+                    resource_id,
+                    self.client.inner().sut.gateways_for_resource(resource_id),
+                    preferred_gateways,
+                );
                 let gateway = self.gateways.get_mut(&gateway_id).expect("unknown gateway");
                 let resource = portal.map_client_resource_to_gateway_resource(resource_id);
 
@@ -1042,7 +1073,7 @@ impl TunnelTest {
 
 enum AuthorizeFlowError {
     Client(NoTurnServers),
-    Gateway(NoTurnServers),
+    Gateway(anyhow::Error),
 }
 
 fn address_from_destination(destination: &Destination, state: &TunnelTest, src: &IpAddr) -> IpAddr {
@@ -1130,6 +1161,8 @@ fn on_gateway_event(
                     .unwrap()
             })
         }
+        GatewayEvent::FlowCompleted(_) => {}
+        GatewayEvent::DnsQueryCompleted(_) => {}
         GatewayEvent::Error(_) => unreachable!("GatewayState never emits `TunnelError`"),
     }
 }
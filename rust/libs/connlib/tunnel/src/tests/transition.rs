@@ -1,7 +1,7 @@
 use crate::{
     client::{CidrResource, IPV4_RESOURCES, IPV6_RESOURCES, Resource},
     dns,
-    messages::{UpstreamDo53, UpstreamDoH},
+    messages::{UpstreamDo53, UpstreamDoH, UpstreamDoT},
     proptest::{host_v4, host_v6},
 };
 use connlib_model::{RelayId, ResourceId, Site};
@@ -71,6 +71,8 @@ pub(crate) enum Transition {
     UpdateUpstreamDo53Servers(Vec<UpstreamDo53>),
     /// The upstream DoH servers changed.
     UpdateUpstreamDoHServers(Vec<UpstreamDoH>),
+    /// The upstream DoT servers changed.
+    UpdateUpstreamDoTServers(Vec<UpstreamDoT>),
     /// The upstream search domain changed.
     UpdateUpstreamSearchDomain(Option<DomainName>),
 
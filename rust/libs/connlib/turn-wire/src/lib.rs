@@ -0,0 +1,8 @@
+//! Wire formats that the relay and `snownet` must agree on.
+//!
+//! Both sides depend on this crate so the encoding cannot drift apart.
+
+mod relay_load;
+pub mod stream;
+
+pub use relay_load::RelayLoad;
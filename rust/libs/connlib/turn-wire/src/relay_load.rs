@@ -0,0 +1,134 @@
+use bytecodec::bytes::{BytesEncoder, CopyableBytesDecoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use stun_codec::{Attribute, AttributeType};
+
+/// Firezone-specific STUN attribute in which relays advertise their current load.
+///
+/// Relays attach this to all BINDING and ALLOCATE success responses so clients can prefer relays with spare capacity for new connections.
+/// The codepoint is in the comprehension-optional range, meaning clients that don't know this attribute simply ignore it.
+/// Older relays don't send it, in which case we don't know anything about their load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RelayLoad {
+    /// The number of active allocations.
+    pub allocations: u32,
+    /// The maximum number of allocations, i.e. the size of the port range.
+    pub capacity: u32,
+    /// The number of bytes per second relayed in userspace.
+    pub relayed_bytes_per_sec: u64,
+}
+
+impl RelayLoad {
+    pub const CODEPOINT: u16 = 0xFF01;
+
+    /// The fraction of the relay's capacity that is in use, between 0 and 1.
+    pub fn utilisation(&self) -> f64 {
+        if self.capacity == 0 {
+            return 1.0;
+        }
+
+        (f64::from(self.allocations) / f64::from(self.capacity)).min(1.0)
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let value = (u128::from(self.allocations) << 96)
+            | (u128::from(self.capacity) << 64)
+            | u128::from(self.relayed_bytes_per_sec);
+
+        value.to_be_bytes()
+    }
+
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        let value = u128::from_be_bytes(bytes);
+
+        Self {
+            allocations: (value >> 96) as u32,
+            capacity: (value >> 64) as u32,
+            relayed_bytes_per_sec: value as u64,
+        }
+    }
+}
+
+impl Attribute for RelayLoad {
+    type Decoder = RelayLoadDecoder;
+    type Encoder = RelayLoadEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RelayLoadDecoder(CopyableBytesDecoder<[u8; 16]>);
+
+impl Decode for RelayLoadDecoder {
+    type Item = RelayLoad;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(RelayLoad::from_bytes)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for RelayLoadDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attr_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attr_type.as_u16() == RelayLoad::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RelayLoadEncoder(BytesEncoder<[u8; 16]>);
+
+impl Encode for RelayLoadEncoder {
+    type Item = RelayLoad;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.to_bytes())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for RelayLoadEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_roundtrip() {
+        let load = RelayLoad {
+            allocations: 10,
+            capacity: 16384,
+            relayed_bytes_per_sec: 1_000_000_000,
+        };
+
+        assert_eq!(RelayLoad::from_bytes(load.to_bytes()), load);
+    }
+}
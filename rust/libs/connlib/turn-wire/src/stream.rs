@@ -0,0 +1,85 @@
+//! Framing of STUN and channel-data messages on stream-based transports (TCP and TLS).
+//!
+//! STUN messages and channel-data messages can be told apart by their first two bits.
+//! Both carry their length in bytes 2..4, which allows us to find the message boundaries within the stream.
+//! Over TCP and TLS, channel-data messages are padded to a multiple of 4 bytes, see <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+//! STUN messages are always a multiple of 4 bytes long.
+
+/// The port on which relays accept TURN over TLS.
+pub const RELAY_TLS_PORT: u16 = 443;
+
+const HEADER_LEN: usize = 4;
+const STUN_HEADER_LEN: usize = 20;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Stream contains neither a STUN nor a channel-data message")]
+pub struct InvalidFrame;
+
+/// Encodes a STUN or channel-data message as a frame to be written to a stream.
+pub fn encode(message: &[u8]) -> Vec<u8> {
+    let padded_len = message.len().next_multiple_of(4);
+
+    let mut frame = Vec::with_capacity(padded_len);
+    frame.extend_from_slice(message);
+    frame.resize(padded_len, 0);
+
+    frame
+}
+
+/// Decodes the next frame from the given buffer.
+///
+/// Returns the message (without any padding) and the number of bytes to consume from the buffer.
+/// Returns [`None`] if the buffer does not yet contain a complete frame.
+pub fn decode(buffer: &[u8]) -> Result<Option<(&[u8], usize)>, InvalidFrame> {
+    let Some(header) = buffer.get(..HEADER_LEN) else {
+        return Ok(None);
+    };
+
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    let message_len = match header[0] >> 6 {
+        0b00 => STUN_HEADER_LEN + length,
+        0b01 => HEADER_LEN + length,
+        _ => return Err(InvalidFrame),
+    };
+    let frame_len = message_len.next_multiple_of(4);
+
+    if buffer.len() < frame_len {
+        return Ok(None);
+    }
+
+    Ok(Some((&buffer[..message_len], frame_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_data_is_padded_to_multiple_of_4() {
+        let message = [0x40, 0x00, 0x00, 0x03, 1, 2, 3];
+
+        let frame = encode(&message);
+
+        assert_eq!(frame, [0x40, 0x00, 0x00, 0x03, 1, 2, 3, 0]);
+        assert_eq!(decode(&frame), Ok(Some((message.as_slice(), 8))));
+    }
+
+    #[test]
+    fn decodes_stun_message_followed_by_partial_frame() {
+        let mut buffer = vec![0x00, 0x01, 0x00, 0x00];
+        buffer.extend_from_slice(&[0; 16]);
+        buffer.extend_from_slice(&[0x40, 0x00, 0x00, 0x08, 1, 2]);
+
+        let (message, consumed) = decode(&buffer).unwrap().unwrap();
+
+        assert_eq!(message.len(), 20);
+        assert_eq!(consumed, 20);
+        assert_eq!(decode(&buffer[consumed..]), Ok(None));
+    }
+
+    #[test]
+    fn rejects_unknown_message_type() {
+        assert_eq!(decode(&[0x80, 0x00, 0x00, 0x00]), Err(InvalidFrame));
+    }
+}
@@ -0,0 +1,159 @@
+//! The local control API of the headless Client.
+//!
+//! The headless Client listens on a Unix domain socket and speaks newline-delimited JSON:
+//! Every line written to the socket is a [`Request`] that is answered with exactly one line containing a [`Response`].
+//!
+//! For example:
+//!
+//! ```text
+//! > {"command":"set_internet_resource_state","active":true}
+//! < {"type":"ok"}
+//! ```
+
+#![cfg_attr(test, allow(clippy::unwrap_used))]
+
+use anyhow::{Context as _, Result};
+use connlib_model::{GatewayView, ResourceView};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+/// Where the headless Client listens for control connections by default.
+///
+/// This lives in the same runtime directory as the IPC socket of the Tunnel service.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/dev.firezone.client/headless.sock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Query the overall status, including our connections to Gateways.
+    Status,
+    /// Query the resources we have access to.
+    Resources,
+    /// Activate or deactivate the Internet Resource.
+    SetInternetResourceState { active: bool },
+    /// Reconnect to the portal and re-establish all connections.
+    Reset,
+    /// Replace the current log filter, e.g. `info,connlib=debug`.
+    ApplyLogFilter { directives: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Status(Status),
+    Resources { resources: Vec<ResourceView> },
+    Ok,
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    /// The version of the headless Client.
+    pub version: String,
+    /// Whether the TUN device has been configured and the tunnel is ready to use.
+    pub tunnel_ready: bool,
+    pub gateways: Vec<GatewayView>,
+}
+
+/// Encodes a message as a single line of JSON.
+pub fn encode<T>(message: &T) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    let mut line = serde_json::to_vec(message).context("Failed to serialize message")?;
+    line.push(b'\n');
+
+    Ok(line)
+}
+
+/// Decodes a single line of JSON.
+pub fn decode<T>(line: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str(line.trim_end()).context("Failed to deserialize message")
+}
+
+/// Sends a single request to the headless Client listening on `path` and waits for its response.
+#[cfg(unix)]
+pub fn request(path: &std::path::Path, request: &Request) -> Result<Response> {
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to `{}`", path.display()))?;
+
+    stream
+        .write_all(&encode(request)?)
+        .context("Failed to send request")?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context("Failed to read response")?;
+
+    anyhow::ensure!(!line.is_empty(), "Connection closed without a response");
+
+    decode(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_wire_format() {
+        assert_eq!(
+            encode(&Request::SetInternetResourceState { active: true }).unwrap(),
+            b"{\"command\":\"set_internet_resource_state\",\"active\":true}\n"
+        );
+        assert_eq!(
+            decode::<Request>("{\"command\":\"status\"}\n").unwrap(),
+            Request::Status
+        );
+        assert!(decode::<Request>("{\"command\":\"shutdown\"}").is_err());
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let response = Response::Status(Status {
+            version: "1.5.7".to_owned(),
+            tunnel_ready: true,
+            gateways: vec![GatewayView {
+                id: connlib_model::GatewayId::from_u128(1),
+                status: connlib_model::GatewayStatus::Connected,
+                relayed: false,
+                resources: vec![connlib_model::ResourceId::from_u128(2)],
+            }],
+        });
+
+        let line = String::from_utf8(encode(&response).unwrap()).unwrap();
+
+        assert_eq!(decode::<Response>(&line).unwrap(), response);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn request_over_unix_socket() {
+        use std::io::{BufRead as _, BufReader, Write as _};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("headless.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+
+            assert_eq!(decode::<Request>(&line).unwrap(), Request::Reset);
+
+            (&stream)
+                .write_all(&encode(&Response::Ok).unwrap())
+                .unwrap();
+        });
+
+        assert_eq!(request(&path, &Request::Reset).unwrap(), Response::Ok);
+
+        server.join().unwrap();
+    }
+}
@@ -43,6 +43,10 @@ pub mod attr {
         KeyValue::new("network.transport", "udp")
     }
 
+    pub fn network_transport_tcp() -> KeyValue {
+        KeyValue::new("network.transport", "tcp")
+    }
+
     pub fn network_type_for_packet(p: &IpPacket) -> KeyValue {
         match p.version() {
             IpVersion::V4 => network_type_ipv4(),
@@ -39,7 +39,8 @@ pub fn handle_turn(ctx: aya_ebpf::programs::XdpContext) -> u32 {
             xdp_action::XDP_PASS
         }
         // In a double symmetric NAT setup, it is easily possible for packets to arrive from IPs that don't have channel bindings.
-        Err(e @ Error::NoEntry(_)) => {
+        // Exhausted budgets are expected for misbehaving clients and counted separately.
+        Err(e @ (Error::NoEntry(_) | Error::BudgetExhausted)) => {
             debug!(&ctx,target: "eBPF", "XXX drop packet: {}", e.as_str());
 
             xdp_action::XDP_DROP
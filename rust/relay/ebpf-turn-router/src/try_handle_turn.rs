@@ -1,4 +1,5 @@
 mod adjust_head;
+mod budget;
 mod channel_data;
 mod checksum;
 mod config;
@@ -18,6 +19,7 @@ pub use error::Error;
 
 use aya_ebpf::programs::XdpContext;
 use aya_log_ebpf::*;
+use budget::Channel;
 use channel_data::CdHdr;
 use ebpf_shared::{
     ClientAndChannel, ClientAndChannelV4, ClientAndChannelV6, PortAndPeer, PortAndPeerV4,
@@ -46,18 +48,20 @@ pub fn try_handle_turn(ctx: &XdpContext) -> Result<(), Error> {
     // SAFETY: The offset must point to the start of a valid `EthHdr`.
     let eth = unsafe { ref_mut_at::<EthHdr>(ctx, 0)? };
 
-    let num_bytes = match eth.ether_type() {
+    let (channel, num_bytes) = match eth.ether_type() {
         Ok(EtherType::Ipv4) => try_handle_turn_ipv4(ctx)?,
         Ok(EtherType::Ipv6) => try_handle_turn_ipv6(ctx)?,
         _ => return Err(Error::NotIp),
     };
+    budget::consume(channel, num_bytes)?;
     stats::emit_data_relayed(ctx, num_bytes);
 
     Ok(())
 }
 
+/// Returns the channel and the number of relayed bytes.
 #[inline(always)]
-fn try_handle_turn_ipv4(ctx: &XdpContext) -> Result<u16, Error> {
+fn try_handle_turn_ipv4(ctx: &XdpContext) -> Result<(Channel, u16), Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv4Hdr`.
     let ipv4 = unsafe { ref_mut_at::<Ipv4Hdr>(ctx, EthHdr::LEN)? };
 
@@ -80,22 +84,23 @@ fn try_handle_turn_ipv4(ctx: &XdpContext) -> Result<u16, Error> {
     }
 
     if (LOWER_PORT..=UPPER_PORT).contains(&udp.dst_port()) {
-        try_handle_from_ipv4_udp(ctx)?;
+        let channel = try_handle_from_ipv4_udp(ctx)?;
 
-        return Ok(udp_payload_len);
+        return Ok((channel, udp_payload_len));
     }
 
     if udp.dst_port() == 3478 {
-        try_handle_from_ipv4_channel_data(ctx)?;
+        let channel = try_handle_from_ipv4_channel_data(ctx)?;
 
-        return Ok(udp_payload_len - CdHdr::LEN as u16);
+        return Ok((channel, udp_payload_len - CdHdr::LEN as u16));
     }
 
     Err(Error::NotTurn)
 }
 
+/// Returns the channel and the number of relayed bytes.
 #[inline(always)]
-fn try_handle_turn_ipv6(ctx: &XdpContext) -> Result<u16, Error> {
+fn try_handle_turn_ipv6(ctx: &XdpContext) -> Result<(Channel, u16), Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv6Hdr`.
     let ipv6 = unsafe { ref_mut_at::<Ipv6Hdr>(ctx, EthHdr::LEN)? };
 
@@ -113,22 +118,23 @@ fn try_handle_turn_ipv6(ctx: &XdpContext) -> Result<u16, Error> {
     }
 
     if (LOWER_PORT..=UPPER_PORT).contains(&udp.dst_port()) {
-        try_handle_from_ipv6_udp(ctx)?;
+        let channel = try_handle_from_ipv6_udp(ctx)?;
 
-        return Ok(udp_payload_len);
+        return Ok((channel, udp_payload_len));
     }
 
     if udp.dst_port() == 3478 {
-        try_handle_from_ipv6_channel_data(ctx)?;
+        let channel = try_handle_from_ipv6_channel_data(ctx)?;
 
-        return Ok(udp_payload_len - CdHdr::LEN as u16);
+        return Ok((channel, udp_payload_len - CdHdr::LEN as u16));
     }
 
     Err(Error::NotTurn)
 }
 
+/// Returns the channel we relayed the packet on.
 #[inline(always)]
-fn try_handle_from_ipv4_udp(ctx: &XdpContext) -> Result<(), Error> {
+fn try_handle_from_ipv4_udp(ctx: &XdpContext) -> Result<Channel, Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv4Hdr`.
     let ipv4 = unsafe { ref_mut_at::<Ipv4Hdr>(ctx, EthHdr::LEN)? };
 
@@ -158,16 +164,19 @@ fn try_handle_from_ipv4_udp(ctx: &XdpContext) -> Result<(), Error> {
         cc.channel()
     );
 
+    let channel = Channel::new(pp.allocation_port(), cc.channel());
+
     match cc {
         ClientAndChannel::V4(cc) => from_ipv4_udp::to_ipv4_channel(ctx, &cc)?,
         ClientAndChannel::V6(cc) => from_ipv4_udp::to_ipv6_channel(ctx, &cc)?,
     }
 
-    Ok(())
+    Ok(channel)
 }
 
+/// Returns the channel we relayed the packet on.
 #[inline(always)]
-fn try_handle_from_ipv4_channel_data(ctx: &XdpContext) -> Result<(), Error> {
+fn try_handle_from_ipv4_channel_data(ctx: &XdpContext) -> Result<Channel, Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv4Hdr`.
     let ipv4 = unsafe { ref_mut_at::<Ipv4Hdr>(ctx, EthHdr::LEN)? };
 
@@ -214,6 +223,8 @@ fn try_handle_from_ipv4_channel_data(ctx: &XdpContext) -> Result<(), Error> {
         pp.allocation_port(),
     );
 
+    let channel = Channel::new(pp.allocation_port(), channel_number);
+
     if is_own_public_ip(pp.peer_ip())? {
         let pp = pp.flip_ports();
         let cc = routing::get_client_and_channel(pp)?;
@@ -232,7 +243,7 @@ fn try_handle_from_ipv4_channel_data(ctx: &XdpContext) -> Result<(), Error> {
             ClientAndChannel::V6(cc) => from_ipv4_channel::to_ipv6_channel(ctx, &cc)?,
         }
 
-        return Ok(());
+        return Ok(channel);
     }
 
     match pp {
@@ -240,11 +251,12 @@ fn try_handle_from_ipv4_channel_data(ctx: &XdpContext) -> Result<(), Error> {
         PortAndPeer::V6(pp) => from_ipv4_channel::to_ipv6_udp(ctx, &pp)?,
     }
 
-    Ok(())
+    Ok(channel)
 }
 
+/// Returns the channel we relayed the packet on.
 #[inline(always)]
-fn try_handle_from_ipv6_udp(ctx: &XdpContext) -> Result<(), Error> {
+fn try_handle_from_ipv6_udp(ctx: &XdpContext) -> Result<Channel, Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv6Hdr`.
     let ipv6 = unsafe { ref_mut_at::<Ipv6Hdr>(ctx, EthHdr::LEN)? };
 
@@ -274,16 +286,19 @@ fn try_handle_from_ipv6_udp(ctx: &XdpContext) -> Result<(), Error> {
         cc.channel()
     );
 
+    let channel = Channel::new(pp.allocation_port(), cc.channel());
+
     match cc {
         ClientAndChannel::V4(cc) => from_ipv6_udp::to_ipv4_channel(ctx, &cc)?,
         ClientAndChannel::V6(cc) => from_ipv6_udp::to_ipv6_channel(ctx, &cc)?,
     }
 
-    Ok(())
+    Ok(channel)
 }
 
+/// Returns the channel we relayed the packet on.
 #[inline(always)]
-fn try_handle_from_ipv6_channel_data(ctx: &XdpContext) -> Result<(), Error> {
+fn try_handle_from_ipv6_channel_data(ctx: &XdpContext) -> Result<Channel, Error> {
     // SAFETY: The offset must point to the start of a valid `Ipv6Hdr`.
     let ipv6 = unsafe { ref_mut_at::<Ipv6Hdr>(ctx, EthHdr::LEN)? };
 
@@ -330,6 +345,8 @@ fn try_handle_from_ipv6_channel_data(ctx: &XdpContext) -> Result<(), Error> {
         pp.allocation_port(),
     );
 
+    let channel = Channel::new(pp.allocation_port(), channel_number);
+
     if is_own_public_ip(pp.peer_ip())? {
         let pp = pp.flip_ports();
         let cc = routing::get_client_and_channel(pp)?;
@@ -348,7 +365,7 @@ fn try_handle_from_ipv6_channel_data(ctx: &XdpContext) -> Result<(), Error> {
             ClientAndChannel::V6(cc) => from_ipv6_channel::to_ipv6_channel(ctx, &cc)?,
         }
 
-        return Ok(());
+        return Ok(channel);
     }
 
     match pp {
@@ -356,7 +373,7 @@ fn try_handle_from_ipv6_channel_data(ctx: &XdpContext) -> Result<(), Error> {
         PortAndPeer::V6(pp) => from_ipv6_channel::to_ipv6_udp(ctx, &pp)?,
     }
 
-    Ok(())
+    Ok(channel)
 }
 
 #[inline(always)]
@@ -0,0 +1,73 @@
+//! Per-channel byte budgets for enforcing the relay's rate limits and quotas.
+//!
+//! Userspace periodically refills the budget of each channel of a limited allocation, keyed by the allocation's port and the channel number in big-endian order.
+//! Channels without an entry are unlimited.
+
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use aya_ebpf::{
+    macros::map,
+    maps::{HashMap, PerCpuArray},
+};
+
+use crate::try_handle_turn::Error;
+
+const NUM_ENTRIES: u32 = 0x10000;
+
+#[map]
+static CHANNEL_BUDGETS: HashMap<[u8; 4], i64> = HashMap::with_max_entries(NUM_ENTRIES, 0);
+
+/// The number of packets we dropped because their channel's budget was exhausted.
+#[map]
+static BUDGET_EXHAUSTED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+/// A channel of an allocation.
+#[derive(Clone, Copy)]
+pub struct Channel {
+    allocation_port: u16,
+    number: u16,
+}
+
+impl Channel {
+    #[inline(always)]
+    pub fn new(allocation_port: u16, number: u16) -> Self {
+        Self {
+            allocation_port,
+            number,
+        }
+    }
+
+    #[inline(always)]
+    fn key(&self) -> [u8; 4] {
+        let [p1, p2] = self.allocation_port.to_be_bytes();
+        let [c1, c2] = self.number.to_be_bytes();
+
+        [p1, p2, c1, c2]
+    }
+}
+
+#[inline(always)]
+pub fn consume(channel: Channel, num_bytes: u16) -> Result<(), Error> {
+    let Some(budget) = CHANNEL_BUDGETS.get_ptr_mut(&channel.key()) else {
+        return Ok(());
+    };
+
+    // SAFETY: The pointer is valid and aligned because it points into the map.
+    let budget = unsafe { AtomicI64::from_ptr(budget) };
+    let num_bytes = i64::from(num_bytes);
+
+    // Checking and subtracting is not atomic: Concurrent packets on other CPUs may push the budget below zero.
+    // Userspace accounts for that on the next refill.
+    if budget.load(Ordering::Relaxed) < num_bytes {
+        if let Some(counter) = BUDGET_EXHAUSTED.get_ptr_mut(0) {
+            // SAFETY: The pointer is valid and aligned because it points into the map.
+            unsafe { AtomicU64::from_ptr(counter) }.fetch_add(1, Ordering::Relaxed);
+        }
+
+        return Err(Error::BudgetExhausted);
+    }
+
+    budget.fetch_sub(num_bytes, Ordering::Relaxed);
+
+    Ok(())
+}
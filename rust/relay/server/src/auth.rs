@@ -18,6 +18,9 @@
 //! As such, a TURN client can never create a set of credentials themselves because they are missing the `relay_secret`.
 //! In addition, a relay can validate such a username and password combination without having to store any state other than the `relay_secret`.
 //!
+//! In standalone mode, there is no portal.
+//! Instead, the `relay_secret` is loaded from a file or the environment and credentials are minted by hand using [`generate_credentials`].
+//!
 //! All STUN messages other than `BINDING` requests MUST be authenticated by the client.
 //!
 //! ## Server authentication
@@ -237,6 +240,23 @@ pub fn generate_password(relay_secret: &SecretString, expiry: u64, username_salt
     BASE64_STANDARD_NO_PAD.encode(array.as_slice())
 }
 
+/// Mints a username and password that are valid until `expiry`.
+pub fn generate_credentials(
+    relay_secret: &SecretString,
+    expiry: SystemTime,
+    username_salt: &str,
+) -> (String, String) {
+    let expiry = expiry
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let username = format!("{expiry}:{username_salt}");
+    let password = generate_password(relay_secret, expiry, username_salt);
+
+    (username, password)
+}
+
 pub(crate) fn systemtime_from_unix(seconds: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
 }
@@ -286,6 +306,32 @@ mod tests {
         result.expect("credentials to be valid");
     }
 
+    #[test]
+    fn generated_credentials_are_valid() {
+        let (username, password) = generate_credentials(
+            &RELAY_SECRET_1.into(),
+            systemtime_from_unix(1685200000),
+            SAMPLE_USERNAME,
+        );
+
+        let message_integrity = MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.clone()).unwrap(),
+            &FIREZONE,
+            &password,
+        )
+        .unwrap();
+
+        let result = message_integrity.verify(
+            &RELAY_SECRET_1.into(),
+            &username,
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert_eq!(username, "1685200000:n23JJ2wKKtt30oXi");
+        result.expect("credentials to be valid");
+    }
+
     #[test]
     fn expired_is_not_valid() {
         let message_integrity = message_integrity(
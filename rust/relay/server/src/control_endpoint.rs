@@ -1,22 +1,58 @@
-use axum::Router;
-use axum::extract::{Query, State};
-use axum::http::StatusCode;
-use axum::routing::post;
+use crate::{AllocationPort, AllocationStats};
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
 use logging::FilterReloadHandle;
+use std::fmt::Write as _;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
 
-/// Runs an HTTP server that responds to `POST /log_filter?directives=` and sets the given directives as the new log-filter.
+/// A request from the control endpoint to the event-loop owning the [`Server`](crate::Server).
+#[derive(Debug)]
+pub enum Request {
+    Allocations(oneshot::Sender<Vec<AllocationStats>>),
+    Stats(oneshot::Sender<Stats>),
+    RevokeAllocation(AllocationPort, oneshot::Sender<bool>),
+    Drain(oneshot::Sender<()>),
+}
+
+/// Mirrors the counters exposed by the [`Server`](crate::Server).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Stats {
+    pub num_allocations: usize,
+    pub num_active_channels: usize,
+    pub num_relayed_bytes: u64,
+    pub num_limit_hits: u64,
+}
+
+/// Runs an HTTP server for introspecting and controlling the relay.
+///
+/// - `POST /log_filter?directives=` sets the given directives as the new log-filter.
+/// - `GET /allocations` lists all current allocations.
+/// - `GET /stats` returns the aggregated [`Stats`].
+/// - `GET /metrics` returns the [`Stats`] in the Prometheus text format.
+/// - `POST /allocations/{port}/revoke` deletes the allocation on the given port.
+/// - `POST /drain` stops accepting new allocations and shuts down once the existing ones are gone.
 pub async fn serve(
     addr: impl Into<SocketAddr>,
     filter_reload_handle: FilterReloadHandle,
+    server: mpsc::Sender<Request>,
 ) -> std::io::Result<()> {
     let addr = addr.into();
 
     let service = Router::new()
         .route("/log_filter", post(set_log_filter))
+        .route("/allocations", get(allocations))
+        .route("/allocations/{port}/revoke", post(revoke_allocation))
+        .route("/drain", post(drain))
+        .route("/stats", get(stats))
+        .route("/metrics", get(metrics))
         .with_state(AppState {
             handle: Arc::new(filter_reload_handle),
+            server,
         })
         .into_make_service();
 
@@ -42,12 +78,162 @@ async fn set_log_filter(Query(params): Query<QueryParams>, state: State<AppState
     }
 }
 
+async fn allocations(state: State<AppState>) -> Result<Json<Vec<Allocation>>, StatusCode> {
+    let allocations = state.request(Request::Allocations).await?;
+
+    Ok(Json(
+        allocations.into_iter().map(Allocation::from).collect(),
+    ))
+}
+
+async fn stats(state: State<AppState>) -> Result<Json<Stats>, StatusCode> {
+    let stats = state.request(Request::Stats).await?;
+
+    Ok(Json(stats))
+}
+
+async fn metrics(state: State<AppState>) -> Result<impl IntoResponse, StatusCode> {
+    let stats = state.request(Request::Stats).await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        prometheus_text(&stats),
+    ))
+}
+
+async fn revoke_allocation(Path(port): Path<u16>, state: State<AppState>) -> StatusCode {
+    let port = AllocationPort::new(port);
+
+    match state
+        .request(|tx| Request::RevokeAllocation(port, tx))
+        .await
+    {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(code) => code,
+    }
+}
+
+async fn drain(state: State<AppState>) -> StatusCode {
+    match state.request(Request::Drain).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(code) => code,
+    }
+}
+
+fn prometheus_text(stats: &Stats) -> String {
+    let mut text = String::new();
+
+    for (name, kind, help, value) in [
+        (
+            "relay_allocations",
+            "gauge",
+            "The number of active allocations",
+            stats.num_allocations as u64,
+        ),
+        (
+            "relay_active_channels",
+            "gauge",
+            "The number of bound channels",
+            stats.num_active_channels as u64,
+        ),
+        (
+            "relay_data_relayed_userspace_bytes_total",
+            "counter",
+            "The number of bytes relayed in userspace",
+            stats.num_relayed_bytes,
+        ),
+        (
+            "relay_limit_hits_total",
+            "counter",
+            "The number of packets dropped because a rate limit or quota was exceeded",
+            stats.num_limit_hits,
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        let _ = writeln!(text, "{name} {value}");
+    }
+
+    text
+}
+
 #[derive(Clone)]
 struct AppState {
     handle: Arc<FilterReloadHandle>,
+    server: mpsc::Sender<Request>,
+}
+
+impl AppState {
+    /// Sends a request to the event-loop and waits for its response.
+    async fn request<T>(
+        &self,
+        make_request: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> Result<T, StatusCode> {
+        let (tx, rx) = oneshot::channel();
+
+        self.server
+            .send(make_request(tx))
+            .await
+            .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+        rx.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct QueryParams {
     directives: String,
 }
+
+#[derive(serde::Serialize)]
+struct Allocation {
+    port: u16,
+    client: SocketAddr,
+    lifetime_remaining_secs: u64,
+    num_active_channels: usize,
+    num_relayed_bytes: u64,
+}
+
+impl From<AllocationStats> for Allocation {
+    fn from(stats: AllocationStats) -> Self {
+        Self {
+            port: stats.port.value(),
+            client: stats.client.into_socket(),
+            lifetime_remaining_secs: stats.expires_in.as_secs(),
+            num_active_channels: stats.num_active_channels,
+            num_relayed_bytes: stats.num_relayed_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let text = prometheus_text(&Stats {
+            num_allocations: 3,
+            num_active_channels: 5,
+            num_relayed_bytes: 1024,
+            num_limit_hits: 7,
+        });
+
+        assert_eq!(
+            text,
+            "# HELP relay_allocations The number of active allocations\n\
+             # TYPE relay_allocations gauge\n\
+             relay_allocations 3\n\
+             # HELP relay_active_channels The number of bound channels\n\
+             # TYPE relay_active_channels gauge\n\
+             relay_active_channels 5\n\
+             # HELP relay_data_relayed_userspace_bytes_total The number of bytes relayed in userspace\n\
+             # TYPE relay_data_relayed_userspace_bytes_total counter\n\
+             relay_data_relayed_userspace_bytes_total 1024\n\
+             # HELP relay_limit_hits_total The number of packets dropped because a rate limit or quota was exceeded\n\
+             # TYPE relay_limit_hits_total counter\n\
+             relay_limit_hits_total 7\n"
+        );
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 use anyhow::{Context as _, Result};
 use aya::{
     Pod,
-    maps::{HashMap, MapData, PerCpuArray, PerCpuValues, PerfEventArray},
+    maps::{HashMap, MapData, MapError, PerCpuArray, PerCpuValues, PerfEventArray},
     programs::{Xdp, XdpFlags},
 };
 use aya_log::EbpfLogger;
@@ -276,6 +276,68 @@ impl Program {
         Ok(())
     }
 
+    /// Returns what is left of the budget of the given channel, [`None`] if it doesn't have one.
+    pub fn channel_budget(
+        &mut self,
+        port: AllocationPort,
+        channel: ChannelNumber,
+    ) -> Result<Option<i64>> {
+        match self
+            .channel_budgets_map_mut()?
+            .get(&channel_budget_key(port, channel), 0)
+        {
+            Ok(budget) => Ok(Some(budget)),
+            Err(MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_channel_budget(
+        &mut self,
+        port: AllocationPort,
+        channel: ChannelNumber,
+        budget: u64,
+    ) -> Result<()> {
+        self.channel_budgets_map_mut()?.insert(
+            channel_budget_key(port, channel),
+            i64::try_from(budget).unwrap_or(i64::MAX),
+            0,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_channel_budget(
+        &mut self,
+        port: AllocationPort,
+        channel: ChannelNumber,
+    ) -> Result<()> {
+        match self
+            .channel_budgets_map_mut()?
+            .remove(&channel_budget_key(port, channel))
+        {
+            Ok(()) | Err(MapError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The total number of packets the eBPF kernel dropped because their allocation exhausted its budget.
+    pub fn num_budget_exhausted(&mut self) -> Result<u64> {
+        let map = self
+            .ebpf
+            .map_mut("BUDGET_EXHAUSTED")
+            .context("Map `BUDGET_EXHAUSTED` not found")?;
+        let values = PerCpuArray::<_, u64>::try_from(map)
+            .context("Failed to convert map")?
+            .get(&0, 0)?;
+
+        Ok(values.iter().sum())
+    }
+
+    fn channel_budgets_map_mut(&mut self) -> Result<HashMap<&mut MapData, [u8; 4], i64>> {
+        self.hash_map_mut("CHANNEL_BUDGETS")
+    }
+
     fn chan_to_udp_44_map_mut(
         &mut self,
     ) -> Result<HashMap<&mut MapData, ClientAndChannelV4, PortAndPeerV4>> {
@@ -339,6 +401,14 @@ impl Program {
     }
 }
 
+/// The key of a channel in the `CHANNEL_BUDGETS` map: The allocation port and channel number, both in big-endian order.
+fn channel_budget_key(port: AllocationPort, channel: ChannelNumber) -> [u8; 4] {
+    let [p1, p2] = port.value().to_be_bytes();
+    let [c1, c2] = channel.value().to_be_bytes();
+
+    [p1, p2, c1, c2]
+}
+
 fn set_interface_ipv4_address(ebpf: &mut aya::Ebpf, addr: Ipv4Addr) -> Result<()> {
     set_per_cpu_map(ebpf, "INT_ADDR_V4", addr.octets())
         .context("Failed to set IPv4 interface address")?;
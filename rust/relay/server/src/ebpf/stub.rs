@@ -43,4 +43,25 @@ impl Program {
     ) -> Result<()> {
         Ok(())
     }
+
+    pub fn channel_budget(&mut self, _: AllocationPort, _: ChannelNumber) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    pub fn set_channel_budget(
+        &mut self,
+        _: AllocationPort,
+        _: ChannelNumber,
+        _: u64,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn remove_channel_budget(&mut self, _: AllocationPort, _: ChannelNumber) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn num_budget_exhausted(&mut self) -> Result<u64> {
+        Ok(0)
+    }
 }
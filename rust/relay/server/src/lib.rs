@@ -11,11 +11,12 @@ pub mod ebpf;
 #[allow(clippy::unwrap_used)]
 pub mod proptest;
 pub mod sockets;
+pub mod streams;
 
 pub use net_ext::IpAddrExt;
 pub use server::{
-    Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Allocate, AllocationPort, AllocationStats, Attribute, Binding, ChannelBind, ChannelData,
+    ClientMessage, Command, CreatePermission, Limit, Limits, Refresh, RelayLoad, Server,
 };
 pub use sleep::Sleep;
 use stun_codec::rfc5389::attributes::Software;
@@ -5,9 +5,10 @@ use backoff::ExponentialBackoffBuilder;
 use bin_shared::{http_health_check, signals};
 use clap::Parser;
 use firezone_relay::sockets::Sockets;
+use firezone_relay::streams::{self, Streams};
 use firezone_relay::{
-    AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack, PeerSocket, Server,
-    Sleep, VERSION, control_endpoint, ebpf, sockets,
+    AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack, Limit, Limits,
+    PeerSocket, Server, Sleep, VERSION, control_endpoint, ebpf, sockets,
 };
 use futures::{FutureExt, future};
 use logging::{FilterReloadHandle, err_with_src, sentry_layer};
@@ -16,12 +17,14 @@ use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{ExposeSecret, SecretString};
 use std::borrow::Cow;
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Poll, ready};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use stun_codec::rfc5766::attributes::ChannelNumber;
 use telemetry::{RELAY_DSN, Telemetry};
 use tokio::sync::mpsc;
@@ -33,10 +36,16 @@ use url::Url;
 
 const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
+/// How often we refill the budgets of limited allocations in the eBPF kernel.
+const OFFLOAD_BUDGET_INTERVAL: Duration = Duration::from_millis(100);
+
 const MAX_PARTITION_TIME: Duration = Duration::from_secs(60 * 60 * 24); // 24 hours
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
     #[arg(long, env)]
     public_ip4_addr: Option<Ipv4Addr>,
@@ -60,10 +69,17 @@ struct Args {
     #[arg(long, env, hide = true, default_value = "65535")]
     highest_port: u16,
     #[arg(long, env = "FIREZONE_API_URL")]
-    api_url: Url,
+    api_url: Option<Url>,
     /// Token generated by the portal to authorize websocket connection.
     #[arg(env = "FIREZONE_TOKEN")]
-    token: SecretString,
+    token: Option<SecretString>,
+    /// Run without connecting to the portal.
+    ///
+    /// Clients authenticate with credentials derived from a static secret, see the `credentials` subcommand.
+    #[arg(long, env = "FIREZONE_STANDALONE", default_value_t = false)]
+    standalone: bool,
+    #[command(flatten)]
+    auth: AuthSecretArgs,
     /// Used as the human name for this Relay to display in the portal. If not provided,
     /// the system hostname is used by default.
     #[arg(env = "FIREZONE_NAME")]
@@ -114,15 +130,121 @@ struct Args {
     #[command(flatten)]
     health_check: http_health_check::HealthCheckArgs,
 
+    /// The maximum sustained rate in bytes per second that can be relayed per allocation.
+    #[arg(long, env)]
+    allocation_rate_limit: Option<u64>,
+
+    /// The maximum burst in bytes that can be relayed per allocation.
+    ///
+    /// Defaults to one second worth of the allocation rate limit.
+    #[arg(long, env)]
+    allocation_burst: Option<u64>,
+
+    /// The maximum number of bytes that can be relayed per allocation.
+    #[arg(long, env)]
+    allocation_quota: Option<u64>,
+
+    /// The maximum sustained rate in bytes per second that can be relayed for all allocations of a username.
+    #[arg(long, env)]
+    username_rate_limit: Option<u64>,
+
+    /// The maximum burst in bytes that can be relayed for all allocations of a username.
+    ///
+    /// Defaults to one second worth of the username rate limit.
+    #[arg(long, env)]
+    username_burst: Option<u64>,
+
+    /// The maximum number of bytes that can be relayed for all allocations of a username.
+    #[arg(long, env)]
+    username_quota: Option<u64>,
+
+    /// How often the allocation and username quotas reset.
+    ///
+    /// If unset, quotas never reset.
+    #[arg(long, env)]
+    quota_window: Option<humantime::Duration>,
+
+    /// Accept TURN over TCP on the listen port, for clients whose network blocks UDP.
+    #[arg(long, env, default_value_t = false)]
+    tcp: bool,
+
+    /// The port to listen on for TURN over TLS.
+    ///
+    /// Clients expect TURN over TLS on port 443.
+    #[arg(long, env, hide = true, default_value = "443")]
+    tls_listen_port: u16,
+
+    /// Path to a PEM-encoded certificate chain.
+    ///
+    /// If set together with `--tls-key`, we accept TURN over TLS on the TLS listen port.
+    #[arg(long, env, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM-encoded private key of the TLS certificate.
+    #[arg(long, env, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
     /// The address of the local interface where we should serve our control endpoint.
     #[arg(long, env, hide = true, default_value = "127.0.0.1:9999")]
     control_endpoint: SocketAddr,
 
+    /// How long to wait for existing allocations to go away when draining before shutting down anyway.
+    ///
+    /// Draining is triggered by SIGUSR1 or `POST /drain` on the control endpoint.
+    #[arg(long, env, default_value = "1h")]
+    max_drain_time: humantime::Duration,
+
     /// Enable sentry.io crash-reporting agent.
     #[arg(long, env = "TELEMETRY", default_value_t = false)]
     telemetry: bool,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Cmd {
+    /// Mint a username and password for a relay running in standalone mode.
+    Credentials {
+        #[command(flatten)]
+        auth: AuthSecretArgs,
+
+        /// How long the credentials should be valid for. Accepts human times, e.g. "5m" or "1h" or "30d".
+        #[arg(long, default_value = "1d")]
+        ttl: humantime::Duration,
+    },
+}
+
+/// Where to load the secret from that clients authenticate with.
+///
+/// By default, the secret is randomly generated on startup and shared with the portal.
+#[derive(clap::Args, Debug)]
+#[group(multiple = false)]
+struct AuthSecretArgs {
+    /// Path to a file containing the secret.
+    #[arg(long, env = "FIREZONE_AUTH_SECRET_FILE")]
+    auth_secret_file: Option<PathBuf>,
+    /// The secret itself.
+    #[arg(long, env = "FIREZONE_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<SecretString>,
+}
+
+impl AuthSecretArgs {
+    fn load(&self) -> Result<Option<SecretString>> {
+        let secret = match (&self.auth_secret, &self.auth_secret_file) {
+            (Some(secret), _) => secret.expose_secret().trim().to_owned(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read auth secret from {}", path.display()))?
+                .trim()
+                .to_owned(),
+            (None, None) => return Ok(None),
+        };
+
+        if secret.is_empty() {
+            bail!("Auth secret must not be empty");
+        }
+
+        Ok(Some(SecretString::from(secret)))
+    }
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 enum LogFormat {
     Human,
@@ -137,6 +259,12 @@ fn main() {
 
     let args = Args::parse();
 
+    if let Some(Cmd::Credentials { auth, ttl }) = &args.command {
+        print_credentials(auth, Duration::from(*ttl));
+
+        return;
+    }
+
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -146,7 +274,7 @@ fn main() {
         let mut telemetry = Telemetry::new();
 
         runtime.block_on(telemetry.start(
-            args.api_url.as_str(),
+            args.api_url.as_ref().map(Url::as_str).unwrap_or_default(),
             VERSION.unwrap_or("unknown"),
             RELAY_DSN,
             String::new(), // Relays don't have a Firezone ID.
@@ -224,6 +352,26 @@ async fn try_main(args: Args) -> Result<()> {
         args.listen_port,
         args.lowest_port..=args.highest_port,
     );
+    let server = match args.auth.load()? {
+        Some(auth_secret) => server.with_auth_secret(auth_secret),
+        None if args.standalone => {
+            bail!("Standalone mode requires `--auth-secret` or `--auth-secret-file`")
+        }
+        None => server,
+    };
+    let server = server.with_limits(Limits {
+        allocation: Limit {
+            rate: args.allocation_rate_limit,
+            burst: args.allocation_burst,
+            quota: args.allocation_quota,
+        },
+        username: Limit {
+            rate: args.username_rate_limit,
+            burst: args.username_burst,
+            quota: args.username_quota,
+        },
+        quota_window: args.quota_window.map(Into::into),
+    });
 
     let is_connected = Arc::new(AtomicBool::new(false));
 
@@ -236,46 +384,76 @@ async fn try_main(args: Args) -> Result<()> {
         )
     });
 
+    let (control_tx, control_rx) = mpsc::channel(16);
+
     tokio::spawn(control_endpoint::serve(
         args.control_endpoint,
         filter_reload_handle,
+        control_tx,
     ));
 
-    let login = LoginUrl::relay(
-        args.api_url.clone(),
-        args.name.clone(),
-        args.listen_port,
-        args.public_ip4_addr,
-        args.public_ip6_addr,
-    )?;
+    let channel = if args.standalone {
+        tracing::info!(target: "relay", "Running in standalone mode, not connecting to the portal");
+        is_connected.store(true, Ordering::Relaxed);
 
-    let channel = PhoenixChannel::disconnected(
-        login,
-        args.token.clone(),
-        get_user_agent("relay", env!("CARGO_PKG_VERSION")),
-        "relay",
-        JoinMessage {
-            stamp_secret: server.auth_secret().expose_secret().to_string(),
-        },
-        || {
-            ExponentialBackoffBuilder::default()
-                .with_max_elapsed_time(Some(MAX_PARTITION_TIME))
-                .build()
-        },
-        Arc::new(socket_factory::tcp),
-    );
+        None
+    } else {
+        let api_url = args
+            .api_url
+            .clone()
+            .context("`--api-url` is required unless running in standalone mode")?;
+        let token = args
+            .token
+            .clone()
+            .context("`FIREZONE_TOKEN` is required unless running in standalone mode")?;
+
+        let login = LoginUrl::relay(
+            api_url,
+            args.name.clone(),
+            args.listen_port,
+            args.public_ip4_addr,
+            args.public_ip6_addr,
+        )?;
+
+        Some(PhoenixChannel::disconnected(
+            login,
+            token,
+            get_user_agent("relay", env!("CARGO_PKG_VERSION")),
+            "relay",
+            JoinMessage {
+                stamp_secret: server.auth_secret().expose_secret().to_string(),
+            },
+            || {
+                ExponentialBackoffBuilder::default()
+                    .with_max_elapsed_time(Some(MAX_PARTITION_TIME))
+                    .build()
+            },
+            Arc::new(socket_factory::tcp),
+        ))
+    };
+
+    let streams = make_streams(&args, public_addr)?;
 
     let mut eventloop = Eventloop::new(
         server,
         ebpf,
         channel,
+        control_rx,
+        streams,
         public_addr,
         args.bind_ip4_addr,
         args.bind_ip6_addr,
         is_connected,
+        args.max_drain_time.into(),
     )?;
 
     tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {0}", args.listen_port);
+    if args.tcp {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TCP port {0}", args.listen_port);
+    }
+    if args.tls_cert.is_some() {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TLS port {0}", args.tls_listen_port);
+    }
 
     future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -286,6 +464,67 @@ async fn try_main(args: Args) -> Result<()> {
     Ok(())
 }
 
+#[expect(
+    clippy::print_stdout,
+    clippy::print_stderr,
+    reason = "The `credentials` subcommand runs before logging is set up and prints for the operator."
+)]
+fn print_credentials(auth: &AuthSecretArgs, ttl: Duration) {
+    match mint_credentials(auth, SystemTime::now() + ttl) {
+        Ok((username, password)) => {
+            println!("username: {username}");
+            println!("password: {password}");
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+
+            std::process::exit(1);
+        }
+    }
+}
+
+fn mint_credentials(auth: &AuthSecretArgs, expiry: SystemTime) -> Result<(String, String)> {
+    use rand::distributions::{Alphanumeric, DistString as _};
+
+    let auth_secret = auth
+        .load()?
+        .context("Minting credentials requires `--auth-secret` or `--auth-secret-file`")?;
+    let salt = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+
+    Ok(firezone_relay::auth::generate_credentials(
+        &auth_secret,
+        expiry,
+        &salt,
+    ))
+}
+
+/// Sets up the TCP and TLS listeners for clients that cannot reach us via UDP.
+fn make_streams(args: &Args, public_address: IpStack) -> Result<Streams> {
+    let mut streams = Streams::new();
+
+    let tls_acceptor = match (args.tls_cert.as_deref(), args.tls_key.as_deref()) {
+        (Some(cert), Some(key)) => Some(streams::make_tls_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    let bind_ips = [
+        public_address.as_v4().map(|_| args.bind_ip4_addr.into()),
+        public_address.as_v6().map(|_| args.bind_ip6_addr.into()),
+    ];
+
+    for bind_ip in bind_ips.into_iter().flatten() {
+        if args.tcp {
+            streams.listen_tcp(SocketAddr::new(bind_ip, args.listen_port))?;
+        }
+
+        if let Some(acceptor) = tls_acceptor.clone() {
+            streams.listen_tls(SocketAddr::new(bind_ip, args.tls_listen_port), acceptor)?;
+        }
+    }
+
+    Ok(streams)
+}
+
 /// Sets up our tracing infrastructure.
 ///
 /// See [`log_layer`] for details on the base log layer.
@@ -414,6 +653,16 @@ enum IngressMessages {
 #[derive(serde::Deserialize, Debug)]
 struct Init {}
 
+#[derive(serde::Serialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+enum EgressMessages {
+    /// We no longer accept new allocations and will shut down once the existing ones are gone.
+    Draining {
+        /// The maximum time in seconds until we shut down.
+        max_drain_secs: u64,
+    },
+}
+
 #[derive(serde::Serialize, PartialEq, Debug, Clone)]
 struct JoinMessage {
     stamp_secret: String,
@@ -433,18 +682,32 @@ const MAX_UDP_SIZE: usize = 65536;
 
 struct Eventloop<R> {
     sockets: Sockets,
+    streams: Streams,
 
     server: Server<R>,
-    event_rx: mpsc::Receiver<Result<IngressMessages, phoenix_channel::Error>>,
+    /// Messages from the portal, unless we are running in standalone mode.
+    event_rx: Option<mpsc::Receiver<Result<IngressMessages, phoenix_channel::Error>>>,
+    control_rx: mpsc::Receiver<control_endpoint::Request>,
     sleep: Sleep,
 
     ebpf: Option<ebpf::Program>,
 
     sigterm: signals::Terminate,
+    sigusr1: signals::Drain,
+
+    /// Messages to the portal, unless we are running in standalone mode.
+    portal_tx: Option<mpsc::Sender<EgressMessages>>,
+
+    max_drain_time: Duration,
+    /// When we shut down regardless of any remaining allocations, set once we start draining.
+    drain_deadline: Option<Pin<Box<tokio::time::Sleep>>>,
 
     stats_log_interval: tokio::time::Interval,
     last_num_bytes_relayed: u64,
 
+    offload_budget_interval: tokio::time::Interval,
+    last_num_budget_exhausted: u64,
+
     buffer: [u8; MAX_UDP_SIZE],
 
     /// IPv4 address to bind sockets to.
@@ -460,11 +723,14 @@ where
     fn new(
         server: Server<R>,
         ebpf: Option<ebpf::Program>,
-        portal: PhoenixChannel<JoinMessage, (), IngressMessages, NoParams>,
+        portal: Option<PhoenixChannel<JoinMessage, EgressMessages, IngressMessages, NoParams>>,
+        control_rx: mpsc::Receiver<control_endpoint::Request>,
+        streams: Streams,
         public_address: IpStack,
         bind_ip4: Ipv4Addr,
         bind_ip6: Ipv6Addr,
         is_connected: Arc<AtomicBool>,
+        max_drain_time: Duration,
     ) -> Result<Self> {
         let mut sockets = Sockets::new();
 
@@ -489,19 +755,39 @@ where
                 })?;
         }
 
-        let (event_tx, event_rx) = mpsc::channel(128);
-        tokio::spawn(phoenix_channel_event_loop(portal, event_tx, is_connected));
+        let (event_rx, portal_tx) = portal
+            .map(|portal| {
+                let (event_tx, event_rx) = mpsc::channel(128);
+                let (egress_tx, egress_rx) = mpsc::channel(16);
+                tokio::spawn(phoenix_channel_event_loop(
+                    portal,
+                    event_tx,
+                    egress_rx,
+                    is_connected,
+                ));
+
+                (event_rx, egress_tx)
+            })
+            .unzip();
 
         Ok(Self {
             server,
             event_rx,
+            control_rx,
             sleep: Sleep::default(),
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
+            offload_budget_interval: tokio::time::interval(OFFLOAD_BUDGET_INTERVAL),
+            last_num_budget_exhausted: 0,
             sockets,
+            streams,
             ebpf,
             buffer: [0u8; MAX_UDP_SIZE],
             sigterm: signals::Terminate::new()?,
+            sigusr1: signals::Drain::new()?,
+            portal_tx,
+            max_drain_time,
+            drain_deadline: None,
             bind_ip4,
             bind_ip6,
         })
@@ -517,9 +803,11 @@ where
             if let Some(next_command) = self.server.next_command() {
                 match next_command {
                     Command::SendMessage { payload, recipient } => {
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &mut self.sockets,
+                            &mut self.streams,
                             self.server.listen_port(),
-                            recipient.into_socket(),
+                            recipient,
                             Cow::Owned(payload),
                         ) {
                             tracing::warn!(target: "relay", %recipient, "Failed to send message: {}", err_with_src(&e));
@@ -557,6 +845,13 @@ where
                         peer,
                         allocation_port,
                     } => {
+                        // The new channel needs a budget before the eBPF kernel relays anything on it.
+                        if let Err(e) =
+                            self.refill_offload_budgets(Some(allocation_port), Instant::now())
+                        {
+                            tracing::debug!(target: "relay", allocation = %allocation_port, "Failed to refill eBPF channel budgets: {e:#}");
+                        }
+
                         if let Err(e) = self.create_channel_binding_in_ebpf_map(
                             client,
                             channel_number,
@@ -644,6 +939,7 @@ where
                         packet,
                         PeerSocket::new(from),
                         AllocationPort::new(port),
+                        Instant::now(),
                     ) {
                         let total_length = ChannelData::encode_header_to_slice(
                             channel,
@@ -651,9 +947,11 @@ where
                             header,
                         );
 
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &mut self.sockets,
+                            &mut self.streams,
                             self.server.listen_port(), // Packets coming in from peers always go out on the TURN port
-                            client.into_socket(),
+                            client,
                             Cow::Borrowed(&self.buffer[..total_length]),
                         ) {
                             tracing::warn!(target: "relay", %client, "Failed to relay data to client: {}", err_with_src(&e));
@@ -671,35 +969,76 @@ where
                 Poll::Pending => {}
             }
 
-            // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
+            // Priority 3: Read from clients connected via TCP or TLS.
+            match self.streams.poll_recv(cx) {
+                Poll::Ready(streams::Received::Message { from, message }) => {
+                    if let Some((port, peer)) = self.server.handle_client_input(
+                        &message,
+                        ClientSocket::new(from),
+                        Instant::now(),
+                    ) {
+                        let payload = ChannelData::parse(&message)
+                            .expect("valid ChannelData if we should relay it")
+                            .data();
+
+                        if let Err(e) = self.sockets.try_send(
+                            port.value(),
+                            peer.into_socket(),
+                            Cow::Borrowed(payload),
+                        ) {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {}", err_with_src(&e));
+                        }
+                    }
+
+                    ready = true;
+                }
+                Poll::Ready(streams::Received::Disconnected(client)) => {
+                    self.server
+                        .handle_client_disconnected(ClientSocket::new(client));
+
+                    ready = true;
+                }
+                Poll::Pending => {}
+            }
+
+            // Priority 4: Check when we need to next be woken. This needs to happen after all state modifications.
             if let Some(timeout) = self.server.poll_timeout() {
                 Pin::new(&mut self.sleep).reset(timeout);
                 // Purposely no `ready = true` because we just change the state of `sleep` and we poll it below.
             }
 
-            // Priority 4: Handle time-sensitive tasks:
+            // Priority 5: Handle time-sensitive tasks:
             if let Poll::Ready(deadline) = self.sleep.poll_unpin(cx) {
                 self.server.handle_timeout(deadline);
 
                 ready = true;
             }
 
-            // Priority 5: Handle portal messages
-            match self.event_rx.poll_recv(cx) {
-                Poll::Ready(Some(Ok(IngressMessages::Init(Init {})))) => {
-                    ready = true;
-                }
-                Poll::Ready(Some(Err(e))) => {
-                    return Poll::Ready(Err(
-                        anyhow::Error::new(e).context("Portal connection failed")
-                    ));
-                }
-                Poll::Ready(None) => {
-                    return Poll::Ready(Err(anyhow::Error::msg(
-                        "Portal connection task terminated",
-                    )));
+            // Priority 6: Handle portal messages
+            if let Some(event_rx) = self.event_rx.as_mut() {
+                match event_rx.poll_recv(cx) {
+                    Poll::Ready(Some(Ok(IngressMessages::Init(Init {})))) => {
+                        ready = true;
+                    }
+                    Poll::Ready(Some(Err(e))) => {
+                        return Poll::Ready(Err(
+                            anyhow::Error::new(e).context("Portal connection failed")
+                        ));
+                    }
+                    Poll::Ready(None) => {
+                        return Poll::Ready(Err(anyhow::Error::msg(
+                            "Portal connection task terminated",
+                        )));
+                    }
+                    Poll::Pending => {}
                 }
-                Poll::Pending => {}
+            }
+
+            // Priority 7: Handle requests from the control endpoint
+            if let Poll::Ready(Some(request)) = self.control_rx.poll_recv(cx) {
+                self.handle_control_request(request);
+
+                ready = true;
             }
 
             match self.sigterm.poll_recv(cx) {
@@ -707,6 +1046,41 @@ where
                 Poll::Pending => {}
             }
 
+            if self.sigusr1.poll_recv(cx).is_ready() {
+                self.start_draining();
+
+                ready = true;
+            }
+
+            if self.server.is_draining() && self.server.num_allocations() == 0 {
+                tracing::info!(target: "relay", "All allocations are gone, shutting down");
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(deadline) = self.drain_deadline.as_mut()
+                && deadline.as_mut().poll(cx).is_ready()
+            {
+                tracing::info!(target: "relay", num_allocations = %self.server.num_allocations(), "Max drain time elapsed, shutting down");
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.ebpf.is_some()
+                && self.server.has_limits()
+                && self.offload_budget_interval.poll_tick(cx).is_ready()
+            {
+                if let Err(e) = self.refill_offload_budgets(None, Instant::now()) {
+                    tracing::debug!(target: "relay", "Failed to refill eBPF channel budgets: {e:#}");
+                }
+
+                if let Err(e) = self.count_offloaded_limit_hits() {
+                    tracing::debug!(target: "relay", "Failed to count eBPF limit hits: {e:#}");
+                }
+
+                ready = true;
+            }
+
             if self.stats_log_interval.poll_tick(cx).is_ready() {
                 let num_allocations = self.server.num_allocations();
                 let num_channels = self.server.num_active_channels();
@@ -728,6 +1102,113 @@ where
         }
     }
 
+    fn handle_control_request(&mut self, request: control_endpoint::Request) {
+        match request {
+            control_endpoint::Request::Allocations(tx) => {
+                let _ = tx.send(self.server.allocations(Instant::now()));
+            }
+            control_endpoint::Request::Stats(tx) => {
+                let _ = tx.send(control_endpoint::Stats {
+                    num_allocations: self.server.num_allocations(),
+                    num_active_channels: self.server.num_active_channels(),
+                    num_relayed_bytes: self.server.num_relayed_bytes(),
+                    num_limit_hits: self.server.num_limit_hits(),
+                });
+            }
+            control_endpoint::Request::RevokeAllocation(port, tx) => {
+                // Freeing the allocation and its channel bindings (incl. the eBPF maps) happens via the server's commands.
+                let _ = tx.send(self.server.revoke_allocation(port));
+            }
+            control_endpoint::Request::Drain(tx) => {
+                self.start_draining();
+
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    /// Stops accepting new allocations and tells the portal to no longer hand us out.
+    ///
+    /// We shut down once all existing allocations are gone or the max drain time elapsed.
+    fn start_draining(&mut self) {
+        if self.server.is_draining() {
+            return;
+        }
+
+        tracing::info!(target: "relay", num_allocations = %self.server.num_allocations(), max_drain_time = ?self.max_drain_time, "Draining");
+
+        self.server.start_draining();
+        self.drain_deadline = Some(Box::pin(tokio::time::sleep(self.max_drain_time)));
+
+        if let Some(portal_tx) = self.portal_tx.as_ref()
+            && portal_tx
+                .try_send(EgressMessages::Draining {
+                    max_drain_secs: self.max_drain_time.as_secs(),
+                })
+                .is_err()
+        {
+            tracing::warn!(target: "relay", "Failed to announce drain to portal");
+        }
+    }
+
+    /// Accounts for the traffic relayed by the eBPF kernel and hands new budgets to the channels of limited allocations.
+    ///
+    /// If `only` is set, we only refill the channels of that allocation.
+    /// Idle channels cost a single lookup because their budget doesn't change.
+    fn refill_offload_budgets(&mut self, only: Option<AllocationPort>, now: Instant) -> Result<()> {
+        if !self.server.has_limits() {
+            return Ok(());
+        }
+
+        let Some(ebpf) = self.ebpf.as_mut() else {
+            return Ok(()); // ebPF program not loaded ...
+        };
+
+        for (client, port, channels) in self.server.offloadable_channels() {
+            if only.is_some_and(|only| only != port) {
+                continue;
+            }
+
+            // Clients connected via TCP or TLS are always relayed in userspace.
+            if self.streams.is_connected(client.into_socket()) {
+                continue;
+            }
+
+            let remaining = channels
+                .into_iter()
+                .map(|channel| Ok((channel, ebpf.channel_budget(port, channel)?)))
+                .collect::<Result<Vec<_>>>()?;
+
+            for (channel, budget) in self.server.handle_offloaded_traffic(port, &remaining, now) {
+                let unchanged = remaining
+                    .iter()
+                    .any(|(c, r)| *c == channel && *r == i64::try_from(budget).ok());
+
+                if unchanged {
+                    continue;
+                }
+
+                ebpf.set_channel_budget(port, channel, budget)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn count_offloaded_limit_hits(&mut self) -> Result<()> {
+        let Some(ebpf) = self.ebpf.as_mut() else {
+            return Ok(()); // ebPF program not loaded ...
+        };
+
+        let num_budget_exhausted = ebpf.num_budget_exhausted()?;
+        self.server.handle_offloaded_limit_hits(
+            num_budget_exhausted.saturating_sub(self.last_num_budget_exhausted),
+        );
+        self.last_num_budget_exhausted = num_budget_exhausted;
+
+        Ok(())
+    }
+
     fn create_channel_binding_in_ebpf_map(
         &mut self,
         client: ClientSocket,
@@ -735,6 +1216,11 @@ where
         peer: PeerSocket,
         allocation_port: AllocationPort,
     ) -> Result<()> {
+        // The eBPF kernel only sees UDP traffic, clients connected via TCP or TLS are always relayed in userspace.
+        if self.streams.is_connected(client.into_socket()) {
+            return Ok(());
+        }
+
         let Some(ebpf) = self.ebpf.as_mut() else {
             return Ok(()); // ebPF program not loaded ...
         };
@@ -756,25 +1242,69 @@ where
         };
 
         ebpf.remove_channel_binding(client, channel_number, peer, allocation_port)?;
+        ebpf.remove_channel_budget(allocation_port, channel_number)?;
 
         Ok(())
     }
 }
 
+/// Sends a message to a client, using its TCP or TLS connection if it has one.
+fn send_to_client(
+    sockets: &mut Sockets,
+    streams: &mut Streams,
+    listen_port: u16,
+    client: ClientSocket,
+    payload: Cow<'_, [u8]>,
+) -> io::Result<()> {
+    let client = client.into_socket();
+
+    if streams.is_connected(client) {
+        return streams.try_send(client, &payload);
+    }
+
+    sockets.try_send(listen_port, client, payload)
+}
+
 async fn phoenix_channel_event_loop(
-    mut portal: PhoenixChannel<JoinMessage, (), IngressMessages, NoParams>,
+    mut portal: PhoenixChannel<JoinMessage, EgressMessages, IngressMessages, NoParams>,
     event_tx: mpsc::Sender<Result<IngressMessages, phoenix_channel::Error>>,
+    mut egress_rx: mpsc::Receiver<EgressMessages>,
     is_connected: Arc<AtomicBool>,
 ) {
     update_portal_host_ips(&mut portal).await;
     portal.connect(NoParams);
 
+    // The portal forgets that we are draining when we reconnect, thus we need to remember it here.
+    let mut drain_deadline = None;
+
     loop {
-        match std::future::poll_fn(|cx| portal.poll(cx)).await {
+        let event = tokio::select! {
+            event = std::future::poll_fn(|cx| portal.poll(cx)) => event,
+            Some(msg) = egress_rx.recv() => {
+                let EgressMessages::Draining { max_drain_secs } = &msg;
+                drain_deadline = Some(Instant::now() + Duration::from_secs(*max_drain_secs));
+
+                portal.send("relay", msg);
+                continue;
+            }
+        };
+
+        match event {
             Ok(Event::SuccessResponse { .. }) => {}
             Ok(Event::JoinedRoom { topic }) => {
                 tracing::info!(target: "relay", "Successfully joined room '{topic}'");
                 is_connected.store(true, Ordering::Relaxed);
+
+                if let Some(deadline) = drain_deadline {
+                    portal.send(
+                        "relay",
+                        EgressMessages::Draining {
+                            max_drain_secs: deadline
+                                .saturating_duration_since(Instant::now())
+                                .as_secs(),
+                        },
+                    );
+                }
             }
             Ok(Event::ErrorResponse { topic, req_id, res }) => {
                 tracing::warn!(target: "relay", "Request with ID {req_id} on topic {topic} failed: {res:?}");
@@ -812,7 +1342,7 @@ async fn phoenix_channel_event_loop(
 }
 
 async fn update_portal_host_ips(
-    portal: &mut PhoenixChannel<JoinMessage, (), IngressMessages, NoParams>,
+    portal: &mut PhoenixChannel<JoinMessage, EgressMessages, IngressMessages, NoParams>,
 ) {
     let host = portal.host();
 
@@ -901,4 +1431,72 @@ mod tests {
 
         assert_eq!(args.otlp_grpc_endpoint.unwrap(), "localhost:4317");
     }
+
+    #[test]
+    fn serializes_draining_message() {
+        let json = serde_json::to_string(&EgressMessages::Draining {
+            max_drain_secs: 3600,
+        })
+        .unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"event":"draining","payload":{"max_drain_secs":3600}}"#
+        );
+    }
+
+    #[test]
+    fn args_can_run_standalone_without_portal() {
+        let args = Args::try_parse_from([
+            "relay",
+            "--standalone",
+            "--auth-secret",
+            "s3cr3t",
+            "--public-ip4-addr",
+            "10.0.0.1",
+        ])
+        .unwrap();
+
+        assert!(args.standalone);
+        assert!(args.api_url.is_none());
+        assert!(args.token.is_none());
+        assert_eq!(args.auth.load().unwrap().unwrap().expose_secret(), "s3cr3t");
+    }
+
+    #[test]
+    fn auth_secret_and_file_are_mutually_exclusive() {
+        let result = Args::try_parse_from([
+            "relay",
+            "--standalone",
+            "--auth-secret",
+            "s3cr3t",
+            "--auth-secret-file",
+            "/etc/firezone/relay-secret",
+        ]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn minted_credentials_use_relay_secret() {
+        let args =
+            Args::try_parse_from(["relay", "credentials", "--auth-secret", "s3cr3t"]).unwrap();
+        let Some(Cmd::Credentials { auth, ttl }) = args.command else {
+            panic!("Expected `credentials` subcommand");
+        };
+        assert_eq!(Duration::from(ttl), Duration::from_secs(60 * 60 * 24));
+
+        let (username, password) = mint_credentials(
+            &auth,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1685200000),
+        )
+        .unwrap();
+        let (expiry, salt) = username.split_once(':').unwrap();
+
+        assert_eq!(expiry, "1685200000");
+        assert_eq!(
+            password,
+            firezone_relay::auth::generate_password(&"s3cr3t".into(), 1685200000, salt)
+        );
+    }
 }
@@ -1,10 +1,17 @@
 mod channel_data;
 mod client_message;
+mod limits;
+mod throughput;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
     Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
 };
+pub use crate::server::limits::{Limit, Limits};
+pub use turn_wire::RelayLoad;
+
+use crate::server::limits::Meter;
+use crate::server::throughput::Throughput;
 
 use crate::auth::{self, AuthenticatedMessage, FIREZONE, MessageIntegrityExt, Nonces};
 use crate::net_ext::IpAddrExt;
@@ -48,6 +55,9 @@ use uuid::Uuid;
 /// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
 /// we can index data simply by the sender's [`SocketAddr`].
 ///
+/// Clients connected via TCP or TLS are indexed by the remote address of their connection.
+/// It is the caller's responsibility to send messages to those clients via their connection and to call [`Server::handle_client_disconnected`] once it is closed.
+///
 /// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
 #[derive(Debug)]
 pub struct Server<R> {
@@ -77,10 +87,21 @@ pub struct Server<R> {
 
     nonces: Nonces,
 
+    limits: Limits,
+    /// Whether we are draining, i.e. rejecting new allocations while serving the existing ones.
+    draining: bool,
+    /// Usage of the per-username limits, indexed by the salt of the username.
+    ///
+    /// Only populated if there are per-username limits.
+    meters_by_username: HashMap<String, UsernameMeter>,
+
     allocations_up_down_counter: UpDownCounter<i64>,
     data_relayed_counter: Counter<u64>,
     data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    throughput: Throughput,
     responses_counter: Counter<u64>,
+    limit_hits_counter: Counter<u64>,
+    limit_hits: u64,
 }
 
 /// The commands returned from a [`Server`].
@@ -122,6 +143,18 @@ pub enum Command {
     },
 }
 
+/// A snapshot of a single allocation, see [`Server::allocations`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub port: AllocationPort,
+    pub client: ClientSocket,
+    /// How long until the allocation expires unless it gets refreshed.
+    pub expires_in: Duration,
+    pub num_active_channels: usize,
+    /// The number of bytes relayed in userspace, i.e. excluding traffic handled by the eBPF kernel.
+    pub num_relayed_bytes: u64,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct AllocationPort(u16);
 
@@ -189,6 +222,12 @@ where
             .with_description("The number of bytes relayed")
             .with_unit("b")
             .build();
+        let limit_hits_counter = meter
+            .u64_counter("limit_hits_total")
+            .with_description(
+                "The number of packets dropped because a rate limit or quota was exceeded",
+            )
+            .build();
 
         Self {
             public_address: public_address.into(),
@@ -206,10 +245,46 @@ where
             responses_counter,
             data_relayed_counter,
             data_relayed: 0,
+            throughput: Throughput::default(),
+            limit_hits_counter,
+            limit_hits: 0,
             channel_and_client_by_port_and_peer: Default::default(),
+            limits: Limits::default(),
+            draining: false,
+            meters_by_username: Default::default(),
         }
     }
 
+    /// Enforces the given [`Limits`] on all allocations created from now on.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+
+        self
+    }
+
+    /// Authenticates clients with the given secret instead of a randomly generated one.
+    pub fn with_auth_secret(mut self, auth_secret: SecretString) -> Self {
+        self.auth_secret = auth_secret;
+
+        self
+    }
+
+    /// Stops accepting new allocations.
+    ///
+    /// Existing allocations can still be refreshed and bind channels until they expire.
+    /// New allocations are rejected with 508 (Insufficient Capacity), upon which clients free them and use a different relay.
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    pub fn has_limits(&self) -> bool {
+        !self.limits.is_unlimited()
+    }
+
     pub fn auth_secret(&self) -> &SecretString {
         &self.auth_secret
     }
@@ -245,6 +320,11 @@ where
         self.allocations.len()
     }
 
+    /// The number of packets dropped because a limit was exceeded.
+    pub fn num_limit_hits(&self) -> u64 {
+        self.limit_hits
+    }
+
     pub fn num_active_channels(&self) -> usize {
         self.channels_by_client_and_number
             .iter()
@@ -252,6 +332,86 @@ where
             .count()
     }
 
+    /// Returns a snapshot of all current allocations.
+    pub fn allocations(&self, now: Instant) -> Vec<AllocationStats> {
+        let mut channels_by_allocation = HashMap::<AllocationPort, usize>::new();
+
+        for channel in self
+            .channels_by_client_and_number
+            .values()
+            .filter(|c| c.bound)
+        {
+            *channels_by_allocation
+                .entry(channel.allocation)
+                .or_default() += 1;
+        }
+
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| AllocationStats {
+                port: allocation.port,
+                client: *client,
+                expires_in: allocation.expires_at.saturating_duration_since(now),
+                num_active_channels: channels_by_allocation
+                    .get(&allocation.port)
+                    .copied()
+                    .unwrap_or_default(),
+                num_relayed_bytes: allocation.relayed_bytes,
+            })
+            .collect()
+    }
+
+    /// The bound channels of each client's allocation, i.e. the ones the eBPF kernel may relay traffic on.
+    pub fn offloadable_channels(&self) -> Vec<(ClientSocket, AllocationPort, Vec<ChannelNumber>)> {
+        let mut channels_by_client =
+            BTreeMap::<ClientSocket, (AllocationPort, Vec<ChannelNumber>)>::new();
+
+        for ((client, number), channel) in self
+            .channels_by_client_and_number
+            .iter()
+            .filter(|(_, c)| c.bound)
+        {
+            channels_by_client
+                .entry(*client)
+                .or_insert_with(|| (channel.allocation, Vec::new()))
+                .1
+                .push(*number);
+        }
+
+        channels_by_client
+            .into_iter()
+            .map(|(client, (port, channels))| (client, port, channels))
+            .collect()
+    }
+
+    /// Forcibly deletes the allocation on the given port, including all its channel bindings.
+    ///
+    /// Returns `false` if there is no such allocation.
+    pub fn revoke_allocation(&mut self, port: AllocationPort) -> bool {
+        let Some(client) = self.clients_by_allocation.get(&port).copied() else {
+            return false;
+        };
+
+        tracing::info!(target: "relay", allocation = %port, %client, "Revoking allocation");
+
+        self.delete_allocation(port);
+
+        true
+    }
+
+    /// Deletes the allocation of a client whose TCP or TLS connection has been closed.
+    ///
+    /// Over stream transports, an allocation only lives as long as the connection it was made on.
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        let Some(port) = self.allocations.get(&client).map(|a| a.port) else {
+            return;
+        };
+
+        tracing::info!(target: "relay", allocation = %port, %client, "Client disconnected, deleting allocation");
+
+        self.delete_allocation(port);
+    }
+
     /// Process the bytes received from a client.
     ///
     /// # Returns
@@ -313,11 +473,11 @@ where
                 self.handle_create_permission_request(request, sender)
             }
             ClientMessage::Binding(request) => {
-                self.handle_binding_request(request, sender);
+                self.handle_binding_request(request, sender, now);
                 return None;
             }
             ClientMessage::ChannelData(msg) => {
-                return self.handle_channel_data_message(msg, sender);
+                return self.handle_channel_data_message(msg, sender, now);
             }
         };
 
@@ -371,22 +531,114 @@ where
         msg: &[u8],
         sender: PeerSocket,
         allocation: AllocationPort,
+        now: Instant,
     ) -> Option<(ClientSocket, ChannelNumber)> {
         let Some((client, channel_number)) = self
             .channel_and_client_by_port_and_peer
             .get(&(allocation, sender))
+            .copied()
         else {
             tracing::debug!(target: "relay", %sender, %allocation, "no channel");
 
             return None;
         };
 
-        self.data_relayed_counter.add(msg.len() as u64, &[]);
-        self.data_relayed += msg.len() as u64;
+        if !self.try_consume(client, msg.len() as u64, now) {
+            return None;
+        }
 
         tracing::trace!(target: "wire", num_bytes = %msg.len());
 
-        Some((*client, *channel_number))
+        Some((client, channel_number))
+    }
+
+    /// Accounts for the traffic relayed by the eBPF kernel on the channels of an allocation and computes their new budgets.
+    ///
+    /// `remaining` is what is left of the budget we handed out for each channel the last time, [`None`] if the channel doesn't have one.
+    /// The eBPF kernel should drop all traffic on a channel that exceeds its returned budget.
+    /// Returns nothing if the allocation is not limited.
+    ///
+    /// Budgets are consumed from the allocation's limits when we hand them out and what is left of them is refunded here.
+    /// Thus, userspace and the eBPF kernel can never spend the same bytes.
+    /// To leave some for the packets relayed in userspace, we only ever hand out half of what is available.
+    ///
+    /// This should be called periodically because budgets only ever refill here.
+    /// Concurrent allocations of a username compete for its limit in the order they are refilled, thus per-username limits are only enforced approximately.
+    pub fn handle_offloaded_traffic(
+        &mut self,
+        port: AllocationPort,
+        remaining: &[(ChannelNumber, Option<i64>)],
+        now: Instant,
+    ) -> Vec<(ChannelNumber, u64)> {
+        let Some(allocation) = self
+            .clients_by_allocation
+            .get(&port)
+            .and_then(|client| self.allocations.get_mut(client))
+        else {
+            return Vec::new();
+        };
+        let mut username_meter = self.meters_by_username.get_mut(&allocation.username);
+
+        // Budgets of channels that are gone count as spent.
+        let previous_budgets = std::mem::take(&mut allocation.offload_budgets);
+
+        for (channel, remaining) in remaining {
+            let previous_budget = previous_budgets.get(channel).copied().unwrap_or_default();
+
+            // The kernel may overshoot the budget slightly when racing on multiple CPUs, hence the budget may be negative.
+            match u64::try_from(remaining.unwrap_or_default()) {
+                Ok(unspent) => {
+                    let unspent = unspent.min(previous_budget);
+
+                    allocation.meter.refund(unspent, now);
+                    if let Some(m) = username_meter.as_mut() {
+                        m.meter.refund(unspent, now);
+                    }
+                }
+                Err(_) => {
+                    let overshoot = remaining.unwrap_or_default().unsigned_abs();
+
+                    allocation.meter.consume(overshoot, now);
+                    if let Some(m) = username_meter.as_mut() {
+                        m.meter.consume(overshoot, now);
+                    }
+                }
+            }
+        }
+
+        let Some(available) = [
+            allocation.meter.available(now),
+            username_meter.as_mut().and_then(|m| m.meter.available(now)),
+        ]
+        .into_iter()
+        .flatten()
+        .min() else {
+            return Vec::new();
+        };
+
+        let budget = (available / 2)
+            .checked_div(remaining.len() as u64)
+            .unwrap_or_default();
+
+        remaining
+            .iter()
+            .map(|(channel, _)| {
+                allocation.meter.consume(budget, now);
+                if let Some(m) = username_meter.as_mut() {
+                    m.meter.consume(budget, now);
+                }
+                allocation.offload_budgets.insert(*channel, budget);
+
+                (*channel, budget)
+            })
+            .collect()
+    }
+
+    /// The eBPF kernel dropped packets because their allocation had no budget left.
+    pub fn handle_offloaded_limit_hits(&mut self, num_packets: u64) {
+        self.limit_hits_counter
+            .add(num_packets, &[KeyValue::new("scope", "offloaded")]);
+        self.limit_hits += num_packets;
     }
 
     /// An allocation failed.
@@ -426,6 +678,9 @@ where
             self.delete_allocation(id);
         }
 
+        self.meters_by_username
+            .retain(|_, m| m.num_allocations > 0 || !m.meter.is_idle(now));
+
         for ((client, number), channel) in self
             .channels_by_client_and_number
             .iter_mut()
@@ -463,9 +718,10 @@ where
     }
 
     #[tracing::instrument(level = "info", skip_all, fields(software = request.software().map(|s| field::display(s.description())), tid = %format_args!("{:X}", request.transaction_id().as_bytes().hex()), %sender))]
-    fn handle_binding_request(&mut self, request: &Binding, sender: ClientSocket) {
+    fn handle_binding_request(&mut self, request: &Binding, sender: ClientSocket, now: Instant) {
         let mut message = success_response(BINDING, request.transaction_id());
         message.add_attribute(XorMappedAddress::new(sender.0));
+        message.add_attribute(self.load(now));
 
         tracing::info!("Handled BINDING request");
 
@@ -494,6 +750,14 @@ where
             return Err(error_response);
         }
 
+        if self.draining {
+            let (error_response, msg) = make_error_response(InsufficientCapacity, request);
+
+            tracing::info!(target: "relay", %sender, "{msg}: Relay is draining");
+
+            return Err(error_response);
+        }
+
         let max_available_ports = self.max_available_ports() as usize;
         if self.clients_by_allocation.len() == max_available_ports {
             let (error_response, msg) = make_error_response(InsufficientCapacity, request);
@@ -531,6 +795,7 @@ where
         let allocation = self.create_new_allocation(
             now,
             &effective_lifetime,
+            &username,
             first_relay_address,
             maybe_second_relay_addr,
         );
@@ -553,6 +818,10 @@ where
         message.add_attribute(XorMappedAddress::new(sender.0));
         message.add_attribute(effective_lifetime.clone());
 
+        let mut load = self.load(now);
+        load.allocations += 1; // Account for the allocation we are about to insert.
+        message.add_attribute(load);
+
         self.pending_commands.push_back(Command::CreateAllocation {
             port: allocation.port,
             family: first_relay_address.family(),
@@ -774,6 +1043,7 @@ where
         &mut self,
         message: &ChannelData,
         sender: ClientSocket,
+        now: Instant,
     ) -> Option<(AllocationPort, PeerSocket)> {
         let channel_number = message.channel();
         let data = message.data();
@@ -794,12 +1064,63 @@ where
             return None;
         }
 
+        let port = channel.allocation;
+        let peer = channel.peer_address;
+
+        if !self.try_consume(sender, data.len() as u64, now) {
+            return None;
+        }
+
         tracing::trace!(target: "wire", num_bytes = %data.len());
 
-        self.data_relayed_counter.add(data.len() as u64, &[]);
-        self.data_relayed += data.len() as u64;
+        Some((port, peer))
+    }
+
+    /// Checks the limits of the allocation of `client` and records the relayed bytes if they aren't exceeded.
+    fn try_consume(&mut self, client: ClientSocket, num_bytes: u64, now: Instant) -> bool {
+        let Some(allocation) = self.allocations.get_mut(&client) else {
+            debug_assert!(false, "Channels are always bound to an allocation");
+
+            return false;
+        };
+        let mut username_meter = self.meters_by_username.get_mut(&allocation.username);
+
+        let result = allocation
+            .meter
+            .check(num_bytes, now)
+            .map_err(|e| ("allocation", e))
+            .and_then(|()| {
+                username_meter
+                    .as_mut()
+                    .map_or(Ok(()), |m| m.meter.check(num_bytes, now))
+                    .map_err(|e| ("username", e))
+            });
+
+        if let Err((scope, exceeded)) = result {
+            self.limit_hits_counter.add(
+                1,
+                &[
+                    KeyValue::new("scope", scope),
+                    KeyValue::new("limit", exceeded.as_str()),
+                ],
+            );
+            self.limit_hits += 1;
+
+            tracing::debug!(target: "relay", %client, allocation = %allocation.port, %scope, limit = %exceeded.as_str(), "Dropping packet: Limit exceeded");
+
+            return false;
+        }
+
+        allocation.meter.consume(num_bytes, now);
+        if let Some(m) = username_meter {
+            m.meter.consume(num_bytes, now);
+        }
+        allocation.relayed_bytes += num_bytes;
+
+        self.data_relayed_counter.add(num_bytes, &[]);
+        self.data_relayed += num_bytes;
 
-        Some((channel.allocation, channel.peer_address))
+        true
     }
 
     fn verify_auth(
@@ -866,6 +1187,7 @@ where
         &mut self,
         now: Instant,
         lifetime: &Lifetime,
+        username: &Username,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
     ) -> Allocation {
@@ -882,11 +1204,31 @@ where
             }
         };
 
+        // `verify_auth` has already validated the username.
+        let username = auth::split_username(username.name())
+            .map(|(_, salt)| salt)
+            .unwrap_or(username.name())
+            .to_owned();
+
+        if !self.limits.username.is_unlimited() {
+            self.meters_by_username
+                .entry(username.clone())
+                .or_insert_with(|| UsernameMeter {
+                    meter: Meter::new(self.limits.username, self.limits.quota_window, now),
+                    num_allocations: 0,
+                })
+                .num_allocations += 1;
+        }
+
         Allocation {
             port,
             expires_at: now + lifetime.lifetime(),
             first_relay_addr,
             second_relay_addr,
+            relayed_bytes: 0,
+            username,
+            meter: Meter::new(self.limits.allocation, self.limits.quota_window, now),
+            offload_budgets: BTreeMap::default(),
         }
     }
 
@@ -894,6 +1236,19 @@ where
         self.ports.clone().count() as u16
     }
 
+    /// The current load of this relay, as advertised to clients.
+    fn load(&mut self, now: Instant) -> RelayLoad {
+        RelayLoad {
+            allocations: self.allocations.len() as u32,
+            capacity: if self.draining {
+                0
+            } else {
+                u32::from(self.max_available_ports())
+            },
+            relayed_bytes_per_sec: self.throughput.update(self.data_relayed, now),
+        }
+    }
+
     fn create_channel_binding(
         &mut self,
         client: ClientSocket,
@@ -1055,6 +1410,12 @@ where
             tracing::info!(%peer, %number, allocation = %port, "Deleted channel binding");
         }
 
+        // Keep the meter around after the last allocation is gone, otherwise a new allocation would reset the username's usage.
+        // `handle_timeout` removes it once it no longer restricts anything.
+        if let Some(username_meter) = self.meters_by_username.get_mut(&allocation.username) {
+            username_meter.num_allocations -= 1;
+        }
+
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
             port,
@@ -1142,6 +1503,22 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// The number of bytes relayed in userspace for this allocation, in both directions.
+    relayed_bytes: u64,
+
+    /// The salt of the username that created this allocation.
+    username: String,
+    meter: Meter,
+    /// The budgets we last handed to the eBPF kernel for each channel, see [`Server::handle_offloaded_traffic`].
+    offload_budgets: BTreeMap<ChannelNumber, u64>,
+}
+
+/// The usage of a username's limits, shared by all its allocations.
+#[derive(Debug)]
+struct UsernameMeter {
+    meter: Meter,
+    num_allocations: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -1352,7 +1729,8 @@ stun_codec::define_attribute_enums!(
         Username,
         RequestedAddressFamily,
         AdditionalAddressFamily,
-        Software
+        Software,
+        RelayLoad
     ]
 );
 
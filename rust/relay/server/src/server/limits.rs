@@ -0,0 +1,377 @@
+use std::time::{Duration, Instant};
+
+/// Rate limits and quotas for relayed traffic.
+///
+/// Limits are enforced per allocation and per username.
+/// The username of an allocation is the `salt` part of its TURN credentials, see [`crate::auth`].
+/// Thus, the per-username limits are shared by all concurrent allocations of the same client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub allocation: Limit,
+    pub username: Limit,
+    /// How often quotas reset, [`None`] if they never do.
+    ///
+    /// The per-username usage outlives the allocations of that username until this window expires.
+    /// Creating a new allocation therefore doesn't reset the per-username quota.
+    pub quota_window: Option<Duration>,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.allocation.is_unlimited() && self.username.is_unlimited()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limit {
+    /// The sustained rate in bytes per second.
+    pub rate: Option<u64>,
+    /// How many bytes may be relayed in a single burst.
+    ///
+    /// Defaults to one second worth of [`Limit::rate`].
+    pub burst: Option<u64>,
+    /// The total number of bytes that may be relayed.
+    pub quota: Option<u64>,
+}
+
+impl Limit {
+    pub fn is_unlimited(&self) -> bool {
+        self.rate.is_none() && self.quota.is_none()
+    }
+}
+
+/// Which part of a [`Limit`] has been hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exceeded {
+    Rate,
+    Quota,
+}
+
+impl Exceeded {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Exceeded::Rate => "rate",
+            Exceeded::Quota => "quota",
+        }
+    }
+}
+
+/// Tracks the usage of a single [`Limit`].
+#[derive(Debug, Clone)]
+pub(crate) struct Meter {
+    bucket: Option<TokenBucket>,
+    quota: Option<u64>,
+    quota_window: Option<Duration>,
+    used: u64,
+    window_started_at: Instant,
+}
+
+impl Meter {
+    pub(crate) fn new(limit: Limit, quota_window: Option<Duration>, now: Instant) -> Self {
+        Self {
+            bucket: limit
+                .rate
+                .map(|rate| TokenBucket::new(rate, limit.burst.unwrap_or(rate), now)),
+            quota: limit.quota,
+            quota_window,
+            used: 0,
+            window_started_at: now,
+        }
+    }
+
+    /// Whether this meter is indistinguishable from a new one, i.e. it no longer restricts anything.
+    pub(crate) fn is_idle(&mut self, now: Instant) -> bool {
+        self.reset_expired_quota(now);
+
+        let quota_is_unused = self.quota.is_none() || self.used == 0;
+        let bucket_is_full = self.bucket.as_mut().is_none_or(|b| b.is_full(now));
+
+        quota_is_unused && bucket_is_full
+    }
+
+    /// How many bytes we may currently relay, [`None`] if unlimited.
+    pub(crate) fn available(&mut self, now: Instant) -> Option<u64> {
+        self.reset_expired_quota(now);
+
+        let tokens = self.bucket.as_mut().map(|b| b.available(now));
+        let remaining_quota = self.quota.map(|q| q.saturating_sub(self.used));
+
+        match (tokens, remaining_quota) {
+            (Some(tokens), Some(quota)) => Some(tokens.min(quota)),
+            (Some(available), None) | (None, Some(available)) => Some(available),
+            (None, None) => None,
+        }
+    }
+
+    pub(crate) fn check(&mut self, num_bytes: u64, now: Instant) -> Result<(), Exceeded> {
+        self.reset_expired_quota(now);
+
+        if self
+            .quota
+            .is_some_and(|q| self.used.saturating_add(num_bytes) > q)
+        {
+            return Err(Exceeded::Quota);
+        }
+
+        if self
+            .bucket
+            .as_mut()
+            .is_some_and(|b| b.available(now) < num_bytes)
+        {
+            return Err(Exceeded::Rate);
+        }
+
+        Ok(())
+    }
+
+    /// Records the given number of bytes as relayed.
+    ///
+    /// This never fails: Traffic that has been relayed by the eBPF kernel needs to be accounted for after the fact.
+    pub(crate) fn consume(&mut self, num_bytes: u64, now: Instant) {
+        self.reset_expired_quota(now);
+
+        self.used = self.used.saturating_add(num_bytes);
+
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.consume(num_bytes, now);
+        }
+    }
+
+    /// Gives back bytes that we [`Meter::consume`]d upfront but didn't relay.
+    pub(crate) fn refund(&mut self, num_bytes: u64, now: Instant) {
+        self.used = self.used.saturating_sub(num_bytes);
+
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.refund(num_bytes, now);
+        }
+    }
+
+    fn reset_expired_quota(&mut self, now: Instant) {
+        let Some(window) = self.quota_window else {
+            return;
+        };
+
+        if now.saturating_duration_since(self.window_started_at) < window {
+            return;
+        }
+
+        self.used = 0;
+        self.window_started_at = now;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    /// Refill rate in tokens (i.e. bytes) per second.
+    rate: u64,
+    capacity: u64,
+
+    tokens: u64,
+    /// Tokens that have accrued since the last refill, in billionths of a token.
+    fractional_tokens: u128,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, capacity: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            fractional_tokens: 0,
+            last_refill: now,
+        }
+    }
+
+    fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+
+        self.tokens
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens == self.capacity
+    }
+
+    fn consume(&mut self, num_bytes: u64, now: Instant) {
+        self.refill(now);
+
+        self.tokens = self.tokens.saturating_sub(num_bytes);
+    }
+
+    fn refund(&mut self, num_bytes: u64, now: Instant) {
+        self.refill(now);
+
+        self.tokens = self.tokens.saturating_add(num_bytes).min(self.capacity);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+
+        if self.tokens == self.capacity {
+            self.fractional_tokens = 0; // A full bucket doesn't accumulate anything.
+            return;
+        }
+
+        let accrued = self
+            .fractional_tokens
+            .saturating_add(elapsed.as_nanos().saturating_mul(u128::from(self.rate)));
+        let new_tokens = u64::try_from(accrued / NANOS_PER_SEC).unwrap_or(u64::MAX);
+
+        self.tokens = self.tokens.saturating_add(new_tokens).min(self.capacity);
+        self.fractional_tokens = accrued % NANOS_PER_SEC;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_refills_up_to_burst() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: Some(1000),
+                burst: Some(1500),
+                quota: None,
+            },
+            None,
+            now,
+        );
+
+        assert_eq!(meter.check(1500, now), Ok(()));
+        meter.consume(1500, now);
+        assert_eq!(meter.check(1, now), Err(Exceeded::Rate));
+
+        let now = now + Duration::from_millis(500);
+        assert_eq!(meter.available(now), Some(500));
+
+        let now = now + Duration::from_secs(10);
+        assert_eq!(meter.available(now), Some(1500));
+    }
+
+    #[test]
+    fn fractional_tokens_accumulate() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: Some(1),
+                burst: None,
+                quota: None,
+            },
+            None,
+            now,
+        );
+        meter.consume(1, now);
+
+        for i in 1..10 {
+            assert_eq!(
+                meter.available(now + Duration::from_millis(i * 100)),
+                Some(0)
+            );
+        }
+
+        assert_eq!(meter.available(now + Duration::from_secs(1)), Some(1));
+    }
+
+    #[test]
+    fn quota_is_never_refilled() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: None,
+                burst: None,
+                quota: Some(100),
+            },
+            None,
+            now,
+        );
+
+        meter.consume(60, now);
+        assert_eq!(meter.check(41, now), Err(Exceeded::Quota));
+        assert_eq!(meter.check(40, now), Ok(()));
+
+        meter.consume(60, now); // Offloaded traffic may overshoot the quota.
+        assert_eq!(meter.available(now + Duration::from_secs(3600)), Some(0));
+    }
+
+    #[test]
+    fn fractional_tokens_survive_refills() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: Some(10),
+                burst: Some(100),
+                quota: None,
+            },
+            None,
+            now,
+        );
+        meter.consume(100, now);
+
+        // 150ms are worth 1.5 tokens each, the halves must add up.
+        for i in 1..=10 {
+            meter.available(now + Duration::from_millis(i * 150));
+        }
+
+        assert_eq!(meter.available(now + Duration::from_millis(1500)), Some(15));
+    }
+
+    #[test]
+    fn quota_resets_after_window() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: None,
+                burst: None,
+                quota: Some(100),
+            },
+            Some(Duration::from_secs(60)),
+            now,
+        );
+
+        meter.consume(100, now);
+        assert_eq!(meter.available(now + Duration::from_secs(59)), Some(0));
+        assert!(!meter.is_idle(now + Duration::from_secs(59)));
+
+        assert_eq!(meter.available(now + Duration::from_secs(60)), Some(100));
+        assert!(meter.is_idle(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn refunded_bytes_can_be_spent_again() {
+        let now = Instant::now();
+        let mut meter = Meter::new(
+            Limit {
+                rate: Some(1000),
+                burst: None,
+                quota: Some(5000),
+            },
+            None,
+            now,
+        );
+
+        meter.consume(800, now);
+        meter.refund(300, now);
+
+        assert_eq!(meter.available(now), Some(500));
+        assert_eq!(meter.check(500, now), Ok(()));
+    }
+
+    #[test]
+    fn unlimited_meter_has_no_budget() {
+        let now = Instant::now();
+        let mut meter = Meter::new(Limit::default(), None, now);
+
+        meter.consume(u64::MAX, now);
+
+        assert_eq!(meter.check(u64::MAX, now), Ok(()));
+        assert_eq!(meter.available(now), None);
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+/// Over how long we average the relayed bytes before updating the advertised throughput.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Measures how many bytes per second we relay, averaged over [`THROUGHPUT_WINDOW`].
+#[derive(Debug, Default)]
+pub(crate) struct Throughput {
+    window_start: Option<(Instant, u64)>,
+    bytes_per_sec: u64,
+}
+
+impl Throughput {
+    /// Returns the throughput of the last completed window, given the total number of bytes relayed so far.
+    pub(crate) fn update(&mut self, total_bytes: u64, now: Instant) -> u64 {
+        let Some((start, start_bytes)) = self.window_start else {
+            self.window_start = Some((now, total_bytes));
+            return self.bytes_per_sec;
+        };
+
+        let elapsed = now.duration_since(start);
+
+        if elapsed >= THROUGHPUT_WINDOW {
+            self.bytes_per_sec =
+                (total_bytes.saturating_sub(start_bytes) as f64 / elapsed.as_secs_f64()) as u64;
+            self.window_start = Some((now, total_bytes));
+        }
+
+        self.bytes_per_sec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_is_averaged_over_window() {
+        let mut throughput = Throughput::default();
+        let now = Instant::now();
+
+        assert_eq!(throughput.update(0, now), 0);
+        assert_eq!(throughput.update(1000, now + Duration::from_secs(1)), 0);
+        assert_eq!(throughput.update(5000, now + THROUGHPUT_WINDOW), 1000);
+    }
+}
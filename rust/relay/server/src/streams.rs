@@ -0,0 +1,313 @@
+//! TURN over TCP and TLS, for clients on networks that block UDP.
+//!
+//! STUN and channel-data messages are framed on the stream as per <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>, see [`turn_wire::stream`].
+//!
+//! Connections are unauthenticated until the client allocates, thus we limit how many we accept and how long they may stay silent.
+//!
+//! Only the client leg of an allocation uses the stream, traffic to and from peers is always UDP.
+
+use anyhow::{Context as _, Result};
+use bytes::{Buf as _, BytesMut};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::TcpListener,
+    sync::{Semaphore, mpsc},
+    time::Instant,
+};
+use tokio_rustls::TlsAcceptor;
+use turn_wire::stream::{decode, encode};
+
+/// How many messages we buffer per connection before we start dropping them.
+const MAX_BUFFERED_MESSAGES: usize = 1_000;
+
+/// How many TCP and TLS connections we accept at the same time, across all listeners.
+const MAX_CONNECTIONS: usize = 10_000;
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to send its first complete message after connecting.
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection may go without a complete message from the client.
+///
+/// Clients must refresh their allocation within its lifetime of 10 minutes, so a connection silent for longer is dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A dynamic collection of TCP and TLS connections from clients.
+///
+/// Each connection is driven by its own task.
+/// Connections are identified by the client's [`SocketAddr`], the same way a UDP client would be.
+pub struct Streams {
+    connections: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>,
+    connection_limit: Arc<Semaphore>,
+
+    event_tx: mpsc::Sender<Event>,
+    event_rx: mpsc::Receiver<Event>,
+}
+
+/// A message or disconnect from a client connected via TCP or TLS.
+#[derive(Debug)]
+pub enum Received {
+    Message { from: SocketAddr, message: Vec<u8> },
+    Disconnected(SocketAddr),
+}
+
+enum Event {
+    Connected {
+        client: SocketAddr,
+        outbound: mpsc::Sender<Vec<u8>>,
+    },
+    Received(Received),
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+        Self {
+            connections: Default::default(),
+            connection_limit: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Accepts TURN over TCP connections on the given address.
+    pub fn listen_tcp(&mut self, addr: SocketAddr) -> Result<()> {
+        let listener = bind(addr)?;
+
+        tokio::spawn(accept_loop(
+            listener,
+            None,
+            self.connection_limit.clone(),
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Accepts TURN over TLS connections on the given address.
+    pub fn listen_tls(&mut self, addr: SocketAddr, acceptor: TlsAcceptor) -> Result<()> {
+        let listener = bind(addr)?;
+
+        tokio::spawn(accept_loop(
+            listener,
+            Some(acceptor),
+            self.connection_limit.clone(),
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Whether the given client is connected via TCP or TLS.
+    pub fn is_connected(&self, client: SocketAddr) -> bool {
+        self.connections.contains_key(&client)
+    }
+
+    /// Queues a STUN or channel-data message to be sent to the given client.
+    ///
+    /// Fails if the client isn't connected or its connection is congested.
+    pub fn try_send(&mut self, client: SocketAddr, message: &[u8]) -> io::Result<()> {
+        let connection = self.connections.get(&client).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("No connection to {client}"),
+            )
+        })?;
+
+        connection.try_send(encode(message)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                io::Error::new(io::ErrorKind::WouldBlock, "Connection is congested")
+            }
+            mpsc::error::TrySendError::Closed(_) => {
+                io::Error::new(io::ErrorKind::NotConnected, "Connection is closed")
+            }
+        })
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        loop {
+            let event =
+                std::task::ready!(self.event_rx.poll_recv(cx)).expect("we hold a sender ourselves");
+
+            match event {
+                Event::Connected { client, outbound } => {
+                    self.connections.insert(client, outbound);
+                }
+                Event::Received(Received::Disconnected(client)) => {
+                    self.connections.remove(&client);
+
+                    return Poll::Ready(Received::Disconnected(client));
+                }
+                Event::Received(received) => return Poll::Ready(received),
+            }
+        }
+    }
+}
+
+/// Loads the certificate chain and private key for the TLS listener from the given PEM files.
+pub fn make_tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    use rustls_pki_types::pem::PemObject as _;
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .with_context(|| format!("Failed to open certificate file {}", cert.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse certificate chain")?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read private key from {}", key.display()))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Binds a TCP listener that, like our UDP sockets, only accepts connections of the IP version of `addr`.
+fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    use socket2::*;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .with_context(|| format!("Failed to bind TCP listener on {addr}"))?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    connection_limit: Arc<Semaphore>,
+    event_tx: mpsc::Sender<Event>,
+) {
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(ok) => ok,
+            Err(e) => {
+                tracing::debug!(target: "relay", "Failed to accept TCP connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connection_limit.clone().try_acquire_owned() else {
+            tracing::debug!(target: "relay", %client, "Too many TCP connections, dropping new one");
+            continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let event_tx = event_tx.clone();
+
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => run_connection(stream, client, event_tx.clone()).await,
+                        Ok(Err(e)) => Err(anyhow::Error::new(e).context("TLS handshake failed")),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                    }
+                }
+                None => run_connection(stream, client, event_tx.clone()).await,
+            };
+
+            if let Err(e) = result {
+                tracing::debug!(target: "relay", %client, "Connection failed: {e:#}");
+            }
+
+            let _ = event_tx
+                .send(Event::Received(Received::Disconnected(client)))
+                .await;
+
+            drop(permit);
+        });
+    }
+}
+
+async fn run_connection(
+    stream: impl AsyncRead + AsyncWrite,
+    client: SocketAddr,
+    event_tx: mpsc::Sender<Event>,
+) -> Result<()> {
+    let (outbound_tx, mut outbound_rx) = mpsc::channel::<Vec<u8>>(MAX_BUFFERED_MESSAGES);
+    event_tx
+        .send(Event::Connected {
+            client,
+            outbound: outbound_tx,
+        })
+        .await?;
+
+    tracing::debug!(target: "relay", %client, "Client connected");
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Reading and writing run concurrently so a slow reader on the client's side doesn't stop us from receiving.
+    let write = async {
+        while let Some(frame) = outbound_rx.recv().await {
+            writer
+                .write_all(&frame)
+                .await
+                .context("Failed to write to stream")?;
+        }
+
+        anyhow::Ok(())
+    };
+    let read = async {
+        let mut read_buffer = BytesMut::new();
+        let mut deadline = Instant::now() + FIRST_MESSAGE_TIMEOUT;
+
+        loop {
+            // The deadline only moves on complete messages, trickling single bytes doesn't keep the connection alive.
+            let read = tokio::time::timeout_at(deadline, reader.read_buf(&mut read_buffer))
+                .await
+                .context("Connection timed out")?
+                .context("Failed to read from stream")?;
+            if read == 0 {
+                return anyhow::Ok(());
+            }
+
+            while let Some((message, frame_len)) = decode(&read_buffer)? {
+                let message = message.to_vec();
+                read_buffer.advance(frame_len);
+                deadline = Instant::now() + IDLE_TIMEOUT;
+
+                event_tx
+                    .send(Event::Received(Received::Message {
+                        from: client,
+                        message,
+                    }))
+                    .await?;
+            }
+        }
+    };
+
+    tokio::select! {
+        result = write => result,
+        result = read => result,
+    }
+}
@@ -3,9 +3,11 @@
 use Output::{CreateAllocation, FreeAllocation};
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
-    AddressFamily, Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, SOFTWARE, Server,
+    AddressFamily, Allocate, AllocationPort, AllocationStats, Attribute, Binding, ChannelBind,
+    ChannelData, ClientMessage, ClientSocket, Command, IpStack, Limit, Limits, PeerSocket, Refresh,
+    RelayLoad, SOFTWARE, Server,
 };
+use proptest::prop_assume;
 use rand::rngs::mock::StepRng;
 use secrecy::SecretString;
 use std::iter;
@@ -17,6 +19,7 @@ use stun_codec::rfc5389::attributes::{
 use stun_codec::rfc5389::errors::Unauthorized;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
+use stun_codec::rfc5766::errors::InsufficientCapacity;
 use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
 use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
 use test_strategy::proptest;
@@ -371,6 +374,484 @@ fn freeing_allocation_clears_all_channels(
     assert_eq!(server.server.num_active_channels(), 0);
 }
 
+#[proptest]
+fn revoking_allocation_frees_it_and_its_channels(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                client_to_peer_ping.channel(),
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_channel_binding(source, client_to_peer_ping.channel(), peer, 49152),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let _ = server.server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(source.into()),
+        now,
+    );
+
+    let now = now + Duration::from_secs(60);
+
+    assert_eq!(
+        server.server.allocations(now),
+        vec![AllocationStats {
+            port: AllocationPort::new(49152),
+            client: ClientSocket::new(source.into()),
+            expires_in: lifetime.lifetime() - Duration::from_secs(60),
+            num_active_channels: 1,
+            num_relayed_bytes: client_to_peer_ping.data().len() as u64,
+        }]
+    );
+
+    server.assert_commands(
+        revoke(49152),
+        [
+            delete_channel_binding(source, client_to_peer_ping.channel(), peer, 49152),
+            free_allocation(49152, AddressFamily::V4),
+        ],
+    );
+
+    assert_eq!(server.server.allocations(now), vec![]);
+    assert_eq!(server.server.num_active_channels(), 0);
+    assert!(!server.server.revoke_allocation(AllocationPort::new(49152)));
+}
+
+#[proptest]
+fn disconnecting_stream_client_frees_its_allocation(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    server.allocate_and_bind(source, peer, channel, &username_salt, nonce, now);
+
+    server.assert_commands(
+        disconnect(source),
+        [
+            delete_channel_binding(source, channel, peer, 49152),
+            free_allocation(49152, AddressFamily::V4),
+        ],
+    );
+
+    assert_eq!(server.server.num_allocations(), 0);
+    assert_eq!(server.server.num_active_channels(), 0);
+}
+
+#[proptest]
+fn draining_rejects_new_allocations_but_refreshes_existing_ones(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    server.allocate_and_bind(source, peer, channel, &username_salt, nonce, now);
+
+    server.server.start_draining();
+
+    let other_client = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+    server.assert_commands(
+        from_client(
+            other_client,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                None,
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            other_client,
+            insufficient_capacity_allocate_response(allocate_transaction_id),
+        )],
+    );
+
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 5)).unwrap();
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            source,
+            refresh_response(refresh_transaction_id, lifetime),
+        )],
+    );
+
+    assert_eq!(server.server.num_allocations(), 1);
+}
+
+#[proptest]
+fn allocation_rate_limit_drops_traffic_exceeding_burst(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] packet: ChannelData<'static>,
+    #[strategy(1..2_000u64)] rate: u64,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation: Limit {
+                rate: Some(rate),
+                burst: None,
+                quota: None,
+            },
+            username: Limit::default(),
+            quota_window: None,
+        });
+    server.allocate_and_bind(source, peer, packet.channel(), &username_salt, nonce, now);
+
+    let len = packet.data().len() as u64;
+    let num_packets = rate / len.max(1) + 1;
+    // The burst defaults to one second worth of traffic.
+    let expected_forwarded = if len == 0 { num_packets } else { rate / len };
+
+    let num_forwarded = (0..num_packets)
+        .filter(|_| {
+            server
+                .server
+                .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now)
+                .is_some()
+        })
+        .count() as u64;
+
+    assert_eq!(num_forwarded, expected_forwarded);
+    assert_eq!(
+        server.server.num_limit_hits(),
+        num_packets - expected_forwarded
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    let maybe_forward =
+        server
+            .server
+            .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now);
+
+    assert_eq!(maybe_forward.is_some(), len <= rate);
+}
+
+#[proptest]
+fn allocation_quota_applies_to_both_directions(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer: ChannelData<'static>,
+    peer_to_client: Vec<u8>,
+    #[strategy(0..1_000u64)] quota: u64,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation: Limit {
+                rate: None,
+                burst: None,
+                quota: Some(quota),
+            },
+            username: Limit::default(),
+            quota_window: None,
+        });
+    server.allocate_and_bind(
+        source,
+        peer,
+        client_to_peer.channel(),
+        &username_salt,
+        nonce,
+        now,
+    );
+
+    let mut used = 0;
+
+    for i in 0..20 {
+        let now = now + Duration::from_secs(i); // Quotas don't refill over time.
+
+        let len = client_to_peer.data().len() as u64;
+        let maybe_forward = server.server.handle_client_input(
+            client_to_peer.as_msg(),
+            ClientSocket::new(source.into()),
+            now,
+        );
+        let expected_forward = used + len <= quota;
+        if expected_forward {
+            used += len;
+        }
+
+        assert_eq!(maybe_forward.is_some(), expected_forward);
+
+        let len = peer_to_client.len() as u64;
+        let maybe_forward = server.server.handle_peer_traffic(
+            &peer_to_client,
+            PeerSocket::new(peer.into()),
+            AllocationPort::new(49152),
+            now,
+        );
+        let expected_forward = used + len <= quota;
+        if expected_forward {
+            used += len;
+        }
+
+        assert_eq!(maybe_forward.is_some(), expected_forward);
+    }
+}
+
+#[proptest]
+fn username_quota_is_shared_between_allocations(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source1: SocketAddrV4,
+    #[filter(#source1 != #source2)] source2: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] packet: ChannelData<'static>,
+    #[strategy(0..1_000u64)] quota: u64,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::with_distinct_ports(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation: Limit::default(),
+            username: Limit {
+                rate: None,
+                burst: None,
+                quota: Some(quota),
+            },
+            quota_window: None,
+        });
+    server.allocate_and_bind(source1, peer, packet.channel(), &username_salt, nonce, now);
+    server.allocate_and_bind(source2, peer, packet.channel(), &username_salt, nonce, now);
+
+    assert_eq!(server.server.num_allocations(), 2);
+
+    let len = packet.data().len() as u64;
+    let mut used = 0;
+
+    for source in [source1, source2].into_iter().cycle().take(20) {
+        let maybe_forward = server.server.handle_client_input(
+            packet.as_msg(),
+            ClientSocket::new(source.into()),
+            now,
+        );
+        let expected_forward = used + len <= quota;
+        if expected_forward {
+            used += len;
+        }
+
+        assert_eq!(maybe_forward.is_some(), expected_forward);
+    }
+}
+
+#[proptest]
+fn username_quota_survives_reallocation_until_window_expires(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] packet: ChannelData<'static>,
+    #[strategy(0..1_000u64)] quota: u64,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let len = packet.data().len() as u64;
+    prop_assume!(len > 0);
+
+    let now = Instant::now();
+    let window = Duration::from_secs(3600);
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation: Limit::default(),
+            username: Limit {
+                rate: None,
+                burst: None,
+                quota: Some(quota),
+            },
+            quota_window: Some(window),
+        });
+    server.allocate_and_bind(source, peer, packet.channel(), &username_salt, nonce, now);
+
+    let num_forwarded = (0..=(quota / len))
+        .filter(|_| {
+            server
+                .server
+                .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now)
+                .is_some()
+        })
+        .count() as u64;
+    assert_eq!(num_forwarded, quota / len);
+
+    assert!(server.server.revoke_allocation(AllocationPort::new(49152)));
+    server.allocate_and_bind(source, peer, packet.channel(), &username_salt, nonce, now);
+
+    let maybe_forward =
+        server
+            .server
+            .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now);
+    assert!(maybe_forward.is_none());
+
+    let now = now + window;
+    server.server.handle_timeout(now);
+
+    let maybe_forward =
+        server
+            .server
+            .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now);
+    assert_eq!(maybe_forward.is_some(), len <= quota);
+}
+
+#[proptest]
+fn offloaded_budget_cannot_be_spent_in_userspace(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] packet: ChannelData<'static>,
+    #[strategy(0..1_000u64)] quota: u64,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let port = AllocationPort::new(49152);
+
+    let _guard = logging::test("debug");
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation: Limit {
+                rate: None,
+                burst: None,
+                quota: Some(quota),
+            },
+            username: Limit::default(),
+            quota_window: None,
+        });
+    server.allocate_and_bind(source, peer, packet.channel(), &username_salt, nonce, now);
+
+    let budgets = server
+        .server
+        .handle_offloaded_traffic(port, &[(packet.channel(), None)], now);
+    assert_eq!(budgets, vec![(packet.channel(), quota / 2)]);
+
+    let len = packet.data().len() as u64;
+    let maybe_forward =
+        server
+            .server
+            .handle_client_input(packet.as_msg(), ClientSocket::new(source.into()), now);
+    let forwarded = maybe_forward.is_some();
+    assert_eq!(forwarded, len <= quota - quota / 2);
+
+    // The kernel didn't spend anything, so we get the entire budget back.
+    let used_in_userspace = if forwarded { len } else { 0 };
+    let budgets = server.server.handle_offloaded_traffic(
+        port,
+        &[(packet.channel(), Some((quota / 2) as i64))],
+        now,
+    );
+    assert_eq!(
+        budgets,
+        vec![(packet.channel(), (quota - used_in_userspace) / 2)]
+    );
+}
+
 // #[test]
 // fn server_waits_for_5_minutes_before_allowing_reuse_of_channel_number_after_expiry() {
 //     // todo!()
@@ -474,6 +955,7 @@ fn ping_pong_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -710,6 +1192,7 @@ fn ping_pong_ip6_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -729,12 +1212,75 @@ impl TestServer {
         }
     }
 
+    /// Creates a server that picks a different port for each allocation.
+    fn with_distinct_ports(relay_public_addr: impl Into<IpStack>) -> Self {
+        Self {
+            server: Server::new(
+                relay_public_addr,
+                StepRng::new(0, 1 << 18),
+                3478,
+                49152..=65535,
+            ),
+        }
+    }
+
     fn with_nonce(mut self, nonce: Uuid) -> Self {
         self.server.add_nonce(nonce);
 
         self
     }
 
+    fn with_limits(mut self, limits: Limits) -> Self {
+        self.server = self.server.with_limits(limits);
+
+        self
+    }
+
+    /// Creates an allocation for `source` with a channel to `peer`, ignoring all commands.
+    fn allocate_and_bind(
+        &mut self,
+        source: SocketAddrV4,
+        peer: SocketAddrV4,
+        channel: ChannelNumber,
+        username_salt: &str,
+        nonce: Uuid,
+        now: Instant,
+    ) {
+        let secret = self.auth_secret().to_owned();
+
+        let _ = self.server.handle_client_message(
+            ClientMessage::Allocate(
+                Allocate::new_authenticated_udp_implicit_ip4(
+                    TransactionId::new([0; 12]),
+                    None,
+                    valid_username(username_salt),
+                    &secret,
+                    nonce,
+                )
+                .unwrap(),
+            ),
+            ClientSocket::new(source.into()),
+            now,
+        );
+        let _ = self.server.handle_client_message(
+            ClientMessage::ChannelBind(
+                ChannelBind::new(
+                    TransactionId::new([1; 12]),
+                    channel,
+                    XorPeerAddress::new(peer.into()),
+                    valid_username(username_salt),
+                    &secret,
+                    nonce,
+                )
+                .unwrap(),
+            ),
+            ClientSocket::new(source.into()),
+            now,
+        );
+
+        while self.server.next_command().is_some() {}
+    }
+
     fn auth_secret(&self) -> &SecretString {
         self.server.auth_secret()
     }
@@ -747,6 +1293,12 @@ impl TestServer {
             Input::Time(now) => {
                 self.server.handle_timeout(now);
             }
+            Input::Revoke(port) => {
+                assert!(self.server.revoke_allocation(port));
+            }
+            Input::Disconnect(client) => {
+                self.server.handle_client_disconnected(client);
+            }
         }
 
         for expected_output in output {
@@ -888,6 +1440,7 @@ fn binding_response(
         Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, transaction_id);
     message.add_attribute(SOFTWARE.clone());
     message.add_attribute(XorMappedAddress::new(address.into()));
+    message.add_attribute(relay_load(0));
 
     message
 }
@@ -908,10 +1461,20 @@ fn allocate_response(
     )));
     message.add_attribute(XorMappedAddress::new(source.into()));
     message.add_attribute(lifetime.clone());
+    message.add_attribute(relay_load(1));
 
     message
 }
 
+/// The load advertised by a [`TestServer`] with the given number of allocations and no relayed traffic yet.
+fn relay_load(allocations: u32) -> RelayLoad {
+    RelayLoad {
+        allocations,
+        capacity: 16384, // 49152..=65535
+        relayed_bytes_per_sec: 0,
+    }
+}
+
 fn unauthorized_allocate_response(
     transaction_id: TransactionId,
     nonce: Uuid,
@@ -926,6 +1489,15 @@ fn unauthorized_allocate_response(
     message
 }
 
+fn insufficient_capacity_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(SOFTWARE.clone());
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+    message
+}
+
 fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::SuccessResponse, REFRESH, transaction_id);
@@ -953,6 +1525,8 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, Instant),
     Time(Instant),
+    Revoke(AllocationPort),
+    Disconnect(ClientSocket),
 }
 
 fn from_client<'a>(
@@ -967,6 +1541,14 @@ fn forward_time_to<'a>(when: Instant) -> Input<'a> {
     Input::Time(when)
 }
 
+fn revoke<'a>(port: u16) -> Input<'a> {
+    Input::Revoke(AllocationPort::new(port))
+}
+
+fn disconnect<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnect(ClientSocket::new(client.into()))
+}
+
 #[derive(Debug)]
 enum Output {
     SendMessage((ClientSocket, Message<Attribute>)),
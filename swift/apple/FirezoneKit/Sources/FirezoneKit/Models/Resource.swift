@@ -67,6 +67,7 @@ public enum ResourceStatus: String, Codable, Sendable {
   case offline = "Offline"
   case online = "Online"
   case unknown = "Unknown"
+  case degraded = "Degraded"
 
   public func toSiteStatus() -> String {
     switch self {
@@ -76,6 +77,8 @@ public enum ResourceStatus: String, Codable, Sendable {
       return "Gateway connected"
     case .unknown:
       return "No activity"
+    case .degraded:
+      return "Resource degraded"
     }
   }
 
@@ -91,6 +94,8 @@ public enum ResourceStatus: String, Codable, Sendable {
         No connection has been attempted to Resources in this Site.
         Access a Resource to establish a Gateway connection.
         """
+    case .degraded:
+      return "The Gateway reports that this Resource is not fully reachable from its network."
     }
   }
 
@@ -104,6 +109,8 @@ public enum ResourceStatus: String, Codable, Sendable {
         return NSImage(named: NSImage.statusUnavailableName)
       case .unknown:
         return NSImage(named: NSImage.statusNoneName)
+      case .degraded:
+        return NSImage(named: NSImage.statusPartiallyAvailableName)
       }
     }
   #endif
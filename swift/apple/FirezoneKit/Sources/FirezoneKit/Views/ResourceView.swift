@@ -94,6 +94,8 @@ import SwiftUI
         return .red
       case .unknown:
         return .gray
+      case .degraded:
+        return .yellow
       }
     }
   }
@@ -541,6 +541,7 @@ extension FirezoneKit.ResourceStatus {
     case .unknown: self = .unknown
     case .online: self = .online
     case .offline: self = .offline
+    case .degraded: self = .degraded
     }
   }
 }
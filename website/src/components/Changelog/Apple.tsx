@@ -25,6 +25,34 @@ export default function Apple() {
     <Entries downloadLinks={downloadLinks} title="macOS / iOS">
       {/* When you cut a release, remove any solved issues from the "known issues" lists over in `client-apps`. This must not be done when the issue's PR merges. */}
       <Unreleased>
+        <ChangeItem>
+          Moves new flows to another Gateway of the Site when a Gateway is
+          draining, so existing connections survive Gateway restarts.
+        </ChangeItem>
+        <ChangeItem>
+          Marks Resources as degraded when the Gateway reports that they are
+          not fully reachable from its network.
+        </ChangeItem>
+        <ChangeItem>
+          Forwards DNS queries for configured zones to a dedicated upstream
+          resolver, routing them through the tunnel if the resolver is part of
+          a resource.
+        </ChangeItem>
+        <ChangeItem>
+          Keeps connections to multiple Gateways of a Site and spreads new
+          flows across them, failing over to another Gateway if one becomes
+          unavailable.
+        </ChangeItem>
+        <ChangeItem>
+          Prefers Relays that are closer and less loaded when setting up new
+          connections and only keeps allocations on the two most suitable
+          Relays.
+        </ChangeItem>
+        <ChangeItem>
+          Discovers the MTU of the path to each Gateway and Client by probing
+          it and replies with ICMP &quot;packet too big&quot; errors for packets
+          that exceed it.
+        </ChangeItem>
         <ChangeItem pull="11988">
           Fixes a crash if the currently active log file gets deleted.
         </ChangeItem>
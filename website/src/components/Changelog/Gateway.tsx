@@ -23,6 +23,65 @@ export default function Gateway() {
   return (
     <Entries downloadLinks={downloadLinks} title="Gateway">
       <Unreleased>
+        <ChangeItem>
+          Translates packets between IPv4 and IPv6 (NAT64 / NAT46) for DNS
+          Resources that only resolve to addresses of one IP version, instead
+          of responding with an ICMP unreachable error.
+        </ChangeItem>
+        <ChangeItem>
+          Adds a drain mode, triggered by <code>SIGUSR1</code>. A draining
+          Gateway rejects new Clients, asks connected Clients to move new flows
+          to other Gateways of the Site and shuts down once existing flows have
+          ended or <code>--max-drain-time</code> has passed.
+        </ChangeItem>
+        <ChangeItem>
+          Reports Resources and domains to Clients as unhealthy when resolving
+          them fails or their traffic shows TCP reset storms or ICMP
+          unreachable errors.
+        </ChangeItem>
+        <ChangeItem>
+          Adds <code>FIREZONE_RESOURCE_PROBES</code> to periodically probe
+          Resources and report unhealthy ones to Clients, as metrics and via the
+          health-check endpoint.
+        </ChangeItem>
+        <ChangeItem>
+          Adds <code>FIREZONE_DNS_QUERY_LOGS</code> to log DNS queries resolved
+          on behalf of Clients through the same sinks as flow logs.
+        </ChangeItem>
+        <ChangeItem>
+          Prefers Relays that are closer and less loaded when setting up new
+          connections and only keeps allocations on the two most suitable
+          Relays.
+        </ChangeItem>
+        <ChangeItem>
+          Discovers the MTU of the path to each Gateway and Client by probing
+          it and replies with ICMP &quot;packet too big&quot; errors for packets
+          that exceed it.
+        </ChangeItem>
+        <ChangeItem>
+          Enables TCP and UDP segmentation offloads on the TUN device on Linux,
+          significantly increasing throughput of TCP connections.
+        </ChangeItem>
+        <ChangeItem>
+          Installs the nftables rules for forwarding and masquerading traffic
+          from Clients itself, on all interfaces with a default route, and
+          updates them when the default routes change. Fails to start if the
+          rules cannot be installed, pass <code>--no-manage-firewall</code> to
+          manage them yourself.
+        </ChangeItem>
+        <ChangeItem>
+          Falls back to connecting to Relays via TCP and TLS if UDP traffic to
+          them is blocked.
+        </ChangeItem>
+        <ChangeItem>
+          Adds <code>FIREZONE_DNS_UPSTREAM</code> and{" "}
+          <code>FIREZONE_DNS_SPLIT</code> to resolve DNS Resources via specific
+          Do53 or DoH servers instead of the system&apos;s resolvers.
+        </ChangeItem>
+        <ChangeItem>
+          Adds <code>FIREZONE_FLOW_LOGS_SINK</code> to export flow logs to a
+          rotating NDJSON file, an IPFIX collector or a syslog server.
+        </ChangeItem>
         <ChangeItem pull="12134">
           Fixes an issue where outdated and thus irrelevant candidates were sent
           to Clients, causing connectivity issues in rare situations.
@@ -10,6 +10,56 @@ export default function Headless({ os }: { os: OS }) {
     <Entries downloadLinks={downloadLinks(os)} title={title(os)}>
       {/* When you cut a release, remove any solved issues from the "known issues" lists over in `client-apps`. This must not be done when the issue's PR merges. */}
       <Unreleased>
+        {os === OS.Linux && (
+          <ChangeItem>
+            Adds per-application split tunneling for the Internet Resource
+            with <code>--internet-resource-include-cgroup</code> and{" "}
+            <code>--internet-resource-exclude-cgroup</code>, which select
+            processes by their cgroup v2.
+          </ChangeItem>
+        )}
+        <ChangeItem>
+          Moves new flows to another Gateway of the Site when a Gateway is
+          draining, so existing connections survive Gateway restarts.
+        </ChangeItem>
+        <ChangeItem>
+          Adds a userspace mode, enabled with <code>--userspace</code>, that
+          runs without a TUN device or DNS control and exposes Resources
+          through local SOCKS5 and HTTP CONNECT proxies.
+        </ChangeItem>
+        <ChangeItem>
+          Forwards DNS queries for configured zones to a dedicated upstream
+          resolver, routing them through the tunnel if the resolver is part of
+          a resource.
+        </ChangeItem>
+        <ChangeItem>
+          Keeps connections to multiple Gateways of a Site and spreads new
+          flows across them, failing over to another Gateway if one becomes
+          unavailable.
+        </ChangeItem>
+        <ChangeItem>
+          Prefers Relays that are closer and less loaded when setting up new
+          connections and only keeps allocations on the two most suitable
+          Relays.
+        </ChangeItem>
+        <ChangeItem>
+          Discovers the MTU of the path to each Gateway and Client by probing
+          it and replies with ICMP &quot;packet too big&quot; errors for packets
+          that exceed it.
+        </ChangeItem>
+        <ChangeItem>
+          Enables TCP and UDP segmentation offloads on the TUN device on Linux,
+          significantly increasing throughput of TCP connections.
+        </ChangeItem>
+        <ChangeItem>
+          Optionally persists the DNS cache across restarts via
+          <code>--persist-dns-cache</code> and answers queries from expired
+          cache entries if the upstream DNS servers are unreachable.
+        </ChangeItem>
+        <ChangeItem>
+          Falls back to connecting to Relays via TCP and TLS if UDP traffic to
+          them is blocked.
+        </ChangeItem>
         <ChangeItem pull="#12111">
           Prevents unbounded log growth by enforcing a 100 MB log size cap with
           automatic cleanup of oldest files.
@@ -37,6 +87,14 @@ export default function Headless({ os }: { os: OS }) {
           for IPv4-only DNS resources if the setting was only changed after a
           DNS query had already been processed.
         </ChangeItem>
+        {os === OS.Linux && (
+          <ChangeItem>
+            Adds a local control API on a Unix socket together with the{" "}
+            <code>firezone client status</code>,{" "}
+            <code>firezone client resources</code> and{" "}
+            <code>firezone client reset</code> commands.
+          </ChangeItem>
+        )}
       </Unreleased>
       <Entry version="1.5.6" date={new Date("2026-01-06")}>
         <ChangeItem pull="11627">